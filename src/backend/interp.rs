@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::jit::bind_var;
+use super::{Backend, BackendResult, Consts, LAST_RESULT};
+use crate::codegen::{FnProto, FnProtos, ProtoOrigin};
+use crate::fold;
+use crate::parser::{
+    binary_fn_name, unary_fn_name, ConstAST, ExprAST, FunctionAST, LogicalOp, PrototypeAST,
+};
+use crate::typeck;
+
+/// Function of the C math library named `name`, which an `extern` declaration in the interpreter
+/// can refer to.
+fn builtin(name: &str) -> Option<fn(f64) -> f64> {
+    match name {
+        "sin" => Some(f64::sin),
+        "cos" => Some(f64::cos),
+        "tan" => Some(f64::tan),
+        "sqrt" => Some(f64::sqrt),
+        "exp" => Some(f64::exp),
+        "log" => Some(f64::ln),
+        "fabs" => Some(f64::abs),
+        "floor" => Some(f64::floor),
+        "ceil" => Some(f64::ceil),
+        _ => None,
+    }
+}
+
+/// Reason for leaving the evaluation of an expression early.
+enum Unwind {
+    /// `break` out of the innermost loop.
+    Break,
+    /// `continue` with the next iteration of the innermost loop.
+    Continue,
+    /// Evaluation failed with the error message.
+    Error(String),
+}
+
+impl Unwind {
+    /// Turn unwinding out of a function body into an error.
+    ///
+    /// The parser only accepts `break` and `continue` in loop bodies, hence they don't leave a
+    /// function body.
+    fn into_error(self) -> String {
+        match self {
+            Unwind::Break => "'break' outside of a loop body".into(),
+            Unwind::Continue => "'continue' outside of a loop body".into(),
+            Unwind::Error(err) => err,
+        }
+    }
+}
+
+impl From<String> for Unwind {
+    fn from(err: String) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+/// Backend evaluating the AST directly, without generating any code.
+///
+/// The interpreter follows the semantics of the code generated by [`Codegen`](crate::codegen),
+/// which makes it a reference to test the LLVM backends against. Externs can only be called if
+/// they name one of the supported functions of the C math library, eg `sin`.
+#[derive(Default)]
+pub struct InterpBackend {
+    /// Keep track of prototype names to their respective ASTs, for type checking.
+    fn_protos: FnProtos,
+
+    /// Definitions of the functions defined so far.
+    fns: HashMap<String, Rc<FunctionAST>>,
+
+    /// Global constants defined so far.
+    consts: Consts,
+
+    /// Result of the last top-level expression, bound to [`LAST_RESULT`] in the next top-level
+    /// expression.
+    last_result: Option<f64>,
+
+    /// Fold constant expressions before evaluating them.
+    fold: bool,
+
+    /// Number of lambdas lifted so far, see [`Backend::lambdas`].
+    lambdas: usize,
+}
+
+impl InterpBackend {
+    /// Create a new interpreter without any functions defined.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enable or disable folding constant expressions of the functions before evaluating them,
+    /// see [`fold`].
+    pub fn set_fold(&mut self, fold: bool) {
+        self.fold = fold;
+    }
+
+    /// Evaluate the expression `expr` with the variables in `scope`, the innermost variable last.
+    ///
+    /// `break` and `continue` unwind to the innermost loop, see [`Unwind`].
+    fn eval(&self, expr: &ExprAST, scope: &mut Vec<(String, f64)>) -> Result<f64, Unwind> {
+        match expr {
+            ExprAST::Number(num) => Ok(*num),
+            ExprAST::Str(_) => Err(Unwind::Error(
+                "Strings are not supported by the interpreter yet".into(),
+            )),
+            ExprAST::Variable(name) => scope
+                .iter()
+                .rev()
+                .find(|(var, _)| var == name)
+                .map(|(_, val)| *val)
+                .ok_or_else(|| format!("Unknown variable '{}'", name).into()),
+            ExprAST::Unary(op, operand) => {
+                let v = self.eval(operand, scope)?;
+                match op.as_str() {
+                    "-" => Ok(-v),
+                    _ => Ok(self.call(&unary_fn_name(op), vec![v])?),
+                }
+            }
+            ExprAST::Binary(op, lhs, rhs) => {
+                let l = self.eval(lhs, scope)?;
+                let r = self.eval(rhs, scope)?;
+                match fold::eval_binop(op, l, r) {
+                    Some(v) => Ok(v),
+                    None => Ok(self.call(&binary_fn_name(op), vec![l, r])?),
+                }
+            }
+            ExprAST::Logical(op, lhs, rhs) => {
+                let truthy = fold::is_true;
+                let res = match op {
+                    LogicalOp::And => {
+                        truthy(self.eval(lhs, scope)?) && truthy(self.eval(rhs, scope)?)
+                    }
+                    LogicalOp::Or => {
+                        truthy(self.eval(lhs, scope)?) || truthy(self.eval(rhs, scope)?)
+                    }
+                };
+                Ok(if res { 1.0 } else { 0.0 })
+            }
+            ExprAST::Call(callee, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, scope))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(self.call(callee, args)?)
+            }
+            ExprAST::If { cond, then, else_ } => {
+                let cond = self.eval(cond, scope)?;
+                if fold::is_true(cond) {
+                    self.eval(then, scope)
+                } else {
+                    self.eval(else_, scope)
+                }
+            }
+            ExprAST::For {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let start = self.eval(start, scope)?;
+
+                // The body runs at least once, the end condition is checked after the body with
+                // the value of the loop variable before the step.
+                scope.push((var.clone(), start));
+                let res = (|| loop {
+                    match self.eval(body, scope) {
+                        Ok(_) | Err(Unwind::Continue) => {}
+                        Err(Unwind::Break) => return Ok(()),
+                        Err(err) => return Err(err),
+                    }
+                    let step = match step {
+                        Some(step) => self.eval(step, scope)?,
+                        None => 1.0,
+                    };
+                    let end = self.eval(end, scope)?;
+
+                    let (_, val) = scope.last_mut().expect("Loop variable must be in scope");
+                    *val += step;
+                    if end == 0.0 || end.is_nan() {
+                        return Ok(());
+                    }
+                })();
+                scope.pop();
+
+                // A for loop always evaluates to 0.0.
+                res.map(|_| 0.0)
+            }
+            ExprAST::VarIn { vars, body } => {
+                // Each variable is in scope from the initializer of the next variable on.
+                let depth = scope.len();
+                let res = (|| {
+                    for (var, init) in vars {
+                        let val = match init {
+                            Some(init) => self.eval(init, scope)?,
+                            None => 0.0,
+                        };
+                        scope.push((var.clone(), val));
+                    }
+                    self.eval(body, scope)
+                })();
+                scope.truncate(depth);
+                res
+            }
+            ExprAST::Assign { name, value } => {
+                let value = self.eval(value, scope)?;
+                let (_, var) = scope
+                    .iter_mut()
+                    .rev()
+                    .find(|(var, _)| var == name)
+                    .ok_or_else(|| Unwind::Error(format!("Unknown variable '{}'", name)))?;
+                *var = value;
+                Ok(value)
+            }
+            ExprAST::Match {
+                value,
+                arms,
+                default,
+            } => {
+                let value = self.eval(value, scope)?;
+                let arm = arms
+                    .iter()
+                    .find(|(pattern, _)| *pattern == value)
+                    .map_or(&**default, |(_, arm)| arm);
+                self.eval(arm, scope)
+            }
+            ExprAST::Break => Err(Unwind::Break),
+            ExprAST::Continue => Err(Unwind::Continue),
+            ExprAST::Lambda(..) => Err(Unwind::Error(
+                "Lambdas must be lifted before evaluation".into(),
+            )),
+        }
+    }
+
+    /// Call the function `name` with the arguments `args`.
+    fn call(&self, name: &str, args: Vec<f64>) -> Result<f64, String> {
+        if let Some(func) = self.fns.get(name) {
+            let FunctionAST(proto, body) = func.as_ref();
+            let mut scope = proto.1.iter().cloned().zip(args).collect();
+            return self.eval(body, &mut scope).map_err(Unwind::into_error);
+        }
+
+        match builtin(name) {
+            Some(f) if args.len() == 1 => Ok(f(args[0])),
+            _ => Err(format!(
+                "Function '{}' is not available in the interpreter",
+                name
+            )),
+        }
+    }
+}
+
+impl Backend for InterpBackend {
+    fn compile_prototype(&mut self, proto: &PrototypeAST) -> BackendResult<()> {
+        let fn_proto = FnProto {
+            proto: Rc::new(proto.clone()),
+            origin: ProtoOrigin::Extern,
+        };
+        self.fn_protos.insert(proto.0.clone(), fn_proto);
+        Ok(())
+    }
+
+    fn compile_function(&mut self, func: &FunctionAST) -> BackendResult<()> {
+        let mut func = func.clone();
+        self.consts.bind(&mut func);
+        typeck::check_function(&func, &self.fn_protos)?;
+
+        let name = func.0 .0.clone();
+        let fn_proto = FnProto {
+            proto: Rc::new(func.0.node.clone()),
+            origin: ProtoOrigin::Def,
+        };
+        self.fn_protos.insert(name.clone(), fn_proto);
+
+        if self.fold {
+            fold::fold_function(&mut func);
+        }
+        self.fns.insert(name, Rc::new(func));
+        Ok(())
+    }
+
+    fn call_top_level(&mut self, func: &FunctionAST) -> BackendResult<Option<f64>> {
+        // Substitute the result of the previous top-level expression for `it`.
+        let mut func = func.clone();
+        if let Some(value) = self.last_result {
+            bind_var(&mut func.1, LAST_RESULT, value);
+        }
+        self.consts.bind(&mut func);
+        typeck::check_function(&func, &self.fn_protos)?;
+        if self.fold {
+            fold::fold_function(&mut func);
+        }
+
+        let res = self
+            .eval(&func.1, &mut Vec::new())
+            .map_err(Unwind::into_error)?;
+        self.last_result = Some(res);
+        Ok(Some(res))
+    }
+
+    fn compile_const(&mut self, c: &ConstAST) -> BackendResult<()> {
+        self.consts.define(c)
+    }
+
+    fn operators(&self) -> Vec<Rc<PrototypeAST>> {
+        super::operators(&self.fn_protos)
+    }
+
+    fn lambdas(&mut self) -> &mut usize {
+        &mut self.lambdas
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InterpBackend;
+    use crate::backend::{Backend, BackendResult};
+    use crate::parser::{parse_items, Item};
+
+    fn run(src: &str) -> Vec<BackendResult<Option<f64>>> {
+        run_with(InterpBackend::new(), src)
+    }
+
+    fn run_with(mut backend: InterpBackend, src: &str) -> Vec<BackendResult<Option<f64>>> {
+        parse_items(src)
+            .unwrap()
+            .into_iter()
+            .map(|item| match item {
+                Item::Def(func) => backend.compile_function(&func).map(|_| None),
+                Item::Extern(proto) => backend.compile_prototype(&proto).map(|_| None),
+                Item::TopLevel(func) => backend.call_top_level(&func),
+                Item::Const(c) => backend.compile_const(&c).map(|_| None),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interpret() {
+        let res = run("def fib(x) if x < 3 then 1 else fib(x-1) + fib(x-2); fib(10); it * 2");
+        assert_eq!(res, vec![Ok(None), Ok(Some(55.0)), Ok(Some(110.0))]);
+
+        // Comparisons with NaN are true, conditions are false.
+        let res = run(
+            "def sq(x) x * x; def inf() sq(sq(sq(sq(sq(sq(sq(sq(sq(10))))))))); \
+             def nan() inf() - inf(); nan() < 1; if nan() then 1 else 2; \
+             nan() >= nan(); nan() == nan(); nan() != nan(); 1 > 2; 2 <= 2",
+        );
+        assert_eq!(
+            res[3..],
+            [1.0, 2.0, 1.0, 0.0, 1.0, 0.0, 1.0].map(|v| Ok(Some(v)))
+        );
+        assert_eq!(run("for i = 0, i < 3 in i")[0], Ok(Some(0.0)));
+        assert_eq!(
+            run("var a = 2, b = a * 3, c in a + b + c")[0],
+            Ok(Some(8.0))
+        );
+        assert_eq!(run("def neg(x) -x; 2 - -neg(-3)")[1], Ok(Some(5.0)));
+        assert_eq!(run("def f(x) x % 3 + 2 ^ x; f(5)")[1], Ok(Some(34.0)));
+        assert_eq!(
+            run("def binary| 5 (a b) if a then 1 else b; def unary!(v) v == 0; !1 | 2 * 3")[2],
+            Ok(Some(6.0))
+        );
+        assert_eq!(
+            run("def f(x) match x * 2 with 2 -> 10 | 4 -> 20 | _ -> x; f(1) + f(2) + f(5)")[1],
+            Ok(Some(35.0))
+        );
+
+        // The right operand of '&&' and '||' only runs if the left one doesn't decide the result.
+        let res = run("extern putchard(c); 0 && putchard(1); 2 || putchard(1); 0 || -1");
+        assert_eq!(res[1..], [Ok(Some(0.0)), Ok(Some(1.0)), Ok(Some(1.0))]);
+        assert_eq!(
+            run("def f(x) var a = x in (for i = 0, i < 3 in a = a * 2) + a; f(1)")[1],
+            Ok(Some(16.0))
+        );
+        assert_eq!(
+            run("def f() var s = 0 in (for i = 0, i < 10 in \
+                 if i == 5 then break else if i == 2 then continue else s = s + i) + s; f()")[1],
+            Ok(Some(8.0))
+        );
+
+        let mut backend = InterpBackend::new();
+        backend.set_fold(true);
+        let res = run_with(backend, "def f(x) x * (2 + 1); f(2); it + (1 < 2)");
+        assert_eq!(res, vec![Ok(None), Ok(Some(6.0)), Ok(Some(7.0))]);
+
+        // Constants can refer to earlier constants and are shadowed by parameters.
+        let res = run("const a = 2; const b = -a * 3; def f(a) a + b; f(1) + a; const c = f(1)");
+        assert_eq!(res[3], Ok(Some(-3.0)));
+        assert_eq!(
+            res[4],
+            Err("Initializer of constant 'c' is not a constant expression".into())
+        );
+
+        let res = run("extern sin(x); extern putchard(c); sin(0); putchard(42); y");
+        assert_eq!(res[2], Ok(Some(0.0)));
+        assert!(res[3].is_err());
+        assert_eq!(res[4], Err("unknown variable 'y'".into()));
+    }
+}
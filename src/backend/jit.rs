@@ -0,0 +1,1060 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use super::{Backend, BackendResult, Consts, ItemTiming};
+use crate::codegen::{
+    check_redeclaration, Codegen, CodegenOptions, CodegenStats, FnProto, FnProtos, ProtoOrigin,
+};
+use crate::llvm::{Context, Disassembler, JitFn, LLJit, Module, ResourceTracker};
+use crate::parser::{
+    binary_fn_name, parse_items, unary_fn_name, ConstAST, ExprAST, FunctionAST, Item, PrototypeAST,
+    ANON_EXPR,
+};
+use crate::stdlib;
+use crate::typeck;
+use crate::Either;
+
+/// Name of the variable bound to the result of the last top-level expression.
+pub const LAST_RESULT: &str = "it";
+
+/// Backend compiling items to LLVM IR and executing them with the LLVM JIT.
+///
+/// We jit every function definition into its own LLVM module, all modules share a single LLVM
+/// context. The backend keeps track of the prototypes and the JIT resources of all the functions
+/// defined so far and removes stale entries once they are superseded.
+///
+/// In batch mode, see [`JitBackend::set_batch_definitions`], consecutive definitions are linked
+/// into one module instead, which is added to the JIT before the next top-level expression.
+pub struct JitBackend<'jit> {
+    jit: &'jit LLJit,
+
+    /// LLVM context shared by all modules of the backend.
+    ctx: Context,
+
+    /// LLVM module the next item is emitted into.
+    module: Module,
+
+    /// Keep track of prototype names to their respective ASTs.
+    ///
+    /// To allow calling functions defined in previous LLVM modules we keep track of their
+    /// prototypes and generate IR for their declarations when they are called from another
+    /// module.
+    fn_protos: FnProtos,
+
+    /// Prototypes of the native functions registered with [`JitBackend::register_native`].
+    natives: FnProtos,
+
+    /// Code of the functions defined in the JIT.
+    fn_jit_rt: JitResources<'jit>,
+
+    /// Definitions not added to the JIT yet, `None` if batch mode is disabled.
+    batch: Option<Batch>,
+
+    /// ASTs of the functions added to the JIT as part of a batch or of the standard library.
+    ///
+    /// Redefining one of them removes the whole module from the JIT, the other functions of
+    /// the module are compiled again from their AST.
+    batch_asts: HashMap<String, FunctionAST>,
+
+    /// Defer compiling function bodies until first use.
+    lazy: bool,
+
+    /// Definitions of the functions whose compilation is deferred.
+    lazy_fns: HashMap<String, FunctionAST>,
+
+    /// Textual LLVM IR of the last definition of each function, in the order the functions were
+    /// first defined.
+    fn_ir: Vec<(String, String)>,
+
+    /// Functions declared in the modules of the definitions in `fn_ir` with their number of
+    /// arguments, eg callees compiled in other modules and intrinsics.
+    fn_decls: HashMap<String, usize>,
+
+    opts: CodegenOptions,
+
+    /// Dump the LLVM IR of each compiled item to stderr.
+    dump_ir: bool,
+
+    /// Statistics about the code generated by this backend.
+    stats: CodegenStats,
+
+    /// Phase timings of each compiled item, in order.
+    timings: Vec<ItemTiming>,
+
+    /// Parse time recorded by the driver for the next item.
+    parse_time: Duration,
+
+    /// Result of the last top-level expression, bound to [`LAST_RESULT`] in the next top-level
+    /// expression.
+    last_result: Option<f64>,
+
+    /// Global constants defined so far.
+    consts: Consts,
+
+    /// Number of top-level expressions compiled so far, used for the unique names
+    /// `__anon_expr.N` of their functions.
+    ///
+    /// Unlike the other state it is kept by [`JitBackend::reset`], such that a name is never
+    /// reused while the JIT may still hold a symbol of that name.
+    anon_exprs: usize,
+
+    /// Number of lambdas lifted so far, see [`Backend::lambdas`]. Kept by
+    /// [`JitBackend::reset`] for the same reason as `anon_exprs`.
+    lambdas: usize,
+}
+
+impl<'jit> JitBackend<'jit> {
+    /// Create a new backend emitting code into `jit` using the codegen options `opts`.
+    pub fn new(jit: &'jit LLJit, opts: CodegenOptions) -> Self {
+        let ctx = Context::new();
+        JitBackend {
+            jit,
+            module: Module::with_ctx(&ctx),
+            ctx,
+            fn_protos: HashMap::new(),
+            natives: HashMap::new(),
+            fn_jit_rt: JitResources::default(),
+            batch: None,
+            batch_asts: HashMap::new(),
+            lazy: false,
+            lazy_fns: HashMap::new(),
+            fn_ir: Vec::new(),
+            fn_decls: HashMap::new(),
+            opts,
+            dump_ir: true,
+            stats: CodegenStats::default(),
+            timings: Vec::new(),
+            parse_time: Duration::default(),
+            last_result: None,
+            consts: Consts::default(),
+            anon_exprs: 0,
+            lambdas: 0,
+        }
+    }
+
+    /// Compile the function `func` into the current module.
+    ///
+    /// If codegen fails, the partially emitted module is discarded and the prototype of a
+    /// previous definition with the same name is restored.
+    fn codegen_function(&mut self, func: &FunctionAST) -> BackendResult<()> {
+        let name = &func.0 .0;
+        let prev_proto = self.fn_protos.get(name).cloned();
+
+        // Report unknown names before emitting any IR.
+        typeck::check_function(func, &self.fn_protos)?;
+
+        let start = Instant::now();
+        match Codegen::compile(
+            &self.module,
+            &mut self.fn_protos,
+            &self.opts,
+            &mut self.stats,
+            Either::B(func),
+        ) {
+            Ok(func_ir) => {
+                let elapsed = start.elapsed();
+                let opt = self
+                    .stats
+                    .fn_stats
+                    .last()
+                    .map_or(Duration::ZERO, |s| s.opt_time);
+
+                if self.dump_ir {
+                    func_ir.dump();
+                }
+
+                self.push_timing(name, elapsed - opt, opt);
+                Ok(())
+            }
+            Err(err) => {
+                match prev_proto {
+                    Some(proto) => self.fn_protos.insert(name.clone(), proto),
+                    None => self.fn_protos.remove(name),
+                };
+                self.module = Module::with_ctx(&self.ctx);
+                Err(err)
+            }
+        }
+    }
+
+    /// Record the timing of the item `name` compiled last, consuming the parse time recorded by
+    /// the driver.
+    fn push_timing(&mut self, name: &str, codegen: Duration, opt: Duration) {
+        self.timings.push(ItemTiming {
+            name: name.into(),
+            parse: std::mem::take(&mut self.parse_time),
+            codegen,
+            opt,
+            ..Default::default()
+        });
+    }
+
+    /// Get the timing of the item compiled last.
+    fn last_timing(&mut self) -> &mut ItemTiming {
+        self.timings
+            .last_mut()
+            .expect("Timing must be recorded after codegen!")
+    }
+
+    /// Add the current module to the JIT and initialize a new module.
+    fn add_module(&mut self) -> ResourceTracker<'jit> {
+        let module = std::mem::replace(&mut self.module, Module::with_ctx(&self.ctx));
+        self.jit.add_module(module)
+    }
+
+    /// Compile the function `func` and hand it over to the JIT.
+    fn define_function(&mut self, func: &FunctionAST) -> BackendResult<()> {
+        self.codegen_function(func)?;
+
+        // Keep the IR of the definition around, the module is handed over to the JIT.
+        let name = &func.0 .0;
+        let fn_ir = self
+            .module
+            .get_fn(name)
+            .expect("Function must be defined in the module after codegen!")
+            .print_to_string();
+        match self.fn_ir.iter_mut().find(|(n, _)| n == name) {
+            Some((_, ir)) => *ir = fn_ir,
+            None => self.fn_ir.push((name.clone(), fn_ir)),
+        }
+        record_decls(&self.module, &mut self.fn_decls);
+
+        if self.batch.is_some() {
+            self.batch_asts.insert(name.clone(), func.clone());
+        }
+        self.install_function(name)
+    }
+
+    /// Compile the deferred definition of the function `name`, if any, together with the
+    /// deferred functions it calls.
+    fn materialize(&mut self, name: &str) -> BackendResult<()> {
+        let func = match self.lazy_fns.remove(name) {
+            Some(func) => func,
+            None => return Ok(()),
+        };
+
+        // The parse time recorded by the driver belongs to the item which triggered the
+        // compilation.
+        let parse_time = std::mem::take(&mut self.parse_time);
+        let res = self
+            .materialize_calls(&func.1)
+            .and_then(|_| self.define_function(&func));
+        self.parse_time = parse_time;
+
+        res.map_err(|err| {
+            self.fn_protos.remove(name);
+            // Drop the span, it points into the deferred function instead of the current item.
+            format!("In deferred function '{}': {}", name, err).into()
+        })
+    }
+
+    /// Compile the deferred definitions of all functions called in `expr`.
+    fn materialize_calls(&mut self, expr: &ExprAST) -> BackendResult<()> {
+        let mut callees = Vec::new();
+        collect_calls(expr, &mut callees);
+        callees
+            .iter()
+            .try_for_each(|callee| self.materialize(callee))
+    }
+
+    /// Compile the deferred definitions of all functions.
+    pub fn materialize_all(&mut self) -> BackendResult<()> {
+        let mut names: Vec<String> = self.lazy_fns.keys().cloned().collect();
+        names.sort();
+        names.iter().try_for_each(|name| self.materialize(name))
+    }
+
+    /// Enable or disable deferring the compilation of function definitions, disabled by default.
+    ///
+    /// When enabled, only the prototype of a new function is recorded and its body is compiled on
+    /// first use, that is when a top-level expression or a compiled function calls it. This keeps
+    /// loading big libraries cheap, as only the functions actually called are compiled.
+    /// Redefinitions of compiled functions are not deferred.
+    pub fn set_lazy_definitions(&mut self, lazy: bool) {
+        self.lazy = lazy;
+    }
+
+    /// Check if the function `name` is pending in batch mode.
+    fn is_pending(&self, name: &str) -> bool {
+        matches!(&self.batch, Some(batch) if batch.names.iter().any(|n| n == name))
+    }
+
+    /// Hand over the current module defining the function `name` either to the JIT or to the
+    /// pending batch.
+    fn install_function(&mut self, name: &str) -> BackendResult<()> {
+        // A module can only define a function once, add the pending batch with the previous
+        // definition to the JIT first, it is removed below.
+        if self.is_pending(name) {
+            self.flush();
+        }
+
+        // If we already jitted that function, remove the last definition from the JIT. This must
+        // happen before adding the new module to the JIT, as otherwise the symbol would be defined
+        // twice.
+        let siblings = self.fn_jit_rt.remove(name);
+
+        if let Some(batch) = &mut self.batch {
+            let module = std::mem::replace(&mut self.module, Module::with_ctx(&self.ctx));
+            match &batch.module {
+                Some(batch_module) => batch_module.link(module)?,
+                None => batch.module = Some(module),
+            }
+            batch.names.push(name.into());
+        } else {
+            // Add module to the JIT and keep track of the ResourceTracker to keep the module code
+            // in the JIT.
+            //
+            // The JIT compiles lazily, the machine code is only generated once the function is
+            // looked up when called from a top-level expression.
+            let start = Instant::now();
+            let rt = self.add_module();
+            self.last_timing().jit = start.elapsed();
+
+            self.fn_jit_rt.insert(vec![name.into()], rt);
+            self.batch_asts.remove(name);
+        }
+
+        // Compile the functions removed together with the last definition again, without dumping
+        // their unchanged IR.
+        let dump_ir = std::mem::replace(&mut self.dump_ir, false);
+        let res = siblings.iter().try_for_each(|sibling| {
+            let func = self.batch_asts[sibling].clone();
+            self.codegen_function(&func)?;
+            self.install_function(sibling)
+        });
+        self.dump_ir = dump_ir;
+        res
+    }
+
+    /// Enable or disable batch mode, disabled by default.
+    ///
+    /// In batch mode consecutive function definitions are collected in one module, which gives
+    /// the optimizer the chance to inline calls between them and reduces the per module overhead
+    /// of the JIT. The batch is added to the JIT before the next top-level expression or when
+    /// calling [`JitBackend::flush`].
+    ///
+    /// Disabling batch mode flushes the pending definitions.
+    pub fn set_batch_definitions(&mut self, enable: bool) {
+        if enable {
+            self.batch.get_or_insert_with(Batch::default);
+        } else {
+            self.flush();
+            self.batch = None;
+        }
+    }
+
+    /// Add the definitions pending in batch mode to the JIT.
+    pub fn flush(&mut self) {
+        let batch = match &mut self.batch {
+            Some(batch) => std::mem::take(batch),
+            None => return,
+        };
+
+        if let Some(module) = batch.module {
+            let rt = self.jit.add_module(module);
+            self.fn_jit_rt.insert(batch.names, rt);
+        }
+    }
+
+    /// Load the [standard library](stdlib) into the session.
+    ///
+    /// The library is compiled into a single module. If `cache_dir` is given, the compiled module
+    /// is stored there as bitcode and read back in later sessions instead of compiling the
+    /// library again. Failing to read or write the cache is not an error.
+    pub fn load_stdlib(&mut self, cache_dir: Option<&Path>) -> BackendResult<()> {
+        let items = parse_items(stdlib::SOURCE)
+            .map_err(|errs| format!("Invalid standard library: {}", errs[0]))?;
+        let file = stdlib::cache_file_name(self.opts.opt_level, &self.jit.triple());
+        let cache = cache_dir
+            .map(|dir| dir.join(file))
+            .and_then(|path| path.to_str().map(String::from));
+
+        // A cache which can't be read, eg written by another LLVM version, or which doesn't define
+        // the library is ignored and overwritten.
+        let cached = cache
+            .as_ref()
+            .and_then(|path| Module::read_bitcode_file(&self.ctx, path).ok())
+            .filter(|module| is_stdlib_module(module, &items));
+        let module = match cached {
+            Some(module) => module,
+            None => {
+                // The cached library must not depend on the session, eg on the address of the
+                // interrupt flag, hence only the optimization level is taken over.
+                let opts = CodegenOptions {
+                    opt_level: self.opts.opt_level,
+                    ..Default::default()
+                };
+
+                let module = Module::with_ctx(&self.ctx);
+                let mut stats = CodegenStats::default();
+                for item in &items {
+                    let compilee = match item {
+                        Item::Def(func) => Either::B(&func.node),
+                        Item::Extern(proto) => Either::A(&proto.node),
+                        Item::TopLevel(_) | Item::Const(_) => continue,
+                    };
+                    Codegen::compile(&module, &mut self.fn_protos, &opts, &mut stats, compilee)?;
+                }
+
+                if let Some(path) = &cache {
+                    let _ = Path::new(path).parent().map(std::fs::create_dir_all);
+                    let _ = module.write_bitcode_to_file(path);
+                }
+                module
+            }
+        };
+
+        // Register the library functions like definitions of the session, such that they can be
+        // redefined.
+        let mut names = Vec::new();
+        for item in items {
+            let (proto, origin) = match &item {
+                Item::Def(func) => (&func.0.node, ProtoOrigin::Def),
+                Item::Extern(proto) => (&proto.node, ProtoOrigin::Extern),
+                Item::TopLevel(_) | Item::Const(_) => continue,
+            };
+            let fn_proto = FnProto {
+                proto: Rc::new(proto.clone()),
+                origin,
+            };
+            self.fn_protos.insert(proto.0.clone(), fn_proto);
+
+            if let Item::Def(func) = item {
+                let name = func.0 .0.clone();
+                if let Some(fn_value) = module.get_fn(&name) {
+                    self.fn_ir.push((name.clone(), fn_value.print_to_string()));
+                }
+                self.batch_asts.insert(name.clone(), func.node);
+                names.push(name);
+            }
+        }
+
+        record_decls(&module, &mut self.fn_decls);
+        let rt = self.jit.add_module(module);
+        self.fn_jit_rt.insert(names, rt);
+        Ok(())
+    }
+
+    /// Register the native function `name` taking `arity` arguments at the address `addr`.
+    ///
+    /// The function is defined as absolute symbol in the JIT and declared like an extern, such
+    /// that kaleidoscope code can call it without an `extern` declaration. Native functions
+    /// can't be redefined and are kept across [`JitBackend::reset`].
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be the address of a function taking `arity` f64 arguments and returning f64,
+    /// which stays valid as long as the JIT.
+    pub unsafe fn register_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        addr: u64,
+    ) -> BackendResult<()> {
+        if self.fn_jit_rt.contains(name)
+            || self.is_pending(name)
+            || self.lazy_fns.contains_key(name)
+            || self.natives.contains_key(name)
+        {
+            return Err(format!("Function '{}' is already defined", name).into());
+        }
+        if let Some(fn_proto) = self.fn_protos.get(name) {
+            let args = fn_proto.proto.1.len();
+            if args != arity {
+                return Err(format!(
+                    "type mismatch: function '{}' is declared with {} argument(s), found {}",
+                    name, args, arity
+                )
+                .into());
+            }
+        }
+
+        self.jit.define_absolute(name, addr)?;
+
+        let params = (0..arity).map(|i| format!("x{}", i)).collect();
+        let fn_proto = FnProto {
+            proto: Rc::new(PrototypeAST(name.into(), params, 0)),
+            origin: ProtoOrigin::Extern,
+        };
+        self.fn_protos.insert(name.into(), fn_proto.clone());
+        self.natives.insert(name.into(), fn_proto);
+        Ok(())
+    }
+
+    /// Get mutable access to the codegen options used for the following items.
+    pub fn opts_mut(&mut self) -> &mut CodegenOptions {
+        &mut self.opts
+    }
+
+    /// Reset the backend to a fresh session.
+    ///
+    /// Removes the code of all functions from the JIT, forgets all prototypes and starts over
+    /// with a new module and empty statistics. The codegen options and the native functions are
+    /// kept.
+    pub fn reset(&mut self) {
+        // Dropping the ResourceTrackers removes the code from the JIT.
+        self.fn_jit_rt.clear();
+        self.jit.prune_objects(|_| false);
+        if let Some(batch) = &mut self.batch {
+            *batch = Batch::default();
+        }
+        self.batch_asts.clear();
+        self.lazy_fns.clear();
+        self.fn_protos = self.natives.clone();
+        self.fn_ir.clear();
+        self.fn_decls.clear();
+        self.module = Module::with_ctx(&self.ctx);
+        self.stats = CodegenStats::default();
+        self.timings.clear();
+        self.last_result = None;
+        self.consts = Consts::default();
+    }
+
+    /// Disassemble the machine code of the function `name` defined in the JIT.
+    ///
+    /// Requires the object capture of the JIT to be enabled, see
+    /// [`LLJit::enable_object_capture`]. Definitions pending in batch mode and deferred
+    /// definitions are not in the JIT yet.
+    pub fn disassemble(&self, name: &str) -> BackendResult<String> {
+        if self.is_pending(name) {
+            return Err(format!("Function '{}' is not added to the JIT yet", name).into());
+        }
+        if self.lazy_fns.contains_key(name) {
+            return Err(format!("Function '{}' is not compiled yet", name).into());
+        }
+        if !self.fn_jit_rt.contains(name) {
+            return Err(format!("Unknown function '{}'", name).into());
+        }
+
+        let (addr, code) = self
+            .jit
+            .function_code(name)
+            .ok_or_else(|| format!("No machine code found for function '{}'", name))?;
+
+        let mut out = format!("{}:\n", name);
+        for (pc, insn) in Disassembler::host().disassemble(&code, addr) {
+            out.push_str(&format!("  {:#x}: {}\n", pc, insn));
+        }
+        Ok(out)
+    }
+
+    /// Look up the function `name` defined in the JIT, which must take `arity` arguments.
+    ///
+    /// Definitions pending in batch mode and deferred definitions are not in the JIT yet.
+    pub fn lookup<F: JitFn>(&self, name: &str, arity: usize) -> BackendResult<F> {
+        if self.is_pending(name) {
+            return Err(format!("Function '{}' is not added to the JIT yet", name).into());
+        }
+        if self.lazy_fns.contains_key(name) {
+            return Err(format!("Function '{}' is not compiled yet", name).into());
+        }
+        if !self.fn_jit_rt.contains(name) {
+            return Err(format!("Unknown function '{}'", name).into());
+        }
+
+        let args = self.fn_protos[name].proto.1.len();
+        if args != arity {
+            return Err(format!(
+                "type mismatch: function '{}' takes {} argument(s), found {}",
+                name, args, arity
+            )
+            .into());
+        }
+        Ok(self.jit.find_symbol(name)?)
+    }
+
+    /// Phase timings of each item compiled so far, in order.
+    pub fn timings(&self) -> &[ItemTiming] {
+        &self.timings
+    }
+
+    /// Get the LLVM IR of all functions defined so far as a self-contained textual IR module.
+    ///
+    /// Only the last definition of each function is included. Functions declared with `extern`
+    /// and all other functions called by the definitions, eg intrinsics, are emitted as
+    /// declarations. Top-level expressions are not included.
+    pub fn session_ir(&self) -> String {
+        let mut ir = String::from("; ModuleID = 'session'\n");
+
+        let externs = self
+            .fn_protos
+            .values()
+            .filter(|p| p.origin == ProtoOrigin::Extern)
+            .map(|p| (p.proto.0.as_str(), p.proto.1.len()));
+        let decls = self
+            .fn_decls
+            .iter()
+            .map(|(name, args)| (name.as_str(), *args));
+        let mut decls: Vec<_> = externs
+            .chain(decls)
+            .filter(|(name, _)| !self.fn_ir.iter().any(|(n, _)| n == name))
+            .collect();
+        decls.sort_unstable();
+        decls.dedup_by_key(|(name, _)| *name);
+
+        for (name, args) in decls {
+            let args = vec!["double"; args].join(", ");
+            ir.push_str(&format!("\ndeclare double {}({})\n", ir_global(name), args));
+        }
+
+        for (_, fn_ir) in &self.fn_ir {
+            ir.push('\n');
+            ir.push_str(fn_ir);
+        }
+
+        ir
+    }
+
+    /// Enable or disable dumping the LLVM IR of each compiled item, enabled by default.
+    pub fn set_dump_ir(&mut self, dump_ir: bool) {
+        self.dump_ir = dump_ir;
+    }
+
+    /// Statistics about the code generated by this backend.
+    pub fn stats(&self) -> &CodegenStats {
+        &self.stats
+    }
+
+    /// Dump the LLVM IR of the module currently emitted into to stdout.
+    pub fn dump(&self) {
+        self.module.dump();
+    }
+}
+
+impl Backend for JitBackend<'_> {
+    fn compile_prototype(&mut self, proto: &PrototypeAST) -> BackendResult<()> {
+        let start = Instant::now();
+        let proto_ir = Codegen::compile(
+            &self.module,
+            &mut self.fn_protos,
+            &self.opts,
+            &mut self.stats,
+            Either::A(proto),
+        )?;
+        let elapsed = start.elapsed();
+
+        if self.dump_ir {
+            proto_ir.dump();
+        }
+
+        self.push_timing(&proto.0, elapsed, Duration::ZERO);
+        Ok(())
+    }
+
+    fn compile_function(&mut self, func: &FunctionAST) -> BackendResult<()> {
+        let mut func = func.clone();
+        self.consts.bind(&mut func);
+        let name = &func.0 .0;
+
+        if self.natives.contains_key(name) {
+            return Err(format!(
+                "Function '{}' is a native function and can't be redefined",
+                name
+            )
+            .into());
+        }
+
+        // Only defer the body of new functions, redefinitions replace the code in the JIT right
+        // away.
+        if self.lazy && !self.fn_jit_rt.contains(name) && !self.is_pending(name) {
+            // Run the checks of the eager path right away, such that errors are reported at the
+            // definition instead of at the first call.
+            check_redeclaration(&self.fn_protos, &func.0)?;
+            typeck::check_function(&func, &self.fn_protos)?;
+
+            let fn_proto = FnProto {
+                proto: Rc::new(func.0.node.clone()),
+                origin: ProtoOrigin::Def,
+            };
+            self.fn_protos.insert(name.clone(), fn_proto);
+            self.lazy_fns.insert(name.clone(), func.clone());
+            self.push_timing(name, Duration::ZERO, Duration::ZERO);
+            return Ok(());
+        }
+
+        // The deferred functions called by the definition must be in the JIT before it.
+        self.lazy_fns.remove(name);
+        self.materialize_calls(&func.1)?;
+        self.define_function(&func)
+    }
+
+    fn call_top_level(&mut self, func: &FunctionAST) -> BackendResult<Option<f64>> {
+        // The expression may call deferred definitions or definitions still pending in batch
+        // mode.
+        self.materialize_calls(&func.1)?;
+        self.flush();
+
+        // Compile the expression into a function with a unique name, such that its symbol can't
+        // clash with the one of a previous expression still in the JIT.
+        let mut func = func.clone();
+        let name = format!("{}.{}", ANON_EXPR, self.anon_exprs);
+        self.anon_exprs += 1;
+        func.0.node.0 = name.clone();
+
+        // Substitute the result of the previous top-level expression for `it`.
+        if let Some(value) = self.last_result {
+            bind_var(&mut func.1, LAST_RESULT, value);
+        }
+        self.consts.bind(&mut func);
+        self.codegen_function(&func)?;
+
+        // Add module to the JIT. Code will be removed when `rt` is dropped.
+        let start = Instant::now();
+        let rt = self.add_module();
+
+        // Looking up the symbol compiles the module to machine code, which fails if the expression
+        // calls an undefined function.
+        let fp = match self.jit.find_symbol::<unsafe extern "C" fn() -> f64>(&name) {
+            Ok(fp) => fp,
+            Err(err) => {
+                drop(rt);
+                self.fn_protos.remove(&name);
+                return Err(err.into());
+            }
+        };
+        self.last_timing().jit = start.elapsed();
+
+        // Discard interrupts which arrived before the evaluation started.
+        if let Some(flag) = self.opts.interrupt_flag {
+            flag.store(false, Ordering::SeqCst);
+        }
+
+        // Call the top level expression.
+        let start = Instant::now();
+        let res = unsafe { fp() };
+        self.last_timing().exec = start.elapsed();
+
+        // The anonymous function is not referenced anymore, remove it from the JIT as well as its
+        // prototype. Forget the machine code of the expression and of superseded definitions, the
+        // lookup may have emitted new object files.
+        drop(rt);
+        self.fn_protos.remove(&name);
+        self.jit.prune_objects(|name| self.fn_jit_rt.contains(name));
+
+        // The result of an interrupted evaluation is meaningless.
+        if let Some(flag) = self.opts.interrupt_flag {
+            if flag.swap(false, Ordering::SeqCst) {
+                return Err("Evaluation interrupted".into());
+            }
+        }
+
+        self.last_result = Some(res);
+        Ok(Some(res))
+    }
+
+    fn compile_const(&mut self, c: &ConstAST) -> BackendResult<()> {
+        self.consts.define(c)
+    }
+
+    fn operators(&self) -> Vec<Rc<PrototypeAST>> {
+        super::operators(&self.fn_protos)
+    }
+
+    fn lambdas(&mut self) -> &mut usize {
+        &mut self.lambdas
+    }
+}
+
+/// Check that `module` is a valid module defining the functions of the library `items`.
+fn is_stdlib_module(module: &Module, items: &[Item]) -> bool {
+    let defines = |PrototypeAST(name, args, _): &PrototypeAST| matches!(module.get_fn(name), Some(f) if f.basic_blocks() > 0 && f.args() == args.len());
+    module.verify().is_ok()
+        && items.iter().all(|item| match item {
+            Item::Def(func) => defines(&func.0),
+            _ => true,
+        })
+}
+
+/// Record the functions declared in `module` in `decls`, the definitions in the module may call
+/// them.
+fn record_decls(module: &Module, decls: &mut HashMap<String, usize>) {
+    for f in module.functions() {
+        if f.basic_blocks() == 0 {
+            decls.insert(f.get_name().into(), f.args());
+        }
+    }
+}
+
+/// Write the global `name` like LLVM in textual IR, eg `@"binary|"`.
+///
+/// Names with chars not allowed in plain names are quoted, quotes, backslashes and non-printable
+/// chars are escaped as `\XX`.
+fn ir_global(name: &str) -> String {
+    let plain = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-$._".contains(c));
+    if plain {
+        return format!("@{}", name);
+    }
+
+    let mut out = String::from("@\"");
+    for b in name.bytes() {
+        if (b' '..=b'~').contains(&b) && b != b'\\' && b != b'"' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\{:02X}", b));
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Code of the functions defined in the JIT.
+///
+/// When adding an IR module to the JIT, it will hand out a ResourceTracker. When the
+/// ResourceTracker is dropped, the code generated from the corresponding module will be removed
+/// from the JIT.
+///
+/// For each function we want to keep the code generated for the last definition, hence we need to
+/// keep their ResourceTracker alive. Functions added to the JIT in the same module share their
+/// ResourceTracker.
+#[derive(Default)]
+struct JitResources<'jit>(HashMap<String, Rc<ResourceTracker<'jit>>>);
+
+impl<'jit> JitResources<'jit> {
+    /// Keep the code of the functions `names` added to the JIT with the tracker `rt` alive.
+    fn insert(&mut self, names: Vec<String>, rt: ResourceTracker<'jit>) {
+        let rt = Rc::new(rt);
+        for name in names {
+            self.0.insert(name, Rc::clone(&rt));
+        }
+    }
+
+    /// Check if the function `name` is defined in the JIT.
+    fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// Remove the code of the function `name` from the JIT.
+    ///
+    /// The code of a module is only removed as a whole, return the names of the other functions
+    /// removed with it.
+    fn remove(&mut self, name: &str) -> Vec<String> {
+        let rt = match self.0.remove(name) {
+            Some(rt) => rt,
+            None => return Vec::new(),
+        };
+
+        let siblings: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(_, other)| Rc::ptr_eq(other, &rt))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for sibling in &siblings {
+            self.0.remove(sibling);
+        }
+
+        // Dropping the last reference to the ResourceTracker removes the code from the JIT.
+        drop(rt);
+        siblings
+    }
+
+    /// Remove the code of all functions from the JIT.
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Definitions collected in batch mode, see [`JitBackend::set_batch_definitions`].
+#[derive(Default)]
+struct Batch {
+    /// Module the definitions are linked into.
+    module: Option<Module>,
+    /// Names of the functions defined in `module`.
+    names: Vec<String>,
+}
+
+/// Add `callee` to `callees` unless it is already contained.
+fn push_callee(callee: String, callees: &mut Vec<String>) {
+    if !callees.contains(&callee) {
+        callees.push(callee);
+    }
+}
+
+/// Collect the names of all functions called in `expr` into `callees`.
+fn collect_calls(expr: &ExprAST, callees: &mut Vec<String>) {
+    match expr {
+        ExprAST::Number(_)
+        | ExprAST::Str(_)
+        | ExprAST::Variable(_)
+        | ExprAST::Break
+        | ExprAST::Continue => {}
+        ExprAST::Unary(op, operand) => {
+            // User-defined operators call the function of the operator.
+            if !typeck::UNOPS.contains(&op.as_str()) {
+                push_callee(unary_fn_name(op), callees);
+            }
+            collect_calls(operand, callees);
+        }
+        ExprAST::Binary(op, lhs, rhs) => {
+            if !typeck::BINOPS.contains(&op.as_str()) {
+                push_callee(binary_fn_name(op), callees);
+            }
+            collect_calls(lhs, callees);
+            collect_calls(rhs, callees);
+        }
+        ExprAST::Logical(_, lhs, rhs) => {
+            collect_calls(lhs, callees);
+            collect_calls(rhs, callees);
+        }
+        ExprAST::Call(callee, args) => {
+            push_callee(callee.clone(), callees);
+            for arg in args {
+                collect_calls(arg, callees);
+            }
+        }
+        ExprAST::If { cond, then, else_ } => {
+            collect_calls(cond, callees);
+            collect_calls(then, callees);
+            collect_calls(else_, callees);
+        }
+        ExprAST::For {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            collect_calls(start, callees);
+            collect_calls(end, callees);
+            if let Some(step) = step {
+                collect_calls(step, callees);
+            }
+            collect_calls(body, callees);
+        }
+        ExprAST::VarIn { vars, body } => {
+            for init in vars.iter().filter_map(|(_, init)| init.as_ref()) {
+                collect_calls(init, callees);
+            }
+            collect_calls(body, callees);
+        }
+        ExprAST::Assign { value, .. } => collect_calls(value, callees),
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            collect_calls(value, callees);
+            for (_, arm) in arms {
+                collect_calls(arm, callees);
+            }
+            collect_calls(default, callees);
+        }
+        ExprAST::Lambda(_, body) => collect_calls(body, callees),
+    }
+}
+
+/// Replace all references to the variable `name` in `expr` with the constant `value`.
+///
+/// References to a loop variable or a `var` variable with the same name, which shadows `name`,
+/// are kept.
+pub(super) fn bind_var(expr: &mut ExprAST, name: &str, value: f64) {
+    match expr {
+        ExprAST::Variable(var) if var == name => *expr = ExprAST::Number(value),
+        ExprAST::Number(_)
+        | ExprAST::Str(_)
+        | ExprAST::Variable(_)
+        | ExprAST::Break
+        | ExprAST::Continue => {}
+        ExprAST::Unary(_, operand) => bind_var(operand, name, value),
+        ExprAST::Binary(_, lhs, rhs) | ExprAST::Logical(_, lhs, rhs) => {
+            bind_var(lhs, name, value);
+            bind_var(rhs, name, value);
+        }
+        ExprAST::Call(_, args) => {
+            for arg in args {
+                bind_var(arg, name, value);
+            }
+        }
+        ExprAST::If { cond, then, else_ } => {
+            bind_var(cond, name, value);
+            bind_var(then, name, value);
+            bind_var(else_, name, value);
+        }
+        ExprAST::For {
+            var,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            // The loop variable is in scope for all but the start expression.
+            bind_var(start, name, value);
+            if var != name {
+                bind_var(end, name, value);
+                if let Some(step) = step {
+                    bind_var(step, name, value);
+                }
+                bind_var(body, name, value);
+            }
+        }
+        ExprAST::VarIn { vars, body } => {
+            // Each variable is in scope from the initializer of the next variable on.
+            for (var, init) in vars {
+                if let Some(init) = init {
+                    bind_var(init, name, value);
+                }
+                if var == name {
+                    return;
+                }
+            }
+            bind_var(body, name, value);
+        }
+        ExprAST::Assign { value: rhs, .. } => bind_var(rhs, name, value),
+        ExprAST::Match {
+            value: scrutinee,
+            arms,
+            default,
+        } => {
+            bind_var(scrutinee, name, value);
+            for (_, arm) in arms {
+                bind_var(arm, name, value);
+            }
+            bind_var(default, name, value);
+        }
+        ExprAST::Lambda(params, body) => {
+            if !params.iter().any(|param| param == name) {
+                bind_var(body, name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bind_var, collect_calls, ir_global};
+    use crate::parser::parse_expr;
+
+    #[test]
+    fn bind_last_result() {
+        let mut expr = parse_expr("it + (for it = it, it in it)").unwrap();
+        bind_var(&mut expr, "it", 2.0);
+
+        // Spans are ignored when comparing, the loop variable shadows 'it' after the start.
+        assert_eq!(expr, parse_expr("2 + (for it = 2, it in it)").unwrap());
+    }
+
+    #[test]
+    fn quote_ir_globals() {
+        assert_eq!(ir_global("fib"), "@fib");
+        assert_eq!(ir_global("llvm.pow.f64"), "@llvm.pow.f64");
+        assert_eq!(ir_global("binary|"), "@\"binary|\"");
+        assert_eq!(ir_global("unary!"), "@\"unary!\"");
+        assert_eq!(ir_global("a\"b\\"), "@\"a\\22b\\5C\"");
+        assert_eq!(ir_global("1x"), "@\"1x\"");
+    }
+
+    #[test]
+    fn collect_called_functions() {
+        let expr = parse_expr("foo(bar(1)) + (if baz() then foo(2) else 3)").unwrap();
+        let mut callees = Vec::new();
+        collect_calls(&expr, &mut callees);
+        assert_eq!(callees, vec!["foo", "bar", "baz"]);
+    }
+}
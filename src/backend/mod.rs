@@ -0,0 +1,181 @@
+//! Execution backends for the kaleidoscope driver.
+//!
+//! A [`Backend`] receives the items parsed by the driver and is responsible for compiling and,
+//! depending on the backend, executing them. This allows alternative backends to plug into the
+//! same driver.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::codegen::{CodegenError, FnProtos};
+use crate::desugar;
+use crate::fold;
+use crate::parser::{ConstAST, ExprAST, FunctionAST, Item, PrototypeAST};
+
+mod interp;
+mod jit;
+mod module;
+
+pub use interp::InterpBackend;
+pub use jit::{JitBackend, LAST_RESULT};
+pub use module::ModuleBackend;
+
+/// Result type of [`Backend`] operations with [`CodegenError`] as Error type.
+///
+/// Only codegen errors locate the offending expression, the errors of other phases, eg type
+/// checking, concern the whole item.
+pub type BackendResult<T> = Result<T, CodegenError>;
+
+/// Time spent in the different phases of compiling and running a single item.
+#[derive(Debug, Default, Clone)]
+pub struct ItemTiming {
+    /// Name of the item, the function name or `__anon_expr.N` for top-level expressions, see
+    /// [`ANON_EXPR`](crate::parser::ANON_EXPR).
+    pub name: String,
+    /// Lexing and parsing.
+    pub parse: Duration,
+    /// Generating the LLVM IR, excluding the optimization passes.
+    pub codegen: Duration,
+    /// Running the optimization passes.
+    pub opt: Duration,
+    /// Adding the module to the JIT and compiling it to machine code.
+    pub jit: Duration,
+    /// Executing a top-level expression.
+    pub exec: Duration,
+}
+
+/// Interface between the driver and an execution backend.
+pub trait Backend {
+    /// Declare the external function `proto`.
+    fn compile_prototype(&mut self, proto: &PrototypeAST) -> BackendResult<()>;
+
+    /// Compile the function definition `func`, making it callable from later items.
+    fn compile_function(&mut self, func: &FunctionAST) -> BackendResult<()>;
+
+    /// Compile and run the top-level expression `func` and return its result.
+    ///
+    /// Backends which do not execute code return `None`.
+    fn call_top_level(&mut self, func: &FunctionAST) -> BackendResult<Option<f64>>;
+
+    /// Define the global constant `c`, which the functions compiled afterwards can refer to.
+    fn compile_const(&mut self, c: &ConstAST) -> BackendResult<()>;
+
+    /// Get the prototypes of the functions implementing user-defined operators.
+    ///
+    /// The driver declares the operators in the parser of each new input, such that operators
+    /// defined by a previous input can be used, see [`Parser::declare_operator`].
+    ///
+    /// [`Parser::declare_operator`]: crate::parser::Parser::declare_operator
+    fn operators(&self) -> Vec<Rc<PrototypeAST>>;
+
+    /// Get the number of lambdas lifted for the backend so far, used for the names `__lambda.N`
+    /// of the lifted functions, which must be unique among the functions of the backend, see
+    /// [`compile_lambdas`].
+    fn lambdas(&mut self) -> &mut usize;
+
+    /// Record the time the driver spent lexing and parsing the item passed next to the backend.
+    ///
+    /// Backends which do not keep track of timings ignore it.
+    fn record_parse_time(&mut self, _parse: Duration) {}
+}
+
+/// Compile the `item` with the `backend`, including the lambdas it contains, and run it if it is a
+/// top-level expression.
+///
+/// Return the result of a top-level expression, `None` for other items or if the backend does not
+/// execute code.
+pub fn compile_item<B: Backend + ?Sized>(
+    backend: &mut B,
+    item: Item,
+) -> BackendResult<Option<f64>> {
+    match item {
+        Item::Def(mut func) => {
+            compile_lambdas(backend, &mut func)?;
+            backend.compile_function(&func).map(|_| None)
+        }
+        Item::Extern(proto) => backend.compile_prototype(&proto).map(|_| None),
+        Item::TopLevel(mut func) => {
+            compile_lambdas(backend, &mut func)?;
+            backend.call_top_level(&func)
+        }
+        Item::Const(c) => backend.compile_const(&c).map(|_| None),
+    }
+}
+
+/// Lift the lambdas in the function `func` and compile the lifted functions with the `backend`,
+/// see [`desugar::lift_lambdas`].
+///
+/// Must be called before passing `func` to the backend, which only compiles lifted lambdas. If a
+/// lambda is used in an unsupported way, none of the lambdas is compiled.
+pub fn compile_lambdas<B: Backend + ?Sized>(
+    backend: &mut B,
+    func: &mut FunctionAST,
+) -> BackendResult<()> {
+    for lambda in desugar::lift_lambdas(func, backend.lambdas())? {
+        backend.compile_function(&lambda)?;
+    }
+    Ok(())
+}
+
+/// Get the prototypes of the operator functions among `fn_protos`, see [`Backend::operators`].
+///
+/// Identifiers like `binaryx` are included as well, the parser only declares operators.
+pub(crate) fn operators(fn_protos: &FnProtos) -> Vec<Rc<PrototypeAST>> {
+    fn_protos
+        .values()
+        .filter(|fn_proto| {
+            ["binary", "unary"]
+                .iter()
+                .any(|p| fn_proto.proto.0.starts_with(p))
+        })
+        .map(|fn_proto| fn_proto.proto.clone())
+        .collect()
+}
+
+/// Values of the global constants defined so far.
+///
+/// Constants are substituted for the variables referring to them before a function is compiled,
+/// such that the backends only see number literals.
+#[derive(Default)]
+pub(crate) struct Consts(HashMap<String, f64>);
+
+impl Consts {
+    /// Define the constant `c`, whose initializer must fold to a number. The initializer may
+    /// refer to constants defined before.
+    pub(crate) fn define(&mut self, c: &ConstAST) -> BackendResult<()> {
+        let ConstAST(name, init) = c;
+        let mut init = init.clone();
+        self.bind_expr(&mut init);
+        fold::fold_expr(&mut init);
+
+        match init.node {
+            ExprAST::Number(value) => {
+                self.0.insert(name.clone(), value);
+                Ok(())
+            }
+            _ => Err(format!(
+                "Initializer of constant '{}' is not a constant expression",
+                name
+            )
+            .into()),
+        }
+    }
+
+    /// Substitute the constants into the body of the function `func`. Parameters shadow
+    /// constants with the same name.
+    pub(crate) fn bind(&self, func: &mut FunctionAST) {
+        let FunctionAST(proto, body) = func;
+        for (name, &value) in &self.0 {
+            if !proto.1.contains(name) {
+                jit::bind_var(body, name, value);
+            }
+        }
+    }
+
+    fn bind_expr(&self, expr: &mut ExprAST) {
+        for (name, &value) in &self.0 {
+            jit::bind_var(expr, name, value);
+        }
+    }
+}
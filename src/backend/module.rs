@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{Backend, BackendResult, Consts};
+use crate::codegen::{Codegen, CodegenOptions, CodegenStats, FnProtos, ProtoOrigin};
+use crate::llvm::Module;
+use crate::parser::{ConstAST, FunctionAST, PrototypeAST, Spanned, ANON_EXPR};
+use crate::Either;
+
+/// Backend compiling all items into a single LLVM module without executing them.
+///
+/// Top-level expressions are compiled into functions with the unique names `__anon_expr.N`, such
+/// that the module can be written out, eg as object file, once all items are compiled.
+///
+/// In case codegen fails, the function is left in an incomplete state in the module, hence the
+/// module must not be used after an error.
+pub struct ModuleBackend {
+    module: Module,
+
+    /// Keep track of prototype names to their respective ASTs.
+    fn_protos: FnProtos,
+
+    /// Names of the functions generated for the top-level expressions, in order.
+    top_level: Vec<String>,
+
+    /// Global constants defined so far.
+    consts: Consts,
+
+    /// Number of lambdas lifted so far, see [`Backend::lambdas`].
+    lambdas: usize,
+
+    opts: CodegenOptions,
+
+    /// Statistics about the code generated by this backend.
+    stats: CodegenStats,
+}
+
+impl ModuleBackend {
+    /// Create a new backend emitting code into a new module using the codegen options `opts`.
+    pub fn new(opts: CodegenOptions) -> Self {
+        ModuleBackend {
+            module: Module::new(),
+            fn_protos: HashMap::new(),
+            top_level: Vec::new(),
+            consts: Consts::default(),
+            lambdas: 0,
+            opts,
+            stats: CodegenStats::default(),
+        }
+    }
+
+    /// Get the module all items are emitted into.
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Names of the functions generated for the top-level expressions, in order.
+    pub fn top_level(&self) -> &[String] {
+        &self.top_level
+    }
+
+    /// Prototypes of the functions declared with `extern` but not defined, sorted by name.
+    ///
+    /// These must be provided when linking the module.
+    pub fn externs(&self) -> Vec<&PrototypeAST> {
+        self.protos(ProtoOrigin::Extern)
+    }
+
+    /// Prototypes of the functions defined with `def`, sorted by name.
+    ///
+    /// The functions generated for the top-level expressions are not included.
+    pub fn definitions(&self) -> Vec<&PrototypeAST> {
+        let mut defs = self.protos(ProtoOrigin::Def);
+        defs.retain(|p| !self.top_level.contains(&p.0));
+        defs
+    }
+
+    fn protos(&self, origin: ProtoOrigin) -> Vec<&PrototypeAST> {
+        let mut protos: Vec<_> = self
+            .fn_protos
+            .values()
+            .filter(|p| p.origin == origin)
+            .map(|p| p.proto.as_ref())
+            .collect();
+        protos.sort_by(|a, b| a.0.cmp(&b.0));
+        protos
+    }
+
+    /// Emit a C `main` function calling the top-level expressions in order, such that the module
+    /// can be linked into an executable.
+    pub fn compile_main(&self) -> BackendResult<()> {
+        Codegen::compile_main(&self.module, &self.top_level).map(drop)
+    }
+
+    /// Statistics about the code generated by this backend.
+    pub fn stats(&self) -> &CodegenStats {
+        &self.stats
+    }
+
+    fn compile(&mut self, compilee: Either<&PrototypeAST, &FunctionAST>) -> BackendResult<()> {
+        Codegen::compile(
+            &self.module,
+            &mut self.fn_protos,
+            &self.opts,
+            &mut self.stats,
+            compilee,
+        )
+        .map(drop)
+    }
+}
+
+impl Backend for ModuleBackend {
+    fn compile_prototype(&mut self, proto: &PrototypeAST) -> BackendResult<()> {
+        self.compile(Either::A(proto))
+    }
+
+    fn compile_function(&mut self, func: &FunctionAST) -> BackendResult<()> {
+        let mut func = func.clone();
+        self.consts.bind(&mut func);
+        self.compile(Either::B(&func))
+    }
+
+    fn call_top_level(&mut self, func: &FunctionAST) -> BackendResult<Option<f64>> {
+        // All top-level expressions live in the same module, give each of them a unique name.
+        let name = format!("{}.{}", ANON_EXPR, self.top_level.len());
+        let proto = PrototypeAST(name.clone(), func.0 .1.clone(), 0);
+        let mut func = FunctionAST(Spanned::new(proto, func.0.span), func.1.clone());
+        self.consts.bind(&mut func);
+
+        self.compile(Either::B(&func))?;
+        self.top_level.push(name);
+
+        Ok(None)
+    }
+
+    fn compile_const(&mut self, c: &ConstAST) -> BackendResult<()> {
+        self.consts.define(c)
+    }
+
+    fn operators(&self) -> Vec<Rc<PrototypeAST>> {
+        super::operators(&self.fn_protos)
+    }
+
+    fn lambdas(&mut self) -> &mut usize {
+        &mut self.lambdas
+    }
+}
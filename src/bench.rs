@@ -0,0 +1,260 @@
+//! Benchmark harness measuring the phases of the compiler on representative programs.
+//!
+//! Each [`Phase`] of a [`Program`] is run repeatedly after some warm-up runs, the collected
+//! samples are summarized in a [`Measurement`]. Comparing the measurements before and after a
+//! change makes performance regressions visible.
+//!
+//! ```no_run
+//! use llvm_kaleidoscope_rs::bench::{self, Phase, PROGRAMS};
+//!
+//! for program in PROGRAMS {
+//!     for phase in Phase::ALL {
+//!         let m = bench::measure(program, phase, &bench::Config::default()).unwrap();
+//!         println!("{}", m);
+//!     }
+//! }
+//! ```
+
+use std::fmt;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, JitBackend, ModuleBackend};
+use crate::codegen::CodegenOptions;
+use crate::lexer::Lexer;
+use crate::llvm::{self, LLJit};
+use crate::parser::{parse_items, Item};
+
+/// Kaleidoscope program used as benchmark.
+#[derive(Debug)]
+pub struct Program {
+    /// Name of the program.
+    pub name: &'static str,
+    /// Source of the program.
+    pub src: &'static str,
+}
+
+/// Naive recursive fibonacci, stressing function calls.
+pub const FIB: Program = Program {
+    name: "fib",
+    src: include_str!("../ks/bench/fib.ks"),
+};
+
+/// Iterations of the mandelbrot set over a grid, stressing floating point arithmetic.
+pub const MANDELBROT: Program = Program {
+    name: "mandelbrot",
+    src: include_str!("../ks/bench/mandelbrot.ks"),
+};
+
+/// All benchmark programs.
+pub const PROGRAMS: &[Program] = &[FIB, MANDELBROT];
+
+/// Phase of the compiler to measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Lexing the source into tokens.
+    Lex,
+    /// Lexing and parsing the source into items.
+    Parse,
+    /// Generating and optimizing the LLVM IR of the items, excluding parsing.
+    Codegen,
+    /// Executing the top-level expressions with the JIT, excluding parsing and codegen.
+    Execute,
+}
+
+impl Phase {
+    /// All phases in the order they run in the compiler.
+    pub const ALL: [Phase; 4] = [Phase::Lex, Phase::Parse, Phase::Codegen, Phase::Execute];
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Lex => "lex",
+            Phase::Parse => "parse",
+            Phase::Codegen => "codegen",
+            Phase::Execute => "execute",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Number of runs of a measurement.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Runs before the measurement, eg to warm up the caches.
+    pub warmup: usize,
+    /// Measured runs.
+    pub samples: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            warmup: 3,
+            samples: 20,
+        }
+    }
+}
+
+/// Samples of measuring one phase of a program.
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    /// Name of the measured program.
+    pub program: &'static str,
+    /// Measured phase.
+    pub phase: Phase,
+    /// Duration of each measured run, in order.
+    pub samples: Vec<Duration>,
+}
+
+impl Measurement {
+    /// Shortest run.
+    pub fn min(&self) -> Duration {
+        self.samples.iter().copied().min().unwrap_or_default()
+    }
+
+    /// Longest run.
+    pub fn max(&self) -> Duration {
+        self.samples.iter().copied().max().unwrap_or_default()
+    }
+
+    /// Arithmetic mean of all runs.
+    pub fn mean(&self) -> Duration {
+        match self.samples.len() {
+            0 => Duration::ZERO,
+            n => self.samples.iter().sum::<Duration>() / n as u32,
+        }
+    }
+
+    /// Median of all runs, which is robust against outliers.
+    pub fn median(&self) -> Duration {
+        let mut samples = self.samples.clone();
+        samples.sort();
+        match samples.len() {
+            0 => Duration::ZERO,
+            n if n % 2 == 0 => (samples[n / 2 - 1] + samples[n / 2]) / 2,
+            n => samples[n / 2],
+        }
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let us = |d: Duration| d.as_secs_f64() * 1e6;
+        write!(
+            f,
+            "{}/{}: median {:.1}us, mean {:.1}us, min {:.1}us, max {:.1}us ({} samples)",
+            self.program,
+            self.phase,
+            us(self.median()),
+            us(self.mean()),
+            us(self.min()),
+            us(self.max()),
+            self.samples.len()
+        )
+    }
+}
+
+/// Measure the `phase` of `program` according to `config`.
+///
+/// Measuring [`Phase::Execute`] initializes the native target for the JIT.
+pub fn measure(program: &Program, phase: Phase, config: &Config) -> Result<Measurement, String> {
+    if phase == Phase::Execute {
+        llvm::initialize_native_taget();
+    }
+
+    for _ in 0..config.warmup {
+        run(program, phase)?;
+    }
+
+    let samples = (0..config.samples)
+        .map(|_| run(program, phase))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Measurement {
+        program: program.name,
+        phase,
+        samples,
+    })
+}
+
+/// Run the `phase` of `program` once and return the time spent in the phase.
+fn run(program: &Program, phase: Phase) -> Result<Duration, String> {
+    let start = Instant::now();
+    match phase {
+        Phase::Lex => {
+            black_box(Lexer::new(program.src.chars()).count());
+            Ok(start.elapsed())
+        }
+        Phase::Parse => {
+            black_box(parse(program)?);
+            Ok(start.elapsed())
+        }
+        Phase::Codegen => {
+            let items = parse(program)?;
+            let mut backend = ModuleBackend::new(CodegenOptions::default());
+
+            let start = Instant::now();
+            for item in &items {
+                match item {
+                    Item::Def(func) => backend.compile_function(func)?,
+                    Item::Extern(proto) => backend.compile_prototype(proto)?,
+                    Item::TopLevel(func) => backend.call_top_level(func).map(drop)?,
+                    Item::Const(c) => backend.compile_const(c)?,
+                }
+            }
+            Ok(start.elapsed())
+        }
+        Phase::Execute => {
+            let items = parse(program)?;
+            let jit = LLJit::new();
+            let mut backend = JitBackend::new(&jit, CodegenOptions::default());
+            backend.set_dump_ir(false);
+
+            for item in &items {
+                match item {
+                    Item::Def(func) => backend.compile_function(func)?,
+                    Item::Extern(proto) => backend.compile_prototype(proto)?,
+                    Item::TopLevel(func) => {
+                        black_box(backend.call_top_level(func)?);
+                    }
+                    Item::Const(c) => backend.compile_const(c)?,
+                }
+            }
+            Ok(backend.timings().iter().map(|t| t.exec).sum())
+        }
+    }
+}
+
+/// Parse all items of `program`.
+fn parse(program: &Program) -> Result<Vec<Item>, String> {
+    parse_items(program.src).map_err(|errs| format!("{}: {}", program.name, errs[0]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Measurement, Phase, PROGRAMS};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_programs() {
+        for program in PROGRAMS {
+            assert!(parse(program).is_ok(), "{}", program.name);
+        }
+    }
+
+    #[test]
+    fn summarize_samples() {
+        let ms = Duration::from_millis;
+        let m = Measurement {
+            program: "p",
+            phase: Phase::Lex,
+            samples: vec![ms(4), ms(1), ms(3), ms(8)],
+        };
+        assert_eq!(m.min(), ms(1));
+        assert_eq!(m.max(), ms(8));
+        assert_eq!(m.mean(), ms(4));
+        assert_eq!(m.median(), Duration::from_micros(3500));
+    }
+}
@@ -1,71 +1,361 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-
-use crate::llvm::{FnValue, FunctionPassManager, IRBuilder, Module, Value};
-use crate::parser::{ExprAST, FunctionAST, PrototypeAST};
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use crate::desugar;
+use crate::fold;
+use crate::lexer::Span;
+pub use crate::llvm::OptLevel;
+use crate::llvm::{BasicBlock, FnValue, FunctionPassManager, IRBuilder, Module, Value};
+use crate::parser::{
+    binary_fn_name, unary_fn_name, Expr, ExprAST, FunctionAST, PrototypeAST, ANON_EXPR,
+};
 use crate::Either;
 
-type CodegenResult<T> = Result<T, String>;
+/// Codegen result with [`CodegenError`] as Error type.
+pub type CodegenResult<T> = Result<T, CodegenError>;
+
+/// Codegen error together with the location of the offending expression.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CodegenError {
+    pub msg: String,
+    /// Span of the expression codegen failed at, `None` if the error concerns the whole item, eg
+    /// a conflicting redeclaration.
+    pub span: Option<Span>,
+}
+
+impl CodegenError {
+    /// Create an error for the expression located at `span`.
+    pub fn at(span: Span, msg: impl Into<String>) -> Self {
+        CodegenError {
+            msg: msg.into(),
+            span: Some(span),
+        }
+    }
+}
+
+impl std::fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl From<String> for CodegenError {
+    fn from(msg: String) -> Self {
+        CodegenError { msg, span: None }
+    }
+}
+
+impl From<&str> for CodegenError {
+    fn from(msg: &str) -> Self {
+        msg.to_string().into()
+    }
+}
+
+impl From<CodegenError> for String {
+    fn from(err: CodegenError) -> Self {
+        err.msg
+    }
+}
+
+/// Intrinsic computing `x ^ y`, its name can't clash with user functions since identifiers can't
+/// contain `.`.
+const POW_INTRINSIC: &str = "llvm.pow.f64";
+
+/// Origin of a function prototype known to the code generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtoOrigin {
+    /// Declared with `extern`.
+    Extern,
+    /// Defined with `def` or generated for a top-level expression.
+    Def,
+}
+
+/// A function prototype tracked across LLVM modules together with its origin.
+///
+/// The prototype is shared, such that copying the entry or redefining a function with the same
+/// prototype doesn't copy the prototype.
+#[derive(Debug, Clone)]
+pub struct FnProto {
+    pub proto: Rc<PrototypeAST>,
+    pub origin: ProtoOrigin,
+}
+
+/// Map of function names to their prototypes.
+///
+/// Used to re-generate function declarations in other LLVM modules.
+pub type FnProtos = HashMap<String, FnProto>;
+
+/// Check that declaring `proto` does not conflict with a previous declaration of a function with
+/// the same name in `fn_protos`.
+pub fn check_redeclaration(fn_protos: &FnProtos, proto: &PrototypeAST) -> CodegenResult<()> {
+    match fn_protos.get(&proto.0) {
+        Some(prev) if prev.proto.1.len() != proto.1.len() => {
+            let prev_kind = match prev.origin {
+                ProtoOrigin::Extern => "declared as extern",
+                ProtoOrigin::Def => "defined",
+            };
+            Err(format!(
+                "Conflicting declaration of '{}' with {} argument(s), previously {} with {} argument(s)",
+                proto.0,
+                proto.1.len(),
+                prev_kind,
+                prev.proto.1.len()
+            ).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Callbacks invoked by [`Codegen`] while emitting a function.
+///
+/// Hooks can use the `builder` to inject additional code into the generated function, for example
+/// calls to counters or tracing functions for profiling or coverage of kaleidoscope code.
+pub trait CodegenHooks {
+    /// Invoked when entering `the_function`, the `builder` is positioned at the end of the entry
+    /// basic block before any code for the function body is emitted.
+    fn function_entry<'llvm>(
+        &self,
+        _module: &'llvm Module,
+        _builder: &IRBuilder<'llvm>,
+        _the_function: FnValue<'llvm>,
+    ) {
+    }
+
+    /// Invoked when leaving `the_function`, the `builder` is positioned right before the return
+    /// instruction returning `ret`.
+    fn function_exit<'llvm>(
+        &self,
+        _module: &'llvm Module,
+        _builder: &IRBuilder<'llvm>,
+        _the_function: FnValue<'llvm>,
+        _ret: Value<'llvm>,
+    ) {
+    }
+}
+
+/// Options controlling the code generation.
+#[derive(Default, Clone)]
+pub struct CodegenOptions {
+    /// Optimization level of the emitted functions.
+    pub opt_level: OptLevel,
+
+    /// Emit a call to `printd` with the result of each top-level expression before returning it.
+    ///
+    /// This way running the top-level expression already prints its result, which is useful when
+    /// executing scripts in batch.
+    pub print_toplevel: bool,
+
+    /// Optional instrumentation hooks invoked for every emitted function.
+    pub hooks: Option<Rc<dyn CodegenHooks>>,
+
+    /// Optional interrupt flag checked by the emitted code on function entry and in every loop
+    /// iteration. Once the flag is set, functions return `0` right away and loops terminate,
+    /// such that a running evaluation finishes early.
+    ///
+    /// The address of the flag is embedded into the code, hence this is only useful for code
+    /// executed in the same process, eg with the JIT.
+    pub interrupt_flag: Option<&'static AtomicBool>,
+
+    /// Fold constant expressions in the AST of each function before emitting it, see
+    /// [`fold`](crate::fold).
+    pub fold: bool,
+}
+
+/// Statistics of a single function emitted by [`Codegen`].
+#[derive(Debug, Clone)]
+pub struct FnStats {
+    /// Name of the function.
+    pub name: String,
+    /// Number of instructions after optimization.
+    pub instructions: usize,
+    /// Number of basic blocks after optimization.
+    pub basic_blocks: usize,
+    /// Time spent running the optimization passes on the function.
+    pub opt_time: Duration,
+}
+
+/// Statistics accumulated by [`Codegen`] over multiple compilations.
+#[derive(Debug, Default, Clone)]
+pub struct CodegenStats {
+    /// Number of emitted functions (definitions and top-level expressions).
+    pub functions: usize,
+    /// Number of emitted extern declarations.
+    pub declarations: usize,
+    /// Statistics of each emitted function, in the order the functions were emitted.
+    pub fn_stats: Vec<FnStats>,
+    /// Total time spent running optimization passes.
+    pub opt_time: Duration,
+}
+
+impl CodegenStats {
+    /// Total number of instructions of all emitted functions.
+    pub fn instructions(&self) -> usize {
+        self.fn_stats.iter().map(|s| s.instructions).sum()
+    }
+
+    /// Total number of basic blocks of all emitted functions.
+    pub fn basic_blocks(&self) -> usize {
+        self.fn_stats.iter().map(|s| s.basic_blocks).sum()
+    }
+}
+
+/// Basic blocks `break` and `continue` branch to in the body of a loop.
+#[derive(Clone, Copy)]
+struct LoopTargets<'llvm> {
+    /// Block computing the next value of the loop variable, target of `continue`.
+    step_bb: BasicBlock<'llvm>,
+    /// Block following the loop, target of `break`.
+    after_bb: BasicBlock<'llvm>,
+}
 
 /// Code generator from kaleidoscope AST to LLVM IR.
 pub struct Codegen<'llvm, 'a> {
     module: &'llvm Module,
     builder: &'a IRBuilder<'llvm>,
     fpm: &'a FunctionPassManager<'llvm>,
-    fn_protos: &'a mut HashMap<String, PrototypeAST>,
+    fn_protos: &'a mut FnProtos,
+    opts: &'a CodegenOptions,
+    stats: &'a mut CodegenStats,
+    /// Targets of the loops enclosing the current expression, innermost last.
+    loops: RefCell<Vec<LoopTargets<'llvm>>>,
 }
 
 impl<'llvm, 'a> Codegen<'llvm, 'a> {
     /// Compile either a [`PrototypeAST`] or a [`FunctionAST`] into the LLVM `module`.
+    ///
+    /// Prototypes of externs and function definitions are recorded in `fn_protos`. Returns an
+    /// error if the prototype conflicts with a previous declaration of the same name.
+    ///
+    /// Statistics about the emitted code are accumulated into `stats`.
     pub fn compile(
         module: &'llvm Module,
-        fn_protos: &mut HashMap<String, PrototypeAST>,
+        fn_protos: &mut FnProtos,
+        opts: &CodegenOptions,
+        stats: &mut CodegenStats,
         compilee: Either<&PrototypeAST, &FunctionAST>,
     ) -> CodegenResult<FnValue<'llvm>> {
         let mut cg = Codegen {
             module,
             builder: &IRBuilder::with_ctx(module),
-            fpm: &FunctionPassManager::with_ctx(module),
+            fpm: &FunctionPassManager::with_ctx(module, opts.opt_level),
             fn_protos,
+            opts,
+            stats,
+            loops: RefCell::new(Vec::new()),
         };
         let mut variables = HashMap::new();
 
         match compilee {
-            Either::A(proto) => Ok(cg.codegen_prototype(proto)),
-            Either::B(func) => cg.codegen_function(func, &mut variables),
+            Either::A(proto) => cg.codegen_extern(proto),
+            Either::B(func) => {
+                let mut func = func.clone();
+                desugar::desugar_function(&mut func);
+                if opts.fold {
+                    fold::fold_function(&mut func);
+                }
+                cg.codegen_function(&func, &mut variables)
+            }
         }
     }
 
+    /// Emit a C `main` function into the LLVM `module`, which calls the functions generated for
+    /// the top-level expressions `top_level` in order and returns `0`.
+    ///
+    /// Returns an error if the module already defines a function called `main`.
+    pub fn compile_main(
+        module: &'llvm Module,
+        top_level: &[String],
+    ) -> CodegenResult<FnValue<'llvm>> {
+        if module.get_fn("main").is_some() {
+            return Err("Function 'main' is already defined".into());
+        }
+
+        let builder = IRBuilder::with_ctx(module);
+
+        let type_i32 = module.type_i32();
+        let the_function = module.add_fn("main", module.type_fn(&mut [], type_i32));
+
+        let bb = module.append_basic_block(the_function, "entry");
+        builder.pos_at_end(bb);
+
+        for name in top_level {
+            let f = module
+                .get_fn(name)
+                .expect("Top-level expression must be compiled into the module!");
+            builder.call(f, &mut [], "calltmp");
+        }
+
+        builder.ret(type_i32.const_i32(0));
+        assert!(the_function.verify());
+
+        Ok(the_function)
+    }
+
+    /// Emit the expression `expr`, `named_values` maps the variables in scope to their allocas.
+    ///
+    /// Errors in the source, eg an unknown variable, point at the span of the offending
+    /// expression.
     fn codegen_expr(
         &self,
-        expr: &ExprAST,
+        expr: &Expr,
         named_values: &mut HashMap<String, Value<'llvm>>,
     ) -> CodegenResult<Value<'llvm>> {
-        match expr {
+        let err = |msg: &str| Err(CodegenError::at(expr.span, msg));
+
+        match &expr.node {
             ExprAST::Number(num) => Ok(self.module.type_f64().const_f64(*num)),
+            ExprAST::Str(_) => err("Strings are not supported by codegen yet"),
             ExprAST::Variable(name) => match named_values.get(name.as_str()) {
-                Some(value) => Ok(*value),
-                None => Err("Unknown variable name".into()),
+                // Load the value from the stack slot of the variable.
+                Some(alloca) => Ok(self.builder.load(self.module.type_f64(), *alloca, name)),
+                None => err("Unknown variable name"),
             },
+            // Unary minus is desugared, other unary operators are user-defined.
+            ExprAST::Unary(op, operand) => {
+                let operand = self.codegen_expr(operand, named_values)?;
+                match self.get_function(&unary_fn_name(op)) {
+                    Some(f) => Ok(self.builder.call(f, &mut [operand], "unop")),
+                    None => err("Unknown unary operator"),
+                }
+            }
             ExprAST::Binary(binop, lhs, rhs) => {
                 let l = self.codegen_expr(lhs, named_values)?;
                 let r = self.codegen_expr(rhs, named_values)?;
 
-                match binop {
-                    '+' => Ok(self.builder.fadd(l, r)),
-                    '-' => Ok(self.builder.fsub(l, r)),
-                    '*' => Ok(self.builder.fmul(l, r)),
-                    '<' => {
-                        let res = self.builder.fcmpult(l, r);
+                match binop.as_str() {
+                    "+" => Ok(self.builder.fadd(l, r, "addtmp")),
+                    "-" => Ok(self.builder.fsub(l, r, "subtmp")),
+                    "*" => Ok(self.builder.fmul(l, r, "multmp")),
+                    "%" => Ok(self.builder.frem(l, r, "remtmp")),
+                    "^" => Ok(self.builder.call(self.get_pow(), &mut [l, r], "powtmp")),
+                    "<" | ">" | "<=" | ">=" | "==" | "!=" => {
+                        let res = match binop.as_str() {
+                            "<" => self.builder.fcmpult(l, r, "cmptmp"),
+                            ">" => self.builder.fcmpugt(l, r, "cmptmp"),
+                            "<=" => self.builder.fcmpule(l, r, "cmptmp"),
+                            ">=" => self.builder.fcmpuge(l, r, "cmptmp"),
+                            "==" => self.builder.fcmpoeq(l, r, "cmptmp"),
+                            _ => self.builder.fcmpune(l, r, "cmptmp"),
+                        };
                         // Turn bool into f64.
-                        Ok(self.builder.uitofp(res, self.module.type_f64()))
+                        Ok(self.builder.uitofp(res, self.module.type_f64(), "booltmp"))
                     }
-                    _ => Err("invalid binary operator".into()),
+                    // If it wasn't a builtin binary operator, it must be a user defined one. Emit
+                    // a call to it.
+                    _ => match self.get_function(&binary_fn_name(binop)) {
+                        Some(f) => Ok(self.builder.call(f, &mut [l, r], "binop")),
+                        None => err("invalid binary operator"),
+                    },
                 }
             }
+            ExprAST::Logical(..) => err("logical operators must be desugared before codegen"),
             ExprAST::Call(callee, args) => match self.get_function(callee) {
                 Some(callee) => {
                     if callee.args() != args.len() {
-                        return Err("Incorrect # arguments passed".into());
+                        return err("Incorrect # arguments passed");
                     }
 
                     // Generate code for function argument expressions.
@@ -74,9 +364,9 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                         .map(|arg| self.codegen_expr(arg, named_values))
                         .collect::<CodegenResult<_>>()?;
 
-                    Ok(self.builder.call(callee, &mut args))
+                    Ok(self.builder.call(callee, &mut args, "calltmp"))
                 }
-                None => Err("Unknown function referenced".into()),
+                None => err("Unknown function referenced"),
             },
             ExprAST::If { cond, then, else_ } => {
                 // For 'if' expressions we are building the following CFG.
@@ -99,7 +389,7 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                     let v = self.codegen_expr(cond, named_values)?;
                     // Compare 'v' against '0' as 'one = ordered not equal'.
                     self.builder
-                        .fcmpone(v, self.module.type_f64().const_f64(0f64))
+                        .fcmpone(v, self.module.type_f64().const_f64(0f64), "ifcond")
                 };
 
                 // Get the function we are currently inserting into.
@@ -110,9 +400,9 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                 //
                 // Append the 'then' basic block to the function, don't insert the 'else' and
                 // 'merge' basic blocks yet.
-                let then_bb = self.module.append_basic_block(the_function);
-                let else_bb = self.module.create_basic_block();
-                let merge_bb = self.module.create_basic_block();
+                let then_bb = self.module.append_basic_block(the_function, "then");
+                let else_bb = self.module.create_basic_block("else");
+                let merge_bb = self.module.create_basic_block("ifcont");
 
                 // Create a conditional branch based on the result of the 'cond' expression.
                 self.builder.cond_br(cond_v, then_bb, else_bb);
@@ -146,6 +436,7 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                 let phi = self.builder.phi(
                     self.module.type_f64(),
                     &[(then_v, then_bb), (else_v, else_bb)],
+                    "iftmp",
                 );
 
                 Ok(*phi)
@@ -160,42 +451,59 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                 // For 'for' expression we build the following structure.
                 //
                 // entry:
+                //   i = alloca double
+                //   ...
                 //   init = start expression
+                //   store %init, %i
                 //   br loop
                 // loop:
-                //   i = phi [%init, %entry], [%new_i, %loop]
                 //   ; loop body ...
-                //   new_i = increment %i by step expression
-                //   ; check end condition and branch
-                // end:
+                //   br loopstep
+                // loopstep:
+                //   ; step and end condition
+                //   new_i = increment (load %i) by step expression
+                //   store %new_i, %i
+                //   ; branch on end condition
+                // afterloop:
+                //
+                // 'continue' branches to 'loopstep' and 'break' to 'afterloop'.
+
+                let the_function = self.builder.get_insert_block().get_parent();
+                // Create an alloca for the variable in the entry block.
+                let alloca = self.create_entry_block_alloca(the_function, var);
 
-                // Compute initial value for the loop variable.
+                // Compute initial value for the loop variable and store it into the alloca.
                 let start_val = self.codegen_expr(start, named_values)?;
+                self.builder.store(start_val, alloca);
 
-                let the_function = self.builder.get_insert_block().get_parent();
-                // Get current basic block (used in the loop variable phi node).
-                let entry_bb = self.builder.get_insert_block();
                 // Add new basic block to emit loop body.
-                let loop_bb = self.module.append_basic_block(the_function);
+                let loop_bb = self.module.append_basic_block(the_function, "loop");
 
                 self.builder.br(loop_bb);
                 self.builder.pos_at_end(loop_bb);
 
-                // Build phi not to pick loop variable in case we come from the 'entry' block.
-                // Which is the case when we enter the loop for the first time.
-                // We will add another incoming value once we computed the updated loop variable
-                // below.
-                let variable = self
-                    .builder
-                    .phi(self.module.type_f64(), &[(start_val, entry_bb)]);
-
                 // Insert the loop variable into the named values map that it can be referenced
                 // from the body as well as the end condition.
                 // In case the loop variable shadows an existing variable remember the shared one.
-                let old_val = named_values.insert(var.into(), *variable);
+                let old_val = named_values.insert(var.into(), alloca);
+
+                // Blocks for the step and following the loop, which are targets of 'continue'
+                // and 'break' in the body.
+                let step_bb = self.module.create_basic_block("loopstep");
+                let after_bb = self.module.create_basic_block("afterloop");
 
                 // Generate the loop body.
-                self.codegen_expr(body, named_values)?;
+                self.loops
+                    .borrow_mut()
+                    .push(LoopTargets { step_bb, after_bb });
+                let body_val = self.codegen_expr(body, named_values);
+                self.loops.borrow_mut().pop();
+                body_val?;
+
+                // Continue with the step, like 'continue' does.
+                self.builder.br(step_bb);
+                the_function.append_basic_block(step_bb);
+                self.builder.pos_at_end(step_bb);
 
                 // Generate step value expression if available else use '1'.
                 let step_val = if let Some(step) = step {
@@ -204,26 +512,42 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                     self.module.type_f64().const_f64(1f64)
                 };
 
-                // Increment loop variable.
-                let next_var = self.builder.fadd(*variable, step_val);
-
-                // Generate the loop end condition.
+                // Generate the loop end condition, which sees the loop variable before the
+                // increment.
                 let end_cond = self.codegen_expr(end, named_values)?;
-                let end_cond = self
-                    .builder
-                    .fcmpone(end_cond, self.module.type_f64().const_f64(0f64));
-
-                // Get current basic block.
-                let loop_end_bb = self.builder.get_insert_block();
-                // Add new basic block following the loop.
-                let after_bb = self.module.append_basic_block(the_function);
+                let end_cond = self.builder.fcmpone(
+                    end_cond,
+                    self.module.type_f64().const_f64(0f64),
+                    "loopcond",
+                );
 
-                // Register additional incoming value for the loop variable. This will choose the
-                // updated loop variable if we are iterating in the loop.
-                variable.add_incoming(next_var, loop_end_bb);
+                // Reload, increment, and restore the alloca. This handles the case where the body
+                // of the loop mutates the variable.
+                let cur_var = self.builder.load(self.module.type_f64(), alloca, var);
+                let next_var = self.builder.fadd(cur_var, step_val, "nextvar");
+                self.builder.store(next_var, alloca);
+
+                // Add basic block checking the interrupt flag before the next iteration.
+                let check_bb = self.opts.interrupt_flag.map(|flag| {
+                    (
+                        flag,
+                        self.module.append_basic_block(the_function, "loopcheck"),
+                    )
+                });
+                // Add the basic block following the loop.
+                the_function.append_basic_block(after_bb);
 
                 // Branch depending on the loop end condition.
-                self.builder.cond_br(end_cond, loop_bb, after_bb);
+                match check_bb {
+                    Some((flag, check_bb)) => {
+                        // Leave the loop early once the evaluation is interrupted.
+                        self.builder.cond_br(end_cond, check_bb, after_bb);
+                        self.builder.pos_at_end(check_bb);
+                        let interrupted = self.load_interrupted(flag);
+                        self.builder.cond_br(interrupted, after_bb, loop_bb);
+                    }
+                    None => self.builder.cond_br(end_cond, loop_bb, after_bb),
+                }
 
                 self.builder.pos_at_end(after_bb);
 
@@ -238,10 +562,78 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
                 // Loops just always return 0.
                 Ok(self.module.type_f64().const_f64(0f64))
             }
+            ExprAST::VarIn { vars, body } => {
+                let the_function = self.builder.get_insert_block().get_parent();
+
+                // Register all variables and emit their initializer.
+                let mut old_vals = Vec::new();
+                for (var, init) in vars {
+                    // Emit the initializer before adding the variable to scope, such that it
+                    // refers to a shadowed variable of the same name, eg 'var a = a in ...'.
+                    let init_val = match init {
+                        Some(init) => self.codegen_expr(init, named_values)?,
+                        None => self.module.type_f64().const_f64(0f64),
+                    };
+
+                    let alloca = self.create_entry_block_alloca(the_function, var);
+                    self.builder.store(init_val, alloca);
+                    old_vals.push((var, named_values.insert(var.into(), alloca)));
+                }
+
+                // Codegen the body, now that all vars are in scope.
+                let body_val = self.codegen_expr(body, named_values)?;
+
+                // Pop all our variables from scope, in reverse order such that a variable
+                // declared twice restores the outer one.
+                for (var, old_val) in old_vals.into_iter().rev() {
+                    match old_val {
+                        Some(old_val) => named_values.insert(var.into(), old_val),
+                        None => named_values.remove(var),
+                    };
+                }
+
+                Ok(body_val)
+            }
+            ExprAST::Assign { name, value } => {
+                // Codegen the value first, it may refer to the variable itself, eg 'a = a + 1'.
+                let val = self.codegen_expr(value, named_values)?;
+
+                // Store the value into the stack slot of the variable.
+                let alloca = match named_values.get(name.as_str()) {
+                    Some(alloca) => *alloca,
+                    None => return err(&format!("Assignment to unknown variable '{}'", name)),
+                };
+                self.builder.store(val, alloca);
+
+                // The assignment evaluates to the assigned value.
+                Ok(val)
+            }
+            ExprAST::Match { .. } => err("match must be desugared before codegen"),
+            ExprAST::Break | ExprAST::Continue => {
+                // The parser only accepts 'break' and 'continue' in the body of a loop.
+                let targets = match self.loops.borrow().last() {
+                    Some(targets) => *targets,
+                    None => return err("'break' or 'continue' outside of a loop body"),
+                };
+                let target = match expr.node {
+                    ExprAST::Break => targets.after_bb,
+                    _ => targets.step_bb,
+                };
+                self.builder.br(target);
+
+                // Code following the jump is unreachable, emit it into a new block without
+                // predecessors.
+                let the_function = self.builder.get_insert_block().get_parent();
+                let bb = self.module.append_basic_block(the_function, "afterjump");
+                self.builder.pos_at_end(bb);
+
+                Ok(self.module.type_f64().const_f64(0f64))
+            }
+            ExprAST::Lambda(..) => err("lambdas must be lifted before codegen"),
         }
     }
 
-    fn codegen_prototype(&self, PrototypeAST(name, args): &PrototypeAST) -> FnValue<'llvm> {
+    fn codegen_prototype(&self, PrototypeAST(name, args, _): &PrototypeAST) -> FnValue<'llvm> {
         let type_f64 = self.module.type_f64();
 
         let mut doubles = Vec::new();
@@ -261,14 +653,40 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
         f
     }
 
+    fn codegen_extern(&mut self, proto: &PrototypeAST) -> CodegenResult<FnValue<'llvm>> {
+        check_redeclaration(self.fn_protos, proto)?;
+
+        let the_function = match self.module.get_fn(&proto.0) {
+            Some(f) => f,
+            None => self.codegen_prototype(proto),
+        };
+
+        // Keep track of external function declaration, unless the function was already defined.
+        if !matches!(
+            self.fn_protos.get(&proto.0),
+            Some(FnProto {
+                origin: ProtoOrigin::Def,
+                ..
+            })
+        ) {
+            self.record_proto(proto, ProtoOrigin::Extern);
+        }
+
+        self.stats.declarations += 1;
+        log_event!(debug, "declared extern '{}'", proto.0);
+        Ok(the_function)
+    }
+
     fn codegen_function(
         &mut self,
         FunctionAST(proto, body): &FunctionAST,
         named_values: &mut HashMap<String, Value<'llvm>>,
     ) -> CodegenResult<FnValue<'llvm>> {
+        check_redeclaration(self.fn_protos, proto)?;
+
         // Insert the function prototype into the `fn_protos` map to keep track for re-generating
         // declarations in other modules.
-        self.fn_protos.insert(proto.0.clone(), proto.clone());
+        self.record_proto(proto, ProtoOrigin::Def);
 
         let the_function = self.get_function(&proto.0)
             .expect("If proto not already generated, get_function will do for us since we updated fn_protos before-hand!");
@@ -278,32 +696,126 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
         }
 
         // Create entry basic block to insert code.
-        let bb = self.module.append_basic_block(the_function);
+        let bb = self.module.append_basic_block(the_function, "entry");
         self.builder.pos_at_end(bb);
 
+        if let Some(flag) = self.opts.interrupt_flag {
+            // Return right away once the evaluation is interrupted, which also unwinds recursive
+            // calls.
+            let interrupted_bb = self.module.append_basic_block(the_function, "interrupted");
+            let body_bb = self.module.append_basic_block(the_function, "body");
+
+            let interrupted = self.load_interrupted(flag);
+            self.builder.cond_br(interrupted, interrupted_bb, body_bb);
+
+            self.builder.pos_at_end(interrupted_bb);
+            self.builder.ret(self.module.type_f64().const_f64(0f64));
+
+            self.builder.pos_at_end(body_bb);
+        }
+
         // New scope, clear the map with the function args.
         named_values.clear();
 
-        // Update the map with the current functions args.
+        // Update the map with the current functions args, each argument is stored into its own
+        // alloca such that it can be mutated like any other variable.
         for idx in 0..the_function.args() {
             let arg = the_function.arg(idx);
-            named_values.insert(arg.get_name().into(), arg);
+            let alloca = self.create_entry_block_alloca(the_function, arg.get_name());
+            self.builder.store(arg, alloca);
+            named_values.insert(arg.get_name().into(), alloca);
+        }
+
+        if let Some(hooks) = &self.opts.hooks {
+            hooks.function_entry(self.module, self.builder, the_function);
         }
 
         // Codegen function body.
-        if let Ok(ret) = self.codegen_expr(body, named_values) {
-            self.builder.ret(ret);
-            assert!(the_function.verify());
+        //
+        // In case codegen fails the function is left in an incomplete state in the module, hence
+        // the caller must discard the module.
+        let ret = self.codegen_expr(body, named_values)?;
+
+        if self.opts.print_toplevel && proto.0.starts_with(ANON_EXPR) {
+            // Wrap the top-level expression such that it prints its own result.
+            let printd = self.get_printd();
+            self.builder.call(printd, &mut [ret], "printtmp");
+        }
 
-            // Run the optimization passes on the function.
-            self.fpm.run(the_function);
+        if let Some(hooks) = &self.opts.hooks {
+            hooks.function_exit(self.module, self.builder, the_function, ret);
+        }
 
-            Ok(the_function)
-        } else {
-            todo!("Failed to codegen function body, erase from module!");
+        self.builder.ret(ret);
+        assert!(the_function.verify());
+
+        // Run the optimization passes on the function.
+        let start = Instant::now();
+        self.fpm.run(the_function);
+        let opt_time = start.elapsed();
+
+        self.stats.functions += 1;
+        self.stats.opt_time += opt_time;
+        self.stats.fn_stats.push(FnStats {
+            name: proto.0.clone(),
+            instructions: the_function.instructions(),
+            basic_blocks: the_function.basic_blocks(),
+            opt_time,
+        });
+        log_event!(
+            debug,
+            "compiled function '{}', {} instructions, optimized in {:?}",
+            proto.0,
+            the_function.instructions(),
+            opt_time
+        );
+
+        Ok(the_function)
+    }
+
+    /// Record `proto` with its `origin` in the `fn_protos` map.
+    ///
+    /// The prototype is only copied if it differs from the one recorded for the same name.
+    fn record_proto(&mut self, proto: &PrototypeAST, origin: ProtoOrigin) {
+        match self.fn_protos.get_mut(&proto.0) {
+            Some(fn_proto) => {
+                if *fn_proto.proto != *proto {
+                    fn_proto.proto = Rc::new(proto.clone());
+                }
+                fn_proto.origin = origin;
+            }
+            None => {
+                let fn_proto = FnProto {
+                    proto: Rc::new(proto.clone()),
+                    origin,
+                };
+                self.fn_protos.insert(proto.0.clone(), fn_proto);
+            }
         }
     }
 
+    /// Create an alloca for the variable `name` in the entry block of `the_function`.
+    ///
+    /// Allocas in the entry block are promoted to registers by the mem2reg pass.
+    ///
+    /// Implement `static AllocaInst *CreateEntryBlockAlloca(..);` from the tutorial.
+    fn create_entry_block_alloca(&self, the_function: FnValue<'llvm>, name: &str) -> Value<'llvm> {
+        let builder = IRBuilder::with_ctx(self.module);
+        builder.pos_at_start(the_function.entry_basic_block());
+        builder.alloca(self.module.type_f64(), name)
+    }
+
+    /// Emit a load of the interrupt `flag` returning the flag as `i1` value.
+    fn load_interrupted(&self, flag: &'static AtomicBool) -> Value<'llvm> {
+        // AtomicBool has the same in-memory representation as a bool, which is loaded as i1.
+        let ptr = self
+            .module
+            .type_ptr()
+            .const_ptr(flag as *const AtomicBool as usize);
+        self.builder
+            .load_volatile(self.module.type_i1(), ptr, "interrupted")
+    }
+
     /// Lookup function with `name` in the LLVM module and return the corresponding value reference.
     /// If the function is not available in the module, check if the prototype is known and codegen
     /// it.
@@ -312,11 +824,29 @@ impl<'llvm, 'a> Codegen<'llvm, 'a> {
         let callee = match self.module.get_fn(name) {
             Some(callee) => callee,
             None => {
-                let proto = self.fn_protos.get(name)?;
+                let FnProto { proto, .. } = self.fn_protos.get(name)?;
                 self.codegen_prototype(proto)
             }
         };
 
         Some(callee)
     }
+
+    /// Lookup the `printd` builtin, declare it in the LLVM module if it is not known yet.
+    fn get_printd(&self) -> FnValue<'llvm> {
+        self.get_function("printd").unwrap_or_else(|| {
+            self.codegen_prototype(&PrototypeAST("printd".into(), vec!["x".into()], 0))
+        })
+    }
+
+    /// Lookup the `llvm.pow.f64` intrinsic, declare it in the LLVM module if it is not known yet.
+    fn get_pow(&self) -> FnValue<'llvm> {
+        self.module.get_fn(POW_INTRINSIC).unwrap_or_else(|| {
+            self.codegen_prototype(&PrototypeAST(
+                POW_INTRINSIC.into(),
+                vec!["x".into(), "y".into()],
+                0,
+            ))
+        })
+    }
 }
@@ -0,0 +1,451 @@
+//! Lowering of the AST to the core language handled by codegen.
+//!
+//! Some expressions are sugar for a combination of simpler ones. Desugaring rewrites them before
+//! codegen, such that [`Codegen`](crate::codegen) only has to emit the core expressions:
+//!
+//! - `-x` becomes `-1 * x`, which also keeps the sign of zero.
+//! - `a && b` becomes `if a then (if b then 1 else 0) else 0`.
+//! - `a || b` becomes `if a then 1 else (if b then 1 else 0)`.
+//! - `match x with 0 -> a | _ -> b` becomes `var __match = x in if __match == 0 then a else b`.
+//!
+//! Desugared nodes keep the span of the expression they replace.
+//!
+//! Lambdas bound with `var` are lifted into named functions by [`lift_lambdas`] before
+//! desugaring.
+
+use crate::codegen::{CodegenError, CodegenResult};
+use crate::lexer::Span;
+use crate::parser::{Expr, ExprAST, FunctionAST, LogicalOp, PrototypeAST, Spanned};
+
+/// Variable holding the value of a desugared `match`, which can't clash with the variables of the
+/// source since identifiers can't start with `_`.
+const MATCH_VAR: &str = "__match";
+
+/// Prefix of the names of the functions lifted from lambdas, see [`lift_lambdas`].
+pub const LAMBDA: &str = "__lambda";
+
+/// Lift the lambdas in the body of the function `func` into functions with the unique names
+/// `__lambda.N`, numbered starting at `next_id`.
+///
+/// A lambda can only be bound to a variable with `var` and called by that name in the rest of the
+/// `var` expression, like `var inc = \(x) x + 1 in inc(1)`. The calls are replaced by calls of the
+/// lifted function and the binding is removed. Any other use of a lambda is an error, function
+/// pointers are not supported yet, in which case no function is returned.
+///
+/// Lambdas don't capture variables, the body of a lambda can only refer to its own parameters.
+/// The lifted functions are returned in the order they must be compiled, nested lambdas first.
+pub fn lift_lambdas(
+    func: &mut FunctionAST,
+    next_id: &mut usize,
+) -> CodegenResult<Vec<FunctionAST>> {
+    let mut lifted = Vec::new();
+    let mut id = *next_id;
+    lift_expr(&mut func.1, &mut id, &mut lifted)?;
+    *next_id = id;
+    Ok(lifted)
+}
+
+fn lift_expr(expr: &mut Expr, id: &mut usize, lifted: &mut Vec<FunctionAST>) -> CodegenResult<()> {
+    match &mut expr.node {
+        ExprAST::Number(_)
+        | ExprAST::Str(_)
+        | ExprAST::Variable(_)
+        | ExprAST::Break
+        | ExprAST::Continue => {}
+        ExprAST::Unary(_, operand) => lift_expr(operand, id, lifted)?,
+        ExprAST::Binary(_, lhs, rhs) | ExprAST::Logical(_, lhs, rhs) => {
+            lift_expr(lhs, id, lifted)?;
+            lift_expr(rhs, id, lifted)?;
+        }
+        ExprAST::Call(_, args) => {
+            for arg in args {
+                lift_expr(arg, id, lifted)?;
+            }
+        }
+        ExprAST::If { cond, then, else_ } => {
+            lift_expr(cond, id, lifted)?;
+            lift_expr(then, id, lifted)?;
+            lift_expr(else_, id, lifted)?;
+        }
+        ExprAST::For {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            lift_expr(start, id, lifted)?;
+            lift_expr(end, id, lifted)?;
+            if let Some(step) = step {
+                lift_expr(step, id, lifted)?;
+            }
+            lift_expr(body, id, lifted)?;
+        }
+        ExprAST::VarIn { vars, body } => {
+            let mut idx = 0;
+            while idx < vars.len() {
+                match vars[idx].1.take() {
+                    Some(Spanned {
+                        node: ExprAST::Lambda(params, lambda_body),
+                        span,
+                    }) => {
+                        let name = lift_lambda(params, *lambda_body, span, id, lifted)?;
+                        let (var, _) = vars.remove(idx);
+                        call_lambda_in_var(&mut vars[idx..], body, &var, &name)?;
+                    }
+                    mut init => {
+                        if let Some(init) = &mut init {
+                            lift_expr(init, id, lifted)?;
+                        }
+                        vars[idx].1 = init;
+                        idx += 1;
+                    }
+                }
+            }
+            lift_expr(body, id, lifted)?;
+
+            // Without variables left, the `var` expression is just its body.
+            if vars.is_empty() {
+                expr.node = std::mem::replace(&mut body.node, ExprAST::Number(0.0));
+            }
+        }
+        ExprAST::Assign { value, .. } => lift_expr(value, id, lifted)?,
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            lift_expr(value, id, lifted)?;
+            for (_, arm) in arms {
+                lift_expr(arm, id, lifted)?;
+            }
+            lift_expr(default, id, lifted)?;
+        }
+        ExprAST::Lambda(..) => {
+            return Err(CodegenError::at(
+                expr.span,
+                "lambdas can only be bound with 'var' and called, function pointers are not \
+                 supported yet",
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Lift the lambda with the parameters `params` and the body `body` located at `span` into a
+/// function and return its name.
+fn lift_lambda(
+    params: Vec<String>,
+    mut body: Expr,
+    span: Span,
+    id: &mut usize,
+    lifted: &mut Vec<FunctionAST>,
+) -> CodegenResult<String> {
+    lift_expr(&mut body, id, lifted)?;
+
+    let name = format!("{}.{}", LAMBDA, id);
+    *id += 1;
+    let proto = Spanned::new(PrototypeAST(name.clone(), params, 0), span);
+    lifted.push(FunctionAST(proto, body));
+    Ok(name)
+}
+
+/// Replace the calls of the variable `var` bound to the lambda lifted into the function `name` in
+/// the remaining variables `vars` and the `body` of a `var` expression.
+fn call_lambda_in_var(
+    vars: &mut [(String, Option<Expr>)],
+    body: &mut Expr,
+    var: &str,
+    name: &str,
+) -> CodegenResult<()> {
+    for (other, init) in vars {
+        if let Some(init) = init {
+            call_lambda(init, var, name)?;
+        }
+        // The variable is shadowed from here on.
+        if other == var {
+            return Ok(());
+        }
+    }
+    call_lambda(body, var, name)
+}
+
+/// Replace the calls of the variable `var` in `expr` with calls of the function `name` the lambda
+/// bound to `var` is lifted into.
+///
+/// Using `var` as a value is an error, unless it is shadowed by a loop variable, another `var`
+/// binding or a lambda parameter.
+fn call_lambda(expr: &mut Expr, var: &str, name: &str) -> CodegenResult<()> {
+    match &mut expr.node {
+        ExprAST::Number(_) | ExprAST::Str(_) | ExprAST::Break | ExprAST::Continue => {}
+        ExprAST::Variable(other) | ExprAST::Assign { name: other, .. } if other == var => {
+            return Err(CodegenError::at(
+                expr.span,
+                format!(
+                    "'{}' is bound to a lambda, which can only be called, function pointers are \
+                     not supported yet",
+                    var
+                ),
+            ))
+        }
+        ExprAST::Variable(_) => {}
+        ExprAST::Unary(_, operand) => call_lambda(operand, var, name)?,
+        ExprAST::Binary(_, lhs, rhs) | ExprAST::Logical(_, lhs, rhs) => {
+            call_lambda(lhs, var, name)?;
+            call_lambda(rhs, var, name)?;
+        }
+        ExprAST::Call(callee, args) => {
+            if callee == var {
+                *callee = name.into();
+            }
+            for arg in args {
+                call_lambda(arg, var, name)?;
+            }
+        }
+        ExprAST::If { cond, then, else_ } => {
+            call_lambda(cond, var, name)?;
+            call_lambda(then, var, name)?;
+            call_lambda(else_, var, name)?;
+        }
+        ExprAST::For {
+            var: other,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            call_lambda(start, var, name)?;
+            if other != var {
+                call_lambda(end, var, name)?;
+                if let Some(step) = step {
+                    call_lambda(step, var, name)?;
+                }
+                call_lambda(body, var, name)?;
+            }
+        }
+        ExprAST::VarIn { vars, body } => call_lambda_in_var(vars, body, var, name)?,
+        ExprAST::Assign { value, .. } => call_lambda(value, var, name)?,
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            call_lambda(value, var, name)?;
+            for (_, arm) in arms {
+                call_lambda(arm, var, name)?;
+            }
+            call_lambda(default, var, name)?;
+        }
+        ExprAST::Lambda(params, body) => {
+            if !params.iter().any(|param| param == var) {
+                call_lambda(body, var, name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Desugar the body of the function `func`.
+pub fn desugar_function(func: &mut FunctionAST) {
+    desugar_expr(&mut func.1);
+}
+
+/// Desugar the expression `expr` and all its subexpressions.
+pub fn desugar_expr(expr: &mut Expr) {
+    match &mut expr.node {
+        ExprAST::Number(_)
+        | ExprAST::Str(_)
+        | ExprAST::Variable(_)
+        | ExprAST::Break
+        | ExprAST::Continue => {}
+        ExprAST::Unary(_, operand) => desugar_expr(operand),
+        ExprAST::Binary(_, lhs, rhs) | ExprAST::Logical(_, lhs, rhs) => {
+            desugar_expr(lhs);
+            desugar_expr(rhs);
+        }
+        ExprAST::Call(_, args) => args.iter_mut().for_each(desugar_expr),
+        ExprAST::If { cond, then, else_ } => {
+            desugar_expr(cond);
+            desugar_expr(then);
+            desugar_expr(else_);
+        }
+        ExprAST::For {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            desugar_expr(start);
+            desugar_expr(end);
+            if let Some(step) = step {
+                desugar_expr(step);
+            }
+            desugar_expr(body);
+        }
+        ExprAST::VarIn { vars, body } => {
+            for init in vars.iter_mut().filter_map(|(_, init)| init.as_mut()) {
+                desugar_expr(init);
+            }
+            desugar_expr(body);
+        }
+        ExprAST::Assign { value, .. } => desugar_expr(value),
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            desugar_expr(value);
+            for (_, arm) in arms {
+                desugar_expr(arm);
+            }
+            desugar_expr(default);
+        }
+        ExprAST::Lambda(_, body) => desugar_expr(body),
+    }
+
+    let span = expr.span;
+    let node = |node| Box::new(Expr::new(node, span));
+    let num = |num| node(ExprAST::Number(num));
+
+    // Take the node to move its operands into the desugared node.
+    expr.node = match std::mem::replace(&mut expr.node, ExprAST::Number(0.0)) {
+        ExprAST::Unary(op, operand) if op == "-" => ExprAST::Binary("*".into(), num(-1.0), operand),
+        ExprAST::Logical(op, lhs, rhs) => {
+            // Turn the right operand into `1` or `0`, like the result of a comparison.
+            let rhs = node(ExprAST::If {
+                cond: rhs,
+                then: num(1.0),
+                else_: num(0.0),
+            });
+            match op {
+                LogicalOp::And => ExprAST::If {
+                    cond: lhs,
+                    then: rhs,
+                    else_: num(0.0),
+                },
+                LogicalOp::Or => ExprAST::If {
+                    cond: lhs,
+                    then: num(1.0),
+                    else_: rhs,
+                },
+            }
+        }
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            // Test the arms in order, the first matching arm is the innermost `then` branch.
+            let var = || node(ExprAST::Variable(MATCH_VAR.into()));
+            let body = arms
+                .into_iter()
+                .rev()
+                .fold(default, |else_, (pattern, arm)| {
+                    node(ExprAST::If {
+                        cond: node(ExprAST::Binary("==".into(), var(), num(pattern))),
+                        then: Box::new(arm),
+                        else_,
+                    })
+                });
+            ExprAST::VarIn {
+                vars: vec![(MATCH_VAR.into(), Some(*value))],
+                body,
+            }
+        }
+        node => node,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{desugar_expr, lift_lambdas};
+    use crate::parser::{parse_expr, FunctionAST, PrototypeAST};
+
+    fn desugar(src: &str) -> String {
+        let mut expr = parse_expr(src).unwrap();
+        desugar_expr(&mut expr);
+        expr.to_string()
+    }
+
+    #[test]
+    fn desugar_sugar() {
+        assert_eq!(desugar("-x + f(-1)"), "-1 * x + f(-1 * 1)");
+        assert_eq!(desugar("a && b"), "if a then if b then 1 else 0 else 0");
+        assert_eq!(
+            desugar("a || -b"),
+            "if a then 1 else if -1 * b then 1 else 0"
+        );
+
+        assert_eq!(
+            desugar("match f(x) with 1 -> a | -2 -> b | _ -> c"),
+            "var __match = f(x) in if __match == 1 then a else if __match == -2 then b else c"
+        );
+
+        // Core expressions and unknown operators are kept.
+        assert_eq!(
+            desugar("for i = 0, i < !x in a = b"),
+            "for i = 0, i < !x in a = b"
+        );
+
+        let mut expr = parse_expr("1 + -x").unwrap();
+        desugar_expr(&mut expr);
+        assert_eq!(expr.span, crate::lexer::Span::new(0, 6));
+    }
+
+    fn lift(src: &str) -> Result<(String, Vec<String>), String> {
+        let body = parse_expr(src).unwrap();
+        let mut func = FunctionAST(PrototypeAST("h".into(), vec![], 0).into(), body);
+        let mut next_id = 0;
+        let lifted = lift_lambdas(&mut func, &mut next_id).map_err(|err| err.msg)?;
+        assert_eq!(next_id, lifted.len());
+        let lifted = lifted.iter().map(|func| func.to_string()).collect();
+        Ok((func.1.to_string(), lifted))
+    }
+
+    #[test]
+    fn lift_bound_lambdas() {
+        // Nested lambdas are lifted first.
+        assert_eq!(
+            lift("var a = 1, f = \\(x) var g = \\() 2 in x + g(), b = f(a) in f(b)"),
+            Ok((
+                "var a = 1, b = __lambda.1(a) in __lambda.1(b)".into(),
+                vec![
+                    "def __lambda.0() 2".into(),
+                    "def __lambda.1(x) x + __lambda.0()".into()
+                ]
+            ))
+        );
+
+        // Without other variables the `var` expression is replaced by its body.
+        assert_eq!(
+            lift("var f = \\(x) x in f(1)"),
+            Ok(("__lambda.0(1)".into(), vec!["def __lambda.0(x) x".into()]))
+        );
+
+        // Shadowed variables are not replaced.
+        assert_eq!(
+            lift("var f = \\(x) x in f(1) + (var f = 2 in f) + for f = f(1), f < 2 in f"),
+            Ok((
+                "__lambda.0(1) + (var f = 2 in f) + (for f = __lambda.0(1), f < 2 in f)".into(),
+                vec!["def __lambda.0(x) x".into()]
+            ))
+        );
+    }
+
+    #[test]
+    fn lift_unsupported_lambdas() {
+        let pointers = "function pointers are not supported yet";
+        for src in [
+            "f(\\(x) x)",
+            "\\() 1",
+            "var f = 1 in f = \\() 1",
+            "var f = \\() 1 in f",
+            "var f = \\() 1 in f = 2",
+            "var f = \\() 1 in g(\\(x) f)",
+        ] {
+            let err = lift(src).unwrap_err();
+            assert!(err.ends_with(pointers), "{}: {}", src, err);
+        }
+    }
+}
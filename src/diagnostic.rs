@@ -0,0 +1,110 @@
+//! Rendering of error messages together with an excerpt of the offending source.
+
+use crate::lexer::Span;
+
+/// Render the error `msg` located at `span` in the source `src` with the name `name`.
+///
+/// The diagnostic shows the source line containing the start of `span` and underlines the span,
+/// spans reaching across multiple lines are underlined up to the end of the first line.
+///
+/// ```text
+/// foo.ks:1:18: error: expected ')' or binary operator, found ';'
+/// def foo(x) (x + 1;
+///                  ^
+/// ```
+pub fn render(name: &str, src: &str, span: Span, msg: &str) -> String {
+    let chars: Vec<char> = src.chars().collect();
+
+    let mut start = span.start.min(chars.len());
+    let mut end = span.end.clamp(start, chars.len());
+
+    // Empty spans (end of input) point right behind the last token instead of at trailing
+    // whitespace.
+    if start == end {
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        end = start;
+    }
+
+    let line_start = chars[..start]
+        .iter()
+        .rposition(|&c| c == '\n')
+        .map_or(0, |idx| idx + 1);
+    let line_end = chars[start..]
+        .iter()
+        .position(|&c| c == '\n')
+        .map_or(chars.len(), |idx| start + idx);
+    let line_no = chars[..line_start].iter().filter(|&&c| c == '\n').count() + 1;
+    let col = start - line_start + 1;
+
+    // Underline up to the end of the line, without trailing whitespace.
+    let mut underline_end = end.min(line_end);
+    while underline_end > start && chars[underline_end - 1].is_whitespace() {
+        underline_end -= 1;
+    }
+    let underline_len = (underline_end - start).max(1);
+
+    let line: String = chars[line_start..line_end].iter().collect();
+    let line = line.trim_end();
+
+    // Keep tabs in the indentation of the caret to align it with the source line.
+    let indent: String = chars[line_start..start]
+        .iter()
+        .map(|&c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    format!(
+        "{}:{}:{}: error: {}\n{}\n{}^{}",
+        name,
+        line_no,
+        col,
+        msg,
+        line,
+        indent,
+        "~".repeat(underline_len - 1)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+    use crate::lexer::Span;
+
+    #[test]
+    fn render_token() {
+        let src = "def foo(x)\n  x + )\n";
+        assert_eq!(
+            render("foo.ks", src, Span::new(17, 18), "unexpected token"),
+            "foo.ks:2:7: error: unexpected token\n  x + )\n      ^"
+        );
+    }
+
+    #[test]
+    fn render_multi_line_span() {
+        let src = "1 +\nfoo(2,\n  3)";
+        assert_eq!(
+            render(
+                "<stdin>",
+                src,
+                Span::new(4, 14),
+                "Unknown function referenced"
+            ),
+            "<stdin>:2:1: error: Unknown function referenced\nfoo(2,\n^~~~~~"
+        );
+    }
+
+    #[test]
+    fn render_eof() {
+        let src = "def foo(x\n\n";
+        assert_eq!(
+            render(
+                "foo.ks",
+                src,
+                Span::new(11, 11),
+                "Expected ')' in prototype"
+            ),
+            "foo.ks:1:10: error: Expected ')' in prototype\ndef foo(x\n         ^"
+        );
+    }
+}
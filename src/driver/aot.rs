@@ -0,0 +1,379 @@
+use crate::backend::ModuleBackend;
+use crate::codegen::CodegenOptions;
+use crate::llvm::{FileType, TargetMachine};
+use crate::parser::PrototypeAST;
+
+use std::path::Path;
+
+use super::{read_file, read_stdin, run_kaleidoscope, DriverConfig, Emit};
+
+/// Compile the source files and `-e` snippets, or stdin if none are given, into a single module
+/// for the target machine `tm`.
+fn compile_module(
+    config: &DriverConfig,
+    tm: &TargetMachine,
+    cg_opts: CodegenOptions,
+) -> Result<ModuleBackend, String> {
+    let mut backend = ModuleBackend::new(cg_opts);
+    backend.module().set_target(tm);
+
+    let opts = config.loop_opts(false, true);
+
+    let mut errors = 0;
+    if config.files.is_empty() && config.eval.is_empty() {
+        if config.trace >= 1 {
+            eprintln!("Parse stdin.");
+        }
+        errors += run_kaleidoscope("<stdin>", &read_stdin()?, &mut backend, opts);
+    }
+    for file in &config.files {
+        if config.trace >= 1 {
+            eprintln!("Parse {}.", file);
+        }
+        errors += run_kaleidoscope(file, &read_file(file)?, &mut backend, opts);
+    }
+    for code in &config.eval {
+        errors += run_kaleidoscope("<cmdline>", code, &mut backend, opts);
+    }
+
+    if errors > 0 {
+        return Err(format!("Compilation failed with {} error(s).", errors));
+    }
+    Ok(backend)
+}
+
+/// Get the output file, either given with `-o` or derived from the first source file by
+/// replacing its extension with `extension`.
+fn output_file(config: &DriverConfig, extension: &str) -> String {
+    match (&config.output, config.files.first()) {
+        (Some(output), _) => output.clone(),
+        (None, Some(file)) => std::path::Path::new(file)
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned(),
+        (None, None) if extension.is_empty() => "a.out".into(),
+        (None, None) => format!("out.{}", extension),
+    }
+}
+
+/// Compile the source files into a single module and write it in the `emit` format to the
+/// output file.
+pub(super) fn emit_module(config: &DriverConfig, emit: Emit) -> Result<(), String> {
+    let tm = config.target_machine()?;
+    let backend = compile_module(config, &tm, config.codegen_options(false))?;
+    let output = output_file(config, emit.extension());
+
+    write_header(config, &backend)?;
+
+    let module = backend.module();
+    match emit {
+        Emit::Ir => module.print_to_file(&output),
+        Emit::Bc => module.write_bitcode_to_file(&output),
+        Emit::Asm => tm.emit_to_file(module, &output, FileType::Asm),
+        Emit::Obj => tm.emit_to_file(module, &output, FileType::Obj),
+        Emit::Wasm => link_wasm(&backend, &tm, &output),
+    }?;
+
+    println!("Wrote {}.", output);
+    Ok(())
+}
+
+/// Emit the module of `backend` as wasm object file and link it with `wasm-ld`, which can be
+/// overridden with the `WASM_LD` environment variable, into the WebAssembly module `output`.
+///
+/// All functions are exported, undeclared externs become imports from the `env` module. The
+/// [`wasm_shim`] providing these imports is written next to `output` with the extension `mjs`.
+fn link_wasm(backend: &ModuleBackend, tm: &TargetMachine, output: &str) -> Result<(), String> {
+    let obj = std::env::temp_dir().join(format!("kaleidoscope-{}.wasm.o", std::process::id()));
+
+    let res = (|| {
+        tm.emit_to_file(backend.module(), &obj.to_string_lossy(), FileType::Obj)?;
+
+        let ld = std::env::var("WASM_LD").unwrap_or_else(|_| "wasm-ld".into());
+        let status = std::process::Command::new(&ld)
+            .args(["--no-entry", "--export-all", "--allow-undefined", "-o"])
+            .arg(output)
+            .arg(&obj)
+            .status()
+            .map_err(|err| format!("Failed to run linker '{}': {}", ld, err))?;
+
+        if !status.success() {
+            return Err(format!("Linker '{}' failed with {}", ld, status));
+        }
+        Ok(())
+    })();
+
+    // Remove the temporary object file, whether linking succeeded or not.
+    let _ = std::fs::remove_file(&obj);
+    res?;
+
+    let shim = Path::new(output).with_extension("mjs");
+    let wasm = Path::new(output)
+        .file_name()
+        .map_or_else(|| output.into(), |f| f.to_string_lossy());
+    std::fs::write(
+        &shim,
+        wasm_shim(&wasm, &backend.externs(), backend.top_level()),
+    )
+    .map_err(|err| format!("Failed to write {}: {}", shim.display(), err))?;
+    println!("Wrote {}.", shim.display());
+    Ok(())
+}
+
+/// JavaScript module instantiating the WebAssembly module `wasm`, providing the `imports` and
+/// running the `top_level` expressions in order.
+///
+/// The builtins `putchard` and `printd` are implemented by the shim, other imports default to
+/// the function of the same name in `Math`, eg `sin`, and can be overridden by the host.
+fn wasm_shim(wasm: &str, imports: &[&PrototypeAST], top_level: &[String]) -> String {
+    let list = |names: &mut dyn Iterator<Item = &str>| {
+        names
+            .map(|n| format!("\"{}\"", n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut shim = format!(
+        "// Host shim for {}, generated by llvm-kaleidoscope-rs.\n//\n",
+        wasm
+    );
+    shim.push_str("// Imports of the module from \"env\", all arguments and results are f64:\n");
+    for proto in imports {
+        shim.push_str(&format!("//   {}\n", proto));
+    }
+    shim.push_str(&format!(
+        "\
+//
+// Run with `node {js}`, or import `run` to pass the module bytes and imports from the host.
+
+const IMPORTS = [{imports}];
+const TOP_LEVEL = [{top_level}];
+
+let line = \"\";
+const builtins = {{
+  putchard: (c) => {{
+    if (c === 10) {{
+      console.log(line);
+      line = \"\";
+    }} else {{
+      line += String.fromCharCode(c);
+    }}
+    return 0;
+  }},
+  printd: (x) => {{
+    console.log(x);
+    return 0;
+  }},
+}};
+
+export async function instantiate(bytes, imports = {{}}) {{
+  const env = {{}};
+  for (const name of IMPORTS) {{
+    env[name] = imports[name] ?? builtins[name] ?? Math[name];
+    if (typeof env[name] !== \"function\") {{
+      throw new Error(`Missing import '${{name}}'`);
+    }}
+  }}
+  const {{ instance }} = await WebAssembly.instantiate(bytes, {{ env }});
+  return instance.exports;
+}}
+
+export async function run(bytes, imports) {{
+  const exports = await instantiate(bytes, imports);
+  return TOP_LEVEL.map((name) => exports[name]());
+}}
+
+if (typeof process !== \"undefined\" && import.meta.url === `file://${{process.argv[1]}}`) {{
+  const {{ readFile }} = await import(\"node:fs/promises\");
+  const bytes = await readFile(new URL(\"{wasm}\", import.meta.url));
+  for (const res of await run(bytes)) {{
+    console.log(res);
+  }}
+}}
+",
+        js = Path::new(wasm).with_extension("mjs").display(),
+        wasm = wasm,
+        imports = list(&mut imports.iter().map(|p| p.0.as_str())),
+        top_level = list(&mut top_level.iter().map(String::as_str)),
+    ));
+    shim
+}
+
+/// Compile the source files into a single module and only write the header given with
+/// `--emit-header`.
+pub(super) fn emit_header(config: &DriverConfig) -> Result<(), String> {
+    let tm = config.target_machine()?;
+    let backend = compile_module(config, &tm, config.codegen_options(false))?;
+    write_header(config, &backend)
+}
+
+/// Write the C prototypes of the functions defined in `backend` to the header file given with
+/// `--emit-header`, if any.
+fn write_header(config: &DriverConfig, backend: &ModuleBackend) -> Result<(), String> {
+    let header = match &config.emit_header {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+
+    std::fs::write(header, c_header(header, &backend.definitions()))
+        .map_err(|err| format!("Failed to write {}: {}", header, err))?;
+    println!("Wrote {}.", header);
+    Ok(())
+}
+
+/// C header declaring the functions `defs`, with an include guard derived from the file name
+/// `header`.
+///
+/// All kaleidoscope values are doubles, hence a function `def fib(x)` is declared as
+/// `double fib(double x);`.
+fn c_header(header: &str, defs: &[&PrototypeAST]) -> String {
+    let guard: String = Path::new(header)
+        .file_name()
+        .map_or_else(|| header.into(), |f| f.to_string_lossy())
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+
+    let mut out = format!(
+        "// Prototypes of the kaleidoscope functions, generated by llvm-kaleidoscope-rs.\n\
+         #ifndef {guard}\n\
+         #define {guard}\n\
+         \n\
+         #ifdef __cplusplus\n\
+         extern \"C\" {{\n\
+         #endif\n\
+         \n",
+        guard = guard
+    );
+    for PrototypeAST(name, args, _) in defs {
+        // Operators like `binary|` can't be declared in C.
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            continue;
+        }
+        let params = if args.is_empty() {
+            "void".into()
+        } else {
+            args.iter()
+                .map(|a| format!("double {}", a))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        out.push_str(&format!("double {}({});\n", name, params));
+    }
+    out.push_str(&format!(
+        "\n\
+         #ifdef __cplusplus\n\
+         }}\n\
+         #endif\n\
+         \n\
+         #endif // {}\n",
+        guard
+    ));
+    out
+}
+
+/// C runtime providing the builtins for executables compiled with `--aot`.
+const AOT_RUNTIME: &str = include_str!("runtime.c");
+
+/// Compile the source files into an executable, which evaluates the top-level expressions in
+/// order and prints their results.
+///
+/// The module is emitted as object file and linked together with the [`AOT_RUNTIME`] by the
+/// system C compiler, which can be overridden with the `CC` environment variable.
+pub(super) fn compile_executable(config: &DriverConfig) -> Result<(), String> {
+    let tm = config.target_machine()?;
+
+    // Let the top-level expressions print their results.
+    let backend = compile_module(config, &tm, config.codegen_options(true))?;
+    backend.compile_main()?;
+    write_header(config, &backend)?;
+
+    let output = output_file(config, "");
+
+    let tmp = std::env::temp_dir();
+    let obj = tmp.join(format!("kaleidoscope-{}.o", std::process::id()));
+    let rt = tmp.join(format!("kaleidoscope-{}-rt.c", std::process::id()));
+
+    let res = (|| {
+        tm.emit_to_file(backend.module(), &obj.to_string_lossy(), FileType::Obj)?;
+        std::fs::write(&rt, AOT_RUNTIME)
+            .map_err(|err| format!("Failed to write {}: {}", rt.display(), err))?;
+
+        let cc = std::env::var("CC").unwrap_or_else(|_| "cc".into());
+        let status = std::process::Command::new(&cc)
+            .arg("-o")
+            .arg(&output)
+            .arg(&obj)
+            .arg(&rt)
+            .status()
+            .map_err(|err| format!("Failed to run linker '{}': {}", cc, err))?;
+
+        if !status.success() {
+            return Err(format!("Linker '{}' failed with {}", cc, status));
+        }
+        Ok(())
+    })();
+
+    // Remove temporary files, whether linking succeeded or not.
+    let _ = std::fs::remove_file(&obj);
+    let _ = std::fs::remove_file(&rt);
+
+    res?;
+    println!("Wrote {}.", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{c_header, output_file, wasm_shim};
+    use crate::driver::DriverConfig;
+    use crate::parser::PrototypeAST;
+
+    #[test]
+    fn output_file_name() {
+        let mut config = DriverConfig {
+            files: vec!["ks/fib.ks".into(), "ks/mandel.ks".into()],
+            ..Default::default()
+        };
+        assert_eq!(output_file(&config, "o"), "ks/fib.o");
+        assert_eq!(output_file(&config, ""), "ks/fib");
+
+        config.output = Some("prog".into());
+        assert_eq!(output_file(&config, "o"), "prog");
+
+        let config = DriverConfig::default();
+        assert_eq!(output_file(&config, "ll"), "out.ll");
+        assert_eq!(output_file(&config, ""), "a.out");
+    }
+
+    #[test]
+    fn wasm_shim_imports() {
+        let sin = PrototypeAST("sin".into(), vec!["x".into()], 0);
+        let shim = wasm_shim("fib.wasm", &[&sin], &["__anon_expr.0".into()]);
+
+        assert!(shim.starts_with("// Host shim for fib.wasm"));
+        assert!(shim.contains("//   sin(x)\n"));
+        assert!(shim.contains("const IMPORTS = [\"sin\"];\n"));
+        assert!(shim.contains("const TOP_LEVEL = [\"__anon_expr.0\"];\n"));
+        assert!(shim.contains("new URL(\"fib.wasm\", import.meta.url)"));
+        assert!(shim.contains("Run with `node fib.mjs`"));
+    }
+
+    #[test]
+    fn c_header_prototypes() {
+        let fib = PrototypeAST("fib".into(), vec!["x".into()], 0);
+        let add = PrototypeAST("add".into(), vec!["a".into(), "b".into()], 0);
+        let one = PrototypeAST("one".into(), vec![], 0);
+        let or = PrototypeAST("binary|".into(), vec!["a".into(), "b".into()], 5);
+        let header = c_header("out/fib-lib.h", &[&add, &or, &fib, &one]);
+
+        assert!(header.contains("#ifndef FIB_LIB_H\n#define FIB_LIB_H\n"));
+        assert!(header.contains(
+            "double add(double a, double b);\ndouble fib(double x);\ndouble one(void);\n"
+        ));
+        assert!(header.ends_with("#endif // FIB_LIB_H\n"));
+    }
+}
@@ -0,0 +1,492 @@
+use crate::codegen::{CodegenOptions, OptLevel};
+use crate::llvm::TargetMachine;
+
+use super::LoopOpts;
+
+/// Output formats of the `--emit` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    /// Textual LLVM IR.
+    Ir,
+    /// LLVM bitcode.
+    Bc,
+    /// Native assembly.
+    Asm,
+    /// Native object file.
+    Obj,
+    /// WebAssembly module for the `wasm32` target, linked with `wasm-ld`, together with a
+    /// JavaScript host shim.
+    Wasm,
+}
+
+impl Emit {
+    fn from_str(s: &str) -> Option<Emit> {
+        match s {
+            "ir" => Some(Emit::Ir),
+            "bc" => Some(Emit::Bc),
+            "asm" => Some(Emit::Asm),
+            "obj" => Some(Emit::Obj),
+            "wasm" => Some(Emit::Wasm),
+            _ => None,
+        }
+    }
+
+    /// File extension of the output file.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Emit::Ir => "ll",
+            Emit::Bc => "bc",
+            Emit::Asm => "s",
+            Emit::Obj => "o",
+            Emit::Wasm => "wasm",
+        }
+    }
+}
+
+/// Target triple of `--emit=wasm`, unless another wasm32 triple is given with `--target`.
+pub const WASM_TRIPLE: &str = "wasm32-unknown-unknown";
+
+/// Usage text printed for `-h`.
+pub const USAGE: &str = "\
+Usage: llvm-kaleidoscope-rs [-i] [-q] [-v] [-O0..3] [-e code] [--emit=ir|bc|asm|obj|wasm | --aot] [-o file] [--emit-header file] [file..]
+
+Compile and run the given kaleidoscope files in order. Definitions persist across
+files. Without files or -e, read from stdin.
+
+If stdin is not a terminal, it is compiled like a file without prompts, stopping at
+the first error with a non-zero exit status. The rest of the input is still parsed to
+report all syntax errors at once.
+
+Options:
+  -i                    Enter the REPL after running the files.
+  -e code               Run the given code after the files and exit, eg
+                        -e 'def sq(x) x*x; sq(4)'. Can be given multiple times.
+  --no-std              Do not load the standard library into the JIT session.
+  --keep-going          Do not stop at the first error when stdin is not a terminal
+                        or in code given with -e.
+  -q, --no-dump-ir      Do not dump the generated LLVM IR. This is the default when
+                        stdin is not a terminal.
+  --dump-ir             Dump the generated LLVM IR, even if stdin is not a
+                        terminal.
+  -v, --trace           Log the parsed items and their AST to stderr, given
+                        twice (or -vv) also print the token stream.
+  --trace=level         Set the trace level, 0 (off), 1 (AST) or 2 (AST and
+                        tokens).
+  --emit=ir|bc|asm|obj  Compile all files into a single module and write it as
+                        LLVM IR, LLVM bitcode, assembly or object file instead of
+                        running it.
+  --emit=wasm           Compile all files into a WebAssembly module for the wasm32
+                        target, linked with wasm-ld (or WASM_LD), and write a
+                        JavaScript shim (.mjs) providing the imports next to it.
+  --aot                 Compile all files into an executable, which evaluates the
+                        top-level expressions and prints their results.
+  -o file               Output file for --emit and --aot, defaults to the name of
+                        the first file with the extension of the output format.
+  --emit-header file    Write C prototypes of the defined functions to the given
+                        header file, eg `double fib(double x);`. Can be combined
+                        with --emit and --aot, otherwise only writes the header.
+  --target triple       Target triple for --emit, eg aarch64-linux-gnu, defaults to
+                        the host.
+  --cpu name            Target CPU for --emit, eg cortex-a72, defaults to a generic
+                        CPU of the target, or the host CPU without --target.
+  --features list       Target CPU features for --emit, eg +neon,-crc.
+  -O0, -O1, -O2, -O3    Optimization level of the optimization passes and the
+                        machine code generation, defaults to -O2. At -O0 only
+                        variables are promoted from stack slots to registers.
+  --fold                Fold constant expressions in the AST before generating
+                        code, eg to compare the IR against LLVM's own folding.
+  --fmt                 Print the files (or stdin) formatted in the canonical layout
+                        instead of running them.
+  --lsp                 Serve the language server protocol on stdin/stdout, requires
+                        the lsp feature.
+  -h, --help            Print this help.
+
+Environment:
+  KALEIDOSCOPE_OPT_LEVEL=0..3  Optimization level, overridden by -O.
+  KALEIDOSCOPE_DUMP_IR=0|1     Disable or enable dumping the LLVM IR, overridden by
+                               -q and --dump-ir.
+  KALEIDOSCOPE_TARGET=triple   Target triple for --emit, overridden by --target.
+  WASM_LD=linker               Linker for --emit=wasm, defaults to wasm-ld.
+  KALEIDOSCOPE_LOG=level       Log events of the pipeline up to the level (error, warn,
+                               info, debug or trace) to stderr, requires the log feature.
+";
+
+/// Configuration of the driver, parsed once from the environment and the command line.
+#[derive(Debug, Default, PartialEq)]
+pub struct DriverConfig {
+    /// Print the usage and exit.
+    pub help: bool,
+    /// Source files to compile, in order.
+    pub files: Vec<String>,
+    /// Source snippets given with `-e`, compiled in order after the files.
+    pub eval: Vec<String>,
+    /// Drop into the REPL after compiling the source files.
+    pub interactive: bool,
+    /// Write the compiled module in the given format instead of running it.
+    pub emit: Option<Emit>,
+    /// Compile into an executable instead of running it.
+    pub aot: bool,
+    /// Output file for `--emit` and `--aot`.
+    pub output: Option<String>,
+    /// Header file to write the C prototypes of the defined functions to.
+    pub emit_header: Option<String>,
+    /// Dump the generated LLVM IR, if not given only dump when stdin is a terminal.
+    pub dump_ir: Option<bool>,
+    /// Trace level of the driver loop.
+    pub trace: u8,
+    /// Continue after errors when reading from a pipe.
+    pub keep_going: bool,
+    /// Do not load the standard library.
+    pub no_std: bool,
+    /// Fold constant expressions before codegen.
+    pub fold: bool,
+    /// Run the language server instead of compiling.
+    pub lsp: bool,
+    /// Format the sources instead of compiling.
+    pub fmt: bool,
+    /// Optimization level of the generated code.
+    pub opt_level: OptLevel,
+    /// Target triple for `--emit`, if not given compile for the host.
+    pub target: Option<String>,
+    /// Level of the events logged to stderr.
+    pub log_level: Option<String>,
+    /// Target CPU for `--emit`.
+    pub cpu: Option<String>,
+    /// Target CPU features for `--emit`.
+    pub features: Option<String>,
+}
+
+impl DriverConfig {
+    /// Parse the configuration from the environment variables looked up with `env` and the
+    /// command line arguments `args` (without the program name), where command line options
+    /// take precedence over the environment.
+    ///
+    /// Returns an error for invalid options or invalid combinations of options.
+    pub fn parse<A, E>(args: A, env: E) -> Result<DriverConfig, String>
+    where
+        A: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut config = DriverConfig::default();
+        config.parse_env(&env)?;
+        config.parse_args(args)?;
+        config.parse_env_target(&env);
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Parse the `KALEIDOSCOPE_*` environment variables.
+    fn parse_env<E: Fn(&str) -> Option<String>>(&mut self, env: &E) -> Result<(), String> {
+        let var = |name| env(name).filter(|v| !v.is_empty());
+
+        if let Some(level) = var("KALEIDOSCOPE_OPT_LEVEL") {
+            match level.parse().ok().and_then(OptLevel::from_level) {
+                Some(level) => self.opt_level = level,
+                None => {
+                    return Err(format!(
+                        "Invalid optimization level '{}' in KALEIDOSCOPE_OPT_LEVEL.",
+                        level
+                    ))
+                }
+            }
+        }
+        if let Some(dump_ir) = var("KALEIDOSCOPE_DUMP_IR") {
+            self.dump_ir = Some(dump_ir != "0");
+        }
+        if let Some(level) = var("KALEIDOSCOPE_LOG") {
+            self.log_level = Some(level);
+        }
+        Ok(())
+    }
+
+    /// Apply the `KALEIDOSCOPE_TARGET` environment variable, unless the target is given with
+    /// `--target`.
+    ///
+    /// The target only matters for `--emit`, otherwise the variable is ignored such that it
+    /// doesn't get in the way of running code in the JIT, `--fmt` or `--lsp`. The same holds for
+    /// targets other than wasm32 with `--emit=wasm`.
+    fn parse_env_target<E: Fn(&str) -> Option<String>>(&mut self, env: &E) {
+        if self.target.is_some() {
+            return;
+        }
+        let target = env("KALEIDOSCOPE_TARGET").filter(|v| !v.is_empty());
+        self.target = match &self.emit {
+            Some(Emit::Wasm) => target.filter(|t| t.starts_with("wasm32")),
+            Some(_) => target,
+            None => None,
+        };
+    }
+
+    /// Parse the command line arguments.
+    fn parse_args<A: IntoIterator<Item = String>>(&mut self, args: A) -> Result<(), String> {
+        let mut argv = args.into_iter();
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "-i" => self.interactive = true,
+                "--aot" => self.aot = true,
+                "-q" | "--no-dump-ir" => self.dump_ir = Some(false),
+                "--dump-ir" => self.dump_ir = Some(true),
+                "--keep-going" => self.keep_going = true,
+                "--no-std" => self.no_std = true,
+                "--fold" => self.fold = true,
+                "--lsp" => self.lsp = true,
+                "--fmt" => self.fmt = true,
+                "-v" | "--trace" => self.trace += 1,
+                "-vv" => self.trace += 2,
+                "-o" => match argv.next() {
+                    Some(output) => self.output = Some(output),
+                    None => return Err("Option '-o' requires a file name.".into()),
+                },
+                "--emit-header" => match argv.next() {
+                    Some(header) => self.emit_header = Some(header),
+                    None => return Err("Option '--emit-header' requires a file name.".into()),
+                },
+                "-e" => match argv.next() {
+                    Some(code) => self.eval.push(code),
+                    None => return Err("Option '-e' requires code to run.".into()),
+                },
+                "-h" | "--help" => self.help = true,
+                "--target" | "--cpu" | "--features" => {
+                    let value = argv
+                        .next()
+                        .ok_or_else(|| format!("Option '{}' requires a value.", arg))?;
+                    match arg.as_str() {
+                        "--target" => self.target = Some(value),
+                        "--cpu" => self.cpu = Some(value),
+                        _ => self.features = Some(value),
+                    }
+                }
+                "-O0" => self.opt_level = OptLevel::O0,
+                "-O1" => self.opt_level = OptLevel::O1,
+                "-O2" => self.opt_level = OptLevel::O2,
+                "-O3" => self.opt_level = OptLevel::O3,
+                _ if arg.starts_with("--trace=") => match arg["--trace=".len()..].parse() {
+                    Ok(level) => self.trace = level,
+                    Err(_) => return Err(format!("Invalid trace level in '{}'.", arg)),
+                },
+                _ if arg.starts_with("--emit=") => match Emit::from_str(&arg["--emit=".len()..]) {
+                    Some(emit) => self.emit = Some(emit),
+                    None => return Err(format!("Unknown output format in '{}'.", arg)),
+                },
+                _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'.", arg)),
+                _ => self.files.push(arg),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject invalid combinations of options.
+    fn check(&self) -> Result<(), String> {
+        if self.emit.is_some() && self.aot {
+            return Err("Option '--emit' can not be combined with '--aot'.".into());
+        }
+        if (self.emit.is_some() || self.aot || self.emit_header.is_some()) && self.interactive {
+            return Err(
+                "Option '-i' can not be combined with '--emit', '--emit-header' or '--aot'.".into(),
+            );
+        }
+        if self.fmt
+            && (self.emit.is_some()
+                || self.emit_header.is_some()
+                || self.aot
+                || self.interactive
+                || !self.eval.is_empty())
+        {
+            return Err(
+                "Option '--fmt' can not be combined with '--emit', '--emit-header', '--aot', '-i' or '-e'."
+                    .into(),
+            );
+        }
+        if self.emit == Some(Emit::Wasm)
+            && matches!(&self.target, Some(t) if !t.starts_with("wasm32"))
+        {
+            return Err("Option '--emit=wasm' requires a wasm32 target.".into());
+        }
+        if self.emit.is_none() && !self.aot && self.output.is_some() {
+            return Err("Option '-o' requires '--emit' or '--aot'.".into());
+        }
+        // Only given on the command line, the environment doesn't set a target without `--emit`.
+        if self.emit.is_none()
+            && (self.target.is_some() || self.cpu.is_some() || self.features.is_some())
+        {
+            return Err("Options '--target', '--cpu' and '--features' require '--emit'.".into());
+        }
+        Ok(())
+    }
+
+    /// Codegen options for the configured optimization level.
+    ///
+    /// If `print_toplevel` is set, top-level expressions print their own result.
+    pub fn codegen_options(&self, print_toplevel: bool) -> CodegenOptions {
+        CodegenOptions {
+            opt_level: self.opt_level,
+            print_toplevel,
+            fold: self.fold,
+            ..Default::default()
+        }
+    }
+
+    /// Options of the driver loop, `keep_going` is overridden by `--keep-going`.
+    pub(super) fn loop_opts(&self, print_results: bool, keep_going: bool) -> LoopOpts {
+        LoopOpts {
+            print_results,
+            trace: self.trace,
+            keep_going: keep_going || self.keep_going,
+        }
+    }
+
+    /// Whether code is generated for another target than the host, which requires all targets
+    /// to be initialized.
+    pub fn cross_compile(&self) -> bool {
+        self.target.is_some() || self.emit == Some(Emit::Wasm)
+    }
+
+    /// Create the TargetMachine for `--emit` and `--aot`, either for the configured target or
+    /// the host.
+    ///
+    /// Giving a cpu or features without a target triple selects them for the host's triple.
+    pub fn target_machine(&self) -> Result<TargetMachine, String> {
+        if !self.cross_compile() && self.cpu.is_none() && self.features.is_none() {
+            return TargetMachine::host(self.opt_level);
+        }
+
+        let triple = match (&self.target, self.emit) {
+            (Some(triple), _) => triple.clone(),
+            (None, Some(Emit::Wasm)) => WASM_TRIPLE.into(),
+            (None, _) => TargetMachine::host(OptLevel::O0)?.triple(),
+        };
+        TargetMachine::new(
+            &triple,
+            self.cpu.as_deref().unwrap_or(""),
+            self.features.as_deref().unwrap_or(""),
+            self.opt_level,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DriverConfig, Emit};
+    use crate::codegen::OptLevel;
+
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> Result<DriverConfig, String> {
+        let env: Vec<(String, String)> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        DriverConfig::parse(args.iter().map(|a| a.to_string()), |name| {
+            env.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+        })
+    }
+
+    #[test]
+    fn parse_args() {
+        let config = parse(
+            &[
+                "-q",
+                "-vv",
+                "--no-std",
+                "--fold",
+                "-e",
+                "1+2",
+                "a.ks",
+                "--emit=obj",
+                "-o",
+                "a.o",
+                "--emit-header",
+                "a.h",
+                "b.ks",
+            ],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            DriverConfig {
+                files: vec!["a.ks".into(), "b.ks".into()],
+                eval: vec!["1+2".into()],
+                emit: Some(Emit::Obj),
+                output: Some("a.o".into()),
+                emit_header: Some("a.h".into()),
+                dump_ir: Some(false),
+                trace: 2,
+                no_std: true,
+                fold: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_env() {
+        let env = [
+            ("KALEIDOSCOPE_OPT_LEVEL", "0"),
+            ("KALEIDOSCOPE_DUMP_IR", "1"),
+            ("KALEIDOSCOPE_TARGET", "aarch64-linux-gnu"),
+            ("KALEIDOSCOPE_LOG", "debug"),
+        ];
+
+        let config = parse(&["--emit=obj"], &env).unwrap();
+        assert_eq!(config.opt_level, OptLevel::O0);
+        assert_eq!(config.dump_ir, Some(true));
+        assert_eq!(config.target.as_deref(), Some("aarch64-linux-gnu"));
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+
+        // Command line options take precedence.
+        let config = parse(&["--emit=obj", "--target", "riscv64", "-q", "-O3"], &env).unwrap();
+        assert_eq!(config.target.as_deref(), Some("riscv64"));
+        assert_eq!(config.dump_ir, Some(false));
+        assert_eq!(config.opt_level, OptLevel::O3);
+
+        assert!(parse(&[], &[("KALEIDOSCOPE_OPT_LEVEL", "4")]).is_err());
+
+        // The target is ignored unless emitting code for it.
+        let target = [("KALEIDOSCOPE_TARGET", "aarch64-linux-gnu")];
+        for args in [&[][..], &["-i"], &["--fmt", "a.ks"], &["--lsp"], &["--aot"]] {
+            let config = parse(args, &target).unwrap();
+            assert_eq!(config.target, None, "{:?}", args);
+        }
+        let config = parse(&["--emit=wasm"], &target).unwrap();
+        assert_eq!(config.target, None);
+        let config = parse(&["--emit=wasm"], &[("KALEIDOSCOPE_TARGET", "wasm32-wasi")]).unwrap();
+        assert_eq!(config.target.as_deref(), Some("wasm32-wasi"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse(&["--foo"], &[]).is_err());
+        assert!(parse(&["-o"], &[]).is_err());
+        assert!(parse(&["--emit=exe"], &[]).is_err());
+        assert!(parse(&["--emit=ir", "--aot"], &[]).is_err());
+        assert!(parse(&["-i", "--aot"], &[]).is_err());
+        assert!(parse(&["-o", "a.o"], &[]).is_err());
+        assert!(parse(&["--emit-header"], &[]).is_err());
+        assert!(parse(&["-i", "--emit-header", "a.h"], &[]).is_err());
+        assert!(parse(&["--target"], &[]).is_err());
+        assert!(parse(&["--target", "aarch64-linux-gnu"], &[]).is_err());
+        assert!(parse(&["--aot", "--cpu", "cortex-a72"], &[]).is_err());
+        assert_eq!(
+            parse(
+                &["--features", "+neon"],
+                &[("KALEIDOSCOPE_TARGET", "aarch64")]
+            ),
+            Err("Options '--target', '--cpu' and '--features' require '--emit'.".into())
+        );
+        let target = [("KALEIDOSCOPE_TARGET", "aarch64-linux-gnu")];
+        let config = parse(
+            &["--emit=obj", "--cpu", "cortex-a72", "--features", "+neon"],
+            &target,
+        );
+        assert_eq!(
+            config.map(|c| (c.target, c.cpu, c.features)),
+            Ok((
+                Some("aarch64-linux-gnu".into()),
+                Some("cortex-a72".into()),
+                Some("+neon".into())
+            ))
+        );
+        assert!(parse(&["--emit=wasm", "--target", "aarch64-linux-gnu"], &[]).is_err());
+        assert!(parse(&["--emit=wasm", "--target", "wasm32-wasi"], &[]).is_ok());
+    }
+}
@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Interrupt flag set by the SIGINT handler and checked by the jitted code, see
+/// [`CodegenOptions::interrupt_flag`](crate::codegen::CodegenOptions::interrupt_flag).
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_sig: libc::c_int) {
+    // If the flag is still set from a previous interrupt, the running code does not check it, for
+    // example when blocked in an extern function. Give up and exit.
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Install a SIGINT handler which sets [`INTERRUPTED`] instead of terminating the process.
+///
+/// A second SIGINT before the flag is cleared terminates the process.
+pub(super) fn install() {
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+
+        let ret = libc::sigaction(libc::SIGINT, &sa, std::ptr::null_mut());
+        assert_eq!(ret, 0, "Failed to install SIGINT handler!");
+    }
+}
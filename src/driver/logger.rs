@@ -0,0 +1,29 @@
+//! Logger printing the events of the pipeline to stderr, only available with the `log` feature.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Install the logger for the events up to `level`, eg `debug`.
+pub fn init(level: &str) -> Result<(), String> {
+    let level: LevelFilter = level
+        .parse()
+        .map_err(|_| format!("Invalid log level '{}' in KALEIDOSCOPE_LOG.", level))?;
+    log::set_logger(&LOGGER).map_err(|err| format!("Failed to install logger: {}", err))?;
+    log::set_max_level(level);
+    Ok(())
+}
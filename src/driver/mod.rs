@@ -0,0 +1,449 @@
+//! Driver of the `llvm-kaleidoscope-rs` binary.
+//!
+//! The driver parses the [`DriverConfig`] from the command line and the environment and either
+//! runs the given sources with the LLVM JIT, optionally entering the REPL afterwards, or compiles
+//! them into an output file with `--emit`, `--emit-header` and `--aot`.
+
+use crate::backend::{compile_item, Backend, JitBackend};
+use crate::diagnostic;
+use crate::fmt;
+use crate::lexer::{Lexer, Span};
+use crate::line_reader::{Editor, LineReader, StdinReader};
+use crate::llvm::{self, TargetMachine};
+use crate::parser::{Item, Parser};
+use crate::stdlib;
+
+use std::io::Read;
+use std::time::Instant;
+
+mod aot;
+mod config;
+mod interrupt;
+#[cfg(feature = "log")]
+mod logger;
+mod repl;
+
+pub use config::{DriverConfig, Emit, USAGE};
+
+/// Options of the driver loop.
+#[derive(Clone, Copy)]
+struct LoopOpts {
+    /// Print the result of top-level expressions.
+    print_results: bool,
+    /// Trace level, `1` logs each parsed item together with its AST, `2` additionally prints the
+    /// tokens.
+    trace: u8,
+    /// Continue compiling the next items after an error, otherwise only parse them to report
+    /// further syntax errors.
+    keep_going: bool,
+}
+
+/// Drive the `backend` with the items parsed by `parser` from the source `src` named `name`.
+///
+/// Errors are reported with an excerpt of the offending source. Parse errors point at the
+/// offending token, codegen errors at the offending expression if known, or else at the whole
+/// item. After a parse error the parser recovers at
+/// the next item, so all syntax errors of the source are reported.
+///
+/// Return the number of errors reported.
+fn main_loop<I, B>(
+    parser: Parser<I>,
+    name: &str,
+    src: &str,
+    backend: &mut B,
+    opts: LoopOpts,
+) -> usize
+where
+    I: Iterator<Item = char>,
+    B: Backend,
+{
+    let mut errors = 0;
+    let report = |span: Span, err: &str| eprintln!("{}", diagnostic::render(name, src, span, err));
+
+    let mut start = Instant::now();
+    for item in parser {
+        // After the first error only parse the remaining items, unless asked to keep going.
+        let compile = errors == 0 || opts.keep_going;
+
+        match item {
+            Ok(item) if compile => {
+                backend.record_parse_time(start.elapsed());
+                let span = item.span();
+                if opts.trace >= 1 {
+                    trace_item(&item);
+                }
+                match compile_item(backend, item) {
+                    Ok(Some(res)) if opts.print_results => println!("Evaluated to {}", res),
+                    Ok(_) => {}
+                    Err(err) => {
+                        report(err.span.unwrap_or(span), &err.msg);
+                        errors += 1;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                report(err.span, &err.msg);
+                errors += 1;
+            }
+        }
+        start = Instant::now();
+    }
+
+    errors
+}
+
+/// Print the parsed `item` to stderr.
+fn trace_item(item: &Item) {
+    match item {
+        Item::Def(func) => {
+            eprintln!("Parse 'def'");
+            eprintln!("{:#?}", func);
+        }
+        Item::Extern(proto) => {
+            eprintln!("Parse 'extern'");
+            eprintln!("{:#?}", proto);
+        }
+        Item::TopLevel(func) => {
+            eprintln!("Parse top-level expression");
+            eprintln!("{:#?}", func);
+        }
+        Item::Const(c) => {
+            eprintln!("Parse 'const'");
+            eprintln!("{:#?}", c);
+        }
+    }
+}
+
+/// Compile and run the source `src` named `name` with the `backend`.
+///
+/// Return the number of errors reported.
+fn run_kaleidoscope<B: Backend>(name: &str, src: &str, backend: &mut B, opts: LoopOpts) -> usize {
+    // Create parser for kaleidoscope.
+    let mut parser = Parser::new(Lexer::new(src.chars()));
+    parser.set_trace_tokens(opts.trace >= 2);
+    for proto in backend.operators() {
+        parser.declare_operator(&proto);
+    }
+
+    main_loop(parser, name, src, backend, opts)
+}
+
+/// Read the source from `reader`, invalid UTF-8 sequences are replaced.
+fn read_source(mut reader: impl Read) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Read the source file `file`.
+fn read_file(file: &str) -> Result<String, String> {
+    std::fs::File::open(file)
+        .and_then(read_source)
+        .map_err(|err| format!("Failed to read file {}: {}", file, err))
+}
+
+/// Read all of stdin.
+fn read_stdin() -> Result<String, String> {
+    read_source(std::io::stdin()).map_err(|err| format!("Failed to read stdin: {}", err))
+}
+
+/// Run the driver with the command line arguments `args` (without the program name).
+///
+/// Return the exit status of the program.
+pub fn run<A: IntoIterator<Item = String>>(args: A) -> i32 {
+    let config = match DriverConfig::parse(args, |name| std::env::var(name).ok()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return 1;
+        }
+    };
+
+    if config.help {
+        print!("{}", USAGE);
+        return 0;
+    }
+
+    if let Some(level) = &config.log_level {
+        init_log(level);
+    }
+
+    if config.lsp {
+        return run_lsp();
+    }
+
+    if config.fmt {
+        return run_fmt(&config).unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            1
+        });
+    }
+
+    // Initialize native target for jitting and code generation.
+    llvm::initialize_native_taget();
+
+    // Cross compilation requires the other targets as well.
+    if config.cross_compile() {
+        llvm::initialize_all_targets();
+    }
+
+    let res = match config.emit {
+        Some(emit) => aot::emit_module(&config, emit).map(|_| 0),
+        None if config.aot => aot::compile_executable(&config).map(|_| 0),
+        None if config.emit_header.is_some() => aot::emit_header(&config).map(|_| 0),
+        None => run_jit(&config),
+    };
+
+    // De-allocate managed static LLVM data.
+    llvm::shutdown();
+
+    res.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        1
+    })
+}
+
+/// Print the files, or stdin without files, formatted in the canonical layout.
+///
+/// Return the exit status of the program.
+fn run_fmt(config: &DriverConfig) -> Result<i32, String> {
+    let sources = if config.files.is_empty() {
+        vec![("<stdin>", read_stdin()?)]
+    } else {
+        config
+            .files
+            .iter()
+            .map(|file| Ok((file.as_str(), read_file(file)?)))
+            .collect::<Result<_, String>>()?
+    };
+
+    let mut status = 0;
+    for (name, src) in sources {
+        match fmt::format(&src) {
+            Ok(out) => print!("{}", out),
+            Err(err) => {
+                eprintln!("{}", diagnostic::render(name, &src, err.span, &err.msg));
+                status = 1;
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// Serve the language server protocol on stdin and stdout.
+///
+/// Return the exit status of the program.
+fn run_lsp() -> i32 {
+    #[cfg(feature = "lsp")]
+    let res = crate::lsp::serve_stdio().map_err(|err| format!("Language server failed: {}", err));
+    #[cfg(not(feature = "lsp"))]
+    let res = Err::<(), _>("Built without the language server, enable the 'lsp' feature.");
+
+    match res {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+/// Log the events of the pipeline up to `level` to stderr.
+///
+/// Failing to install the logger is not fatal, the driver runs without logging.
+fn init_log(level: &str) {
+    #[cfg(feature = "log")]
+    let res = logger::init(level);
+    #[cfg(not(feature = "log"))]
+    let res = Err::<(), _>(format!(
+        "Built without logging, enable the 'log' feature to log at level '{}'.",
+        level
+    ));
+
+    if let Err(err) = res {
+        eprintln!("Warning: {}", err);
+    }
+}
+
+/// Run the sources with the LLVM JIT and enter the REPL if requested.
+///
+/// Return the exit status of the program.
+fn run_jit(config: &DriverConfig) -> Result<i32, String> {
+    // Create a new JIT, based on the LLVM LLJIT, generating code with the configured optimization
+    // level.
+    let jit = llvm::LLJit::with_target_machine(TargetMachine::host(config.opt_level)?);
+
+    // Enable lookup of dynamic symbols in the current process from the JIT.
+    jit.enable_process_symbols();
+
+    // Without a terminal, piped input is compiled in batch mode instead of running the REPL.
+    let stdin_tty = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    let no_input = config.files.is_empty() && config.eval.is_empty();
+    let interactive = config.interactive || (no_input && stdin_tty);
+
+    // Keep the object files emitted by the JIT to disassemble functions in the REPL.
+    if interactive {
+        jit.enable_object_capture();
+    }
+
+    // When executing a script, let the top level expressions print their results.
+    let mut cg_opts = config.codegen_options(true);
+
+    // In interactive sessions Ctrl-C only cancels the running evaluation.
+    if interactive {
+        interrupt::install();
+        cg_opts.interrupt_flag = Some(&interrupt::INTERRUPTED);
+    }
+
+    let mut backend = JitBackend::new(&jit, cg_opts);
+
+    if !config.no_std {
+        backend.load_stdlib(stdlib::default_cache_dir().as_deref())?;
+    }
+
+    // Keep the output of scripts and one-liners clean, unless explicitly asked for the IR.
+    let dump_ir = config
+        .dump_ir
+        .unwrap_or(stdin_tty && config.eval.is_empty());
+    backend.set_dump_ir(dump_ir);
+
+    let mut errors = 0;
+
+    for file in &config.files {
+        if config.trace >= 1 {
+            eprintln!("Parse {}.", file);
+        }
+        let opts = config.loop_opts(false, true);
+        errors += run_kaleidoscope(file, &read_file(file)?, &mut backend, opts);
+    }
+
+    // One-shot snippets from the command line, stop at the first error as in batch mode.
+    for code in &config.eval {
+        let opts = config.loop_opts(false, false);
+        errors += run_kaleidoscope("<cmdline>", code, &mut backend, opts);
+    }
+
+    if interactive {
+        // Print the banner to stderr next to the prompt, keeping stdout for the results.
+        eprintln!("Parse stdin.");
+        eprintln!("ENTER to parse current input.");
+        eprintln!("C-c   to cancel a running evaluation.");
+        eprintln!("C-d   to exit.");
+        eprintln!("C-r   to search the history.");
+        eprintln!("it    refers to the result of the last expression.");
+        eprintln!(":load <file> to run a file in the current session.");
+        eprintln!(":save <file> to write the IR of the session's functions to a file.");
+        eprintln!(":reset       to start a fresh session.");
+        eprintln!(":time        to show the time spent per phase for each item.");
+        eprintln!(":disasm <fn> to show the machine code of a function.");
+
+        // In the REPL the driver prints the result of top level expressions.
+        backend.opts_mut().print_toplevel = false;
+
+        // Offer line editing and history when reading from a terminal.
+        let mut reader: Box<dyn LineReader> = if stdin_tty {
+            Box::new(Editor::new(Editor::default_history_file()))
+        } else {
+            Box::new(StdinReader)
+        };
+
+        repl::repl(&mut backend, reader.as_mut(), config.loop_opts(true, true));
+    } else if no_input {
+        // Batch mode, compile stdin like a file.
+        let opts = config.loop_opts(false, false);
+        errors += run_kaleidoscope("<stdin>", &read_stdin()?, &mut backend, opts);
+    }
+
+    // Dump all the emitted LLVM IR to stdout.
+    if dump_ir {
+        backend.dump();
+    }
+
+    Ok(if errors > 0 && !interactive { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::{run_kaleidoscope, LoopOpts};
+    use crate::backend::{Backend, BackendResult};
+    use crate::parser::{ConstAST, FunctionAST, PrototypeAST};
+
+    /// Backend recording the names of the items it receives, failing for functions named `bad`.
+    #[derive(Default)]
+    struct RecordBackend(Vec<String>, usize);
+
+    impl Backend for RecordBackend {
+        fn compile_prototype(&mut self, proto: &PrototypeAST) -> BackendResult<()> {
+            self.0.push(format!("extern {}", proto.0));
+            Ok(())
+        }
+
+        fn compile_function(&mut self, func: &FunctionAST) -> BackendResult<()> {
+            if func.0 .0 == "bad" {
+                return Err("bad function".into());
+            }
+            self.0.push(format!("def {}", func.0 .0));
+            Ok(())
+        }
+
+        fn call_top_level(&mut self, func: &FunctionAST) -> BackendResult<Option<f64>> {
+            self.0.push(func.0 .0.clone());
+            Ok(None)
+        }
+
+        fn compile_const(&mut self, c: &ConstAST) -> BackendResult<()> {
+            self.0.push(format!("const {}", c.0));
+            Ok(())
+        }
+
+        fn operators(&self) -> Vec<Rc<PrototypeAST>> {
+            Vec::new()
+        }
+
+        fn lambdas(&mut self) -> &mut usize {
+            &mut self.1
+        }
+    }
+
+    fn opts(keep_going: bool) -> LoopOpts {
+        LoopOpts {
+            print_results: false,
+            trace: 0,
+            keep_going,
+        }
+    }
+
+    #[test]
+    fn drive_backend() {
+        let mut backend = RecordBackend::default();
+        let src = "extern sin(x); def foo(x) x; ; const a = 1; foo(1)";
+        assert_eq!(run_kaleidoscope("t", src, &mut backend, opts(false)), 0);
+        assert_eq!(
+            backend.0,
+            vec!["extern sin", "def foo", "const a", crate::parser::ANON_EXPR]
+        );
+
+        // Lambdas are lifted into functions compiled ahead of the item.
+        let mut backend = RecordBackend::default();
+        let src = "def f() var g = \\(x) x in g(1); def h() \\(x) x";
+        assert_eq!(run_kaleidoscope("t", src, &mut backend, opts(true)), 1);
+        assert_eq!(backend.0, vec!["def __lambda.0", "def f"]);
+    }
+
+    #[test]
+    fn count_errors() {
+        let src = "def bad(x) x; def foo(x) x + ); def bar(x) x";
+
+        let mut backend = RecordBackend::default();
+        assert_eq!(run_kaleidoscope("t", src, &mut backend, opts(true)), 2);
+        assert_eq!(backend.0, vec!["def bar"]);
+
+        // Stop compiling at the first error, but still report the syntax errors.
+        let mut backend = RecordBackend::default();
+        assert_eq!(run_kaleidoscope("t", src, &mut backend, opts(false)), 2);
+        assert!(backend.0.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::backend::{ItemTiming, JitBackend};
+use crate::lexer::Lexer;
+use crate::line_reader::LineReader;
+use crate::parser::{ParseErrorKind, Parser};
+
+use std::time::Duration;
+
+use super::{read_source, run_kaleidoscope, LoopOpts};
+
+/// Check if `input` ends in the middle of an item, for example with an unclosed parenthesis, a
+/// missing `then` or an unterminated string, such that more input is required to parse it.
+fn is_incomplete(input: &str) -> bool {
+    // Only the first error counts, later items are not reached before it is fixed.
+    let mut parser = Parser::new(Lexer::new(input.chars()));
+    matches!(
+        parser.find_map(Result::err),
+        Some(err) if err.kind == ParseErrorKind::NeedMoreInput
+    )
+}
+
+/// Execute the REPL command `cmd` (without the leading `:`).
+///
+/// Supported commands:
+/// - `load <file>`: Run the items of `file` in the current session, the functions defined in
+///   `file` are only compiled once they are called.
+/// - `save <file>`: Write the LLVM IR of all functions defined in the session to `file`.
+/// - `reset`: Forget all functions and start a fresh session.
+/// - `time`: Print the time spent in the different phases for each item of the session.
+/// - `disasm <fn>`: Print the machine code generated for the function `fn`.
+fn repl_command(cmd: &str, backend: &mut JitBackend<'_>, opts: LoopOpts) {
+    let (name, arg) = match cmd.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (cmd, ""),
+    };
+
+    match name {
+        "load" if arg.is_empty() => eprintln!("Error: Usage ':load <file>'."),
+        "load" => match std::fs::File::open(arg).and_then(read_source) {
+            Ok(src) => {
+                // Only compile the functions of the file once they are used.
+                backend.set_lazy_definitions(true);
+                run_kaleidoscope(arg, &src, backend, opts);
+                backend.set_lazy_definitions(false);
+            }
+            Err(err) => eprintln!("Error: Failed to read file {}: {}", arg, err),
+        },
+        "save" if arg.is_empty() => eprintln!("Error: Usage ':save <file>'."),
+        "save" => {
+            if let Err(err) = backend.materialize_all() {
+                eprintln!("Error: {}", err);
+            } else if let Err(err) = std::fs::write(arg, backend.session_ir()) {
+                eprintln!("Error: Failed to write file {}: {}", arg, err);
+            }
+        }
+        "reset" => backend.reset(),
+        "time" => print_timings(backend.timings()),
+        "disasm" if arg.is_empty() => eprintln!("Error: Usage ':disasm <function>'."),
+        "disasm" => match backend.disassemble(arg) {
+            Ok(asm) => print!("{}", asm),
+            Err(err) => eprintln!("Error: {}", err),
+        },
+        _ => eprintln!("Error: Unknown command ':{}'.", name),
+    }
+}
+
+/// Print the phase timings of each item as a table.
+fn print_timings(timings: &[ItemTiming]) {
+    let us = |d: Duration| d.as_secs_f64() * 1e6;
+
+    println!(
+        "{:<16} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        "item", "parse[us]", "codegen[us]", "opt[us]", "jit[us]", "exec[us]"
+    );
+    for t in timings {
+        println!(
+            "{:<16} {:>11.1} {:>11.1} {:>11.1} {:>11.1} {:>11.1}",
+            t.name,
+            us(t.parse),
+            us(t.codegen),
+            us(t.opt),
+            us(t.jit),
+            us(t.exec)
+        );
+    }
+}
+
+/// Read-eval-print loop over stdin.
+///
+/// Input is collected line by line, as long as the input ends in the middle of an item, a
+/// continuation prompt is shown instead of evaluating the input.
+///
+/// Lines starting with `:` are REPL commands, see [`repl_command`].
+pub(super) fn repl(backend: &mut JitBackend<'_>, reader: &mut dyn LineReader, opts: LoopOpts) {
+    let mut input = String::new();
+
+    loop {
+        let prompt = if input.is_empty() { "ready> " } else { "...> " };
+
+        match reader.read_line(prompt) {
+            Ok(None) => break,
+            Ok(Some(line)) => {
+                reader.add_history(&line);
+                input.push_str(&line);
+                input.push('\n');
+            }
+            Err(err) => {
+                eprintln!("Error: Failed to read stdin: {}", err);
+                break;
+            }
+        }
+
+        if let Some(cmd) = input.trim().strip_prefix(':') {
+            repl_command(cmd, backend, opts);
+            input.clear();
+            continue;
+        }
+
+        if is_incomplete(&input) {
+            continue;
+        }
+
+        run_kaleidoscope("<stdin>", &input, backend, opts);
+        input.clear();
+    }
+
+    // Evaluate remaining incomplete input to report the errors.
+    if !input.is_empty() {
+        run_kaleidoscope("<stdin>", &input, backend, opts);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_incomplete;
+
+    #[test]
+    fn incomplete_input() {
+        assert!(!is_incomplete(""));
+        assert!(!is_incomplete("def foo(x) x + 1\nfoo(2)\n"));
+        assert!(is_incomplete("def foo(x)\n"));
+        assert!(is_incomplete("foo(1,\n"));
+        assert!(is_incomplete("if x < 3 then\n"));
+        assert!(is_incomplete("foo(\"abc\n"));
+
+        // Errors before the end of the input are reported right away.
+        assert!(!is_incomplete("def foo(x) x + )\n"));
+        assert!(!is_incomplete("foo('ab')\n"));
+    }
+}
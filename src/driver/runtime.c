@@ -0,0 +1,24 @@
+// Runtime support for kaleidoscope executables compiled with `--aot`.
+//
+// Provides the builtins the driver otherwise exports to the JIT from the host process.
+
+#include <stdio.h>
+#include <stdlib.h>
+
+double putchard(double c) {
+    putchar((char)c);
+    return 0;
+}
+
+double printd(double x) {
+    // Print the shortest representation which reads back as `x`, like the driver does.
+    char buf[32];
+    for (int prec = 1; prec <= 17; ++prec) {
+        snprintf(buf, sizeof(buf), "%.*g", prec, x);
+        if (strtod(buf, NULL) == x) {
+            break;
+        }
+    }
+    printf("%s\n", buf);
+    return 0;
+}
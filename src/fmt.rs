@@ -0,0 +1,113 @@
+//! Source formatter printing kaleidoscope source in a canonical layout.
+//!
+//! Each item is printed on its own line with canonical spacing and minimal parentheses, the body
+//! of a definition is indented on the line following its prototype. Comments and single blank
+//! lines between items are kept, comments within an item are moved in front of the item.
+
+use crate::lexer::{Lexer, Span, Token};
+use crate::parser::{Item, ParseError, Parser};
+
+/// Indentation of function bodies.
+const INDENT: &str = "  ";
+
+/// Format the source `src`.
+///
+/// Return the first syntax error if `src` can't be parsed, as the formatted output would lose
+/// the invalid parts.
+pub fn format(src: &str) -> Result<String, ParseError> {
+    let chars: Vec<char> = src.chars().collect();
+    let blank_line = |from: usize, to: usize| {
+        from <= to && chars[from..to].iter().filter(|&&c| c == '\n').count() >= 2
+    };
+
+    // Token spans without comments, to find the last token of each item.
+    let tokens: Vec<Span> = Lexer::new(src.chars()).map(|(_, span)| span).collect();
+
+    let mut lexer = Lexer::new(src.chars());
+    lexer.set_keep_comments(true);
+    let mut comments = lexer
+        .filter_map(|(tok, span)| match tok {
+            Token::Comment(text) => Some((text, span)),
+            _ => None,
+        })
+        .peekable();
+
+    let mut parser = Parser::new(Lexer::new(src.chars()));
+    parser.get_next_token();
+
+    let mut out = String::new();
+    let mut pos = 0;
+    loop {
+        let start = parser.cur_span().start;
+        let item = match parser.cur_tok() {
+            Token::Eof => break,
+            Token::Delim(';') => {
+                parser.get_next_token();
+                continue;
+            }
+            Token::Def => parser.parse_definition().map(Item::Def),
+            Token::Extern => parser.parse_extern().map(Item::Extern),
+            Token::Const => parser.parse_const().map(Item::Const),
+            _ => parser.parse_top_level_expr().map(Item::TopLevel),
+        }?;
+
+        // The item ends with the last token before the current one.
+        let next = parser.cur_span().start;
+        let end = tokens
+            .iter()
+            .take_while(|span| span.end <= next)
+            .last()
+            .map_or(start, |span| span.end);
+
+        // Comments in front of the item and within the item.
+        while let Some((text, span)) = comments.next_if(|(_, span)| span.start < end) {
+            if !out.is_empty() && blank_line(pos, span.start) {
+                out.push('\n');
+            }
+            out.push_str(&format!("#{}\n", text));
+            pos = pos.max(span.end);
+        }
+
+        if !out.is_empty() && blank_line(pos, start) {
+            out.push('\n');
+        }
+        match item {
+            Item::Def(func) => out.push_str(&format!("def {}\n{}{};\n", func.0, INDENT, func.1)),
+            Item::Extern(proto) => out.push_str(&format!("extern {};\n", proto)),
+            Item::TopLevel(func) => out.push_str(&format!("{};\n", func)),
+            Item::Const(c) => out.push_str(&format!("{};\n", c)),
+        }
+        pos = pos.max(end);
+    }
+
+    // Comments after the last item.
+    for (text, span) in comments {
+        if !out.is_empty() && blank_line(pos, span.start) {
+            out.push('\n');
+        }
+        out.push_str(&format!("#{}\n", text));
+        pos = span.end;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::format;
+
+    #[test]
+    fn format_source() {
+        let src = "# Header.\n\nextern   sin(x) ;\ndef foo(a b)  # inline\n  if a<b then (a+b)*2 else sin( a )\n\n\n\nfoo(1,2);;\n# Trailer.\n";
+        let out = "# Header.\n\nextern sin(x);\n# inline\ndef foo(a, b)\n  if a < b then (a + b) * 2 else sin(a);\n\nfoo(1, 2);\n# Trailer.\n";
+        assert_eq!(format(src).unwrap(), out);
+        assert_eq!(format(out).unwrap(), out);
+        assert_eq!(format("const  pi=3.14 ;").unwrap(), "const pi = 3.14;\n");
+    }
+
+    #[test]
+    fn format_invalid() {
+        let err = format("def foo(x) x +;").unwrap_err();
+        assert_eq!(err.span.start, 14);
+    }
+}
@@ -0,0 +1,204 @@
+//! Constant folding on the AST.
+//!
+//! Folding evaluates operators over number literals and selects the branch of `if` and `match`
+//! expressions with a constant condition before codegen, following the semantics of the code generated by
+//! [`Codegen`](crate::codegen). This simplifies the AST for the interpreter and allows comparing
+//! the IR of folded and unfolded functions against LLVM's own constant folding.
+
+use crate::parser::{Expr, ExprAST, FunctionAST, LogicalOp};
+
+/// Check if the condition `v` is true, which is the case if it is ordered and not equal to zero,
+/// like `fcmp one`.
+pub fn is_true(v: f64) -> bool {
+    v != 0.0 && !v.is_nan()
+}
+
+/// Evaluate the binary operator `op` on `l` and `r`, `None` if `op` is not a built-in operator.
+///
+/// Comparisons evaluate to `1.0` or `0.0`. Relational operators are unordered, like `fcmp ult`,
+/// `==` is ordered and `!=` unordered, like `fcmp oeq` and `fcmp une`.
+pub fn eval_binop(op: &str, l: f64, r: f64) -> Option<f64> {
+    let unordered = l.is_nan() || r.is_nan();
+    let res = match op {
+        "+" => l + r,
+        "-" => l - r,
+        "*" => l * r,
+        // Like `frem` and the `llvm.pow.f64` intrinsic.
+        "%" => l % r,
+        "^" => l.powf(r),
+        "<" => bool_to_f64(unordered || l < r),
+        ">" => bool_to_f64(unordered || l > r),
+        "<=" => bool_to_f64(unordered || l <= r),
+        ">=" => bool_to_f64(unordered || l >= r),
+        "==" => bool_to_f64(l == r),
+        "!=" => bool_to_f64(l != r),
+        _ => return None,
+    };
+    Some(res)
+}
+
+/// Turn the result of a comparison into `1.0` or `0.0`, like `uitofp`.
+fn bool_to_f64(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Fold the body of the function `func`.
+pub fn fold_function(func: &mut FunctionAST) {
+    fold_expr(&mut func.1);
+}
+
+/// Fold the expression `expr` bottom-up.
+///
+/// Folded nodes keep the span of the expression they replace, a constant `if` is replaced by the
+/// selected branch including its span. Expressions with unknown operators are kept, such that the
+/// backends report them.
+pub fn fold_expr(expr: &mut Expr) {
+    match &mut expr.node {
+        ExprAST::Number(_)
+        | ExprAST::Str(_)
+        | ExprAST::Variable(_)
+        | ExprAST::Break
+        | ExprAST::Continue => {}
+        ExprAST::Unary(_, operand) => fold_expr(operand),
+        ExprAST::Binary(_, lhs, rhs) | ExprAST::Logical(_, lhs, rhs) => {
+            fold_expr(lhs);
+            fold_expr(rhs);
+        }
+        ExprAST::Call(_, args) => args.iter_mut().for_each(fold_expr),
+        ExprAST::If { cond, then, else_ } => {
+            fold_expr(cond);
+            fold_expr(then);
+            fold_expr(else_);
+        }
+        ExprAST::For {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            fold_expr(start);
+            fold_expr(end);
+            if let Some(step) = step {
+                fold_expr(step);
+            }
+            fold_expr(body);
+        }
+        ExprAST::VarIn { vars, body } => {
+            for init in vars.iter_mut().filter_map(|(_, init)| init.as_mut()) {
+                fold_expr(init);
+            }
+            fold_expr(body);
+        }
+        ExprAST::Assign { value, .. } => fold_expr(value),
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            fold_expr(value);
+            for (_, arm) in arms {
+                fold_expr(arm);
+            }
+            fold_expr(default);
+        }
+        ExprAST::Lambda(_, body) => fold_expr(body),
+    }
+
+    let num = |expr: &Expr| match expr.node {
+        ExprAST::Number(num) => Some(num),
+        _ => None,
+    };
+
+    let folded = match &mut expr.node {
+        ExprAST::Unary(op, operand) if op == "-" => num(operand).map(|v| -v),
+        ExprAST::Binary(op, lhs, rhs) => match (num(lhs), num(rhs)) {
+            (Some(l), Some(r)) => eval_binop(op, l, r),
+            _ => None,
+        },
+        // The left operand may decide the result on its own, the right operand is skipped then.
+        ExprAST::Logical(op, lhs, rhs) => match (op, num(lhs).map(is_true), num(rhs)) {
+            (LogicalOp::And, Some(false), _) => Some(0.0),
+            (LogicalOp::Or, Some(true), _) => Some(1.0),
+            (_, Some(_), Some(r)) => Some(bool_to_f64(is_true(r))),
+            _ => None,
+        },
+        ExprAST::If { cond, then, else_ } => {
+            if let Some(cond) = num(cond) {
+                let branch = if is_true(cond) { then } else { else_ };
+                let branch = std::mem::replace(&mut **branch, ExprAST::Number(0.0).into());
+                *expr = branch;
+            }
+            None
+        }
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            if let Some(value) = num(value) {
+                let branch = arms
+                    .iter_mut()
+                    .find(|(pattern, _)| *pattern == value)
+                    .map_or(&mut **default, |(_, arm)| arm);
+                let branch = std::mem::replace(branch, ExprAST::Number(0.0).into());
+                *expr = branch;
+            }
+            None
+        }
+        _ => None,
+    };
+
+    if let Some(num) = folded {
+        expr.node = ExprAST::Number(num);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fold_expr;
+    use crate::parser::parse_expr;
+
+    fn fold(src: &str) -> String {
+        let mut expr = parse_expr(src).unwrap();
+        fold_expr(&mut expr);
+        expr.to_string()
+    }
+
+    #[test]
+    fn fold_constants() {
+        assert_eq!(fold("1 + 2 * 3 - -4"), "11");
+        assert_eq!(fold("x + 2 * 3"), "x + 6");
+        assert_eq!(fold("(1 < 2) + (2 <= 1) + (1 != 1)"), "1");
+        assert_eq!(fold("-7 % 4 + 2 ^ 3 ^ 2"), "61");
+        assert_eq!(fold("f(1 + 1, -(2))"), "f(2, -2)");
+        assert_eq!(
+            fold("for i = 1 + 1, i < 2 * 5 in x = 3 * 3"),
+            "for i = 2, i < 10 in x = 9"
+        );
+
+        // Conditions are only folded if they are constant.
+        assert_eq!(fold("if 1 < 2 then x else y"), "x");
+        assert_eq!(fold("if 0 then x else 1 + 1"), "2");
+        assert_eq!(fold("if x then 1 + 1 else y"), "if x then 2 else y");
+        assert_eq!(fold("match 1 + 1 with 1 -> a | 2 -> b | _ -> c"), "b");
+        assert_eq!(fold("match 3 with 1 -> a | _ -> c"), "c");
+        assert_eq!(
+            fold("match x with 1 -> 2 * 2 | _ -> c"),
+            "match x with 1 -> 4 | _ -> c"
+        );
+
+        // The right operand of a logical operator is dropped if the left one decides.
+        assert_eq!(fold("0 && f(x)"), "0");
+        assert_eq!(fold("2 || f(x)"), "1");
+        assert_eq!(fold("1 && 3"), "1");
+        assert_eq!(fold("x && 0"), "x && 0");
+
+        // Unknown operators are kept for the backends to report them.
+        assert_eq!(fold("!(1 + 1)"), "!2");
+    }
+}
@@ -0,0 +1,223 @@
+//! Bounded entry points for fuzzing the crate, eg from cargo-fuzz harnesses.
+//!
+//! Arbitrary inputs can trivially exhaust the stack or memory, eg with deeply nested
+//! expressions. The entry points therefore bound the resources spent on an input with [`Limits`],
+//! such that the fuzzer finds real bugs instead. This module is only available with the `fuzz`
+//! feature.
+//!
+//! There is an entry point per pipeline stage, [`lex`], [`parse`] and [`codegen`], and
+//! [`codegen_verify`] which additionally runs the LLVM verifier on the produced module. None of
+//! them executes generated code.
+//!
+//! ```ignore
+//! #![no_main]
+//! use llvm_kaleidoscope_rs::fuzz;
+//!
+//! libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+//!     fuzz::codegen_verify(data, &fuzz::Limits::default());
+//! });
+//! ```
+
+use crate::backend::{Backend, ModuleBackend};
+use crate::codegen::CodegenOptions;
+use crate::lexer::Lexer;
+use crate::parser::{Expr, ExprAST, Item, Parser};
+
+/// Resource limits for a single fuzz input.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Maximum size of the input in bytes, larger inputs are ignored.
+    pub max_input_len: usize,
+    /// Maximum nesting depth of expressions, see [`Parser::set_max_depth`].
+    pub max_depth: usize,
+    /// Maximum number of AST nodes of all parsed items, parsing stops once exceeded.
+    pub max_nodes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_input_len: 64 * 1024,
+            max_depth: 64,
+            max_nodes: 10_000,
+        }
+    }
+}
+
+/// Number of AST nodes in `expr`.
+fn expr_nodes(expr: &Expr) -> usize {
+    1 + match &expr.node {
+        ExprAST::Number(_)
+        | ExprAST::Str(_)
+        | ExprAST::Variable(_)
+        | ExprAST::Break
+        | ExprAST::Continue => 0,
+        ExprAST::Unary(_, operand) => expr_nodes(operand),
+        ExprAST::Binary(_, lhs, rhs) | ExprAST::Logical(_, lhs, rhs) => {
+            expr_nodes(lhs) + expr_nodes(rhs)
+        }
+        ExprAST::Call(_, args) => args.iter().map(expr_nodes).sum(),
+        ExprAST::If { cond, then, else_ } => {
+            expr_nodes(cond) + expr_nodes(then) + expr_nodes(else_)
+        }
+        ExprAST::For {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            expr_nodes(start)
+                + expr_nodes(end)
+                + step.as_deref().map_or(0, expr_nodes)
+                + expr_nodes(body)
+        }
+        ExprAST::VarIn { vars, body } => {
+            vars.iter()
+                .filter_map(|(_, init)| init.as_ref())
+                .map(expr_nodes)
+                .sum::<usize>()
+                + expr_nodes(body)
+        }
+        ExprAST::Assign { value, .. } => expr_nodes(value),
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            expr_nodes(value)
+                + arms.iter().map(|(_, arm)| expr_nodes(arm)).sum::<usize>()
+                + expr_nodes(default)
+        }
+        ExprAST::Lambda(_, body) => expr_nodes(body),
+    }
+}
+
+/// Number of AST nodes in `item`.
+fn item_nodes(item: &Item) -> usize {
+    match item {
+        Item::Def(func) | Item::TopLevel(func) => 1 + expr_nodes(&func.1),
+        Item::Extern(_) => 1,
+        Item::Const(c) => 1 + expr_nodes(&c.1),
+    }
+}
+
+/// Lex `data` as kaleidoscope source within `limits`, once without and once with comments,
+/// invalid UTF-8 sequences are replaced.
+///
+/// Return the number of tokens without comments, or `None` if the input exceeds the size limit.
+pub fn lex(data: &[u8], limits: &Limits) -> Option<usize> {
+    if data.len() > limits.max_input_len {
+        return None;
+    }
+
+    let src = String::from_utf8_lossy(data);
+    let tokens = Lexer::new(src.chars()).count();
+
+    let mut lexer = Lexer::new(src.chars());
+    lexer.set_keep_comments(true);
+    let with_comments = lexer.count();
+    assert!(with_comments >= tokens, "Comments must only add tokens");
+
+    Some(tokens)
+}
+
+/// Lex and parse `data` as kaleidoscope source within `limits`, invalid UTF-8 sequences are
+/// replaced.
+///
+/// Return the items parsed successfully, the parser recovers from errors at the next item. Return
+/// `None` if the input exceeds the size limit.
+pub fn parse(data: &[u8], limits: &Limits) -> Option<Vec<Item>> {
+    if data.len() > limits.max_input_len {
+        return None;
+    }
+
+    let src = String::from_utf8_lossy(data);
+    let mut parser = Parser::new(Lexer::new(src.chars()));
+    parser.set_max_depth(limits.max_depth);
+
+    let mut items = Vec::new();
+    let mut nodes = 0;
+    while nodes <= limits.max_nodes {
+        match parser.parse_item() {
+            Ok(Some(item)) => {
+                nodes += item_nodes(&item);
+                items.push(item);
+            }
+            Ok(None) => break,
+            Err(err) => {
+                parser.recover(err);
+            }
+        }
+    }
+
+    Some(items)
+}
+
+/// Parse `data` with [`parse`] and generate LLVM IR for the items into a module, without
+/// executing any code.
+///
+/// Codegen stops at the first error, as the module must not be used afterwards. Return the number
+/// of items compiled, or `None` if the input exceeds the size limit.
+pub fn codegen(data: &[u8], limits: &Limits) -> Option<usize> {
+    compile(data, limits, false)
+}
+
+/// Like [`codegen`], but verify the module after each compiled item.
+///
+/// # Panics
+///
+/// Panics with the message of the LLVM verifier if codegen produced an invalid module.
+pub fn codegen_verify(data: &[u8], limits: &Limits) -> Option<usize> {
+    compile(data, limits, true)
+}
+
+fn compile(data: &[u8], limits: &Limits, verify: bool) -> Option<usize> {
+    let items = parse(data, limits)?;
+    let mut backend = ModuleBackend::new(CodegenOptions::default());
+
+    let compiled = items
+        .iter()
+        .take_while(|item| {
+            let ok = match item {
+                Item::Def(func) => backend.compile_function(func).is_ok(),
+                Item::Extern(proto) => backend.compile_prototype(proto).is_ok(),
+                Item::TopLevel(func) => backend.call_top_level(func).is_ok(),
+                Item::Const(c) => backend.compile_const(c).is_ok(),
+            };
+            if ok && verify {
+                if let Err(err) = backend.module().verify() {
+                    panic!("Invalid module after compiling {:?}:\n{}", item, err);
+                }
+            }
+            ok
+        })
+        .count();
+
+    Some(compiled)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lex, parse, Limits};
+
+    #[test]
+    fn parse_limits() {
+        let limits = Limits {
+            max_input_len: 1024,
+            max_depth: 8,
+            max_nodes: 4,
+        };
+
+        assert_eq!(lex(&[b'('; 2048], &limits), None);
+        assert_eq!(lex(b"def f(x) # comment\n x + 1.5", &limits), Some(8));
+        assert_eq!(parse(&[b'('; 2048], &limits), None);
+        assert_eq!(parse(b"((((((((((1))))))))); 2", &limits).unwrap().len(), 1);
+        assert_eq!(
+            parse(b"\xff def f(x) x + 1 \xfe 1+2+3; 4", &limits)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -1,16 +1,202 @@
-#[derive(Debug, PartialEq)]
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::BufRead;
+
+/// Check if `c` is part of an operator.
+///
+/// Consecutive operator chars are only lexed as a single [`Token::Op`] if they form a built-in or
+/// declared operator, see [`Lexer::declare_op`].
+pub fn is_op_char(c: char) -> bool {
+    matches!(
+        c,
+        '!' | '%' | '&' | '*' | '+' | '-' | '/' | '<' | '=' | '>' | '?' | '^' | '|' | '~'
+    )
+}
+
+/// Built-in operators consisting of multiple operator chars.
+const MULTI_CHAR_OPS: &[&str] = &["<=", ">=", "==", "!=", "&&", "||", "->"];
+
+/// Parse the hex float `s` without the `0x` prefix, eg `1.8p3` for 12.0.
+fn parse_hex_float(s: &str) -> Option<f64> {
+    let (mantissa, exp) = match s.find(['p', 'P']) {
+        Some(pos) => (&s[..pos], s[pos + 1..].parse::<i32>().ok()?),
+        None => (s, 0),
+    };
+    let (int, frac) = match mantissa.find('.') {
+        Some(pos) => (&mantissa[..pos], &mantissa[pos + 1..]),
+        None => (mantissa, ""),
+    };
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+
+    let mut num = 0f64;
+    for c in int.chars().chain(frac.chars()) {
+        num = num * 16.0 + f64::from(c.to_digit(16)?);
+    }
+
+    // Each fractional hex digit scales the mantissa by 2^4.
+    let exp = exp.checked_sub(4 * i32::try_from(frac.len()).ok()?)?;
+    Some(num * 2f64.powi(exp))
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Eof,
     Def,
     Extern,
     Identifier(String),
+    /// Number literal with fraction or exponent, like `1.5` or `1e3`.
     Number(f64),
+    /// Number literal without fraction and exponent, like `42` or `0x2a`.
+    Integer(i64),
+    /// Operator, a single operator char like `+` or a known sequence of them like `<=`, see
+    /// [`is_op_char`].
+    Op(String),
+    /// Delimiter like `(` or `;`, any other single char which does not start another token.
+    Delim(char),
+    /// String literal enclosed in double quotes, with the escape sequences `\n`, `\t`, `\\`, `\"`,
+    /// `\'` and `\xNN` for ASCII chars replaced.
+    String(String),
+    /// Character literal enclosed in single quotes like `'a'` or `'\n'`, with the same escape
+    /// sequences as strings.
     Char(char),
     If,
     Then,
     Else,
     For,
     In,
+    Binary,
+    Unary,
+    Var,
+    Const,
+    Match,
+    With,
+    Break,
+    Continue,
+    /// Keyword reserved in addition to the builtin keywords, see [`Lexer::with_keywords`].
+    Keyword(String),
+    /// Comment text following the `#` up to the end of the line, only emitted when enabled with
+    /// [`Lexer::set_keep_comments`].
+    Comment(String),
+    /// Malformed token like `12.34.56` or an unterminated string, with the error message.
+    Error(String),
+}
+
+/// Location of a token in the source as range `[start, end)` of char offsets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Token {
+    /// Check if the token is the operator `op`.
+    pub fn is_op(&self, op: &str) -> bool {
+        matches!(self, Token::Op(tok) if tok == op)
+    }
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+/// Position in the source, with 1-based line and column (counted in chars) and the byte offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub col: usize,
+    pub offset: usize,
+}
+
+impl Default for Location {
+    fn default() -> Self {
+        Location {
+            line: 1,
+            col: 1,
+            offset: 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Iterator over the chars of a [`BufRead`] source, reading and decoding the input on demand.
+///
+/// The input is decoded line by line, invalid UTF-8 sequences are replaced with
+/// [`char::REPLACEMENT_CHARACTER`]. A read error ends the input, the error can be queried with
+/// [`ReadChars::error`].
+///
+/// The buffers for reading and decoding are reused across lines, such that reading allocates only
+/// when a line is longer than all lines before.
+pub struct ReadChars<R> {
+    reader: R,
+    /// Raw bytes of the line read last.
+    buf: Vec<u8>,
+    /// Line decoded last.
+    line: String,
+    /// Byte offset of the next char in `line`.
+    pos: usize,
+    error: Option<std::io::Error>,
+}
+
+impl<R: BufRead> ReadChars<R> {
+    pub fn new(reader: R) -> ReadChars<R> {
+        ReadChars {
+            reader,
+            buf: Vec::new(),
+            line: String::new(),
+            pos: 0,
+            error: None,
+        }
+    }
+
+    /// Get the error which ended the input, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<R: BufRead> Iterator for ReadChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.pos == self.line.len() {
+            if self.error.is_some() {
+                return None;
+            }
+
+            self.buf.clear();
+            match self.reader.read_until(b'\n', &mut self.buf) {
+                Ok(_) => {}
+                Err(err) => self.error = Some(err),
+            }
+
+            // A line ends at a char boundary and can be decoded on its own. Valid UTF-8 is copied
+            // without an intermediate allocation.
+            self.line.clear();
+            self.line.push_str(&String::from_utf8_lossy(&self.buf));
+            self.pos = 0;
+        }
+
+        let c = self.line[self.pos..].chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+}
+
+/// Token lexed ahead with [`Lexer::peek_n`], together with its position and source text.
+struct Peeked {
+    tok: Token,
+    span: Span,
+    loc: Location,
+    lexeme: String,
 }
 
 pub struct Lexer<I>
@@ -19,6 +205,31 @@ where
 {
     input: I,
     last_char: Option<char>,
+    /// Char offset of `last_char` in the input.
+    pos: usize,
+    /// Char offset of the start of the token lexed last.
+    tok_start: usize,
+    /// Char offset of the end of the token lexed last.
+    tok_end: usize,
+    /// Location of `last_char` in the input.
+    loc: Location,
+    /// Location of the start of the token lexed last.
+    tok_loc: Location,
+    /// Source text of the token lexed last.
+    lexeme: String,
+    /// Tokens lexed ahead of the token returned last, in order.
+    peeked: VecDeque<Peeked>,
+    /// Keywords reserved in addition to the builtin keywords.
+    keywords: HashSet<String>,
+    /// Operators declared in addition to the built-in operators.
+    ops: HashSet<String>,
+    /// The token lexed last is `binary` or `unary`, which is followed by the operator being
+    /// declared.
+    op_decl: bool,
+    /// Emit comments as tokens instead of skipping them.
+    keep_comments: bool,
+    /// The input ended in the middle of a token.
+    incomplete: bool,
 }
 
 impl<I> Lexer<I>
@@ -27,23 +238,189 @@ where
 {
     pub fn new(mut input: I) -> Lexer<I> {
         let last_char = input.next();
-        Lexer { input, last_char }
+        Lexer {
+            input,
+            last_char,
+            pos: 0,
+            tok_start: 0,
+            tok_end: 0,
+            loc: Location::default(),
+            tok_loc: Location::default(),
+            lexeme: String::new(),
+            peeked: VecDeque::new(),
+            keywords: HashSet::new(),
+            ops: HashSet::new(),
+            op_decl: false,
+            keep_comments: false,
+            incomplete: false,
+        }
+    }
+
+    /// Create a lexer which reserves the `keywords` in addition to the builtin keywords, eg for a
+    /// language extension adding `while`.
+    ///
+    /// The additional keywords are lexed as [`Token::Keyword`] instead of identifiers. Builtin
+    /// keywords keep their own token.
+    pub fn with_keywords<K>(input: I, keywords: K) -> Lexer<I>
+    where
+        K: IntoIterator,
+        K::Item: Into<String>,
+    {
+        let mut lexer = Lexer::new(input);
+        lexer.keywords = keywords.into_iter().map(Into::into).collect();
+        lexer
+    }
+
+    /// Declare the user-defined operator `op`, such that a sequence of operator chars forming `op`
+    /// is lexed as a single [`Token::Op`].
+    ///
+    /// The operator following the `binary` and `unary` keywords is declared automatically.
+    pub fn declare_op(&mut self, op: &str) {
+        self.ops.insert(op.into());
+    }
+
+    /// Check if `prefix` is the prefix of a built-in or declared operator.
+    fn is_op_prefix(&self, prefix: &str) -> bool {
+        MULTI_CHAR_OPS
+            .iter()
+            .copied()
+            .chain(self.ops.iter().map(String::as_str))
+            .any(|op| op.starts_with(prefix))
+    }
+
+    /// Enable or disable emitting comments as [`Token::Comment`] instead of skipping them, eg to
+    /// round-trip the source in tooling.
+    pub fn set_keep_comments(&mut self, keep_comments: bool) {
+        self.keep_comments = keep_comments;
+    }
+
+    /// Get the input of the lexer.
+    pub fn input(&self) -> &I {
+        &self.input
     }
 
     fn step(&mut self) -> Option<char> {
+        if let Some(c) = self.last_char {
+            self.loc.offset += c.len_utf8();
+            if c == '\n' {
+                self.loc.line += 1;
+                self.loc.col = 1;
+            } else {
+                self.loc.col += 1;
+            }
+        }
         self.last_char = self.input.next();
+        self.pos += 1;
         self.last_char
     }
 
+    /// Check if the input ended in the middle of a token, like an unterminated string or a number
+    /// ending in its exponent, which was returned as [`Token::Error`].
+    ///
+    /// The token could be completed with more input, eg a REPL can ask for the next line instead
+    /// of reporting the error.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    /// Get the current location of the lexer, which is the position of the next char to lex.
+    ///
+    /// Tokens looked at with [`peek`](Lexer::peek) are lexed already, hence the location is
+    /// behind them.
+    pub fn location(&self) -> Location {
+        self.loc
+    }
+
+    /// Get the source text of the token returned last by [`gettok`](Lexer::gettok), exactly as
+    /// written in the input, eg `12.34.56` for a malformed number.
+    ///
+    /// The lexeme of [`Token::Eof`] is empty.
+    pub fn lexeme(&self) -> &str {
+        &self.lexeme
+    }
+
+    /// Get the location of the start of the token returned last by [`gettok`](Lexer::gettok).
+    pub fn token_location(&self) -> Location {
+        self.tok_loc
+    }
+
+    /// Get the location of the token returned last by [`gettok`](Lexer::gettok).
+    pub fn span(&self) -> Span {
+        Span::new(self.tok_start, self.tok_end)
+    }
+
+    /// Look at the token following the token returned last by [`gettok`](Lexer::gettok),
+    /// without consuming it.
+    pub fn peek(&mut self) -> &Token {
+        self.peek_n(0)
+    }
+
+    /// Look at the `n`-th token following the token returned last by [`gettok`](Lexer::gettok),
+    /// without consuming it, `peek_n(0)` is the next token.
+    ///
+    /// Peeking doesn't change the [`span`](Lexer::span), [`lexeme`](Lexer::lexeme) and
+    /// [`token_location`](Lexer::token_location) of the token returned last.
+    pub fn peek_n(&mut self, n: usize) -> &Token {
+        if self.peeked.len() <= n {
+            // Lexing overwrites the state of the token returned last, restore it afterwards.
+            let span = self.span();
+            let loc = self.tok_loc;
+            let lexeme = std::mem::take(&mut self.lexeme);
+
+            while self.peeked.len() <= n {
+                let tok = self.lex();
+                self.peeked.push_back(Peeked {
+                    tok,
+                    span: self.span(),
+                    loc: self.tok_loc,
+                    lexeme: std::mem::take(&mut self.lexeme),
+                });
+            }
+
+            self.tok_start = span.start;
+            self.tok_end = span.end;
+            self.tok_loc = loc;
+            self.lexeme = lexeme;
+        }
+
+        &self.peeked[n].tok
+    }
+
     /// Lex and return the next token.
     ///
     /// Implement `int gettok();` from the tutorial.
     pub fn gettok(&mut self) -> Token {
+        match self.peeked.pop_front() {
+            Some(peeked) => {
+                self.tok_start = peeked.span.start;
+                self.tok_end = peeked.span.end;
+                self.tok_loc = peeked.loc;
+                self.lexeme = peeked.lexeme;
+                peeked.tok
+            }
+            None => self.lex(),
+        }
+    }
+
+    /// Lex the next token from the input, ignoring the peeked tokens.
+    fn lex(&mut self) -> Token {
+        let tok = self.lex_token();
+        self.tok_end = self.pos;
+        self.op_decl = matches!(tok, Token::Binary | Token::Unary);
+        tok
+    }
+
+    /// Lex the next token from the input, without recording the end of its span.
+    fn lex_token(&mut self) -> Token {
         // Eat up whitespaces.
         while matches!(self.last_char, Some(c) if c.is_ascii_whitespace()) {
             self.step();
         }
 
+        self.tok_start = self.pos;
+        self.tok_loc = self.loc;
+        self.lexeme.clear();
+
         // Unpack last char or return EOF.
         let last_char = if let Some(c) = self.last_char {
             c
@@ -51,20 +428,22 @@ where
             return Token::Eof;
         };
 
+        // Tokens are accumulated in the `lexeme` buffer, which is reused across tokens, such that
+        // only tokens carrying a string allocate.
+
         // Identifier: [a-zA-Z][a-zA-Z0-9]*
         if last_char.is_ascii_alphabetic() {
-            let mut ident = String::new();
-            ident.push(last_char);
+            self.lexeme.push(last_char);
 
             while let Some(c) = self.step() {
                 if c.is_ascii_alphanumeric() {
-                    ident.push(c)
+                    self.lexeme.push(c)
                 } else {
                     break;
                 }
             }
 
-            match ident.as_ref() {
+            match self.lexeme.as_ref() {
                 "def" => return Token::Def,
                 "extern" => return Token::Extern,
                 "if" => return Token::If,
@@ -72,49 +451,262 @@ where
                 "else" => return Token::Else,
                 "for" => return Token::For,
                 "in" => return Token::In,
+                "binary" => return Token::Binary,
+                "unary" => return Token::Unary,
+                "var" => return Token::Var,
+                "const" => return Token::Const,
+                "match" => return Token::Match,
+                "with" => return Token::With,
+                "break" => return Token::Break,
+                "continue" => return Token::Continue,
+                kw if self.keywords.contains(kw) => return Token::Keyword(self.lexeme.clone()),
                 _ => {}
             }
 
-            return Token::Identifier(ident);
+            return Token::Identifier(self.lexeme.clone());
         }
 
-        // Number: [0-9.]+
+        // Number: [0-9.]+ ([eE][+-]?[0-9]+)?
+        //       | 0[xX][0-9a-fA-F.]+ ([pP][+-]?[0-9]+)?
         if last_char.is_ascii_digit() || last_char == '.' {
-            let mut num = String::new();
-            num.push(last_char);
+            self.lexeme.push(last_char);
 
+            let mut hex = false;
+            let mut exp = false;
             while let Some(c) = self.step() {
-                if c.is_ascii_digit() || c == '.' {
-                    num.push(c)
+                if self.lexeme == "0" && matches!(c, 'x' | 'X') {
+                    hex = true;
+                } else if !exp && matches!((hex, c), (false, 'e' | 'E') | (true, 'p' | 'P')) {
+                    exp = true;
+                } else if exp {
+                    // The exponent is decimal, optionally starting with a sign.
+                    let sign = matches!(c, '+' | '-')
+                        && matches!(self.lexeme.chars().last(), Some('e' | 'E' | 'p' | 'P'));
+                    if !c.is_ascii_digit() && !sign {
+                        break;
+                    }
+                } else if !(c.is_digit(if hex { 16 } else { 10 }) || c == '.') {
+                    break;
+                }
+                self.lexeme.push(c);
+            }
+
+            // Literals without fraction and exponent are integers, unless they don't fit into an
+            // i64.
+            if !exp && !self.lexeme.contains('.') {
+                let int = if hex {
+                    i64::from_str_radix(&self.lexeme[2..], 16)
                 } else {
+                    self.lexeme.parse()
+                };
+                if let Ok(int) = int {
+                    return Token::Integer(int);
+                }
+            }
+
+            let num = if hex {
+                parse_hex_float(&self.lexeme[2..])
+            } else {
+                self.lexeme.parse().ok()
+            };
+            if num.is_none() && self.last_char.is_none() {
+                // Input ending after the prefix of a hex literal or in an exponent is incomplete.
+                self.incomplete = (hex && self.lexeme.len() == 2)
+                    || (exp && !self.lexeme.ends_with(|c: char| c.is_ascii_digit()));
+            }
+            return match num {
+                Some(num) => Token::Number(num),
+                None => Token::Error(format!("invalid number '{}'", self.lexeme)),
+            };
+        }
+
+        // String: '"' ([^"\\] | '\\' any)* '"'
+        if last_char == '"' {
+            return match self.lex_quoted('"') {
+                Some(Ok(string)) => Token::String(string),
+                Some(Err(err)) => Token::Error(err),
+                None => Token::Error("unterminated string literal".into()),
+            };
+        }
+
+        // Char: '\'' ([^'\\] | '\\' any) '\''
+        if last_char == '\'' {
+            let lit = match self.lex_quoted('\'') {
+                Some(Ok(lit)) => lit,
+                Some(Err(err)) => return Token::Error(err),
+                None => return Token::Error("unterminated character literal".into()),
+            };
+
+            let mut chars = lit.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) => Token::Char(c),
+                _ => Token::Error(format!(
+                    "character literal {} must contain exactly one char",
+                    self.lexeme
+                )),
+            };
+        }
+
+        // Comment: '#' up to the end of the line.
+        if last_char == '#' && self.keep_comments {
+            self.lexeme.push('#');
+
+            while let Some(c) = self.step() {
+                if c == '\r' || c == '\n' {
                     break;
                 }
+                self.lexeme.push(c);
             }
 
-            let num: f64 = num.parse().unwrap_or_default();
-            return Token::Number(num);
+            return Token::Comment(self.lexeme[1..].into());
         }
 
         // Eat up comment.
         if last_char == '#' {
             loop {
                 match self.step() {
-                    Some(c) if c == '\r' || c == '\n' => return self.gettok(),
+                    Some(c) if c == '\r' || c == '\n' => return self.lex_token(),
                     None => return Token::Eof,
                     _ => { /* consume comment */ }
                 }
             }
         }
 
+        // Operator: [!%&*+-/<=>?^|~]+
+        //
+        // Take the longest run of operator chars which is the prefix of a known operator, such
+        // that eg `x*-1` is lexed as `x * - 1`. The operator declared after `binary` and `unary`
+        // takes all operator chars and is known from then on.
+        if is_op_char(last_char) {
+            self.lexeme.push(last_char);
+
+            while let Some(c) = self.step() {
+                if !is_op_char(c) {
+                    break;
+                }
+                self.lexeme.push(c);
+                if !self.op_decl && !self.is_op_prefix(&self.lexeme) {
+                    self.lexeme.pop();
+                    break;
+                }
+            }
+
+            if self.op_decl {
+                self.ops.insert(self.lexeme.clone());
+            }
+            return Token::Op(self.lexeme.clone());
+        }
+
         // Advance last char and return currently last char.
         self.step();
-        Token::Char(last_char)
+        self.lexeme.push(last_char);
+        Token::Delim(last_char)
+    }
+}
+
+impl<I> Lexer<I>
+where
+    I: Iterator<Item = char>,
+{
+    /// Lex the literal enclosed in `quote`, starting at the opening quote, and return its value
+    /// with the escape sequences replaced.
+    ///
+    /// Return `None` if the literal is not terminated until the end of the input and an error if
+    /// it contains an invalid escape sequence.
+    fn lex_quoted(&mut self, quote: char) -> Option<Result<String, String>> {
+        self.lexeme.push(quote);
+        self.step();
+
+        let mut lit = String::new();
+        let mut err = None;
+        while let Some(c) = self.last_char {
+            self.lexeme.push(c);
+            self.step();
+            if c == quote {
+                return Some(err.map_or(Ok(lit), Err));
+            }
+            if c != '\\' {
+                lit.push(c);
+                continue;
+            }
+
+            let esc_start = self.lexeme.len() - 1;
+            let esc = match self.last_char {
+                Some(esc) => esc,
+                None => break,
+            };
+            self.lexeme.push(esc);
+            self.step();
+
+            let unescaped = match esc {
+                'n' => Some('\n'),
+                't' => Some('\t'),
+                '\\' | '"' | '\'' => Some(esc),
+                'x' => self.lex_hex_escape(),
+                _ => None,
+            };
+            match unescaped {
+                Some(c) => lit.push(c),
+                // Keep lexing up to the closing quote, such that the error covers the literal.
+                None if err.is_none() => {
+                    let seq = &self.lexeme[esc_start..];
+                    err = Some(format!("invalid escape sequence '{}'", seq));
+                }
+                None => {}
+            }
+        }
+
+        self.incomplete = true;
+        None
+    }
+
+    /// Lex the two hex digits of a `\xNN` escape sequence, which must denote an ASCII char.
+    fn lex_hex_escape(&mut self) -> Option<char> {
+        let mut value = 0;
+        for _ in 0..2 {
+            let c = self.last_char?;
+            let digit = c.to_digit(16)?;
+            self.lexeme.push(c);
+            self.step();
+            value = value * 16 + digit;
+        }
+
+        u8::try_from(value)
+            .ok()
+            .filter(u8::is_ascii)
+            .map(char::from)
+    }
+}
+
+impl<R: BufRead> Lexer<ReadChars<R>> {
+    /// Create a lexer reading the input from `reader` on demand, see [`ReadChars`].
+    pub fn from_reader(reader: R) -> Lexer<ReadChars<R>> {
+        Lexer::new(ReadChars::new(reader))
+    }
+}
+
+/// Iterate over the tokens of the input together with their location, ending before
+/// [`Token::Eof`].
+impl<I> Iterator for Lexer<I>
+where
+    I: Iterator<Item = char>,
+{
+    type Item = (Token, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.gettok() {
+            Token::Eof => None,
+            tok => Some((tok, self.span())),
+        }
     }
 }
 
+impl<I> std::iter::FusedIterator for Lexer<I> where I: Iterator<Item = char> {}
+
 #[cfg(test)]
 mod test {
-    use super::{Lexer, Token};
+    use super::{Lexer, Location, ReadChars, Span, Token};
+    use std::io::BufReader;
 
     #[test]
     fn test_identifier() {
@@ -127,9 +719,14 @@ mod test {
 
     #[test]
     fn test_keyword() {
-        let mut lex = Lexer::new("def extern".chars());
+        let mut lex = Lexer::new("def extern const match with break continue".chars());
         assert_eq!(Token::Def, lex.gettok());
         assert_eq!(Token::Extern, lex.gettok());
+        assert_eq!(Token::Const, lex.gettok());
+        assert_eq!(Token::Match, lex.gettok());
+        assert_eq!(Token::With, lex.gettok());
+        assert_eq!(Token::Break, lex.gettok());
+        assert_eq!(Token::Continue, lex.gettok());
         assert_eq!(Token::Eof, lex.gettok());
     }
 
@@ -146,7 +743,104 @@ mod test {
         assert_eq!(Token::Eof, lex.gettok());
 
         let mut lex = Lexer::new("12.34.56".chars());
-        assert_eq!(Token::Number(0f64), lex.gettok());
+        assert_eq!(
+            Token::Error("invalid number '12.34.56'".into()),
+            lex.gettok()
+        );
+        assert_eq!("12.34.56", lex.lexeme());
+        assert_eq!(Token::Eof, lex.gettok());
+        assert_eq!("", lex.lexeme());
+    }
+
+    #[test]
+    fn test_number_exponent() {
+        let mut lex = Lexer::new("1e-3 2.5E+2 .5e1 1e308 3e-x".chars());
+        assert_eq!(Token::Number(1e-3), lex.gettok());
+        assert_eq!(Token::Number(2.5e2), lex.gettok());
+        assert_eq!(Token::Number(5.0), lex.gettok());
+        assert_eq!(Token::Number(1e308), lex.gettok());
+        assert_eq!(Token::Error("invalid number '3e-'".into()), lex.gettok());
+        assert_eq!(Token::Identifier("x".into()), lex.gettok());
+
+        let mut lex = Lexer::new("1e5-2".chars());
+        assert_eq!(Token::Number(1e5), lex.gettok());
+        assert_eq!(Token::Op("-".into()), lex.gettok());
+        assert_eq!(Token::Integer(2), lex.gettok());
+    }
+
+    #[test]
+    fn test_integer() {
+        let mut lex = Lexer::new("42 42.0 0x2a 0x2a.0p0 99999999999999999999".chars());
+        assert_eq!(Token::Integer(42), lex.gettok());
+        assert_eq!(Token::Number(42.0), lex.gettok());
+        assert_eq!(Token::Integer(42), lex.gettok());
+        assert_eq!(Token::Number(42.0), lex.gettok());
+        assert_eq!(Token::Number(1e20), lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
+    #[test]
+    fn test_number_hex() {
+        let mut lex = Lexer::new("0x1f 0X1.8p3 0x.8 0x1p-2 0xe-1 0x".chars());
+        assert_eq!(Token::Integer(31), lex.gettok());
+        assert_eq!(Token::Number(12.0), lex.gettok());
+        assert_eq!(Token::Number(0.5), lex.gettok());
+        assert_eq!(Token::Number(0.25), lex.gettok());
+        assert_eq!(Token::Integer(14), lex.gettok());
+        assert_eq!(Token::Op("-".into()), lex.gettok());
+        assert_eq!(Token::Integer(1), lex.gettok());
+        assert_eq!(Token::Error("invalid number '0x'".into()), lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
+    #[test]
+    fn test_string() {
+        let mut lex = Lexer::new(r#""a b" "" "x\"y\n\t\\\x41"("#.chars());
+        assert_eq!(Token::String("a b".into()), lex.gettok());
+        assert_eq!(Token::String("".into()), lex.gettok());
+        assert_eq!(Token::String("x\"y\n\t\\A".into()), lex.gettok());
+        assert_eq!(
+            (Span::new(9, 25), r#""x\"y\n\t\\\x41""#),
+            (lex.span(), lex.lexeme())
+        );
+        assert_eq!(Token::Delim('('), lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+
+        let mut lex = Lexer::new(r#""\q" "a\x4g\q" "\x80" "\x"a"#.chars());
+        let err = |seq| Token::Error(format!("invalid escape sequence '{}'", seq));
+        assert_eq!(err(r"\q"), lex.gettok());
+        assert_eq!((err(r"\x4"), r#""a\x4g\q""#), (lex.gettok(), lex.lexeme()));
+        assert_eq!(err(r"\x80"), lex.gettok());
+        assert_eq!(err(r"\x"), lex.gettok());
+        assert_eq!(Token::Identifier("a".into()), lex.gettok());
+
+        let mut lex = Lexer::new(r#"a "b\"#.chars());
+        assert_eq!(Token::Identifier("a".into()), lex.gettok());
+        let err = Token::Error("unterminated string literal".into());
+        assert_eq!((err, r#""b\"#), (lex.gettok(), lex.lexeme()));
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
+    #[test]
+    fn test_char() {
+        let mut lex = Lexer::new(r#"'a' 'ä' '\n' '\'' '"' '' 'ab' 'x"#.chars());
+        assert_eq!(Token::Char('a'), lex.gettok());
+        assert_eq!(Token::Char('ä'), lex.gettok());
+        assert_eq!(Token::Char('\n'), lex.gettok());
+        assert_eq!((Token::Char('\''), r#"'\''"#), (lex.gettok(), lex.lexeme()));
+        assert_eq!(Token::Char('"'), lex.gettok());
+        let err = |lit| {
+            Token::Error(format!(
+                "character literal {} must contain exactly one char",
+                lit
+            ))
+        };
+        assert_eq!(err("''"), lex.gettok());
+        assert_eq!(err("'ab'"), lex.gettok());
+        assert_eq!(
+            Token::Error("unterminated character literal".into()),
+            lex.gettok()
+        );
         assert_eq!(Token::Eof, lex.gettok());
     }
 
@@ -161,25 +855,82 @@ mod test {
         assert_eq!(Token::Eof, lex.gettok());
     }
 
+    #[test]
+    fn test_keep_comments() {
+        let mut lex = Lexer::new("a # one\r\n#two\n b #".chars());
+        lex.set_keep_comments(true);
+        assert_eq!(Token::Identifier("a".into()), lex.gettok());
+        assert_eq!(Token::Comment(" one".into()), lex.gettok());
+        assert_eq!((Span::new(2, 7), "# one"), (lex.span(), lex.lexeme()));
+        assert_eq!(Token::Comment("two".into()), lex.gettok());
+        assert_eq!(Token::Identifier("b".into()), lex.gettok());
+        assert_eq!(Token::Comment("".into()), lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
     #[test]
     fn test_chars() {
         let mut lex = Lexer::new("a+b-c".chars());
         assert_eq!(Token::Identifier("a".into()), lex.gettok());
-        assert_eq!(Token::Char('+'), lex.gettok());
+        assert_eq!(Token::Op("+".into()), lex.gettok());
+        assert_eq!(Token::Identifier("b".into()), lex.gettok());
+        assert_eq!(Token::Op("-".into()), lex.gettok());
+        assert_eq!(Token::Identifier("c".into()), lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
+    #[test]
+    fn test_ops() {
+        let mut lex = Lexer::new("a<=-b;(c)!=d".chars());
+        assert_eq!(Token::Identifier("a".into()), lex.gettok());
+        assert_eq!(Token::Op("<=".into()), lex.gettok());
+        assert_eq!(Token::Op("-".into()), lex.gettok());
         assert_eq!(Token::Identifier("b".into()), lex.gettok());
-        assert_eq!(Token::Char('-'), lex.gettok());
+        assert_eq!(Token::Delim(';'), lex.gettok());
+        assert_eq!(Token::Delim('('), lex.gettok());
         assert_eq!(Token::Identifier("c".into()), lex.gettok());
+        assert_eq!(Token::Delim(')'), lex.gettok());
+        assert_eq!(Token::Op("!=".into()), lex.gettok());
+        assert_eq!((Span::new(9, 11), "!="), (lex.span(), lex.lexeme()));
+        assert_eq!(Token::Identifier("d".into()), lex.gettok());
         assert_eq!(Token::Eof, lex.gettok());
+
+        // Unknown sequences of operator chars are lexed one char at a time.
+        let ops = |src: &str| {
+            let mut lex = Lexer::new(src.chars());
+            let mut ops = Vec::new();
+            loop {
+                match lex.gettok() {
+                    Token::Op(op) => ops.push(op),
+                    Token::Eof => return ops,
+                    _ => {}
+                }
+            }
+        };
+        assert_eq!(ops("x*-1 a==-1 a<-b"), ["*", "-", "==", "-", "<", "-"]);
+        assert_eq!(ops("a--b ~~ a-->b"), ["-", "-", "~", "~", "-", "->"]);
+
+        // The operator following 'binary' and 'unary' is declared.
+        assert_eq!(
+            ops("def binary~~ (a b) a; a ~~ b ~~~"),
+            ["~~", "~~", "~~", "~"]
+        );
+        assert_eq!(ops("def unary-->(a) a; -->x"), ["-->", "-->"]);
+
+        let mut lex = Lexer::new("a-->b".chars());
+        lex.declare_op("-->");
+        assert_eq!(Token::Identifier("a".into()), lex.gettok());
+        assert_eq!(Token::Op("-->".into()), lex.gettok());
     }
 
     #[test]
     fn test_whitespaces() {
         let mut lex = Lexer::new("    +a  b      c!    ".chars());
-        assert_eq!(Token::Char('+'), lex.gettok());
+        assert_eq!(Token::Op("+".into()), lex.gettok());
         assert_eq!(Token::Identifier("a".into()), lex.gettok());
         assert_eq!(Token::Identifier("b".into()), lex.gettok());
         assert_eq!(Token::Identifier("c".into()), lex.gettok());
-        assert_eq!(Token::Char('!'), lex.gettok());
+        assert_eq!(Token::Op("!".into()), lex.gettok());
         assert_eq!(Token::Eof, lex.gettok());
 
         let mut lex = Lexer::new("\n    a \n\r  b \r \n     c \r\r  \n   ".chars());
@@ -203,4 +954,130 @@ mod test {
         assert_eq!(Token::For, lex.gettok());
         assert_eq!(Token::In, lex.gettok());
     }
+
+    #[test]
+    fn test_operator_keywords() {
+        let mut lex = Lexer::new("binary unary var binaryx".chars());
+        assert_eq!(Token::Binary, lex.gettok());
+        assert_eq!(Token::Unary, lex.gettok());
+        assert_eq!(Token::Var, lex.gettok());
+        assert_eq!(Token::Identifier("binaryx".into()), lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut lex = Lexer::new("foo(x) + 1".chars());
+        assert_eq!(&Token::Identifier("foo".into()), lex.peek());
+        assert_eq!(Token::Identifier("foo".into()), lex.gettok());
+        assert_eq!(&Token::Identifier("x".into()), lex.peek_n(1));
+        assert_eq!(&Token::Op("+".into()), lex.peek_n(3));
+        assert_eq!((Span::new(0, 3), "foo"), (lex.span(), lex.lexeme()));
+        assert_eq!(&Token::Delim('('), lex.peek());
+
+        assert_eq!(Token::Delim('('), lex.gettok());
+        assert_eq!((Span::new(3, 4), "("), (lex.span(), lex.lexeme()));
+        assert_eq!(Token::Identifier("x".into()), lex.gettok());
+        assert_eq!(Token::Delim(')'), lex.gettok());
+        assert_eq!(Token::Op("+".into()), lex.gettok());
+        assert_eq!(&Token::Eof, lex.peek_n(1));
+        assert_eq!(Token::Integer(1), lex.gettok());
+        assert_eq!(Span::new(9, 10), lex.span());
+        assert_eq!(Token::Eof, lex.gettok());
+        assert_eq!(&Token::Eof, lex.peek());
+    }
+
+    #[test]
+    fn test_extra_keywords() {
+        let mut lex =
+            Lexer::with_keywords("while x return def".chars(), ["while", "return", "def"]);
+        assert_eq!(Token::Keyword("while".into()), lex.gettok());
+        assert_eq!(Token::Identifier("x".into()), lex.gettok());
+        assert_eq!(Token::Keyword("return".into()), lex.gettok());
+        assert_eq!(Token::Def, lex.gettok());
+        assert_eq!(Token::Eof, lex.gettok());
+    }
+
+    #[test]
+    fn test_incomplete() {
+        for src in ["\"abc", "'\\", "1e", "2.5e-", "0x"] {
+            let mut lex = Lexer::new(src.chars());
+            assert!(matches!(lex.gettok(), Token::Error(_)));
+            assert!(lex.is_incomplete(), "{}", src);
+        }
+
+        for src in ["12.34.56", "1e x", "\"abc\" 1"] {
+            let mut lex = Lexer::new(src.chars());
+            lex.by_ref().count();
+            assert!(!lex.is_incomplete(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        let mut lex = Lexer::new("def foo(x) # comment\n  x+12.5".chars());
+        let mut next = || (lex.gettok(), lex.span());
+        assert_eq!((Token::Def, Span::new(0, 3)), next());
+        assert_eq!((Token::Identifier("foo".into()), Span::new(4, 7)), next());
+        assert_eq!((Token::Delim('('), Span::new(7, 8)), next());
+        assert_eq!((Token::Identifier("x".into()), Span::new(8, 9)), next());
+        assert_eq!((Token::Delim(')'), Span::new(9, 10)), next());
+        assert_eq!((Token::Identifier("x".into()), Span::new(23, 24)), next());
+        assert_eq!((Token::Op("+".into()), Span::new(24, 25)), next());
+        assert_eq!((Token::Number(12.5), Span::new(25, 29)), next());
+        assert_eq!((Token::Eof, Span::new(29, 29)), next());
+    }
+
+    #[test]
+    fn test_location() {
+        let loc = |line, col, offset| Location { line, col, offset };
+
+        let mut lex = Lexer::new("def f(x)\n  \"ä\" + x\n\n# c\n1".chars());
+        let mut next = || (lex.gettok(), lex.token_location());
+        assert_eq!((Token::Def, loc(1, 1, 0)), next());
+        assert_eq!((Token::Identifier("f".into()), loc(1, 5, 4)), next());
+        next();
+        next();
+        next();
+        assert_eq!((Token::String("ä".into()), loc(2, 3, 11)), next());
+        assert_eq!((Token::Op("+".into()), loc(2, 7, 16)), next());
+        assert_eq!((Token::Identifier("x".into()), loc(2, 9, 18)), next());
+        assert_eq!((Token::Integer(1), loc(5, 1, 25)), next());
+        assert_eq!(lex.location(), loc(5, 2, 26));
+    }
+
+    #[test]
+    fn test_reader() {
+        // Read in small chunks to split multi-byte chars across reads.
+        let src: &[u8] = b"def f(x) # \xc3\xa4\n x + \xff1";
+        let mut lex = Lexer::from_reader(BufReader::with_capacity(3, src));
+        let toks: Vec<_> = lex.by_ref().map(|(tok, _)| tok).collect();
+        assert_eq!(toks.len(), 9);
+        assert_eq!(toks[5], Token::Identifier("x".into()));
+        assert_eq!(toks[7], Token::Delim(char::REPLACEMENT_CHARACTER));
+        assert_eq!(toks[8], Token::Integer(1));
+        assert!(lex.input().error().is_none());
+
+        let chars: String = ReadChars::new("a\nä\n\nb".as_bytes()).collect();
+        assert_eq!(chars, "a\nä\n\nb");
+    }
+
+    #[test]
+    fn test_iterator() {
+        let toks: Vec<_> = Lexer::new("foo(1) # bar".chars()).collect();
+        assert_eq!(
+            toks,
+            vec![
+                (Token::Identifier("foo".into()), Span::new(0, 3)),
+                (Token::Delim('('), Span::new(3, 4)),
+                (Token::Integer(1), Span::new(4, 5)),
+                (Token::Delim(')'), Span::new(5, 6)),
+            ]
+        );
+
+        let idents = Lexer::new("def f(x) x".chars())
+            .filter(|(tok, _)| matches!(tok, Token::Identifier(_)))
+            .count();
+        assert_eq!(idents, 3);
+    }
 }
@@ -1,9 +1,33 @@
 use std::convert::TryFrom;
 
+/// Log an event of the pipeline with the [`log`](https://docs.rs/log) crate at the log `$level`,
+/// eg `debug`. Without the `log` feature the event is compiled out.
+macro_rules! log_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::$level!($($arg)+);
+    };
+}
+
+pub mod backend;
+pub mod bench;
 pub mod codegen;
+pub mod desugar;
+pub mod diagnostic;
+pub mod driver;
+pub mod fmt;
+pub mod fold;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 pub mod lexer;
+pub mod line_reader;
 pub mod llvm;
+#[cfg(feature = "lsp")]
+pub mod lsp;
 pub mod parser;
+pub mod session;
+pub mod stdlib;
+pub mod typeck;
 
 /// Fixed size of [`SmallCStr`] including the trailing `\0` byte.
 pub const SMALL_STR_SIZE: usize = 16;
@@ -0,0 +1,520 @@
+//! Reading lines of input for the REPL.
+//!
+//! [`LineReader`] abstracts over the plain [`StdinReader`] and the [`Editor`], which offers line
+//! editing and a persistent history when stdin is a terminal.
+//!
+//! The [`Editor`] supports the following keys:
+//! - `Left`/`Right`, `Home`/`End`, `C-b`/`C-f`, `C-a`/`C-e`: Move the cursor.
+//! - `Backspace`, `Delete`, `C-d`: Delete characters.
+//! - `C-u`/`C-k`: Delete up to the start/end of the line.
+//! - `Up`/`Down`, `C-p`/`C-n`: Browse the history.
+//! - `C-r`: Search the history backwards, `C-r` again to find older entries, `C-g` to abort.
+//! - `C-c`: Discard the current line.
+//! - `C-d` on an empty line: End of input.
+
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+
+/// Source of input lines for the REPL.
+pub trait LineReader {
+    /// Show `prompt` and read the next line of input without the trailing newline.
+    ///
+    /// Returns `None` at the end of the input.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>>;
+
+    /// Add `line` to the history, readers without history ignore it.
+    fn add_history(&mut self, _line: &str) {}
+}
+
+/// Line reader reading plain lines from stdin without any editing support.
+pub struct StdinReader;
+
+impl LineReader for StdinReader {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Maximum number of entries kept in the history.
+const HISTORY_SIZE: usize = 1000;
+
+/// Input history, oldest entry first, optionally persisted to a file.
+#[derive(Default)]
+struct History {
+    entries: Vec<String>,
+    file: Option<PathBuf>,
+}
+
+impl History {
+    /// Load the history from `file`, a missing file starts with an empty history.
+    fn load(file: Option<PathBuf>) -> History {
+        let mut entries: Vec<String> = file
+            .as_ref()
+            .and_then(|f| std::fs::read_to_string(f).ok())
+            .map(|s| s.lines().map(String::from).collect())
+            .unwrap_or_default();
+
+        let skip = entries.len().saturating_sub(HISTORY_SIZE);
+        entries.drain(..skip);
+
+        History { entries, file }
+    }
+
+    /// Add `line` to the history and append it to the history file.
+    ///
+    /// Blank lines and repetitions of the last entry are not added.
+    fn add(&mut self, line: &str) {
+        if line.trim().is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return;
+        }
+
+        self.entries.push(line.into());
+        if self.entries.len() > HISTORY_SIZE {
+            self.entries.remove(0);
+        }
+
+        if let Some(file) = &self.file {
+            // The history is a convenience, failing to persist it is not worth an error.
+            if let Ok(mut f) = OpenOptions::new().create(true).append(true).open(file) {
+                let _ = writeln!(f, "{}", line);
+            }
+        }
+    }
+
+    /// Search backwards for the newest entry containing `query` with an index before `before`.
+    fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|e| e.contains(query))
+    }
+}
+
+/// Line being edited with the cursor position as char index.
+#[derive(Debug, Default)]
+struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    /// Delete the char before the cursor.
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// Delete the char under the cursor.
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+
+    fn kill_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    fn kill_to_end(&mut self) {
+        self.chars.truncate(self.cursor);
+    }
+
+    /// Replace the line with `line` and move the cursor to the end.
+    fn set(&mut self, line: &str) {
+        self.chars = line.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    fn line(&self) -> String {
+        self.chars.iter().collect()
+    }
+}
+
+/// Key press decoded from the terminal input.
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    /// Control key combination, eg `Ctrl(b'a')` for `C-a`.
+    Ctrl(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Unknown,
+}
+
+/// Read and decode the next key press from `input`, returns `None` at the end of the input.
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let mut next = || -> io::Result<Option<u8>> {
+        let mut b = [0u8];
+        Ok((input.read(&mut b)? == 1).then(|| b[0]))
+    };
+
+    let b = match next()? {
+        Some(b) => b,
+        None => return Ok(None),
+    };
+
+    let key = match b {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => match next()? {
+            Some(b'[') | Some(b'O') => match next()? {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                Some(b'C') => Key::Right,
+                Some(b'D') => Key::Left,
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                Some(d) if d.is_ascii_digit() => {
+                    // Sequences of the form `ESC [ <num> ~`.
+                    let mut num = vec![d];
+                    loop {
+                        match next()? {
+                            Some(b'~') | None => break,
+                            Some(d) => num.push(d),
+                        }
+                    }
+                    match num.as_slice() {
+                        b"1" | b"7" => Key::Home,
+                        b"3" => Key::Delete,
+                        b"4" | b"8" => Key::End,
+                        _ => Key::Unknown,
+                    }
+                }
+                _ => Key::Unknown,
+            },
+            _ => Key::Unknown,
+        },
+        0x01..=0x1a => Key::Ctrl(b'a' + b - 1),
+        0x00..=0x1f => Key::Unknown,
+        _ if b < 0x80 => Key::Char(b as char),
+        _ => {
+            // Multi byte UTF-8 sequence, the number of leading ones gives its length.
+            let len = (b.leading_ones() as usize).clamp(2, 4);
+            let mut bytes = vec![b];
+            for _ in 1..len {
+                match next()? {
+                    Some(b) => bytes.push(b),
+                    None => break,
+                }
+            }
+            std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+                .map_or(Key::Unknown, Key::Char)
+        }
+    };
+
+    Ok(Some(key))
+}
+
+/// Puts the terminal on stdin into raw mode, the previous mode is restored when dropped.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut orig: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut orig) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Read single key presses without echo, keep output processing for `\n`.
+            let mut raw = orig;
+            raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode(orig))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.0);
+        }
+    }
+}
+
+/// State of an incremental history search.
+#[derive(Default)]
+struct Search {
+    query: String,
+    /// Index of the matching history entry.
+    idx: Option<usize>,
+}
+
+/// Line reader with line editing and a persistent history for terminals.
+///
+/// The terminal is only put into raw mode while reading a line, such that the program runs with
+/// the usual terminal settings while evaluating the input.
+pub struct Editor {
+    history: History,
+}
+
+impl Editor {
+    /// Create an editor with the history persisted to `history_file`.
+    ///
+    /// Entries of a previous session are loaded from the file, without a file the history only
+    /// lasts for the lifetime of the editor.
+    pub fn new(history_file: Option<PathBuf>) -> Editor {
+        Editor {
+            history: History::load(history_file),
+        }
+    }
+
+    /// The default history file `~/.kaleidoscope_history`.
+    pub fn default_history_file() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kaleidoscope_history"))
+    }
+
+    fn render(&self, out: &mut impl Write, prompt: &str, buf: &LineBuffer) -> io::Result<()> {
+        write!(out, "\r{}{}\x1b[K", prompt, buf.line())?;
+
+        // Move the cursor to its position in the line.
+        let col = prompt.chars().count() + buf.cursor;
+        write!(out, "\r")?;
+        if col > 0 {
+            write!(out, "\x1b[{}C", col)?;
+        }
+        out.flush()
+    }
+
+    fn render_search(&self, out: &mut impl Write, search: &Search) -> io::Result<()> {
+        let found = search.idx.map_or("", |idx| &self.history.entries[idx]);
+        write!(
+            out,
+            "\r(reverse-i-search)`{}': {}\x1b[K",
+            search.query, found
+        )?;
+        out.flush()
+    }
+}
+
+impl LineReader for Editor {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let _raw = RawMode::enable()?;
+
+        let mut input = io::stdin();
+        let mut out = io::stderr();
+
+        let mut buf = LineBuffer::default();
+        let mut search: Option<Search> = None;
+
+        // Index of the history entry shown while browsing, `len` refers to the edited line.
+        let mut hist_idx = self.history.entries.len();
+        let mut edited = String::new();
+
+        loop {
+            match &search {
+                Some(search) => self.render_search(&mut out, search)?,
+                None => self.render(&mut out, prompt, &buf)?,
+            }
+
+            let key = match read_key(&mut input)? {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+
+            if let Some(s) = &mut search {
+                match key {
+                    Key::Char(c) => {
+                        s.query.push(c);
+                        let from = s.idx.map_or(self.history.entries.len(), |idx| idx + 1);
+                        s.idx = self.history.search(&s.query, from);
+                    }
+                    Key::Backspace => {
+                        s.query.pop();
+                        s.idx = self.history.search(&s.query, self.history.entries.len());
+                    }
+                    Key::Ctrl(b'r') => {
+                        let from = s.idx.unwrap_or(self.history.entries.len());
+                        s.idx = self.history.search(&s.query, from).or(s.idx);
+                    }
+                    Key::Ctrl(b'g') | Key::Ctrl(b'c') => search = None,
+                    Key::Enter => {
+                        if let Some(idx) = s.idx {
+                            buf.set(&self.history.entries[idx]);
+                        }
+                        self.render(&mut out, prompt, &buf)?;
+                        write!(out, "\r\n")?;
+                        return Ok(Some(buf.line()));
+                    }
+                    _ => {
+                        // Any other key accepts the match for further editing.
+                        if let Some(idx) = s.idx {
+                            buf.set(&self.history.entries[idx]);
+                        }
+                        search = None;
+                    }
+                }
+                continue;
+            }
+
+            match key {
+                Key::Enter => {
+                    write!(out, "\r\n")?;
+                    return Ok(Some(buf.line()));
+                }
+                Key::Ctrl(b'd') if buf.is_empty() => {
+                    write!(out, "\r\n")?;
+                    return Ok(None);
+                }
+                Key::Ctrl(b'c') => {
+                    write!(out, "^C\r\n")?;
+                    buf = LineBuffer::default();
+                    hist_idx = self.history.entries.len();
+                }
+                Key::Ctrl(b'd') | Key::Delete => buf.delete(),
+                Key::Backspace => buf.backspace(),
+                Key::Left | Key::Ctrl(b'b') => buf.left(),
+                Key::Right | Key::Ctrl(b'f') => buf.right(),
+                Key::Home | Key::Ctrl(b'a') => buf.home(),
+                Key::End | Key::Ctrl(b'e') => buf.end(),
+                Key::Ctrl(b'u') => buf.kill_to_start(),
+                Key::Ctrl(b'k') => buf.kill_to_end(),
+                Key::Up | Key::Ctrl(b'p') if hist_idx > 0 => {
+                    if hist_idx == self.history.entries.len() {
+                        edited = buf.line();
+                    }
+                    hist_idx -= 1;
+                    buf.set(&self.history.entries[hist_idx]);
+                }
+                Key::Down | Key::Ctrl(b'n') if hist_idx < self.history.entries.len() => {
+                    hist_idx += 1;
+                    match self.history.entries.get(hist_idx) {
+                        Some(entry) => buf.set(entry),
+                        None => buf.set(&edited),
+                    }
+                }
+                Key::Ctrl(b'r') => search = Some(Search::default()),
+                Key::Char(c) => buf.insert(c),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_history(&mut self, line: &str) {
+        self.history.add(line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_key, History, Key, LineBuffer};
+
+    #[test]
+    fn line_buffer_edit() {
+        let mut buf = LineBuffer::default();
+        "def f(x)".chars().for_each(|c| buf.insert(c));
+        buf.home();
+        buf.delete();
+        buf.delete();
+        buf.delete();
+        assert_eq!(buf.line(), " f(x)");
+
+        buf.end();
+        buf.left();
+        buf.backspace();
+        buf.insert('y');
+        assert_eq!(buf.line(), " f(y)");
+
+        buf.kill_to_end();
+        assert_eq!(buf.line(), " f(y");
+        buf.left();
+        buf.kill_to_start();
+        assert_eq!(buf.line(), "y");
+        assert_eq!(buf.cursor, 0);
+    }
+
+    #[test]
+    fn history_search() {
+        let mut hist = History::default();
+        hist.add("def foo(x) x");
+        hist.add("foo(1)");
+        hist.add("foo(1)");
+        hist.add("   ");
+        hist.add("def bar(y) y");
+        assert_eq!(hist.entries.len(), 3);
+
+        assert_eq!(hist.search("def", 3), Some(2));
+        assert_eq!(hist.search("def", 2), Some(0));
+        assert_eq!(hist.search("foo", 3), Some(1));
+        assert_eq!(hist.search("baz", 3), None);
+    }
+
+    #[test]
+    fn decode_keys() {
+        let mut input: &[u8] = b"a\x1b[A\x1b[3~\x01\x7f\r\xc3\xa4";
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut input).unwrap() {
+            keys.push(key);
+        }
+        assert_eq!(
+            keys,
+            vec![
+                Key::Char('a'),
+                Key::Up,
+                Key::Delete,
+                Key::Ctrl(b'a'),
+                Key::Backspace,
+                Key::Enter,
+                Key::Char('ä'),
+            ]
+        );
+    }
+}
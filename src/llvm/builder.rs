@@ -1,8 +1,10 @@
 use llvm_sys::{
     core::{
-        LLVMAddIncoming, LLVMBuildBr, LLVMBuildCondBr, LLVMBuildFAdd, LLVMBuildFCmp, LLVMBuildFMul,
-        LLVMBuildFSub, LLVMBuildPhi, LLVMBuildRet, LLVMBuildUIToFP, LLVMCreateBuilderInContext,
-        LLVMDisposeBuilder, LLVMGetInsertBlock, LLVMPositionBuilderAtEnd,
+        LLVMAddIncoming, LLVMBuildAlloca, LLVMBuildBr, LLVMBuildCondBr, LLVMBuildFAdd,
+        LLVMBuildFCmp, LLVMBuildFMul, LLVMBuildFRem, LLVMBuildFSub, LLVMBuildLoad2, LLVMBuildPhi,
+        LLVMBuildRet, LLVMBuildStore, LLVMBuildUIToFP, LLVMCreateBuilderInContext,
+        LLVMDisposeBuilder, LLVMGetFirstInstruction, LLVMGetInsertBlock, LLVMPositionBuilderAtEnd,
+        LLVMPositionBuilderBefore, LLVMSetVolatile,
     },
     prelude::{LLVMBuilderRef, LLVMValueRef},
     LLVMRealPredicate,
@@ -25,6 +27,9 @@ extern "C" {
 }
 
 /// Wrapper for a LLVM IR Builder.
+///
+/// Instructions producing a value take a `name` for the result, similar to the `C++` API. LLVM
+/// makes names unique within a function by appending a counter (eg `addtmp`, `addtmp1`, ..).
 pub struct IRBuilder<'llvm> {
     builder: LLVMBuilderRef,
     _ctx: PhantomData<&'llvm ()>,
@@ -53,6 +58,19 @@ impl<'llvm> IRBuilder<'llvm> {
         }
     }
 
+    /// Position the IR Builder at the start of the given Basic Block, before its first
+    /// instruction.
+    pub fn pos_at_start(&self, bb: BasicBlock<'llvm>) {
+        unsafe {
+            let first = LLVMGetFirstInstruction(bb.bb_ref());
+            if first.is_null() {
+                LLVMPositionBuilderAtEnd(self.builder, bb.bb_ref());
+            } else {
+                LLVMPositionBuilderBefore(self.builder, first);
+            }
+        }
+    }
+
     /// Get the BasicBlock the IRBuilder currently inputs into.
     ///
     /// # Panics
@@ -70,7 +88,7 @@ impl<'llvm> IRBuilder<'llvm> {
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn fadd(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+    pub fn fadd(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
         debug_assert!(lhs.is_f64(), "fadd: Expected f64 as lhs operand!");
         debug_assert!(rhs.is_f64(), "fadd: Expected f64 as rhs operand!");
 
@@ -79,10 +97,10 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fadd\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
     }
 
     /// Emit a [fsub](https://llvm.org/docs/LangRef.html#fsub-instruction) instruction.
@@ -90,7 +108,7 @@ impl<'llvm> IRBuilder<'llvm> {
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn fsub(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+    pub fn fsub(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
         debug_assert!(lhs.is_f64(), "fsub: Expected f64 as lhs operand!");
         debug_assert!(rhs.is_f64(), "fsub: Expected f64 as rhs operand!");
 
@@ -99,10 +117,10 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fsub\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
     }
 
     /// Emit a [fmul](https://llvm.org/docs/LangRef.html#fmul-instruction) instruction.
@@ -110,7 +128,7 @@ impl<'llvm> IRBuilder<'llvm> {
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn fmul(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
+    pub fn fmul(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
         debug_assert!(lhs.is_f64(), "fmul: Expected f64 as lhs operand!");
         debug_assert!(rhs.is_f64(), "fmul: Expected f64 as rhs operand!");
 
@@ -119,52 +137,121 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fmul\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
     }
 
-    /// Emit a [fcmpult](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    /// Emit a [frem](https://llvm.org/docs/LangRef.html#frem-instruction) instruction.
     ///
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn fcmpult(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
-        debug_assert!(lhs.is_f64(), "fcmpult: Expected f64 as lhs operand!");
-        debug_assert!(rhs.is_f64(), "fcmpult: Expected f64 as rhs operand!");
+    pub fn frem(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "frem: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "frem: Expected f64 as rhs operand!");
 
         let value_ref = unsafe {
-            LLVMBuildFCmp(
+            LLVMBuildFRem(
                 self.builder,
-                LLVMRealPredicate::LLVMRealULT,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fcmpult\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
     }
 
-    /// Emit a [fcmpone](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    /// Emit a [fcmp](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction with the
+    /// predicate `pred`.
     ///
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn fcmpone(&self, lhs: Value<'llvm>, rhs: Value<'llvm>) -> Value<'llvm> {
-        debug_assert!(lhs.is_f64(), "fcmone: Expected f64 as lhs operand!");
-        debug_assert!(rhs.is_f64(), "fcmone: Expected f64 as rhs operand!");
+    fn fcmp(
+        &self,
+        pred: LLVMRealPredicate,
+        lhs: Value<'llvm>,
+        rhs: Value<'llvm>,
+        name: &str,
+    ) -> Value<'llvm> {
+        debug_assert!(lhs.is_f64(), "fcmp: Expected f64 as lhs operand!");
+        debug_assert!(rhs.is_f64(), "fcmp: Expected f64 as rhs operand!");
 
         let value_ref = unsafe {
             LLVMBuildFCmp(
                 self.builder,
-                LLVMRealPredicate::LLVMRealONE,
+                pred,
                 lhs.value_ref(),
                 rhs.value_ref(),
-                b"fcmpone\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
+    }
+
+    /// Emit a [fcmpult](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpult(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealULT, lhs, rhs, name)
+    }
+
+    /// Emit a [fcmpugt](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpugt(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealUGT, lhs, rhs, name)
+    }
+
+    /// Emit a [fcmpule](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpule(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealULE, lhs, rhs, name)
+    }
+
+    /// Emit a [fcmpuge](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpuge(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealUGE, lhs, rhs, name)
+    }
+
+    /// Emit a [fcmpoeq](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpoeq(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealOEQ, lhs, rhs, name)
+    }
+
+    /// Emit a [fcmpone](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpone(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealONE, lhs, rhs, name)
+    }
+
+    /// Emit a [fcmpune](https://llvm.org/docs/LangRef.html#fcmp-instruction) instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn fcmpune(&self, lhs: Value<'llvm>, rhs: Value<'llvm>, name: &str) -> Value<'llvm> {
+        self.fcmp(LLVMRealPredicate::LLVMRealUNE, lhs, rhs, name)
     }
 
     /// Emit a [uitofp](https://llvm.org/docs/LangRef.html#uitofp-to-instruction) instruction.
@@ -172,7 +259,7 @@ impl<'llvm> IRBuilder<'llvm> {
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn uitofp(&self, val: Value<'llvm>, dest_type: Type<'llvm>) -> Value<'llvm> {
+    pub fn uitofp(&self, val: Value<'llvm>, dest_type: Type<'llvm>, name: &str) -> Value<'llvm> {
         debug_assert!(val.is_int(), "uitofp: Expected integer operand!");
 
         let value_ref = unsafe {
@@ -180,10 +267,79 @@ impl<'llvm> IRBuilder<'llvm> {
                 self.builder,
                 val.value_ref(),
                 dest_type.type_ref(),
-                b"uitofp\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
+            )
+        };
+        Value::new_with_name(value_ref, name)
+    }
+
+    /// Emit an [alloca](https://llvm.org/docs/LangRef.html#alloca-instruction) instruction
+    /// allocating a stack slot for a value of type `alloc_type`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn alloca(&self, alloc_type: Type<'llvm>, name: &str) -> Value<'llvm> {
+        let value_ref =
+            unsafe { LLVMBuildAlloca(self.builder, alloc_type.type_ref(), b"\0".as_ptr().cast()) };
+        Value::new_with_name(value_ref, name)
+    }
+
+    /// Emit a [load](https://llvm.org/docs/LangRef.html#load-instruction) instruction loading a
+    /// value of type `load_type` from `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn load(&self, load_type: Type<'llvm>, ptr: Value<'llvm>, name: &str) -> Value<'llvm> {
+        let value_ref = unsafe {
+            LLVMBuildLoad2(
+                self.builder,
+                load_type.type_ref(),
+                ptr.value_ref(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
+    }
+
+    /// Emit a [store](https://llvm.org/docs/LangRef.html#store-instruction) instruction storing
+    /// `val` to `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn store(&self, val: Value<'llvm>, ptr: Value<'llvm>) {
+        let store_ref = unsafe { LLVMBuildStore(self.builder, val.value_ref(), ptr.value_ref()) };
+        assert!(!store_ref.is_null());
+    }
+
+    /// Emit a volatile [load](https://llvm.org/docs/LangRef.html#load-instruction) instruction
+    /// loading a value of type `load_type` from `ptr`.
+    ///
+    /// Volatile loads are neither removed nor hoisted out of loops by the optimizer, hence they
+    /// observe changes of the memory made outside of the generated code.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn load_volatile(
+        &self,
+        load_type: Type<'llvm>,
+        ptr: Value<'llvm>,
+        name: &str,
+    ) -> Value<'llvm> {
+        let value_ref = unsafe {
+            let load = LLVMBuildLoad2(
+                self.builder,
+                load_type.type_ref(),
+                ptr.value_ref(),
+                b"\0".as_ptr().cast(),
+            );
+            LLVMSetVolatile(load, 1);
+            load
+        };
+        Value::new_with_name(value_ref, name)
     }
 
     /// Emit a [call](https://llvm.org/docs/LangRef.html#call-instruction) instruction.
@@ -191,7 +347,12 @@ impl<'llvm> IRBuilder<'llvm> {
     /// # Panics
     ///
     /// Panics if LLVM API returns a `null` pointer.
-    pub fn call(&self, fn_value: FnValue<'llvm>, args: &mut [Value<'llvm>]) -> Value<'llvm> {
+    pub fn call(
+        &self,
+        fn_value: FnValue<'llvm>,
+        args: &mut [Value<'llvm>],
+        name: &str,
+    ) -> Value<'llvm> {
         let value_ref = unsafe {
             LLVMBuildCall2(
                 self.builder,
@@ -199,10 +360,10 @@ impl<'llvm> IRBuilder<'llvm> {
                 fn_value,
                 args.as_mut_ptr(),
                 args.len() as libc::c_uint,
-                b"call\0".as_ptr().cast(),
+                b"\0".as_ptr().cast(),
             )
         };
-        Value::new(value_ref)
+        Value::new_with_name(value_ref, name)
     }
 
     /// Emit a [ret](https://llvm.org/docs/LangRef.html#ret-instruction) instruction.
@@ -251,9 +412,10 @@ impl<'llvm> IRBuilder<'llvm> {
         &self,
         phi_type: Type<'llvm>,
         incoming: &[(Value<'llvm>, BasicBlock<'llvm>)],
+        name: &str,
     ) -> PhiValue<'llvm> {
         let phi_ref =
-            unsafe { LLVMBuildPhi(self.builder, phi_type.type_ref(), b"phi\0".as_ptr().cast()) };
+            unsafe { LLVMBuildPhi(self.builder, phi_type.type_ref(), b"\0".as_ptr().cast()) };
         assert!(!phi_ref.is_null());
 
         for (val, bb) in incoming {
@@ -268,7 +430,9 @@ impl<'llvm> IRBuilder<'llvm> {
             }
         }
 
-        PhiValue::new(phi_ref)
+        let phi = PhiValue::new(phi_ref);
+        phi.set_name(name);
+        phi
     }
 }
 
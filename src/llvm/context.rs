@@ -0,0 +1,79 @@
+use llvm_sys::{
+    orc2::{
+        LLVMOrcCreateNewThreadSafeContext, LLVMOrcDisposeThreadSafeContext,
+        LLVMOrcThreadSafeContextGetContext, LLVMOrcThreadSafeContextRef,
+    },
+    prelude::LLVMContextRef,
+};
+
+use std::rc::Rc;
+
+/// Wrapper for a LLVM ThreadSafeContext, which can back many [`Module`](super::Module)s.
+///
+/// Types and constants are owned and uniqued by the context, hence sharing a single context
+/// between the modules of a session avoids creating a new context for each module and duplicating
+/// its types and constants.
+///
+/// The wrapper is a cheap handle with shared ownership, the context is disposed once all handles
+/// and all modules created in the context are dropped. Modules handed to the JIT keep their own
+/// reference to the context.
+#[derive(Clone)]
+pub struct Context(Rc<ContextRef>);
+
+struct ContextRef {
+    tsctx: LLVMOrcThreadSafeContextRef,
+    ctx: LLVMContextRef,
+}
+
+impl Context {
+    /// Create a new Context instance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if creating the context fails.
+    pub fn new() -> Self {
+        let (tsctx, ctx) = unsafe {
+            // We generate a thread safe context because we are going to jit the IR modules and
+            // there is no method to create a thread safe context wrapper from an existing context
+            // reference (at the time of writing this).
+            //
+            // ThreadSafeContext has shared ownership (start with ref count 1).
+            // We must explicitly dispose our reference (dec ref count).
+            let tc = LLVMOrcCreateNewThreadSafeContext();
+            assert!(!tc.is_null());
+
+            let c = LLVMOrcThreadSafeContextGetContext(tc);
+            assert!(!c.is_null());
+            (tc, c)
+        };
+
+        Context(Rc::new(ContextRef { tsctx, ctx }))
+    }
+
+    /// Get the raw LLVM ThreadSafeContext reference.
+    #[inline]
+    pub(super) fn tsctx(&self) -> LLVMOrcThreadSafeContextRef {
+        self.0.tsctx
+    }
+
+    /// Get the raw LLVM context reference.
+    #[inline]
+    pub(super) fn ctx(&self) -> LLVMContextRef {
+        self.0.ctx
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ContextRef {
+    fn drop(&mut self) {
+        unsafe {
+            // Dispose ThreadSafeContext reference (dec ref count).
+            LLVMOrcDisposeThreadSafeContext(self.tsctx);
+        }
+    }
+}
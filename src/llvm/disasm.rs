@@ -0,0 +1,83 @@
+use llvm_sys::{
+    disassembler::{
+        LLVMCreateDisasm, LLVMDisasmContextRef, LLVMDisasmDispose, LLVMDisasmInstruction,
+        LLVMDisassembler_Option_PrintImmHex, LLVMSetDisasmOptions,
+    },
+    target_machine::LLVMGetDefaultTargetTriple,
+};
+
+use super::Message;
+
+/// Wrapper for a LLVM Disassembler context.
+pub struct Disassembler {
+    dc: LLVMDisasmContextRef,
+}
+
+impl Disassembler {
+    /// Create a disassembler for the host target.
+    ///
+    /// The native target must be initialized before with
+    /// [`initialize_native_taget`](super::initialize_native_taget).
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn host() -> Disassembler {
+        let triple = Message::from(unsafe { LLVMGetDefaultTargetTriple() })
+            .expect("Expected default target triple from LLVM API");
+
+        let dc = unsafe {
+            LLVMCreateDisasm(
+                &*triple.0,
+                std::ptr::null_mut(), /* DisInfo */
+                0,                    /* TagType */
+                None,                 /* GetOpInfo */
+                None,                 /* SymbolLookUp */
+            )
+        };
+        assert!(!dc.is_null());
+
+        unsafe { LLVMSetDisasmOptions(dc, LLVMDisassembler_Option_PrintImmHex) };
+
+        Disassembler { dc }
+    }
+
+    /// Disassemble the machine `code` located at address `pc` and return the address and the
+    /// textual form of each instruction.
+    pub fn disassemble(&self, code: &[u8], pc: u64) -> Vec<(u64, String)> {
+        let mut code = code.to_vec();
+        let mut insns = Vec::new();
+        let mut off = 0;
+        let mut buf = [0 as libc::c_char; 128];
+
+        while off < code.len() {
+            let len = unsafe {
+                LLVMDisasmInstruction(
+                    self.dc,
+                    code[off..].as_mut_ptr(),
+                    (code.len() - off) as u64,
+                    pc + off as u64,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                )
+            };
+
+            if len == 0 {
+                insns.push((pc + off as u64, "<invalid>".into()));
+                break;
+            }
+
+            let insn = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+            insns.push((pc + off as u64, insn.to_string_lossy().trim().into()));
+            off += len;
+        }
+
+        insns
+    }
+}
+
+impl Drop for Disassembler {
+    fn drop(&mut self) {
+        unsafe { LLVMDisasmDispose(self.dc) };
+    }
+}
@@ -1,30 +1,102 @@
-use llvm_sys::orc2::{
-    lljit::{
-        LLVMOrcCreateLLJIT, LLVMOrcLLJITAddLLVMIRModuleWithRT, LLVMOrcLLJITGetGlobalPrefix,
-        LLVMOrcLLJITGetMainJITDylib, LLVMOrcLLJITLookup, LLVMOrcLLJITRef,
+use llvm_sys::{
+    core::{
+        LLVMCreateMemoryBufferWithMemoryRange, LLVMDisposeMemoryBuffer, LLVMGetBufferSize,
+        LLVMGetBufferStart,
     },
-    LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess, LLVMOrcDefinitionGeneratorRef,
-    LLVMOrcJITDylibAddGenerator, LLVMOrcJITDylibCreateResourceTracker, LLVMOrcJITDylibRef,
-    LLVMOrcReleaseResourceTracker, LLVMOrcResourceTrackerRef, LLVMOrcResourceTrackerRemove,
+    error::LLVMErrorRef,
+    object::{
+        LLVMCreateBinary, LLVMDisposeBinary, LLVMDisposeSymbolIterator, LLVMGetSymbolName,
+        LLVMGetSymbolSize, LLVMMoveToNextSymbol, LLVMObjectFileCopySymbolIterator,
+        LLVMObjectFileIsSymbolIteratorAtEnd,
+    },
+    orc2::{
+        lljit::{
+            LLVMOrcCreateLLJIT, LLVMOrcCreateLLJITBuilder, LLVMOrcLLJITAddLLVMIRModuleWithRT,
+            LLVMOrcLLJITBuilderRef, LLVMOrcLLJITBuilderSetJITTargetMachineBuilder,
+            LLVMOrcLLJITGetGlobalPrefix, LLVMOrcLLJITGetMainJITDylib,
+            LLVMOrcLLJITGetObjTransformLayer, LLVMOrcLLJITGetTripleString, LLVMOrcLLJITLookup,
+            LLVMOrcLLJITMangleAndIntern, LLVMOrcLLJITRef,
+        },
+        LLVMJITEvaluatedSymbol, LLVMJITSymbolFlags, LLVMJITSymbolGenericFlags,
+        LLVMOrcAbsoluteSymbols, LLVMOrcCSymbolMapPair,
+        LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess, LLVMOrcDefinitionGeneratorRef,
+        LLVMOrcDisposeMaterializationUnit, LLVMOrcJITDylibAddGenerator,
+        LLVMOrcJITDylibCreateResourceTracker, LLVMOrcJITDylibDefine, LLVMOrcJITDylibRef,
+        LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine,
+        LLVMOrcObjectTransformLayerSetTransform, LLVMOrcReleaseResourceTracker,
+        LLVMOrcResourceTrackerRef, LLVMOrcResourceTrackerRemove,
+    },
+    prelude::LLVMMemoryBufferRef,
 };
 
-use std::convert::TryFrom;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
 use std::marker::PhantomData;
 
-use super::{Error, Module};
-use crate::SmallCStr;
+use super::{Error, Message, Module, SymName, TargetMachine};
+
+/// Function signatures that can be looked up in the JIT or defined in the JIT, see
+/// [`LLJit::define_absolute`].
+pub trait JitFn: Sized {
+    /// Number of arguments of the function.
+    const ARITY: usize;
+
+    /// Convert the address `addr` of a function in the JIT into a function pointer.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be the address of a function with the signature `Self`.
+    unsafe fn from_addr(addr: u64) -> Self;
+
+    /// Address of the function.
+    fn addr(self) -> u64;
+}
+
+macro_rules! impl_jit_fn {
+    ($arity:expr; $($arg:ident),*) => {
+        impl_jit_fn!(@impl unsafe extern "C" fn($($arg),*) -> f64, $arity);
+        impl_jit_fn!(@impl extern "C" fn($($arg),*) -> f64, $arity);
+    };
+    (@impl $fn:ty, $arity:expr) => {
+        impl JitFn for $fn {
+            const ARITY: usize = $arity;
+
+            unsafe fn from_addr(addr: u64) -> Self {
+                // Function pointers can't be cast from integers directly, go through a data
+                // pointer of the same size.
+                let ptr = addr as usize as *const ();
+                std::mem::transmute::<*const (), Self>(ptr)
+            }
 
-/// Marker trait to constrain function signatures that can be looked up in the JIT.
-pub trait JitFn {}
+            fn addr(self) -> u64 {
+                self as usize as u64
+            }
+        }
+    };
+}
 
-impl JitFn for unsafe extern "C" fn() -> f64 {}
+impl_jit_fn!(0;);
+impl_jit_fn!(1; f64);
+impl_jit_fn!(2; f64, f64);
+impl_jit_fn!(3; f64, f64, f64);
+impl_jit_fn!(4; f64, f64, f64, f64);
 
 /// Wrapper for a LLVM [LLJIT](https://www.llvm.org/docs/ORCv2.html#lljit-and-lllazyjit).
 pub struct LLJit {
     jit: LLVMOrcLLJITRef,
     dylib: LLVMOrcJITDylibRef,
+
+    /// Symbols defined by the object files emitted by the JIT, captured once enabled with
+    /// [`enable_object_capture`](LLJit::enable_object_capture).
+    ///
+    /// Boxed, as the JIT holds a raw pointer to it.
+    objects: Box<RefCell<Vec<ObjectSymbols>>>,
 }
 
+/// Names and sizes of the symbols defined in a captured object file.
+type ObjectSymbols = Vec<(String, u64)>;
+
 impl LLJit {
     /// Create a new LLJit instance.
     ///
@@ -32,12 +104,37 @@ impl LLJit {
     ///
     /// Panics if LLVM API returns a `null` pointer or an error.
     pub fn new() -> LLJit {
+        Self::create(std::ptr::null_mut() /* builder: nullptr -> default */)
+    }
+
+    /// Create a new LLJit instance generating machine code with the TargetMachine `tm`, eg to
+    /// control the optimization level of the code generator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer or an error.
+    pub fn with_target_machine(tm: TargetMachine) -> LLJit {
+        let builder = unsafe {
+            let builder = LLVMOrcCreateLLJITBuilder();
+            assert!(!builder.is_null());
+
+            // Takes ownership of the TargetMachine.
+            let jtmb = LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine(tm.into_raw());
+            assert!(!jtmb.is_null());
+
+            // Takes ownership of the JITTargetMachineBuilder.
+            LLVMOrcLLJITBuilderSetJITTargetMachineBuilder(builder, jtmb);
+            builder
+        };
+
+        Self::create(builder)
+    }
+
+    /// Create a new LLJit instance from `builder`, taking ownership of the builder.
+    fn create(builder: LLVMOrcLLJITBuilderRef) -> LLJit {
         let (jit, dylib) = unsafe {
             let mut jit = std::ptr::null_mut();
-            let err = LLVMOrcCreateLLJIT(
-                &mut jit as _,
-                std::ptr::null_mut(), /* builder: nullptr -> default */
-            );
+            let err = LLVMOrcCreateLLJIT(&mut jit as _, builder);
 
             if let Some(err) = Error::from(err) {
                 panic!("Error: {}", err.as_str());
@@ -49,7 +146,11 @@ impl LLJit {
             (jit, dylib)
         };
 
-        LLJit { jit, dylib }
+        LLJit {
+            jit,
+            dylib,
+            objects: Box::new(RefCell::new(Vec::new())),
+        }
     }
 
     /// Add an LLVM IR module to the JIT. Return a [`ResourceTracker`], which when dropped, will
@@ -72,31 +173,77 @@ impl LLJit {
             rt
         };
 
+        log_event!(debug, "added module to the JIT");
         ResourceTracker::new(rt)
     }
 
+    /// Get the target triple the JIT generates code for, eg `x86_64-pc-linux-gnu`.
+    pub fn triple(&self) -> String {
+        // The string is owned by the JIT.
+        let triple = unsafe { CStr::from_ptr(LLVMOrcLLJITGetTripleString(self.jit)) };
+        triple
+            .to_str()
+            .expect("Expected valid UTF8 string from LLVM API")
+            .into()
+    }
+
     /// Find the symbol with the name `sym` in the JIT.
     ///
-    /// # Panics
-    ///
-    /// Panics if the symbol is not found in the JIT.
-    pub fn find_symbol<F: JitFn>(&self, sym: &str) -> F {
-        let sym =
-            SmallCStr::try_from(sym).expect("Failed to convert 'sym' argument to small C string!");
+    /// Looking up a symbol compiles the module defining it, returns an error if the symbol is not
+    /// found in the JIT, the module references undefined symbols or `sym` is not a valid symbol
+    /// name.
+    pub fn find_symbol<F: JitFn>(&self, sym: &str) -> Result<F, String> {
+        let csym = SymName::new(sym).ok_or_else(|| format!("Invalid symbol name '{}'", sym))?;
 
         unsafe {
             let mut addr = 0u64;
-            let err = LLVMOrcLLJITLookup(self.jit, &mut addr as _, sym.as_ptr());
+            let err = LLVMOrcLLJITLookup(self.jit, &mut addr as _, csym.as_ptr());
 
             if let Some(err) = Error::from(err) {
-                panic!("Error: {}", err.as_str());
+                return Err(err.as_str().into());
             }
 
-            debug_assert_eq!(core::mem::size_of_val(&addr), core::mem::size_of::<F>());
-            std::mem::transmute_copy(&addr)
+            log_event!(debug, "resolved symbol '{}' at {:#x}", sym, addr);
+            Ok(F::from_addr(addr))
         }
     }
 
+    /// Define the symbol `name` at the absolute address `addr` in the JIT, eg to make a function
+    /// of the host process callable from jitted code under another name.
+    ///
+    /// Returns an error if the symbol is already defined in the JIT.
+    pub fn define_absolute(&self, name: &str, addr: u64) -> Result<(), String> {
+        let cname = CString::new(name).map_err(|_| format!("Invalid symbol name '{}'", name))?;
+
+        unsafe {
+            let flags = LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8
+                | LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8;
+            let mut sym = LLVMOrcCSymbolMapPair {
+                Name: LLVMOrcLLJITMangleAndIntern(self.jit, cname.as_ptr()),
+                Sym: LLVMJITEvaluatedSymbol {
+                    Address: addr,
+                    Flags: LLVMJITSymbolFlags {
+                        GenericFlags: flags,
+                        TargetFlags: 0,
+                    },
+                },
+            };
+
+            // The materialization unit takes ownership of the interned name, the unit itself is
+            // owned by the JITDylib once it is defined.
+            let mu = LLVMOrcAbsoluteSymbols(&mut sym, 1);
+            let err = LLVMOrcJITDylibDefine(self.dylib, mu);
+
+            if let Some(err) = Error::from(err) {
+                LLVMOrcDisposeMaterializationUnit(mu);
+                return Err(err.as_str().into());
+            }
+        }
+
+        log_event!(debug, "defined symbol '{}' at {:#x}", name, addr);
+        Ok(())
+    }
+
     /// Enable lookup of dynamic symbols available in the current process from the JIT.
     ///
     /// # Panics
@@ -120,12 +267,152 @@ impl LLJit {
         }
     }
 
+    /// Keep the symbol table of every object file emitted by the JIT, which is required to find
+    /// the machine code of a function with [`function_code`](LLJit::function_code).
+    ///
+    /// The symbol tables are kept until they are pruned with
+    /// [`prune_objects`](LLJit::prune_objects), even if the corresponding code is removed from
+    /// the JIT.
+    pub fn enable_object_capture(&self) {
+        extern "C" fn capture(
+            ctx: *mut libc::c_void,
+            obj: *mut LLVMMemoryBufferRef,
+        ) -> LLVMErrorRef {
+            let objects = unsafe { &*(ctx as *const RefCell<Vec<ObjectSymbols>>) };
+            let data = unsafe {
+                let start = LLVMGetBufferStart(*obj) as *const u8;
+                std::slice::from_raw_parts(start, LLVMGetBufferSize(*obj))
+            };
+            objects.borrow_mut().push(object_symbols(data));
+
+            // Success, the object is passed on unmodified.
+            std::ptr::null_mut()
+        }
+
+        unsafe {
+            let layer = LLVMOrcLLJITGetObjTransformLayer(self.jit);
+            LLVMOrcObjectTransformLayerSetTransform(
+                layer,
+                capture,
+                &*self.objects as *const RefCell<_> as *mut libc::c_void,
+            );
+        }
+    }
+
+    /// Get the address and a copy of the machine code of the function `name` in the JIT.
+    ///
+    /// Returns `None` if the function is not defined in the JIT or its size is unknown, because
+    /// the object file was not captured, see [`enable_object_capture`](LLJit::enable_object_capture).
+    pub fn function_code(&self, name: &str) -> Option<(u64, Vec<u8>)> {
+        let sym = SymName::new(name)?;
+
+        let addr = unsafe {
+            let mut addr = 0u64;
+            let err = LLVMOrcLLJITLookup(self.jit, &mut addr as _, sym.as_ptr());
+            Error::from(err).map_or(Some(addr), |_| None)?
+        };
+
+        // Symbol names in the object file carry the global prefix, if any.
+        let mut obj_name = String::new();
+        if self.global_prefix() != 0 {
+            obj_name.push(self.global_prefix() as u8 as char);
+        }
+        obj_name.push_str(name);
+
+        // Search the newest object first, as a function may have been redefined.
+        let size = self.objects.borrow().iter().rev().find_map(|syms| {
+            syms.iter()
+                .find(|(sym, _)| *sym == obj_name)
+                .map(|(_, size)| *size)
+        })?;
+
+        // The code is alive as long as the function is defined in the JIT.
+        let code = unsafe { std::slice::from_raw_parts(addr as *const u8, size as usize) };
+        Some((addr, code.to_vec()))
+    }
+
+    /// Drop the captured symbol tables of object files whose code is not reachable anymore.
+    ///
+    /// An object file is kept as long as it is the newest one defining a symbol for which
+    /// `is_defined` returns true. The symbol names passed to `is_defined` are stripped of the
+    /// global prefix.
+    pub fn prune_objects(&self, is_defined: impl Fn(&str) -> bool) {
+        let prefix = self.global_prefix();
+        let strip = |sym: &str| -> String {
+            match prefix {
+                0 => sym.into(),
+                prefix => sym.strip_prefix(prefix as u8 as char).unwrap_or(sym).into(),
+            }
+        };
+
+        // Walk from the newest to the oldest object, symbols seen before are superseded.
+        let mut seen = HashSet::new();
+        let mut objects = self.objects.borrow_mut();
+        let mut keep: Vec<bool> = objects
+            .iter()
+            .rev()
+            .map(|syms| {
+                let mut live = false;
+                for (sym, _) in syms {
+                    let sym = strip(sym);
+                    live |= !seen.contains(&sym) && is_defined(&sym);
+                    seen.insert(sym);
+                }
+                live
+            })
+            .collect();
+        keep.reverse();
+
+        let mut keep = keep.into_iter();
+        objects.retain(|_| keep.next().unwrap_or(false));
+    }
+
     /// Return the global prefix character according to the LLJITs data layout.
     fn global_prefix(&self) -> libc::c_char {
         unsafe { LLVMOrcLLJITGetGlobalPrefix(self.jit) }
     }
 }
 
+/// Collect the names and sizes of the symbols defined in the object file `obj`.
+///
+/// Symbols without size, eg undefined references to other functions, are skipped.
+fn object_symbols(obj: &[u8]) -> ObjectSymbols {
+    unsafe {
+        let buf = LLVMCreateMemoryBufferWithMemoryRange(
+            obj.as_ptr().cast(),
+            obj.len(),
+            b"obj\0".as_ptr().cast(),
+            0, /* RequiresNullTerminator */
+        );
+        assert!(!buf.is_null());
+
+        let mut err = std::ptr::null_mut();
+        let bin = LLVMCreateBinary(buf, std::ptr::null_mut(), &mut err);
+        if bin.is_null() {
+            drop(Message::from(err));
+            LLVMDisposeMemoryBuffer(buf);
+            return Vec::new();
+        }
+
+        let mut syms = Vec::new();
+        let it = LLVMObjectFileCopySymbolIterator(bin);
+        while LLVMObjectFileIsSymbolIteratorAtEnd(bin, it) == 0 {
+            let sym_name = LLVMGetSymbolName(it);
+            let size = LLVMGetSymbolSize(it);
+            if !sym_name.is_null() && size > 0 {
+                let sym_name = CStr::from_ptr(sym_name).to_string_lossy().into_owned();
+                syms.push((sym_name, size));
+            }
+            LLVMMoveToNextSymbol(it);
+        }
+
+        LLVMDisposeSymbolIterator(it);
+        LLVMDisposeBinary(bin);
+        LLVMDisposeMemoryBuffer(buf);
+        syms
+    }
+}
+
 /// A resource handle for code added to an [`LLJit`] instance.
 ///
 /// When a `ResourceTracker` handle is dropped, the code corresponding to the handle will be
@@ -9,32 +9,67 @@
 //! API calls.
 
 use llvm_sys::{
-    core::LLVMShutdown,
+    core::{LLVMDisposeMessage, LLVMShutdown},
     error::{LLVMDisposeErrorMessage, LLVMErrorRef, LLVMGetErrorMessage},
     target::{
-        LLVM_InitializeNativeAsmParser, LLVM_InitializeNativeAsmPrinter,
-        LLVM_InitializeNativeTarget,
+        LLVM_InitializeAllAsmPrinters, LLVM_InitializeAllTargetInfos, LLVM_InitializeAllTargetMCs,
+        LLVM_InitializeAllTargets, LLVM_InitializeNativeAsmParser, LLVM_InitializeNativeAsmPrinter,
+        LLVM_InitializeNativeDisassembler, LLVM_InitializeNativeTarget,
     },
 };
 
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+
+use crate::SmallCStr;
 
 mod basic_block;
 mod builder;
+mod context;
+mod disasm;
 mod lljit;
 mod module;
 mod pass_manager;
+mod target_machine;
 mod type_;
 mod value;
 
 pub use basic_block::BasicBlock;
 pub use builder::IRBuilder;
-pub use lljit::{LLJit, ResourceTracker};
+pub use context::Context;
+pub use disasm::Disassembler;
+pub use lljit::{JitFn, LLJit, ResourceTracker};
 pub use module::Module;
 pub use pass_manager::FunctionPassManager;
+pub use target_machine::{FileType, TargetMachine};
 pub use type_::Type;
 pub use value::{FnValue, PhiValue, Value};
 
+/// Optimization level of the generated code, used for the optimization passes as well as the
+/// machine code generation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// No optimizations apart from promoting variables to registers.
+    O0,
+    O1,
+    /// Default optimization level.
+    #[default]
+    O2,
+    O3,
+}
+
+impl OptLevel {
+    /// Get the optimization level for the numeric `level` in `0..=3`.
+    pub fn from_level(level: u8) -> Option<OptLevel> {
+        match level {
+            0 => Some(OptLevel::O0),
+            1 => Some(OptLevel::O1),
+            2 => Some(OptLevel::O2),
+            3 => Some(OptLevel::O3),
+            _ => None,
+        }
+    }
+}
+
 struct Error<'llvm>(&'llvm mut libc::c_char);
 
 impl<'llvm> Error<'llvm> {
@@ -57,12 +92,73 @@ impl Drop for Error<'_> {
     }
 }
 
+/// Symbol name passed to the LLVM API as C string.
+///
+/// Most names fit into a [`SmallCStr`] on the stack, longer names like the ones of generated
+/// functions are allocated on the heap.
+enum SymName {
+    Small(SmallCStr),
+    Large(CString),
+}
+
+impl SymName {
+    /// Create a new symbol name from `name`, returns [`None`] if `name` contains any `\0` bytes.
+    fn new(name: &str) -> Option<SymName> {
+        match SmallCStr::new(&name) {
+            Some(name) => Some(SymName::Small(name)),
+            None => CString::new(name).ok().map(SymName::Large),
+        }
+    }
+
+    /// Return pointer to C string.
+    fn as_ptr(&self) -> *const libc::c_char {
+        match self {
+            SymName::Small(name) => name.as_ptr(),
+            SymName::Large(name) => name.as_ptr(),
+        }
+    }
+}
+
+/// Message string handed out by the LLVM API, which must be disposed by the receiver.
+struct Message<'llvm>(&'llvm mut libc::c_char);
+
+impl<'llvm> Message<'llvm> {
+    fn from(msg: *mut libc::c_char) -> Option<Message<'llvm>> {
+        (!msg.is_null()).then(|| Message(unsafe { &mut *msg }))
+    }
+
+    fn as_str(&self) -> &str {
+        unsafe { CStr::from_ptr(self.0) }
+            .to_str()
+            .expect("Expected valid UTF8 string from LLVM API")
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeMessage(self.0 as *mut libc::c_char);
+        }
+    }
+}
+
 /// Initialize native target for corresponding to host (useful for jitting).
 pub fn initialize_native_taget() {
     unsafe {
         assert_eq!(LLVM_InitializeNativeTarget(), 0);
         assert_eq!(LLVM_InitializeNativeAsmParser(), 0);
         assert_eq!(LLVM_InitializeNativeAsmPrinter(), 0);
+        assert_eq!(LLVM_InitializeNativeDisassembler(), 0);
+    }
+}
+
+/// Initialize all targets supported by LLVM (useful for cross compilation).
+pub fn initialize_all_targets() {
+    unsafe {
+        LLVM_InitializeAllTargetInfos();
+        LLVM_InitializeAllTargets();
+        LLVM_InitializeAllTargetMCs();
+        LLVM_InitializeAllAsmPrinters();
     }
 }
 
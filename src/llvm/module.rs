@@ -1,21 +1,28 @@
 use llvm_sys::{
+    analysis::{LLVMVerifierFailureAction, LLVMVerifyModule},
+    bit_reader::LLVMParseBitcodeInContext2,
+    bit_writer::LLVMWriteBitcodeToFile,
     core::{
         LLVMAddFunction, LLVMAppendBasicBlockInContext, LLVMCreateBasicBlockInContext,
-        LLVMDisposeModule, LLVMDoubleTypeInContext, LLVMDumpModule, LLVMGetNamedFunction,
-        LLVMModuleCreateWithNameInContext,
-    },
-    orc2::{
-        LLVMOrcCreateNewThreadSafeContext, LLVMOrcCreateNewThreadSafeModule,
-        LLVMOrcDisposeThreadSafeContext, LLVMOrcThreadSafeContextGetContext,
-        LLVMOrcThreadSafeContextRef, LLVMOrcThreadSafeModuleRef,
+        LLVMCreateMemoryBufferWithContentsOfFile, LLVMCreateMemoryBufferWithMemoryRangeCopy,
+        LLVMDisposeMemoryBuffer, LLVMDisposeModule, LLVMDoubleTypeInContext, LLVMDumpModule,
+        LLVMGetFirstFunction, LLVMGetNamedFunction, LLVMGetNextFunction, LLVMInt1TypeInContext,
+        LLVMInt32TypeInContext, LLVMModuleCreateWithNameInContext, LLVMPointerTypeInContext,
+        LLVMPrintModuleToFile, LLVMSetTarget,
     },
+    ir_reader::LLVMParseIRInContext,
+    linker::LLVMLinkModules2,
+    orc2::{LLVMOrcCreateNewThreadSafeModule, LLVMOrcThreadSafeModuleRef},
     prelude::{LLVMBool, LLVMContextRef, LLVMModuleRef, LLVMTypeRef},
+    target::{LLVMDisposeTargetData, LLVMSetModuleDataLayout},
+    target_machine::LLVMCreateTargetDataLayout,
     LLVMTypeKind,
 };
 
 use std::convert::TryFrom;
+use std::ffi::CString;
 
-use super::{BasicBlock, FnValue, Type};
+use super::{BasicBlock, Context, FnValue, Message, SymName, TargetMachine, Type};
 use crate::SmallCStr;
 
 // Definition of LLVM C API functions using our `repr(transparent)` types.
@@ -28,43 +35,120 @@ extern "C" {
     ) -> LLVMTypeRef;
 }
 
-/// Wrapper for a LLVM Module with its own LLVM Context.
+/// Wrapper for a LLVM Module, created in a LLVM [`Context`] which may be shared with other
+/// modules.
 pub struct Module {
-    tsctx: LLVMOrcThreadSafeContextRef,
-    ctx: LLVMContextRef,
+    ctx: Context,
     module: LLVMModuleRef,
+    types: Types,
+}
+
+/// Commonly used types, looked up once when creating the module.
+struct Types {
+    f64: LLVMTypeRef,
+    i1: LLVMTypeRef,
+    i32: LLVMTypeRef,
+    ptr: LLVMTypeRef,
 }
 
 impl<'llvm> Module {
-    /// Create a new Module instance.
+    /// Create a new Module instance with its own LLVM Context.
     ///
     /// # Panics
     ///
     /// Panics if creating the context or the module fails.
     pub fn new() -> Self {
-        let (tsctx, ctx, module) = unsafe {
-            // We generate a thread safe context because we are going to jit this IR module and
-            // there is no method to create a thread safe context wrapper from an existing context
-            // reference (at the time of writing this).
-            //
-            // ThreadSafeContext has shared ownership (start with ref count 1).
-            // We must explicitly dispose our reference (dec ref count).
-            let tc = LLVMOrcCreateNewThreadSafeContext();
-            assert!(!tc.is_null());
-
-            let c = LLVMOrcThreadSafeContextGetContext(tc);
-            let m = LLVMModuleCreateWithNameInContext(b"module\0".as_ptr().cast(), c);
-            assert!(!c.is_null() && !m.is_null());
-            (tc, c, m)
+        Self::with_ctx(&Context::new())
+    }
+
+    /// Create a new Module instance in the LLVM Context `ctx`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if creating the module fails.
+    pub fn with_ctx(ctx: &Context) -> Self {
+        let m =
+            unsafe { LLVMModuleCreateWithNameInContext(b"module\0".as_ptr().cast(), ctx.ctx()) };
+        assert!(!m.is_null());
+        Self::from_raw(ctx, m)
+    }
+
+    /// Read a Module from the bitcode file `path` into the LLVM Context `ctx`.
+    pub fn read_bitcode_file(ctx: &Context, path: &str) -> Result<Self, String> {
+        let cpath = CString::new(path).map_err(|_| format!("Invalid file name '{}'", path))?;
+
+        unsafe {
+            let mut buf = std::ptr::null_mut();
+            let mut msg = std::ptr::null_mut();
+            if LLVMCreateMemoryBufferWithContentsOfFile(cpath.as_ptr(), &mut buf, &mut msg) != 0 {
+                let msg = Message::from(msg);
+                let msg = msg.as_ref().map_or("unknown error", |m| m.as_str());
+                return Err(format!("Failed to read '{}': {}", path, msg));
+            }
+
+            // The module is parsed completely, it doesn't reference the buffer afterwards.
+            let mut m = std::ptr::null_mut();
+            let err = LLVMParseBitcodeInContext2(ctx.ctx(), buf, &mut m);
+            LLVMDisposeMemoryBuffer(buf);
+
+            if err != 0 || m.is_null() {
+                return Err(format!("Invalid bitcode in '{}'", path));
+            }
+            Ok(Self::from_raw(ctx, m))
+        }
+    }
+
+    /// Parse a Module from the textual LLVM IR `ir` into the LLVM Context `ctx`.
+    pub fn parse_ir(ctx: &Context, ir: &str) -> Result<Self, String> {
+        unsafe {
+            let buf = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+                ir.as_ptr().cast(),
+                ir.len(),
+                b"ir\0".as_ptr().cast(),
+            );
+
+            // The parser takes ownership of the buffer.
+            let mut m = std::ptr::null_mut();
+            let mut msg = std::ptr::null_mut();
+            if LLVMParseIRInContext(ctx.ctx(), buf, &mut m, &mut msg) != 0 || m.is_null() {
+                let msg = Message::from(msg);
+                let msg = msg.as_ref().map_or("unknown error", |m| m.as_str());
+                return Err(format!("Invalid IR: {}", msg));
+            }
+            Ok(Self::from_raw(ctx, m))
+        }
+    }
+
+    /// Wrap the raw LLVM module `m` created in the LLVM Context `ctx`, taking ownership of `m`.
+    fn from_raw(ctx: &Context, m: LLVMModuleRef) -> Self {
+        let types = unsafe {
+            let c = ctx.ctx();
+
+            // Types are owned by the context, the module only keeps their references.
+            Types {
+                f64: LLVMDoubleTypeInContext(c),
+                i1: LLVMInt1TypeInContext(c),
+                i32: LLVMInt32TypeInContext(c),
+                ptr: LLVMPointerTypeInContext(c, 0 /* AddressSpace */),
+            }
         };
 
-        Module { tsctx, ctx, module }
+        Module {
+            ctx: ctx.clone(),
+            module: m,
+            types,
+        }
+    }
+
+    /// Get the LLVM Context the module is created in.
+    pub fn context(&self) -> &Context {
+        &self.ctx
     }
 
     /// Get the raw LLVM context reference.
     #[inline]
     pub(super) fn ctx(&self) -> LLVMContextRef {
-        self.ctx
+        self.ctx.ctx()
     }
 
     /// Get the raw LLVM module reference.
@@ -84,27 +168,114 @@ impl<'llvm> Module {
         // ThreadSafeModule has unique ownership.
         // Takes ownership of module and increments ThreadSafeContext ref count.
         //
-        // We must not reference/dispose `m` after this call, but we need to dispose our `tsctx`
-        // reference.
-        let tm = unsafe { LLVMOrcCreateNewThreadSafeModule(m, self.tsctx) };
+        // We must not reference/dispose `m` after this call, our `ctx` reference is disposed
+        // separately.
+        let tm = unsafe { LLVMOrcCreateNewThreadSafeModule(m, self.ctx.tsctx()) };
         assert!(!tm.is_null());
 
         tm
     }
 
+    /// Link the module `other` into this module, consuming `other`.
+    ///
+    /// Both modules must be created in the same LLVM Context. Returns an error if the modules
+    /// can't be linked, eg because both define the same function.
+    pub fn link(&self, mut other: Module) -> Result<(), String> {
+        assert_eq!(
+            self.ctx(),
+            other.ctx(),
+            "Modules must share the LLVM Context!"
+        );
+
+        // Linking takes ownership of the source module, even on failure.
+        let src = std::mem::replace(&mut other.module, std::ptr::null_mut());
+        if unsafe { LLVMLinkModules2(self.module, src) } != 0 {
+            return Err("Failed to link modules".into());
+        }
+        Ok(())
+    }
+
+    /// Verify that the Module is valid, returning the messages of the verifier otherwise.
+    pub fn verify(&self) -> Result<(), String> {
+        let mut msg = std::ptr::null_mut();
+        let fail = unsafe {
+            LLVMVerifyModule(
+                self.module,
+                LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                &mut msg,
+            )
+        };
+
+        // The verifier always allocates a message, which is empty on success.
+        let msg = Message::from(msg);
+        if fail != 0 {
+            return Err(msg.map_or_else(|| "Invalid module".into(), |m| m.as_str().into()));
+        }
+        Ok(())
+    }
+
     /// Dump LLVM IR emitted into the Module to stdout.
     pub fn dump(&self) {
         unsafe { LLVMDumpModule(self.module) };
     }
 
+    /// Write the LLVM IR emitted into the Module in textual form to the file `path`.
+    pub fn print_to_file(&self, path: &str) -> Result<(), String> {
+        let path = CString::new(path).map_err(|_| format!("Invalid file name '{}'", path))?;
+
+        let mut err = std::ptr::null_mut();
+        let fail = unsafe { LLVMPrintModuleToFile(self.module, path.as_ptr(), &mut err) };
+
+        if fail != 0 {
+            let err = Message::from(err).expect("Expected error message from LLVM API");
+            return Err(err.as_str().into());
+        }
+        Ok(())
+    }
+
+    /// Write the LLVM IR emitted into the Module as bitcode to the file `path`.
+    pub fn write_bitcode_to_file(&self, path: &str) -> Result<(), String> {
+        let cpath = CString::new(path).map_err(|_| format!("Invalid file name '{}'", path))?;
+
+        if unsafe { LLVMWriteBitcodeToFile(self.module, cpath.as_ptr()) } != 0 {
+            return Err(format!("Failed to write bitcode to '{}'", path));
+        }
+        Ok(())
+    }
+
+    /// Configure the target triple and data layout of the Module for the target machine `tm`.
+    pub fn set_target(&self, tm: &TargetMachine) {
+        let triple = CString::new(tm.triple()).expect("Expected target triple without nul bytes");
+
+        unsafe {
+            LLVMSetTarget(self.module, triple.as_ptr());
+
+            // Module copies the data layout.
+            let dl = LLVMCreateTargetDataLayout(tm.tm());
+            assert!(!dl.is_null());
+            LLVMSetModuleDataLayout(self.module, dl);
+            LLVMDisposeTargetData(dl);
+        }
+    }
+
     /// Get a type reference representing a `f64` float.
-    ///
-    /// # Panics
-    ///
-    /// Panics if LLVM API returns a `null` pointer.
     pub fn type_f64(&self) -> Type<'llvm> {
-        let type_ref = unsafe { LLVMDoubleTypeInContext(self.ctx) };
-        Type::new(type_ref)
+        Type::new(self.types.f64)
+    }
+
+    /// Get a type reference representing a `i1` integer.
+    pub fn type_i1(&self) -> Type<'llvm> {
+        Type::new(self.types.i1)
+    }
+
+    /// Get a type reference representing an opaque pointer `ptr`.
+    pub fn type_ptr(&self) -> Type<'llvm> {
+        Type::new(self.types.ptr)
+    }
+
+    /// Get a type reference representing a `i32` integer.
+    pub fn type_i32(&self) -> Type<'llvm> {
+        Type::new(self.types.i32)
     }
 
     /// Get a type reference representing a `fn(args) -> ret` function.
@@ -129,8 +300,7 @@ impl<'llvm> Module {
     ///
     /// # Panics
     ///
-    /// Panics if LLVM API returns a `null` pointer or `name` could not be converted to a
-    /// [`SmallCStr`].
+    /// Panics if LLVM API returns a `null` pointer or `name` contains a `\0` byte.
     pub fn add_fn(&'llvm self, name: &str, fn_type: Type<'llvm>) -> FnValue<'llvm> {
         debug_assert_eq!(
             fn_type.kind(),
@@ -138,8 +308,7 @@ impl<'llvm> Module {
             "Expected a function type when adding a function!"
         );
 
-        let name = SmallCStr::try_from(name)
-            .expect("Failed to convert 'name' argument to small C string!");
+        let name = SymName::new(name).expect("Failed to convert 'name' argument to C string!");
 
         let value_ref = unsafe { LLVMAddFunction(self.module, name.as_ptr(), fn_type.type_ref()) };
         FnValue::new(value_ref)
@@ -150,44 +319,64 @@ impl<'llvm> Module {
     ///
     /// # Panics
     ///
-    /// Panics if `name` could not be converted to a [`SmallCStr`].
+    /// Panics if `name` contains a `\0` byte.
     pub fn get_fn(&'llvm self, name: &str) -> Option<FnValue<'llvm>> {
-        let name = SmallCStr::try_from(name)
-            .expect("Failed to convert 'name' argument to small C string!");
+        let name = SymName::new(name).expect("Failed to convert 'name' argument to C string!");
 
         let value_ref = unsafe { LLVMGetNamedFunction(self.module, name.as_ptr()) };
 
         (!value_ref.is_null()).then(|| FnValue::new(value_ref))
     }
 
-    /// Append a Basic Block to the end of the function referenced by the value reference
-    /// `fn_value`.
+    /// Get all functions of the module in order, definitions as well as declarations.
+    pub fn functions(&'llvm self) -> Vec<FnValue<'llvm>> {
+        let mut fns = Vec::new();
+        unsafe {
+            let mut value_ref = LLVMGetFirstFunction(self.module);
+            while !value_ref.is_null() {
+                fns.push(FnValue::new(value_ref));
+                value_ref = LLVMGetNextFunction(value_ref);
+            }
+        }
+        fns
+    }
+
+    /// Append a Basic Block with the given `name` to the end of the function referenced by the
+    /// value reference `fn_value`.
     ///
     /// # Panics
     ///
-    /// Panics if LLVM API returns a `null` pointer.
-    pub fn append_basic_block(&'llvm self, fn_value: FnValue<'llvm>) -> BasicBlock<'llvm> {
+    /// Panics if LLVM API returns a `null` pointer or `name` could not be converted to a
+    /// [`SmallCStr`].
+    pub fn append_basic_block(
+        &'llvm self,
+        fn_value: FnValue<'llvm>,
+        name: &str,
+    ) -> BasicBlock<'llvm> {
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to small C string!");
+
         let block = unsafe {
-            LLVMAppendBasicBlockInContext(
-                self.ctx,
-                fn_value.value_ref(),
-                b"block\0".as_ptr().cast(),
-            )
+            LLVMAppendBasicBlockInContext(self.ctx(), fn_value.value_ref(), name.as_ptr())
         };
         assert!(!block.is_null());
 
         BasicBlock::new(block)
     }
 
-    /// Create a free-standing Basic Block without adding it to a function.
+    /// Create a free-standing Basic Block with the given `name` without adding it to a function.
     /// This can be added to a function at a later point in time with
     /// [`FnValue::append_basic_block`].
     ///
     /// # Panics
     ///
-    /// Panics if LLVM API returns a `null` pointer.
-    pub fn create_basic_block(&self) -> BasicBlock<'llvm> {
-        let block = unsafe { LLVMCreateBasicBlockInContext(self.ctx, b"block\0".as_ptr().cast()) };
+    /// Panics if LLVM API returns a `null` pointer or `name` could not be converted to a
+    /// [`SmallCStr`].
+    pub fn create_basic_block(&self, name: &str) -> BasicBlock<'llvm> {
+        let name = SmallCStr::try_from(name)
+            .expect("Failed to convert 'name' argument to small C string!");
+
+        let block = unsafe { LLVMCreateBasicBlockInContext(self.ctx(), name.as_ptr()) };
         assert!(!block.is_null());
 
         BasicBlock::new(block)
@@ -202,9 +391,8 @@ impl Drop for Module {
             if !self.module.is_null() {
                 LLVMDisposeModule(self.module);
             }
-
-            // Dispose ThreadSafeContext reference (dec ref count) in any case.
-            LLVMOrcDisposeThreadSafeContext(self.tsctx);
         }
+
+        // The `ctx` reference is disposed when dropping the field, after the module.
     }
 }
@@ -6,13 +6,17 @@ use llvm_sys::{
     prelude::LLVMPassManagerRef,
     transforms::{
         instcombine::LLVMAddInstructionCombiningPass,
-        scalar::{LLVMAddCFGSimplificationPass, LLVMAddNewGVNPass, LLVMAddReassociatePass},
+        scalar::{
+            LLVMAddAggressiveDCEPass, LLVMAddCFGSimplificationPass, LLVMAddNewGVNPass,
+            LLVMAddReassociatePass, LLVMAddTailCallEliminationPass,
+        },
+        util::LLVMAddPromoteMemoryToRegisterPass,
     },
 };
 
 use std::marker::PhantomData;
 
-use super::{FnValue, Module};
+use super::{FnValue, Module, OptLevel};
 
 /// Wrapper for a LLVM Function PassManager (legacy).
 pub struct FunctionPassManager<'llvm> {
@@ -21,15 +25,15 @@ pub struct FunctionPassManager<'llvm> {
 }
 
 impl<'llvm> FunctionPassManager<'llvm> {
-    /// Create a new Function PassManager with the following optimization passes
-    /// - InstructionCombiningPass
-    /// - ReassociatePass
-    /// - NewGVNPass
-    /// - CFGSimplificationPass
+    /// Create a new Function PassManager with the optimization passes for `opt_level`
+    /// - O0: PromoteMemoryToRegisterPass
+    /// - O1: The O0 pass followed by InstructionCombiningPass, CFGSimplificationPass
+    /// - O2: InstructionCombiningPass, ReassociatePass, NewGVNPass, CFGSimplificationPass
+    /// - O3: The O2 passes followed by TailCallEliminationPass, AggressiveDCEPass
     ///
-    /// The list of selected optimization passes is taken from the tutorial chapter [LLVM
+    /// The list of O2 optimization passes is taken from the tutorial chapter [LLVM
     /// Optimization Passes](https://llvm.org/docs/tutorial/MyFirstLanguageFrontend/LangImpl04.html#id3).
-    pub fn with_ctx(module: &'llvm Module) -> FunctionPassManager<'llvm> {
+    pub fn with_ctx(module: &'llvm Module, opt_level: OptLevel) -> FunctionPassManager<'llvm> {
         let fpm = unsafe {
             // Borrows module reference.
             LLVMCreateFunctionPassManagerForModule(module.module())
@@ -37,14 +41,29 @@ impl<'llvm> FunctionPassManager<'llvm> {
         assert!(!fpm.is_null());
 
         unsafe {
-            // Do simple "peephole" optimizations and bit-twiddling optzns.
-            LLVMAddInstructionCombiningPass(fpm);
-            // Reassociate expressions.
-            LLVMAddReassociatePass(fpm);
-            // Eliminate Common SubExpressions.
-            LLVMAddNewGVNPass(fpm);
-            // Simplify the control flow graph (deleting unreachable blocks, etc).
-            LLVMAddCFGSimplificationPass(fpm);
+            // Promote allocas to registers, codegen keeps all variables in stack slots. This runs
+            // at every level, such that the IR is in SSA form even without optimizations.
+            LLVMAddPromoteMemoryToRegisterPass(fpm);
+            if opt_level >= OptLevel::O1 {
+                // Do simple "peephole" optimizations and bit-twiddling optzns.
+                LLVMAddInstructionCombiningPass(fpm);
+            }
+            if opt_level >= OptLevel::O2 {
+                // Reassociate expressions.
+                LLVMAddReassociatePass(fpm);
+                // Eliminate Common SubExpressions.
+                LLVMAddNewGVNPass(fpm);
+            }
+            if opt_level >= OptLevel::O1 {
+                // Simplify the control flow graph (deleting unreachable blocks, etc).
+                LLVMAddCFGSimplificationPass(fpm);
+            }
+            if opt_level >= OptLevel::O3 {
+                // Turn self-recursive tail calls into loops.
+                LLVMAddTailCallEliminationPass(fpm);
+                // Remove code without side effects, assuming it is dead until proven otherwise.
+                LLVMAddAggressiveDCEPass(fpm);
+            }
 
             let fail = LLVMInitializeFunctionPassManager(fpm);
             assert_eq!(fail, 0);
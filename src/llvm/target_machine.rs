@@ -0,0 +1,170 @@
+use llvm_sys::target_machine::{
+    LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetMachine,
+    LLVMDisposeTargetMachine, LLVMGetDefaultTargetTriple, LLVMGetHostCPUFeatures,
+    LLVMGetHostCPUName, LLVMGetTargetFromTriple, LLVMGetTargetMachineTriple, LLVMRelocMode,
+    LLVMTargetMachineEmitToFile, LLVMTargetMachineRef, LLVMTargetRef,
+};
+
+use std::ffi::CString;
+
+use super::{Message, Module, OptLevel};
+
+/// Kind of the file emitted by [`TargetMachine::emit_to_file`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// Textual assembly file.
+    Asm,
+    /// Relocatable object file.
+    Obj,
+}
+
+/// Wrapper for a LLVM TargetMachine, which generates machine code for a specific target.
+pub struct TargetMachine {
+    tm: LLVMTargetMachineRef,
+}
+
+impl TargetMachine {
+    /// Create a TargetMachine for the host, using the host CPU and its features, generating code
+    /// with the optimization level `opt_level`.
+    ///
+    /// The native target must be initialized before with
+    /// [`initialize_native_taget`](super::initialize_native_taget).
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn host(opt_level: OptLevel) -> Result<TargetMachine, String> {
+        let triple = Message::from(unsafe { LLVMGetDefaultTargetTriple() })
+            .expect("Expected default target triple from LLVM API");
+        let cpu = Message::from(unsafe { LLVMGetHostCPUName() })
+            .expect("Expected host cpu name from LLVM API");
+        let features = Message::from(unsafe { LLVMGetHostCPUFeatures() })
+            .expect("Expected host cpu features from LLVM API");
+
+        Self::create(&*triple.0, &*cpu.0, &*features.0, opt_level)
+    }
+
+    /// Create a TargetMachine for the target `triple`, eg `aarch64-linux-gnu`, generating code
+    /// for the CPU `cpu` with the additional `features`, eg `+avx2,-sse4.1`, with the optimization
+    /// level `opt_level`. An empty `cpu` selects a generic CPU of the target.
+    ///
+    /// Returns an error if the triple is invalid or the target is not registered. Only the
+    /// native target is registered by
+    /// [`initialize_native_taget`](super::initialize_native_taget), other targets are registered
+    /// by [`initialize_all_targets`](super::initialize_all_targets).
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn new(
+        triple: &str,
+        cpu: &str,
+        features: &str,
+        opt_level: OptLevel,
+    ) -> Result<TargetMachine, String> {
+        let cstr =
+            |s: &str, what: &str| CString::new(s).map_err(|_| format!("Invalid {} '{}'", what, s));
+        let triple = cstr(triple, "target triple")?;
+        let cpu = cstr(cpu, "cpu")?;
+        let features = cstr(features, "cpu features")?;
+
+        Self::create(triple.as_ptr(), cpu.as_ptr(), features.as_ptr(), opt_level)
+    }
+
+    fn create(
+        triple: *const libc::c_char,
+        cpu: *const libc::c_char,
+        features: *const libc::c_char,
+        opt_level: OptLevel,
+    ) -> Result<TargetMachine, String> {
+        let mut target: LLVMTargetRef = std::ptr::null_mut();
+        let mut err = std::ptr::null_mut();
+        let fail = unsafe { LLVMGetTargetFromTriple(triple, &mut target, &mut err) };
+        if fail != 0 {
+            let err = Message::from(err).expect("Expected error message from LLVM API");
+            return Err(err.as_str().into());
+        }
+
+        let tm = unsafe {
+            LLVMCreateTargetMachine(
+                target,
+                triple,
+                cpu,
+                features,
+                match opt_level {
+                    OptLevel::O0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+                    OptLevel::O1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+                    OptLevel::O2 => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                    OptLevel::O3 => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+                },
+                // Position independent code, such that objects can be linked into PIE executables.
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            )
+        };
+        assert!(!tm.is_null());
+
+        Ok(TargetMachine { tm })
+    }
+
+    /// Get the raw LLVM target machine reference.
+    #[inline]
+    pub(super) fn tm(&self) -> LLVMTargetMachineRef {
+        self.tm
+    }
+
+    /// Release ownership of the raw LLVM target machine reference, the receiver is responsible
+    /// for disposing it.
+    pub(super) fn into_raw(self) -> LLVMTargetMachineRef {
+        let tm = self.tm;
+        std::mem::forget(self);
+        tm
+    }
+
+    /// Get the target triple of the TargetMachine, eg `x86_64-pc-linux-gnu`.
+    pub fn triple(&self) -> String {
+        let triple = Message::from(unsafe { LLVMGetTargetMachineTriple(self.tm) })
+            .expect("Expected target triple from LLVM API");
+        triple.as_str().into()
+    }
+
+    /// Generate machine code for `module` and write it to the file `path` as `file_type`.
+    ///
+    /// The module should be configured for this target with [`Module::set_target`] before any
+    /// code is emitted into it.
+    pub fn emit_to_file(
+        &self,
+        module: &Module,
+        path: &str,
+        file_type: FileType,
+    ) -> Result<(), String> {
+        let path = CString::new(path).map_err(|_| format!("Invalid file name '{}'", path))?;
+        let file_type = match file_type {
+            FileType::Asm => LLVMCodeGenFileType::LLVMAssemblyFile,
+            FileType::Obj => LLVMCodeGenFileType::LLVMObjectFile,
+        };
+
+        let mut err = std::ptr::null_mut();
+        let fail = unsafe {
+            LLVMTargetMachineEmitToFile(
+                self.tm,
+                module.module(),
+                path.as_ptr(),
+                file_type,
+                &mut err,
+            )
+        };
+
+        if fail != 0 {
+            let err = Message::from(err).expect("Expected error message from LLVM API");
+            return Err(err.as_str().into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeTargetMachine(self.tm) };
+    }
+}
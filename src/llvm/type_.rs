@@ -1,5 +1,8 @@
 use llvm_sys::{
-    core::{LLVMConstReal, LLVMDumpType, LLVMGetTypeKind},
+    core::{
+        LLVMConstInt, LLVMConstIntToPtr, LLVMConstReal, LLVMDumpType, LLVMGetTypeContext,
+        LLVMGetTypeKind, LLVMInt64TypeInContext,
+    },
     prelude::LLVMTypeRef,
     LLVMTypeKind,
 };
@@ -55,4 +58,42 @@ impl<'llvm> Type<'llvm> {
         let value_ref = unsafe { LLVMConstReal(self.type_ref(), n) };
         Value::new(value_ref)
     }
+
+    /// Get a value reference representing the const `i32` value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn const_i32(self, n: i32) -> Value<'llvm> {
+        debug_assert_eq!(
+            self.kind(),
+            LLVMTypeKind::LLVMIntegerTypeKind,
+            "Expected an integer type when creating const i32 value!"
+        );
+
+        let value_ref = unsafe {
+            LLVMConstInt(self.type_ref(), n as u64, 1 /* SignExtend */)
+        };
+        Value::new(value_ref)
+    }
+
+    /// Get a value reference representing the const pointer to the address `addr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM API returns a `null` pointer.
+    pub fn const_ptr(self, addr: usize) -> Value<'llvm> {
+        debug_assert_eq!(
+            self.kind(),
+            LLVMTypeKind::LLVMPointerTypeKind,
+            "Expected a pointer type when creating const pointer value!"
+        );
+
+        let value_ref = unsafe {
+            let i64_type = LLVMInt64TypeInContext(LLVMGetTypeContext(self.type_ref()));
+            let addr = LLVMConstInt(i64_type, addr as u64, 0 /* SignExtend */);
+            LLVMConstIntToPtr(addr, self.type_ref())
+        };
+        Value::new(value_ref)
+    }
 }
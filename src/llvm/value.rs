@@ -4,8 +4,10 @@ use llvm_sys::{
     analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction},
     core::{
         LLVMAddIncoming, LLVMAppendExistingBasicBlock, LLVMCountBasicBlocks, LLVMCountParams,
-        LLVMDumpValue, LLVMGetParam, LLVMGetValueKind, LLVMGetValueName2, LLVMGlobalGetValueType,
-        LLVMIsAFunction, LLVMIsAPHINode, LLVMSetValueName2, LLVMTypeOf,
+        LLVMDumpValue, LLVMGetEntryBasicBlock, LLVMGetFirstBasicBlock, LLVMGetFirstInstruction,
+        LLVMGetNextBasicBlock, LLVMGetNextInstruction, LLVMGetParam, LLVMGetValueKind,
+        LLVMGetValueName2, LLVMGlobalGetValueType, LLVMIsAFunction, LLVMIsAPHINode,
+        LLVMPrintValueToString, LLVMSetValueName2, LLVMTypeOf,
     },
     prelude::LLVMValueRef,
     LLVMTypeKind, LLVMValueKind,
@@ -15,6 +17,7 @@ use std::marker::PhantomData;
 use std::ops::Deref;
 
 use super::BasicBlock;
+use super::Message;
 use super::Type;
 
 /// Wrapper for a LLVM Value Reference.
@@ -33,6 +36,17 @@ impl<'llvm> Value<'llvm> {
         Value(value_ref, PhantomData)
     }
 
+    /// Create a new Value instance and set its name to `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value_ref` is a null pointer.
+    pub(super) fn new_with_name(value_ref: LLVMValueRef, name: &str) -> Self {
+        let value = Value::new(value_ref);
+        value.set_name(name);
+        value
+    }
+
     /// Get the raw LLVM value reference.
     #[inline]
     pub(super) fn value_ref(&self) -> LLVMValueRef {
@@ -61,6 +75,13 @@ impl<'llvm> Value<'llvm> {
         unsafe { LLVMDumpValue(self.value_ref()) };
     }
 
+    /// Print the LLVM Value in textual IR form into a String.
+    pub fn print_to_string(&self) -> String {
+        let msg = Message::from(unsafe { LLVMPrintValueToString(self.value_ref()) })
+            .expect("Expected value string from LLVM API");
+        msg.as_str().into()
+    }
+
     /// Get a type reference representing for the given value reference.
     ///
     /// # Panics
@@ -172,6 +193,35 @@ impl<'llvm> FnValue<'llvm> {
         unsafe { LLVMCountBasicBlocks(self.value_ref()) as usize }
     }
 
+    /// Get the number of instructions in all Basic Blocks of the given function value.
+    pub fn instructions(&self) -> usize {
+        let mut count = 0;
+        unsafe {
+            let mut bb = LLVMGetFirstBasicBlock(self.value_ref());
+            while !bb.is_null() {
+                let mut inst = LLVMGetFirstInstruction(bb);
+                while !inst.is_null() {
+                    count += 1;
+                    inst = LLVMGetNextInstruction(inst);
+                }
+                bb = LLVMGetNextBasicBlock(bb);
+            }
+        }
+        count
+    }
+
+    /// Get the entry Basic Block of the function value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the function has no Basic Blocks.
+    pub fn entry_basic_block(&self) -> BasicBlock<'llvm> {
+        assert!(self.basic_blocks() > 0);
+
+        let bb_ref = unsafe { LLVMGetEntryBasicBlock(self.value_ref()) };
+        BasicBlock::new(bb_ref)
+    }
+
     /// Append a Basic Block to the end of the function value.
     pub fn append_basic_block(&self, bb: BasicBlock<'llvm>) {
         unsafe {
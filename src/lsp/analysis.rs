@@ -0,0 +1,230 @@
+use crate::backend::Consts;
+use crate::codegen::{FnProto, FnProtos, ProtoOrigin};
+use crate::lexer::{Lexer, Span, Token};
+use crate::parser::{Item, Parser, PrototypeAST, ANON_EXPR};
+use crate::typeck;
+
+use std::rc::Rc;
+
+/// Keywords offered for completion.
+const KEYWORDS: &[&str] = &[
+    "def", "extern", "const", "if", "then", "else", "for", "in", "var", "match", "with", "break",
+    "continue",
+];
+
+/// Function defined or declared in a document.
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    pub proto: PrototypeAST,
+    pub origin: ProtoOrigin,
+    /// Span of the function name in its prototype.
+    pub name_span: Span,
+}
+
+/// Error found in a document.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub msg: String,
+}
+
+/// Result of analyzing a kaleidoscope document, all offsets are char offsets into the document.
+pub struct Analysis {
+    tokens: Vec<(Token, Span)>,
+    /// Functions in the order they are defined, a function may be defined multiple times.
+    pub symbols: Vec<Symbol>,
+    /// Syntax and type errors in the order they are found.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    /// Parse and type check all items of the document `src`.
+    pub fn new(src: &str) -> Analysis {
+        let tokens: Vec<_> = Lexer::new(src.chars()).collect();
+
+        let mut parser = Parser::new(Lexer::new(src.chars()));
+        parser.get_next_token();
+
+        let mut symbols = Vec::new();
+        let mut diagnostics = Vec::new();
+        let mut fn_protos = FnProtos::new();
+        let mut consts = Consts::default();
+
+        loop {
+            let item_start = parser.cur_span().start;
+            let item = match parser.cur_tok() {
+                Token::Eof => break,
+                Token::Delim(';') => {
+                    parser.get_next_token();
+                    continue;
+                }
+                Token::Def => parser.parse_definition().map(Item::Def),
+                Token::Extern => parser.parse_extern().map(Item::Extern),
+                Token::Const => parser.parse_const().map(Item::Const),
+                _ => parser.parse_top_level_expr().map(Item::TopLevel),
+            };
+            let span = Span::new(item_start, parser.cur_span().start);
+
+            let mut func = match item {
+                Ok(Item::Def(func)) | Ok(Item::TopLevel(func)) => func.node,
+                Ok(Item::Extern(proto)) => {
+                    let symbol = Symbol::new(proto.node, ProtoOrigin::Extern, &tokens, item_start);
+                    symbols.push(symbol.record(&mut fn_protos));
+                    continue;
+                }
+                Ok(Item::Const(c)) => {
+                    if let Err(err) = consts.define(&c) {
+                        diagnostics.push(Diagnostic { span, msg: err.msg });
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    let err = parser.recover(err);
+                    diagnostics.push(Diagnostic {
+                        span: err.span,
+                        msg: err.msg.clone(),
+                    });
+                    continue;
+                }
+            };
+
+            // Constants are substituted before type checking, like in the backends.
+            consts.bind(&mut func);
+            if let Err(msg) = typeck::check_function(&func, &fn_protos) {
+                diagnostics.push(Diagnostic { span, msg });
+            }
+            if func.0 .0 != ANON_EXPR {
+                let symbol = Symbol::new(func.0.node, ProtoOrigin::Def, &tokens, item_start);
+                symbols.push(symbol.record(&mut fn_protos));
+            }
+        }
+
+        Analysis {
+            tokens,
+            symbols,
+            diagnostics,
+        }
+    }
+
+    /// Name of the identifier at `offset`, the cursor may also be right after the identifier.
+    fn identifier_at(&self, offset: usize) -> Option<(&str, Span)> {
+        self.tokens.iter().find_map(|(tok, span)| match tok {
+            Token::Identifier(name) if span.start <= offset && offset <= span.end => {
+                Some((name.as_str(), *span))
+            }
+            _ => None,
+        })
+    }
+
+    /// Last definition of the function `name`.
+    fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().rev().find(|s| s.proto.0 == name)
+    }
+
+    /// Span of the name of the function referenced at `offset`.
+    pub fn definition(&self, offset: usize) -> Option<Span> {
+        let (name, _) = self.identifier_at(offset)?;
+        self.symbol(name).map(|s| s.name_span)
+    }
+
+    /// Prototype of the function referenced at `offset`.
+    pub fn hover(&self, offset: usize) -> Option<String> {
+        let (name, _) = self.identifier_at(offset)?;
+        let symbol = self.symbol(name)?;
+        let kw = match symbol.origin {
+            ProtoOrigin::Def => "def",
+            ProtoOrigin::Extern => "extern",
+        };
+        Some(format!("{} {}", kw, symbol.proto))
+    }
+
+    /// Keywords and functions starting with the identifier the cursor at `offset` is placed
+    /// behind, sorted by name.
+    pub fn completions(&self, offset: usize) -> Vec<String> {
+        let prefix = match self.identifier_at(offset) {
+            Some((name, span)) if span.end == offset => name,
+            _ => "",
+        };
+
+        let mut names: Vec<String> = KEYWORDS
+            .iter()
+            .copied()
+            .chain(self.symbols.iter().map(|s| s.proto.0.as_str()))
+            .filter(|name| name.starts_with(prefix) && *name != prefix)
+            .map(String::from)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Symbol {
+    /// Create the symbol for the prototype `proto` of the item starting at `item_start`.
+    fn new(
+        proto: PrototypeAST,
+        origin: ProtoOrigin,
+        tokens: &[(Token, Span)],
+        item_start: usize,
+    ) -> Symbol {
+        // The name is the first identifier of the item.
+        let name_span = tokens
+            .iter()
+            .find(|(tok, span)| span.start >= item_start && matches!(tok, Token::Identifier(_)))
+            .map_or(Span::new(item_start, item_start), |(_, span)| *span);
+
+        Symbol {
+            proto,
+            origin,
+            name_span,
+        }
+    }
+
+    /// Record the prototype of the symbol in `fn_protos` for type checking the following items.
+    fn record(self, fn_protos: &mut FnProtos) -> Symbol {
+        let fn_proto = FnProto {
+            proto: Rc::new(self.proto.clone()),
+            origin: self.origin,
+        };
+        fn_protos.insert(self.proto.0.clone(), fn_proto);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Analysis;
+    use crate::lexer::Span;
+
+    const SRC: &str = "extern sin(x);\ndef foo(a b) sin(a) + b;\nfoo(1, 2) + bar(3);\nfo";
+
+    #[test]
+    fn analyze_document() {
+        let analysis = Analysis::new(SRC);
+        assert_eq!(analysis.symbols.len(), 2);
+        assert_eq!(analysis.symbols[1].name_span, Span::new(19, 22));
+
+        let msgs: Vec<_> = analysis.diagnostics.iter().map(|d| &d.msg).collect();
+        assert_eq!(
+            msgs,
+            vec!["unknown function 'bar'", "unknown variable 'fo'"]
+        );
+    }
+
+    #[test]
+    fn navigate_document() {
+        let analysis = Analysis::new(SRC);
+        let call = SRC.find("foo(1").unwrap();
+
+        assert_eq!(analysis.definition(call + 1), Some(Span::new(19, 22)));
+        assert_eq!(analysis.hover(call), Some("def foo(a, b)".into()));
+        assert_eq!(
+            analysis.hover(SRC.find("sin(a)").unwrap()),
+            Some("extern sin(x)".into())
+        );
+        assert_eq!(analysis.definition(0), None);
+
+        assert_eq!(analysis.completions(SRC.len()), vec!["foo", "for"]);
+        assert!(analysis.completions(0).contains(&"extern".to_string()));
+    }
+}
@@ -0,0 +1,310 @@
+//! Minimal JSON value with parser and serializer, sufficient for the messages of the language
+//! server.
+
+use std::fmt;
+
+/// JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    /// Object with its members in order.
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Create an object from the `members`.
+    pub fn obj<const N: usize>(members: [(&str, Json); N]) -> Json {
+        Json::Obj(
+            IntoIterator::into_iter(members)
+                .map(|(k, v)| (k.into(), v))
+                .collect(),
+        )
+    }
+
+    /// Get the member `key` of an object, `Null` if it doesn't exist or this is not an object.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Obj(members) => members
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or(&Json::Null, |(_, v)| v),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Num(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_arr(&self) -> Option<&[Json]> {
+        match self {
+            Json::Arr(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Parse the JSON text `src`.
+    pub fn parse(src: &str) -> Result<Json, String> {
+        let mut p = JsonParser {
+            src: src.as_bytes(),
+            pos: 0,
+        };
+        let value = p.value()?;
+        p.ws();
+        if p.pos != p.src.len() {
+            return Err(format!("unexpected trailing data at {}", p.pos));
+        }
+        Ok(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::Str(s.into())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::Str(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(s) => write_str(f, s),
+            Json::Arr(a) => {
+                f.write_str("[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                f.write_str("]")
+            }
+            Json::Obj(members) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// Write `s` as quoted and escaped JSON string.
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct JsonParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn ws(&mut self) {
+        while matches!(self.src.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, lit: &str) -> bool {
+        if self.src[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.ws();
+        match self.src.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.ws();
+                if self.eat("}") {
+                    return Ok(Json::Obj(members));
+                }
+                loop {
+                    self.ws();
+                    let key = self.string()?;
+                    self.ws();
+                    if !self.eat(":") {
+                        return Err(format!("expected ':' at {}", self.pos));
+                    }
+                    members.push((key, self.value()?));
+                    self.ws();
+                    if self.eat("}") {
+                        return Ok(Json::Obj(members));
+                    }
+                    if !self.eat(",") {
+                        return Err(format!("expected ',' or '}}' at {}", self.pos));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.ws();
+                if self.eat("]") {
+                    return Ok(Json::Arr(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.ws();
+                    if self.eat("]") {
+                        return Ok(Json::Arr(values));
+                    }
+                    if !self.eat(",") {
+                        return Err(format!("expected ',' or ']' at {}", self.pos));
+                    }
+                }
+            }
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b't') if self.eat("true") => Ok(Json::Bool(true)),
+            Some(b'f') if self.eat("false") => Ok(Json::Bool(false)),
+            Some(b'n') if self.eat("null") => Ok(Json::Null),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while matches!(
+                    self.src.get(self.pos),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.pos += 1;
+                }
+                let num = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                num.parse()
+                    .map(Json::Num)
+                    .map_err(|_| format!("invalid number '{}'", num))
+            }
+            _ => Err(format!("unexpected input at {}", self.pos)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if !self.eat("\"") {
+            return Err(format!("expected string at {}", self.pos));
+        }
+
+        let mut buf = Vec::new();
+        loop {
+            let b = *self.src.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let esc = *self.src.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    let c = match esc {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => self.unicode_escape()?,
+                        c => c as char,
+                    };
+                    buf.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                b => buf.push(b),
+            }
+        }
+        String::from_utf8(buf).map_err(|_| "invalid UTF-8 in string".into())
+    }
+
+    /// Decode the `\uXXXX` escape after the `u`, including surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let hex = |p: &mut Self| -> Result<u32, String> {
+            let digits = p.src.get(p.pos..p.pos + 4).ok_or("truncated \\u escape")?;
+            p.pos += 4;
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|d| u32::from_str_radix(d, 16).ok())
+                .ok_or_else(|| "invalid \\u escape".into())
+        };
+
+        let hi = hex(self)?;
+        let code = if (0xd800..0xdc00).contains(&hi) && self.eat("\\u") {
+            let lo = hex(self)?;
+            0x10000 + ((hi - 0xd800) << 10) + (lo.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            hi
+        };
+        Ok(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Json;
+
+    #[test]
+    fn roundtrip() {
+        let src = r#"{"id":1,"params":{"text":"def f(x)\n\"x\"","list":[true,null,-2.5]}}"#;
+        let json = Json::parse(src).unwrap();
+        assert_eq!(json.get("id").as_f64(), Some(1.0));
+        assert_eq!(
+            json.get("params").get("text").as_str(),
+            Some("def f(x)\n\"x\"")
+        );
+        assert_eq!(json.to_string(), src);
+
+        assert_eq!(
+            Json::parse(r#""\u00e4\ud83d\ude00""#),
+            Ok(Json::Str("ä😀".into()))
+        );
+        assert!(Json::parse("{\"a\" 1}").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Language server for kaleidoscope documents, speaking the
+//! [Language Server Protocol](https://microsoft.github.io/language-server-protocol/) over
+//! stdin/stdout.
+//!
+//! The server keeps the open documents in memory and analyzes them on every change, see
+//! [`Analysis`]. It publishes syntax and type errors as diagnostics and answers requests for the
+//! definition of a function, hovers with the prototype of a function and completions of keywords
+//! and defined functions. This module is only available with the `lsp` feature.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+mod analysis;
+mod json;
+
+pub use analysis::{Analysis, Diagnostic, Symbol};
+pub use json::Json;
+
+use crate::lexer::Span;
+
+/// Convert the char `offset` into `src` into an LSP position, zero based line and UTF-16 column.
+fn position(src: &str, offset: usize) -> Json {
+    let (mut line, mut character) = (0, 0);
+    for c in src.chars().take(offset) {
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16();
+        }
+    }
+    Json::obj([("line", line.into()), ("character", character.into())])
+}
+
+/// Convert the LSP position `pos` in `src` into a char offset.
+fn offset(src: &str, pos: &Json) -> usize {
+    let line = pos.get("line").as_f64().unwrap_or(0.0) as usize;
+    let character = pos.get("character").as_f64().unwrap_or(0.0) as usize;
+
+    let mut offset = 0;
+    let mut chars = src.chars().peekable();
+    for _ in 0..line {
+        for c in chars.by_ref() {
+            offset += 1;
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    let mut col = 0;
+    while let Some(c) = chars.next_if(|&c| c != '\n' && col < character) {
+        col += c.len_utf16();
+        offset += 1;
+    }
+    offset
+}
+
+/// Convert `span` in `src` into an LSP range.
+fn range(src: &str, span: Span) -> Json {
+    Json::obj([
+        ("start", position(src, span.start)),
+        ("end", position(src, span.end)),
+    ])
+}
+
+/// Language server state.
+#[derive(Default)]
+struct Server {
+    /// Text of the open documents by URI.
+    docs: HashMap<String, String>,
+}
+
+impl Server {
+    /// Handle the request or notification `msg`, return the response and notifications to send.
+    fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg.get("method").as_str().unwrap_or("");
+        let params = msg.get("params");
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+
+        let result = match method {
+            "initialize" => Json::obj([(
+                "capabilities",
+                Json::obj([
+                    ("textDocumentSync", 1.into()),
+                    ("hoverProvider", true.into()),
+                    ("definitionProvider", true.into()),
+                    ("completionProvider", Json::obj([])),
+                ]),
+            )]),
+            "shutdown" => Json::Null,
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = match method {
+                    "textDocument/didOpen" => params.get("textDocument").get("text"),
+                    // Full document sync, the last change contains the whole text.
+                    _ => params
+                        .get("contentChanges")
+                        .as_arr()
+                        .and_then(|c| c.last())
+                        .map_or(&Json::Null, |c| c.get("text")),
+                };
+                if let Some(text) = text.as_str() {
+                    self.docs.insert(uri.into(), text.into());
+                }
+                return self.publish_diagnostics(uri).into_iter().collect();
+            }
+            "textDocument/didClose" => {
+                self.docs.remove(uri);
+                return Vec::new();
+            }
+            "textDocument/definition" | "textDocument/hover" | "textDocument/completion" => {
+                let src = self.docs.get(uri).map_or("", String::as_str);
+                let analysis = Analysis::new(src);
+                let offset = offset(src, params.get("position"));
+
+                match method {
+                    "textDocument/definition" => {
+                        analysis.definition(offset).map_or(Json::Null, |span| {
+                            Json::obj([("uri", uri.into()), ("range", range(src, span))])
+                        })
+                    }
+                    "textDocument/hover" => analysis
+                        .hover(offset)
+                        .map_or(Json::Null, |proto| Json::obj([("contents", proto.into())])),
+                    _ => Json::Arr(
+                        analysis
+                            .completions(offset)
+                            .into_iter()
+                            .map(|name| Json::obj([("label", name.into())]))
+                            .collect(),
+                    ),
+                }
+            }
+            _ => {
+                // Notifications without id are ignored, unknown requests answered with an error.
+                if *msg.get("id") == Json::Null {
+                    return Vec::new();
+                }
+                return vec![Json::obj([
+                    ("jsonrpc", "2.0".into()),
+                    ("id", msg.get("id").clone()),
+                    (
+                        "error",
+                        Json::obj([
+                            ("code", Json::Num(-32601.0)),
+                            ("message", format!("Unknown method '{}'", method).into()),
+                        ]),
+                    ),
+                ])];
+            }
+        };
+
+        vec![Json::obj([
+            ("jsonrpc", "2.0".into()),
+            ("id", msg.get("id").clone()),
+            ("result", result),
+        ])]
+    }
+
+    /// Notification with the diagnostics of the document `uri`.
+    fn publish_diagnostics(&self, uri: &str) -> Option<Json> {
+        let src = self.docs.get(uri)?;
+        let diagnostics = Analysis::new(src)
+            .diagnostics
+            .into_iter()
+            .map(|d| {
+                Json::obj([
+                    ("range", range(src, d.span)),
+                    ("severity", 1.into()),
+                    ("source", "kaleidoscope".into()),
+                    ("message", d.msg.into()),
+                ])
+            })
+            .collect();
+
+        Some(Json::obj([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                Json::obj([("uri", uri.into()), ("diagnostics", Json::Arr(diagnostics))]),
+            ),
+        ]))
+    }
+}
+
+/// Read the next message framed with a `Content-Length` header from `input`.
+///
+/// Return `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = value.trim().parse().ok();
+        }
+    }
+
+    let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing length"))?;
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf)?;
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Write `msg` framed with a `Content-Length` header to `output`.
+fn write_message(output: &mut impl Write, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serve the language server protocol on `input` and `output` until the client exits.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+
+    while let Some(body) = read_message(&mut input)? {
+        let msg = match Json::parse(&body) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        if msg.get("method").as_str() == Some("exit") {
+            break;
+        }
+
+        for reply in server.handle(&msg) {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+/// Serve the language server protocol on stdin and stdout.
+pub fn serve_stdio() -> io::Result<()> {
+    serve(io::stdin().lock(), io::stdout().lock())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{offset, position, serve, Json};
+
+    #[test]
+    fn convert_positions() {
+        let src = "ab\nä😀x\n";
+        let pos = position(src, 5);
+        assert_eq!(pos.to_string(), r#"{"line":1,"character":3}"#);
+        assert_eq!(offset(src, &pos), 5);
+        assert_eq!(
+            offset(src, &Json::parse(r#"{"line":0,"character":9}"#).unwrap()),
+            2
+        );
+    }
+
+    #[test]
+    fn serve_session() {
+        let msgs = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"f.ks","text":"def f(x) y"}}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"f.ks"},"position":{"line":0,"character":4}}}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ];
+        let input: String = msgs
+            .iter()
+            .map(|m| format!("Content-Length: {}\r\n\r\n{}", m.len(), m))
+            .collect();
+
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(output.matches("Content-Length").count(), 3);
+        assert!(output.contains(r#""message":"unknown variable 'y'""#));
+        assert!(output.contains(r#"{"jsonrpc":"2.0","id":2,"result":{"contents":"def f(x)"}}"#));
+    }
+}
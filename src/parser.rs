@@ -1,48 +1,465 @@
-use crate::lexer::{Lexer, Token};
+use crate::lexer::{is_op_char, Lexer, Location, Span, Token};
 
-#[derive(Debug, PartialEq)]
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum ExprAST {
     /// Number - Expression class for numeric literals like "1.0".
     Number(f64),
 
+    /// Str - Expression class for string literals like `"abc"`, only valid as argument of
+    /// print-style builtins, see [`PRINT_BUILTINS`](crate::typeck::PRINT_BUILTINS).
+    Str(String),
+
     /// Variable - Expression class for referencing a variable, like "a".
     Variable(String),
 
+    /// Unary - Expression class for a unary operator.
+    Unary(String, Box<Expr>),
+
     /// Binary - Expression class for a binary operator.
-    Binary(char, Box<ExprAST>, Box<ExprAST>),
+    Binary(String, Box<Expr>, Box<Expr>),
+
+    /// Logical - Expression class for `&&` and `||`, which only evaluate the right operand if the
+    /// left operand doesn't decide the result already.
+    Logical(LogicalOp, Box<Expr>, Box<Expr>),
 
     /// Call - Expression class for function calls.
-    Call(String, Vec<ExprAST>),
+    Call(String, Vec<Expr>),
 
     /// If - Expression class for if/then/else.
     If {
-        cond: Box<ExprAST>,
-        then: Box<ExprAST>,
-        else_: Box<ExprAST>,
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        else_: Box<Expr>,
     },
 
     /// ForExprAST - Expression class for for/in.
     For {
         var: String,
-        start: Box<ExprAST>,
-        end: Box<ExprAST>,
-        step: Option<Box<ExprAST>>,
-        body: Box<ExprAST>,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        step: Option<Box<Expr>>,
+        body: Box<Expr>,
+    },
+
+    /// VarExprAST - Expression class for var/in, introducing variables with optional
+    /// initializers, which default to 0.0.
+    VarIn {
+        vars: Vec<(String, Option<Expr>)>,
+        body: Box<Expr>,
+    },
+
+    /// Assign - Expression class for assigning `value` to the variable `name`, evaluates to
+    /// `value`.
+    Assign { name: String, value: Box<Expr> },
+
+    /// Match - Expression class for match/with, evaluating to the expression of the first arm
+    /// whose number equals `value`, or to `default` if no arm matches.
+    Match {
+        value: Box<Expr>,
+        arms: Vec<(f64, Expr)>,
+        default: Box<Expr>,
     },
+
+    /// Lambda - Expression class for anonymous functions like `\(x) x + 1`, which are lifted to
+    /// named functions before they are compiled, see
+    /// [`lift_lambdas`](crate::desugar::lift_lambdas).
+    Lambda(Vec<String>, Box<Expr>),
+
+    /// Break - Expression leaving the innermost loop, only valid in the body of a loop.
+    Break,
+
+    /// Continue - Expression continuing with the next iteration of the innermost loop, only valid
+    /// in the body of a loop.
+    Continue,
+}
+
+/// Expression together with its location in the source.
+pub type Expr = Spanned<ExprAST>;
+
+/// AST node together with its location in the source.
+///
+/// The node is accessible through `Deref`, eg to match on a [`Spanned<ExprAST>`]. Spans are
+/// ignored when comparing nodes, such that the same AST parsed from differently formatted
+/// sources compares equal. Nodes not parsed from a source have an empty span at offset `0`.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+impl<T> From<T> for Spanned<T> {
+    fn from(node: T) -> Self {
+        Spanned::new(node, Span::default())
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T> std::ops::Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T> std::ops::DerefMut for Spanned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.node
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.node.fmt(f)
+    }
+}
+
+/// Short-circuiting logical operator, evaluates to `1.0` or `0.0`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogicalOp {
+    /// `&&`, true if both operands are true.
+    And,
+    /// `||`, true if any operand is true.
+    Or,
+}
+
+impl LogicalOp {
+    /// Get the logical operator for the operator token `op`.
+    fn from_op(op: &str) -> Option<LogicalOp> {
+        match op {
+            "&&" => Some(LogicalOp::And),
+            "||" => Some(LogicalOp::Or),
+            _ => None,
+        }
+    }
+
+    /// Get the source representation of the operator.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogicalOp::And => "&&",
+            LogicalOp::Or => "||",
+        }
+    }
 }
 
 /// PrototypeAST - This class represents the "prototype" for a function,
 /// which captures its name, and its argument names (thus implicitly the number
-/// of arguments the function takes).
+/// of arguments the function takes), as well as the precedence if it is a binary operator.
+///
+/// User-defined operators are functions named after the operator, like `binary|` and `unary!`,
+/// see [`binary_fn_name`] and [`unary_fn_name`]. The precedence is `0` for all other functions.
 #[derive(Debug, PartialEq, Clone)]
-pub struct PrototypeAST(pub String, pub Vec<String>);
+pub struct PrototypeAST(pub String, pub Vec<String>, pub usize);
 
 /// FunctionAST - This class represents a function definition itself.
-#[derive(Debug, PartialEq)]
-pub struct FunctionAST(pub PrototypeAST, pub ExprAST);
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionAST(pub Spanned<PrototypeAST>, pub Expr);
+
+/// ConstAST - Global constant definition, binding a name to the value of a constant expression.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConstAST(pub String, pub Expr);
+
+/// Name of the anonymous function generated for top-level expressions.
+pub const ANON_EXPR: &str = "__anon_expr";
+
+/// Top-level item of a kaleidoscope source.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Item {
+    /// Function definition, `def foo(x) x`.
+    Def(Spanned<FunctionAST>),
+    /// External function declaration, `extern sin(x)`.
+    Extern(Spanned<PrototypeAST>),
+    /// Top-level expression wrapped into an anonymous function named [`ANON_EXPR`].
+    TopLevel(Spanned<FunctionAST>),
+    /// Global constant definition, `const pi = 3.14159`.
+    Const(Spanned<ConstAST>),
+}
+
+impl Item {
+    /// Get the location of the item in the source.
+    pub fn span(&self) -> Span {
+        match self {
+            Item::Def(func) | Item::TopLevel(func) => func.span,
+            Item::Extern(proto) => proto.span,
+            Item::Const(c) => c.span,
+        }
+    }
+}
+
+// ------------------------
+//   Printing Source Code
+// ------------------------
+
+impl ExprAST {
+    /// Write `self` as operand of the binary operator with precedence `prec`, adding parentheses
+    /// where required to preserve the structure of the AST.
+    ///
+    /// Binary operators are left associative, therefore a right operand with the same precedence
+    /// needs parentheses. `if`, `for`, `var`, `match` and lambda expressions extend as far right as
+    /// possible and assignments bind weakest, hence they are always parenthesized. Operands of
+    /// unary operators are written with precedence `isize::MAX`, where a nested unary expression is
+    /// parenthesized to keep its operator a separate token.
+    fn fmt_operand(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        prec: isize,
+        rhs: bool,
+    ) -> std::fmt::Result {
+        let parens = match self {
+            ExprAST::Unary(..) => prec == isize::MAX,
+            ExprAST::Binary(op, _, _) => {
+                // User-defined operators have an unknown precedence, operands using them are
+                // always parenthesized.
+                let op_prec = binop_precedence(op);
+                op_prec < prec || (rhs && op_prec == prec)
+            }
+            ExprAST::Logical(op, _, _) => {
+                let op_prec = binop_precedence(op.as_str());
+                op_prec < prec || (rhs && op_prec == prec)
+            }
+            ExprAST::If { .. }
+            | ExprAST::For { .. }
+            | ExprAST::VarIn { .. }
+            | ExprAST::Assign { .. }
+            | ExprAST::Match { .. }
+            | ExprAST::Lambda(..) => true,
+            _ => false,
+        };
+
+        if parens {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+/// Render the expression as kaleidoscope source with minimal parentheses.
+impl std::fmt::Display for ExprAST {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExprAST::Number(num) => write!(f, "{}", num),
+            ExprAST::Str(s) => {
+                // Escape the string with the escape sequences of the lexer.
+                f.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\\' | '"' => write!(f, "\\{}", c)?,
+                        c if c.is_ascii_control() => write!(f, "\\x{:02x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            ExprAST::Variable(name) => f.write_str(name),
+            ExprAST::Unary(op, operand) => {
+                f.write_str(op)?;
+                operand.fmt_operand(f, isize::MAX, false)
+            }
+            ExprAST::Binary(op, lhs, rhs) => {
+                // Parenthesize all binary operands of a user-defined operator, whose precedence is
+                // unknown here.
+                let prec = match binop_precedence(op) {
+                    -1 => isize::MAX - 1,
+                    prec => prec,
+                };
+                lhs.fmt_operand(f, prec, false)?;
+                write!(f, " {} ", op)?;
+                rhs.fmt_operand(f, prec, true)
+            }
+            ExprAST::Logical(op, lhs, rhs) => {
+                let prec = binop_precedence(op.as_str());
+                lhs.fmt_operand(f, prec, false)?;
+                write!(f, " {} ", op.as_str())?;
+                rhs.fmt_operand(f, prec, true)
+            }
+            ExprAST::Call(callee, args) => {
+                write!(f, "{}(", callee)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                f.write_str(")")
+            }
+            ExprAST::If { cond, then, else_ } => {
+                write!(f, "if {} then {} else {}", cond, then, else_)
+            }
+            ExprAST::For {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                write!(f, "for {} = {}, {}", var, start, end)?;
+                if let Some(step) = step {
+                    write!(f, ", {}", step)?;
+                }
+                write!(f, " in {}", body)
+            }
+            ExprAST::VarIn { vars, body } => {
+                f.write_str("var ")?;
+                for (i, (var, init)) in vars.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_str(var)?;
+                    if let Some(init) = init {
+                        write!(f, " = {}", init)?;
+                    }
+                }
+                write!(f, " in {}", body)
+            }
+            ExprAST::Assign { name, value } => write!(f, "{} = {}", name, value),
+            ExprAST::Match {
+                value,
+                arms,
+                default,
+            } => {
+                write!(f, "match {} with ", value)?;
+                for (num, arm) in arms {
+                    // Parenthesize arms which could expose a '|' operator, which would otherwise
+                    // be parsed as the separator of the next arm.
+                    match &arm.node {
+                        ExprAST::Binary(op, _, _) if op == "|" => {
+                            write!(f, "{} -> ({}) | ", num, arm)?
+                        }
+                        ExprAST::If { .. }
+                        | ExprAST::For { .. }
+                        | ExprAST::VarIn { .. }
+                        | ExprAST::Assign { .. }
+                        | ExprAST::Match { .. }
+                        | ExprAST::Lambda(..) => write!(f, "{} -> ({}) | ", num, arm)?,
+                        _ => write!(f, "{} -> {} | ", num, arm)?,
+                    }
+                }
+                write!(f, "_ -> {}", default)
+            }
+            ExprAST::Lambda(params, body) => write!(f, "\\({}) {}", params.join(", "), body),
+            ExprAST::Break => f.write_str("break"),
+            ExprAST::Continue => f.write_str("continue"),
+        }
+    }
+}
+
+/// Render the prototype as kaleidoscope source, eg `foo(a, b)`.
+impl std::fmt::Display for PrototypeAST {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)?;
+        if self.2 > 0 {
+            write!(f, " {}", self.2)?;
+        }
+        write!(f, "({})", self.1.join(", "))
+    }
+}
+
+/// Render the function as kaleidoscope source, top-level expressions are rendered as the bare
+/// expression.
+impl std::fmt::Display for FunctionAST {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 .0 == ANON_EXPR {
+            write!(f, "{}", self.1)
+        } else {
+            write!(f, "def {} {}", self.0, self.1)
+        }
+    }
+}
+
+impl std::fmt::Display for ConstAST {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "const {} = {}", self.0, self.1)
+    }
+}
+
+/// Default limit for the nesting depth of expressions, see [`Parser::set_max_depth`].
+pub const MAX_DEPTH: usize = 256;
+
+/// Precedence of user-defined binary operators declared without a precedence.
+pub const DEFAULT_BINOP_PRECEDENCE: usize = 30;
+
+/// Name of the function implementing the user-defined binary operator `op`, eg `binary|`.
+pub fn binary_fn_name(op: &str) -> String {
+    format!("binary{}", op)
+}
+
+/// Name of the function implementing the user-defined unary operator `op`, eg `unary!`.
+pub fn unary_fn_name(op: &str) -> String {
+    format!("unary{}", op)
+}
+
+/// Description of a binary operator in the expected tokens of error messages.
+const BINOP: &str = "binary operator";
+
+/// Tokens starting an expression.
+const EXPR_START: &[&str] = &[
+    "number",
+    "string",
+    "identifier",
+    "'('",
+    "'if'",
+    "'for'",
+    "'var'",
+    "'match'",
+    "'\\'",
+];
+
+/// Enumerate the `alternatives` for error messages, eg `a, b or c`.
+fn one_of(alternatives: &[&str]) -> String {
+    match alternatives {
+        [] => String::new(),
+        [first] => (*first).into(),
+        [init @ .., last] => format!("{} or {}", init.join(", "), last),
+    }
+}
+
+/// Parse result with [`ParseError`] as Error type.
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// Kind of a [`ParseError`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ParseErrorKind {
+    /// The input is malformed.
+    Syntax,
+    /// The input ended in the middle of an item, eg with a missing `else`, appending more input
+    /// may complete it.
+    NeedMoreInput,
+}
+
+/// Parse error together with the offending token and its location.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub msg: String,
+    pub span: Span,
+    /// Token the parser failed at.
+    pub token: Token,
+    /// Tokens valid in place of `token`, eg `'('` or `identifier`, empty if the token is not the
+    /// issue, eg for a too deeply nested expression.
+    pub expected: Vec<String>,
+}
 
-/// Parse result with String as Error type (to be compliant with tutorial).
-type ParseResult<T> = Result<T, String>;
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
 
 /// Parser for the `kaleidoscope` language.
 pub struct Parser<I>
@@ -51,6 +468,23 @@ where
 {
     lexer: Lexer<I>,
     cur_tok: Option<Token>,
+    cur_span: Span,
+    cur_loc: Location,
+    /// End of the token before `cur_tok`, which is the end of the node parsed last.
+    prev_end: usize,
+    errors: Vec<ParseError>,
+    /// Current nesting depth of expressions.
+    depth: usize,
+    max_depth: usize,
+    /// Number of loop bodies enclosing the current expression, `break` and `continue` are only
+    /// valid inside a loop body.
+    loop_depth: usize,
+    /// Precedences of the user-defined binary operators declared so far.
+    binops: HashMap<String, isize>,
+    /// The current expression is in an arm of a `match`, where `|` separates the arms instead of
+    /// being a binary operator, unless it is enclosed in parentheses.
+    in_match_arm: bool,
+    trace_tokens: bool,
 }
 
 impl<I> Parser<I>
@@ -61,26 +495,207 @@ where
         Parser {
             lexer,
             cur_tok: None,
+            cur_span: Span::default(),
+            cur_loc: Location::default(),
+            prev_end: 0,
+            errors: Vec::new(),
+            depth: 0,
+            max_depth: MAX_DEPTH,
+            loop_depth: 0,
+            binops: HashMap::new(),
+            in_match_arm: false,
+            trace_tokens: false,
+        }
+    }
+
+    /// Enable or disable printing each token read from the lexer to stderr.
+    pub fn set_trace_tokens(&mut self, trace_tokens: bool) {
+        self.trace_tokens = trace_tokens;
+    }
+
+    /// Declare the user-defined operator implemented by the function `proto`, eg an operator
+    /// defined by a previous input of the same session. Other prototypes are ignored.
+    pub fn declare_operator(&mut self, PrototypeAST(name, _, precedence): &PrototypeAST) {
+        let is_op = |op: &&str| !op.is_empty() && op.chars().all(is_op_char);
+
+        if let Some(op) = name.strip_prefix("binary").filter(is_op) {
+            self.lexer.declare_op(op);
+            self.binops.insert(op.into(), *precedence as isize);
+        } else if let Some(op) = name.strip_prefix("unary").filter(is_op) {
+            self.lexer.declare_op(op);
         }
     }
 
+    /// Set the limit for the nesting depth of expressions, defaults to [`MAX_DEPTH`].
+    ///
+    /// Parsing recurses for each nested expression, the limit protects against overflowing the
+    /// stack with deeply nested input like `((((...`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
     // -----------------------
     //   Simple Token Buffer
     // -----------------------
 
     /// Implement the global variable `int CurTok;` from the tutorial.
     ///
-    /// # Panics
-    /// Panics if the parser doesn't have a current token.
+    /// Before the first [`get_next_token`](Parser::get_next_token) the parser doesn't have a
+    /// current token, in which case [`Token::Eof`] is returned.
     pub fn cur_tok(&self) -> &Token {
-        self.cur_tok.as_ref().expect("Parser: Expected cur_token!")
+        self.cur_tok.as_ref().unwrap_or(&Token::Eof)
+    }
+
+    /// Get the location of `cur_tok` in the source.
+    ///
+    /// When parsing fails, this is the location of the offending token.
+    pub fn cur_span(&self) -> Span {
+        self.cur_span
+    }
+
+    /// Get the source text of `cur_tok`, exactly as written in the input.
+    pub fn cur_lexeme(&self) -> &str {
+        self.lexer.lexeme()
+    }
+
+    /// Get the line and column of the start of `cur_tok` in the source.
+    pub fn cur_location(&self) -> Location {
+        self.cur_loc
+    }
+
+    /// Get the precedence of `cur_tok` as binary operator, including the user-defined operators,
+    /// `-1` if it is not a binary operator.
+    ///
+    /// Implement `int GetTokPrecedence();` from the tutorial.
+    fn get_tok_precedence(&self) -> isize {
+        match self.cur_tok() {
+            Token::Op(op) if self.in_match_arm && op == "|" => -1,
+            Token::Op(op) => match binop_precedence(op) {
+                -1 => self.binops.get(op).copied().unwrap_or(-1),
+                prec => prec,
+            },
+            _ => -1,
+        }
     }
 
     /// Advance the `cur_tok` by getting the next token from the lexer.
     ///
     /// Implement the fucntion `int getNextToken();` from the tutorial.
+    ///
+    /// Comments emitted by the lexer are skipped.
     pub fn get_next_token(&mut self) {
-        self.cur_tok = Some(self.lexer.gettok());
+        let mut tok = self.lexer.gettok();
+        while let Token::Comment(_) = tok {
+            tok = self.lexer.gettok();
+        }
+        if self.trace_tokens {
+            eprintln!("Token: {:?}", tok);
+        }
+        self.cur_tok = Some(tok);
+        self.prev_end = self.cur_span.end;
+        self.cur_span = self.lexer.span();
+        log_event!(trace, "token {:?} at {:?}", self.cur_tok(), self.cur_span);
+        self.cur_loc = self.lexer.token_location();
+    }
+
+    /// Attach the span from `start` to the end of the token before `cur_tok` to `node`, which was
+    /// parsed from these tokens.
+    fn spanned<T>(&self, start: usize, node: T) -> Spanned<T> {
+        Spanned::new(node, Span::new(start, self.prev_end))
+    }
+
+    /// Check if the input ended at `cur_tok`, either because it is [`Token::Eof`] or because the
+    /// input ended in the middle of it, see [`Lexer::is_incomplete`].
+    pub fn is_incomplete(&self) -> bool {
+        match self.cur_tok() {
+            Token::Eof => true,
+            Token::Error(_) => self.lexer.is_incomplete(),
+            _ => false,
+        }
+    }
+
+    /// Look at the token following `cur_tok` without consuming it, eg to disambiguate constructs
+    /// starting with the same token.
+    ///
+    /// Comments are skipped like in [`get_next_token`](Parser::get_next_token).
+    pub fn peek_tok(&mut self) -> &Token {
+        let mut n = 0;
+        while let Token::Comment(_) = self.lexer.peek_n(n) {
+            n += 1;
+        }
+        self.lexer.peek_n(n)
+    }
+
+    // ------------------
+    //   Error Recovery
+    // ------------------
+
+    /// Record the parse error `err` and skip tokens until the start of the next item, which is a
+    /// `def`, an `extern`, a `const` or a top-level `;`, to continue parsing after an error.
+    ///
+    /// Return the recorded error.
+    pub fn recover(&mut self, err: ParseError) -> &ParseError {
+        self.errors.push(err);
+
+        while !matches!(
+            self.cur_tok(),
+            Token::Def | Token::Extern | Token::Const | Token::Delim(';') | Token::Eof
+        ) {
+            self.get_next_token();
+        }
+
+        self.errors.last().unwrap()
+    }
+
+    /// Get all the parse errors recorded with [`recover`](Parser::recover) so far.
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    // ------------------
+    //   Error Messages
+    // ------------------
+
+    /// Describe `cur_tok` as it was found in the source.
+    fn found(&self) -> String {
+        match self.cur_tok() {
+            Token::Eof => "end of input".into(),
+            _ => format!("'{}'", self.cur_lexeme()),
+        }
+    }
+
+    /// Error `msg` at `cur_tok`, where the `expected` tokens would be valid.
+    fn error(&self, msg: String, expected: &[&str]) -> ParseError {
+        let kind = if self.is_incomplete() {
+            ParseErrorKind::NeedMoreInput
+        } else {
+            ParseErrorKind::Syntax
+        };
+        ParseError {
+            kind,
+            msg,
+            span: self.cur_span,
+            token: self.cur_tok().clone(),
+            expected: expected.iter().map(|&tok| tok.into()).collect(),
+        }
+    }
+
+    /// Error for `cur_tok` in a position where only the `expected` tokens are valid.
+    fn expected(&self, expected: &[&str]) -> ParseError {
+        // Malformed tokens are reported with the more specific error of the lexer.
+        let msg = match self.cur_tok() {
+            Token::Error(msg) => msg.clone(),
+            _ => format!("expected {}, found {}", one_of(expected), self.found()),
+        };
+        self.error(msg, expected)
+    }
+
+    /// Error for `cur_tok` in a position where only `what` is valid, like [`expected`] with a
+    /// description of the context.
+    ///
+    /// [`expected`]: Parser::expected
+    fn unexpected(&self, what: &str) -> ParseError {
+        self.expected(&[what])
     }
 
     // ----------------------------
@@ -97,7 +712,33 @@ where
                 self.get_next_token();
                 Ok(ExprAST::Number(num))
             }
-            _ => unreachable!(),
+            // All values are doubles, integer literals are converted.
+            Token::Integer(int) => {
+                self.get_next_token();
+                Ok(ExprAST::Number(int as f64))
+            }
+            // Character literals stand for their code point, eg as argument to `putchard`.
+            Token::Char(c) => {
+                self.get_next_token();
+                Ok(ExprAST::Number(f64::from(u32::from(c))))
+            }
+            _ => Err(self.expected(&["number"])),
+        }
+    }
+
+    /// strexpr ::= string
+    fn parse_str_expr(&mut self) -> ParseResult<ExprAST> {
+        match self.cur_tok.take() {
+            Some(Token::String(s)) => {
+                // Consume the string token.
+                self.get_next_token();
+                Ok(ExprAST::Str(s))
+            }
+            other => {
+                // Plug back current token.
+                self.cur_tok = other;
+                Err(self.expected(&["string"]))
+            }
         }
     }
 
@@ -106,20 +747,32 @@ where
     /// Implement `std::unique_ptr<ExprAST> ParseParenExpr();` from the tutorial.
     fn parse_paren_expr(&mut self) -> ParseResult<ExprAST> {
         // Eat '(' token.
-        assert_eq!(*self.cur_tok(), Token::Char('('));
+        if *self.cur_tok() != Token::Delim('(') {
+            return Err(self.expected(&["'('"]));
+        }
         self.get_next_token();
 
-        let v = self.parse_expression()?;
+        let v = self.parse_enclosed_expr()?;
 
-        if *self.cur_tok() == Token::Char(')') {
+        if *self.cur_tok() == Token::Delim(')') {
             // Eat ')' token.
             self.get_next_token();
-            Ok(v)
+            // The span of the expression is extended to the parentheses by the caller.
+            Ok(v.node)
         } else {
-            Err("expected ')'".into())
+            Err(self.expected(&["')'", BINOP]))
         }
     }
 
+    /// Parse an expression enclosed in parentheses, where `|` is a binary operator even in the arm
+    /// of a `match`.
+    fn parse_enclosed_expr(&mut self) -> ParseResult<Expr> {
+        let in_match_arm = std::mem::replace(&mut self.in_match_arm, false);
+        let expr = self.parse_expression();
+        self.in_match_arm = in_match_arm;
+        expr
+    }
+
     /// identifierexpr
     ///   ::= identifier
     ///   ::= identifier '(' expression* ')'
@@ -132,10 +785,14 @@ where
                 self.get_next_token();
                 id
             }
-            _ => unreachable!(),
+            other => {
+                // Plug back current token.
+                self.cur_tok = other;
+                return Err(self.expected(&["identifier"]));
+            }
         };
 
-        if *self.cur_tok() != Token::Char('(') {
+        if *self.cur_tok() != Token::Delim('(') {
             // Simple variable reference.
             Ok(ExprAST::Variable(id_name))
         } else {
@@ -144,27 +801,26 @@ where
             // Eat '(' token.
             self.get_next_token();
 
-            let mut args: Vec<ExprAST> = Vec::new();
+            let mut args: Vec<Expr> = Vec::new();
 
             // If there are arguments collect them.
-            if *self.cur_tok() != Token::Char(')') {
+            if *self.cur_tok() != Token::Delim(')') {
                 loop {
-                    let arg = self.parse_expression()?;
+                    let arg = self.parse_enclosed_expr()?;
                     args.push(arg);
 
-                    if *self.cur_tok() == Token::Char(')') {
+                    if *self.cur_tok() == Token::Delim(')') {
                         break;
                     }
 
-                    if *self.cur_tok() != Token::Char(',') {
-                        return Err("Expected ')' or ',' in argument list".into());
+                    if *self.cur_tok() != Token::Delim(',') {
+                        return Err(self.expected(&["')'", "','", BINOP]));
                     }
 
                     self.get_next_token();
                 }
             }
 
-            assert_eq!(*self.cur_tok(), Token::Char(')'));
             // Eat ')' token.
             self.get_next_token();
 
@@ -177,13 +833,15 @@ where
     /// Implement `std::unique_ptr<ExprAST> ParseIfExpr();` from the tutorial.
     fn parse_if_expr(&mut self) -> ParseResult<ExprAST> {
         // Consume 'if' token.
-        assert_eq!(*self.cur_tok(), Token::If);
+        if *self.cur_tok() != Token::If {
+            return Err(self.expected(&["'if'"]));
+        }
         self.get_next_token();
 
         let cond = self.parse_expression()?;
 
-        if *dbg!(self.cur_tok()) != Token::Then {
-            return Err("Expected 'then'".into());
+        if *self.cur_tok() != Token::Then {
+            return Err(self.expected(&["'then'", BINOP]));
         }
         // Consume 'then' token.
         self.get_next_token();
@@ -191,7 +849,7 @@ where
         let then = self.parse_expression()?;
 
         if *self.cur_tok() != Token::Else {
-            return Err("Expected 'else'".into());
+            return Err(self.expected(&["'else'", BINOP]));
         }
         // Consume 'else' token.
         self.get_next_token();
@@ -210,34 +868,41 @@ where
     /// Implement `std::unique_ptr<ExprAST> ParseForExpr();` from the tutorial.
     fn parse_for_expr(&mut self) -> ParseResult<ExprAST> {
         // Consume the 'for' token.
-        assert_eq!(*self.cur_tok(), Token::For);
+        if *self.cur_tok() != Token::For {
+            return Err(self.expected(&["'for'"]));
+        }
         self.get_next_token();
 
-        let var = match self
-            .parse_identifier_expr()
-            .map_err(|_| String::from("expected identifier after 'for'"))?
-        {
-            ExprAST::Variable(var) => var,
-            _ => unreachable!(),
+        let var = match self.cur_tok.take() {
+            Some(Token::Identifier(var)) => {
+                // Consume the identifier.
+                self.get_next_token();
+                var
+            }
+            other => {
+                // Plug back current token.
+                self.cur_tok = other;
+                return Err(self.unexpected("identifier after 'for'"));
+            }
         };
 
         // Consume the '=' token.
-        if *self.cur_tok() != Token::Char('=') {
-            return Err("expected '=' after for".into());
+        if !self.cur_tok().is_op("=") {
+            return Err(self.expected(&["'='"]));
         }
         self.get_next_token();
 
         let start = self.parse_expression()?;
 
         // Consume the ',' token.
-        if *self.cur_tok() != Token::Char(',') {
-            return Err("expected ',' after for start value".into());
+        if *self.cur_tok() != Token::Delim(',') {
+            return Err(self.expected(&["','", BINOP]));
         }
         self.get_next_token();
 
         let end = self.parse_expression()?;
 
-        let step = if *self.cur_tok() == Token::Char(',') {
+        let step = if *self.cur_tok() == Token::Delim(',') {
             // Consume the ',' token.
             self.get_next_token();
 
@@ -248,93 +913,370 @@ where
 
         // Consume the 'in' token.
         if *self.cur_tok() != Token::In {
-            return Err("expected 'in' after for".into());
+            return Err(if step.is_some() {
+                self.expected(&["'in'", BINOP])
+            } else {
+                self.expected(&["','", "'in'", BINOP])
+            });
         }
         self.get_next_token();
 
-        let body = self.parse_expression()?;
+        self.loop_depth += 1;
+        let body = self.parse_expression();
+        self.loop_depth -= 1;
+        let body = body?;
 
         Ok(ExprAST::For {
             var,
             start: Box::new(start),
             end: Box::new(end),
-            step: step.map(|s| Box::new(s)),
+            step: step.map(Box::new),
             body: Box::new(body),
         })
     }
 
-    /// primary
-    ///   ::= identifierexpr
-    ///   ::= numberexpr
-    ///   ::= parenexpr
+    /// varexpr ::= 'var' identifier ('=' expression)?
+    ///                 (',' identifier ('=' expression)?)* 'in' expression
     ///
-    /// Implement `std::unique_ptr<ExprAST> ParsePrimary();` from the tutorial.
-    fn parse_primary(&mut self) -> ParseResult<ExprAST> {
-        match *self.cur_tok() {
-            Token::Identifier(_) => self.parse_identifier_expr(),
-            Token::Number(_) => self.parse_num_expr(),
-            Token::Char('(') => self.parse_paren_expr(),
-            Token::If => self.parse_if_expr(),
-            Token::For => self.parse_for_expr(),
-            _ => Err("unknown token when expecting an expression".into()),
+    /// Implement `std::unique_ptr<ExprAST> ParseVarExpr();` from the tutorial.
+    fn parse_var_expr(&mut self) -> ParseResult<ExprAST> {
+        // Consume the 'var' token.
+        if *self.cur_tok() != Token::Var {
+            return Err(self.expected(&["'var'"]));
         }
-    }
-
-    // -----------------------------
-    //   Binary Expression Parsing
-    // -----------------------------
-
-    /// /// expression
-    ///   ::= primary binoprhs
-    ///
-    /// Implement `std::unique_ptr<ExprAST> ParseExpression();` from the tutorial.
-    fn parse_expression(&mut self) -> ParseResult<ExprAST> {
-        let lhs = self.parse_primary()?;
-        self.parse_bin_op_rhs(0, lhs)
-    }
+        self.get_next_token();
 
-    /// binoprhs
-    ///   ::= ('+' primary)*
-    ///
-    /// Implement `std::unique_ptr<ExprAST> ParseBinOpRHS(int ExprPrec, std::unique_ptr<ExprAST> LHS);` from the tutorial.
-    fn parse_bin_op_rhs(&mut self, expr_prec: isize, mut lhs: ExprAST) -> ParseResult<ExprAST> {
+        let mut vars = Vec::new();
         loop {
-            let tok_prec = get_tok_precedence(self.cur_tok());
-
-            // Not a binary operator or precedence is too small.
-            if tok_prec < expr_prec {
-                return Ok(lhs);
-            }
-
-            let binop = match self.cur_tok.take() {
-                Some(Token::Char(c)) => {
-                    // Eat binary operator.
+            let var = match self.cur_tok.take() {
+                Some(Token::Identifier(var)) => {
+                    // Consume the identifier.
                     self.get_next_token();
-                    c
+                    var
+                }
+                other => {
+                    // Plug back current token.
+                    self.cur_tok = other;
+                    return Err(self.unexpected("identifier after 'var'"));
                 }
-                _ => unreachable!(),
             };
 
-            // lhs BINOP1 rhs BINOP2 remrhs
-            //     ^^^^^^     ^^^^^^
-            //     tok_prec   next_prec
-            //
-            // In case BINOP1 has higher precedence, we are done here and can build a 'Binary' AST
-            // node between 'lhs' and 'rhs'.
-            //
-            // In case BINOP2 has higher precedence, we take 'rhs' as 'lhs' and recurse into the
-            // 'remrhs' expression first.
-
-            // Parse primary expression after binary operator.
-            let mut rhs = self.parse_primary()?;
+            // Read the optional initializer.
+            let init = if self.cur_tok().is_op("=") {
+                // Consume the '=' token.
+                self.get_next_token();
 
-            let next_prec = get_tok_precedence(self.cur_tok());
-            if tok_prec < next_prec {
-                // BINOP2 has higher precedence thatn BINOP1, recurse into 'remhs'.
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            let has_init = init.is_some();
+            vars.push((var, init));
+
+            // End of the var list, exit the loop.
+            if *self.cur_tok() != Token::Delim(',') {
+                // Consume the 'in' token.
+                if *self.cur_tok() != Token::In {
+                    return Err(if has_init {
+                        self.expected(&["','", "'in'", BINOP])
+                    } else {
+                        self.expected(&["'='", "','", "'in'"])
+                    });
+                }
+                self.get_next_token();
+                break;
+            }
+
+            // Consume the ',' token.
+            self.get_next_token();
+        }
+
+        let body = self.parse_expression()?;
+
+        Ok(ExprAST::VarIn {
+            vars,
+            body: Box::new(body),
+        })
+    }
+
+    /// matchexpr ::= 'match' expression 'with' (pattern '->' expression '|')* '_' '->' expression
+    /// pattern ::= '-'? number
+    ///
+    /// A user-defined `|` operator must be parenthesized in the arms followed by another arm.
+    fn parse_match_expr(&mut self) -> ParseResult<ExprAST> {
+        // Consume the 'match' token.
+        if *self.cur_tok() != Token::Match {
+            return Err(self.expected(&["'match'"]));
+        }
+        self.get_next_token();
+
+        let value = self.parse_expression()?;
+
+        if *self.cur_tok() != Token::With {
+            return Err(self.expected(&["'with'", BINOP]));
+        }
+        // Consume the 'with' token.
+        self.get_next_token();
+
+        let mut arms = Vec::new();
+        loop {
+            // The default arm '_' ends the match.
+            let pattern = if *self.cur_tok() == Token::Delim('_') {
+                self.get_next_token();
+                None
+            } else {
+                let neg = self.cur_tok().is_op("-");
+                if neg {
+                    self.get_next_token();
+                }
+                let num = match self.parse_num_expr() {
+                    Ok(ExprAST::Number(num)) => num,
+                    _ if neg => return Err(self.expected(&["number"])),
+                    _ => return Err(self.expected(&["number", "'_'"])),
+                };
+                Some(if neg { -num } else { num })
+            };
+
+            if !self.cur_tok().is_op("->") {
+                return Err(self.expected(&["'->'"]));
+            }
+            // Consume the '->' token.
+            self.get_next_token();
+
+            // The default arm ends the match, it only stops at a '|' if the match itself is in
+            // an arm of an enclosing match.
+            let in_match_arm = self.in_match_arm;
+            self.in_match_arm |= pattern.is_some();
+            let body = self.parse_expression();
+            self.in_match_arm = in_match_arm;
+            let body = body?;
+            let num = match pattern {
+                Some(num) => num,
+                None => {
+                    return Ok(ExprAST::Match {
+                        value: Box::new(value),
+                        arms,
+                        default: Box::new(body),
+                    })
+                }
+            };
+            arms.push((num, body));
+
+            if !self.cur_tok().is_op("|") {
+                return Err(self.expected(&["'|'", BINOP]));
+            }
+            // Consume the '|' token.
+            self.get_next_token();
+        }
+    }
+
+    /// lambdaexpr ::= '\\' params expression
+    fn parse_lambda_expr(&mut self) -> ParseResult<ExprAST> {
+        // Consume the '\' token.
+        if *self.cur_tok() != Token::Delim('\\') {
+            return Err(self.expected(&["'\\'"]));
+        }
+        self.get_next_token();
+
+        let params = self.parse_params("lambda")?;
+
+        // The body of a lambda is a function body, the loops around the lambda don't enclose it.
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let body = self.parse_expression();
+        self.loop_depth = loop_depth;
+        let body = body?;
+
+        Ok(ExprAST::Lambda(params, Box::new(body)))
+    }
+
+    /// loopjumpexpr ::= 'break' | 'continue'
+    fn parse_loop_jump_expr(&mut self) -> ParseResult<ExprAST> {
+        let jump = match *self.cur_tok() {
+            Token::Break => ExprAST::Break,
+            Token::Continue => ExprAST::Continue,
+            _ => return Err(self.expected(&["'break'", "'continue'"])),
+        };
+
+        if self.loop_depth == 0 {
+            return Err(self.error(
+                format!("'{}' outside of a loop body", self.cur_lexeme()),
+                &[],
+            ));
+        }
+
+        // Consume the 'break' or 'continue' token.
+        self.get_next_token();
+        Ok(jump)
+    }
+
+    /// primary
+    ///   ::= identifierexpr
+    ///   ::= numberexpr
+    ///   ::= strexpr
+    ///   ::= parenexpr
+    ///   ::= ifexpr
+    ///   ::= forexpr
+    ///   ::= varexpr
+    ///   ::= matchexpr
+    ///   ::= lambdaexpr
+    ///   ::= loopjumpexpr
+    ///
+    /// Implement `std::unique_ptr<ExprAST> ParsePrimary();` from the tutorial.
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
+        // All nested expressions are parsed through here.
+        if self.depth >= self.max_depth {
+            return Err(self.error("expression too deeply nested".into(), &[]));
+        }
+        self.depth += 1;
+
+        let start = self.cur_span.start;
+        let res = match *self.cur_tok() {
+            Token::Identifier(_) => self.parse_identifier_expr(),
+            Token::Number(_) | Token::Integer(_) | Token::Char(_) => self.parse_num_expr(),
+            Token::String(_) => self.parse_str_expr(),
+            Token::Delim('(') => self.parse_paren_expr(),
+            Token::If => self.parse_if_expr(),
+            Token::For => self.parse_for_expr(),
+            Token::Var => self.parse_var_expr(),
+            Token::Match => self.parse_match_expr(),
+            Token::Delim('\\') => self.parse_lambda_expr(),
+            Token::Break | Token::Continue => self.parse_loop_jump_expr(),
+            Token::Eof => Err(self.error(
+                format!(
+                    "unexpected end of input when expecting an expression, expected {}",
+                    one_of(EXPR_START)
+                ),
+                EXPR_START,
+            )),
+            Token::Error(ref msg) => Err(self.error(msg.clone(), EXPR_START)),
+            _ => Err(self.error(
+                format!(
+                    "unknown token '{}' when expecting an expression, expected {}",
+                    self.cur_lexeme(),
+                    one_of(EXPR_START)
+                ),
+                EXPR_START,
+            )),
+        };
+
+        self.depth -= 1;
+        res.map(|node| self.spanned(start, node))
+    }
+
+    // -----------------------------
+    //   Binary Expression Parsing
+    // -----------------------------
+
+    /// /// expression
+    ///   ::= unary binoprhs ('=' unary binoprhs)*
+    ///
+    /// Implement `std::unique_ptr<ExprAST> ParseExpression();` from the tutorial.
+    ///
+    /// Assignment binds weakest and is right associative, `a = b = 1` assigns `1` to `b` and then
+    /// to `a`.
+    fn parse_expression(&mut self) -> ParseResult<Expr> {
+        let lhs = self.parse_unary()?;
+        let mut expr = self.parse_bin_op_rhs(0, lhs)?;
+
+        // Collect the destinations of an assignment chain first, such that long chains don't
+        // recurse.
+        let mut dests = Vec::new();
+        while self.cur_tok().is_op("=") {
+            match expr.node {
+                ExprAST::Variable(name) => dests.push((name, expr.span.start)),
+                _ => {
+                    let msg = "destination of '=' must be a variable".into();
+                    return Err(self.error(msg, &[]));
+                }
+            }
+            // Consume the '=' token.
+            self.get_next_token();
+
+            let lhs = self.parse_unary()?;
+            expr = self.parse_bin_op_rhs(0, lhs)?;
+        }
+
+        Ok(dests.into_iter().rev().fold(expr, |value, (name, start)| {
+            let span = Span::new(start, value.span.end);
+            let value = Box::new(value);
+            Spanned::new(ExprAST::Assign { name, value }, span)
+        }))
+    }
+
+    /// unary
+    ///   ::= primary
+    ///   ::= unaryop unary
+    ///
+    /// Implement `std::unique_ptr<ExprAST> ParseUnary();` from the tutorial.
+    ///
+    /// The operators are collected first, such that long chains like `- - - x` don't recurse.
+    /// Checking the operator is left to the backends.
+    fn parse_unary(&mut self) -> ParseResult<Expr> {
+        let mut ops = Vec::new();
+        while let Token::Op(op) = self.cur_tok() {
+            ops.push((op.clone(), self.cur_span.start));
+            // Eat the unary operator.
+            self.get_next_token();
+        }
+
+        let operand = self.parse_primary()?;
+        Ok(ops.into_iter().rev().fold(operand, |operand, (op, start)| {
+            let span = Span::new(start, operand.span.end);
+            Spanned::new(ExprAST::Unary(op, Box::new(operand)), span)
+        }))
+    }
+
+    /// binoprhs
+    ///   ::= ('+' unary)*
+    ///
+    /// Implement `std::unique_ptr<ExprAST> ParseBinOpRHS(int ExprPrec, std::unique_ptr<ExprAST> LHS);` from the tutorial.
+    fn parse_bin_op_rhs(&mut self, expr_prec: isize, mut lhs: Expr) -> ParseResult<Expr> {
+        loop {
+            let tok_prec = self.get_tok_precedence();
+
+            // Not a binary operator or precedence is too small.
+            if tok_prec < expr_prec {
+                return Ok(lhs);
+            }
+
+            let binop = match self.cur_tok.take() {
+                Some(Token::Op(op)) => {
+                    // Eat binary operator.
+                    self.get_next_token();
+                    op
+                }
+                other => {
+                    // Plug back current token.
+                    self.cur_tok = other;
+                    return Err(self.expected(&[BINOP]));
+                }
+            };
+
+            // lhs BINOP1 rhs BINOP2 remrhs
+            //     ^^^^^^     ^^^^^^
+            //     tok_prec   next_prec
+            //
+            // In case BINOP1 has higher precedence, we are done here and can build a 'Binary' AST
+            // node between 'lhs' and 'rhs'.
+            //
+            // In case BINOP2 has higher precedence, we take 'rhs' as 'lhs' and recurse into the
+            // 'remrhs' expression first.
+
+            // Parse unary expression after binary operator.
+            let mut rhs = self.parse_unary()?;
+
+            let next_prec = self.get_tok_precedence();
+            if tok_prec < next_prec {
+                // BINOP2 has higher precedence thatn BINOP1, recurse into 'remhs'.
                 rhs = self.parse_bin_op_rhs(tok_prec + 1, rhs)?
             }
 
-            lhs = ExprAST::Binary(binop, Box::new(lhs), Box::new(rhs));
+            let span = Span::new(lhs.span.start, rhs.span.end);
+            let node = match LogicalOp::from_op(&binop) {
+                Some(op) => ExprAST::Logical(op, Box::new(lhs), Box::new(rhs)),
+                None => ExprAST::Binary(binop, Box::new(lhs), Box::new(rhs)),
+            };
+            lhs = Spanned::new(node, span);
         }
     }
 
@@ -344,33 +1286,114 @@ where
 
     /// prototype
     ///   ::= id '(' id* ')'
+    ///   ::= 'binary' op number? '(' id id ')'
+    ///   ::= 'unary' op '(' id ')'
     ///
     /// Implement `std::unique_ptr<PrototypeAST> ParsePrototype();` from the tutorial.
-    fn parse_prototype(&mut self) -> ParseResult<PrototypeAST> {
-        let id_name = match self.cur_tok.take() {
+    ///
+    /// The precedence of a binary operator is registered right away, such that the operator can
+    /// be used in its own body and all following items.
+    fn parse_prototype(&mut self) -> ParseResult<Spanned<PrototypeAST>> {
+        let start = self.cur_span.start;
+        // Name of the function, number of operands if it is an operator and binary precedence.
+        let (fn_name, operands, precedence) = match self.cur_tok.take() {
             Some(Token::Identifier(id)) => {
                 // Consume the identifier.
                 self.get_next_token();
-                id
+                (id, None, 0)
+            }
+            Some(Token::Unary) => {
+                // Consume the 'unary' token.
+                self.get_next_token();
+                let op = self.parse_operator("unary")?;
+                (unary_fn_name(&op), Some((op, 1)), 0)
+            }
+            Some(Token::Binary) => {
+                // Consume the 'binary' token.
+                self.get_next_token();
+                let op = self.parse_operator("binary")?;
+
+                // Read the precedence if present.
+                let precedence = match *self.cur_tok() {
+                    Token::Integer(prec) if (1..=100).contains(&prec) => {
+                        self.get_next_token();
+                        prec as usize
+                    }
+                    Token::Integer(_) => {
+                        let msg = "Invalid precedence: must be 1..100".into();
+                        return Err(self.error(msg, &[]));
+                    }
+                    _ => DEFAULT_BINOP_PRECEDENCE,
+                };
+                (binary_fn_name(&op), Some((op, 2)), precedence)
             }
             other => {
                 // Plug back current token.
                 self.cur_tok = other;
-                return Err("Expected function name in prototype".into());
+                return Err(self.unexpected("function name in prototype"));
+            }
+        };
+
+        let args = self.parse_params("prototype")?;
+
+        if let Some((op, operands)) = operands {
+            // Verify right number of names for operator.
+            if args.len() != operands {
+                return Err(self.error(
+                    format!(
+                        "Invalid number of operands for operator '{}', expected {}",
+                        op, operands
+                    ),
+                    &[],
+                ));
             }
+            if operands == 2 {
+                self.binops.insert(op, precedence as isize);
+            }
+        }
+
+        Ok(self.spanned(start, PrototypeAST(fn_name, args, precedence)))
+    }
+
+    /// Parse the operator of the prototype of a user-defined `kind` operator, where `kind` is
+    /// `binary` or `unary`.
+    ///
+    /// Built-in operators, including `=`, can't be redefined.
+    fn parse_operator(&mut self, kind: &str) -> ParseResult<String> {
+        let op = match self.cur_tok() {
+            Token::Op(op) => op.clone(),
+            _ => return Err(self.unexpected(&format!("operator after '{}'", kind))),
         };
 
-        if *self.cur_tok() != Token::Char('(') {
-            return Err("Expected '(' in prototype".into());
+        let builtin = match kind {
+            "unary" => op == "-",
+            _ => op == "=" || binop_precedence(&op) != -1,
+        };
+        if builtin {
+            let msg = format!("can't redefine the built-in operator '{}'", op);
+            return Err(self.error(msg, &[]));
+        }
+
+        // Consume the operator.
+        self.get_next_token();
+        Ok(op)
+    }
+
+    /// params ::= '(' (identifier | ',')* ')'
+    ///
+    /// Parse the parameter list of a `what`, eg of a prototype.
+    fn parse_params(&mut self, what: &str) -> ParseResult<Vec<String>> {
+        if *self.cur_tok() != Token::Delim('(') {
+            return Err(self.unexpected(&format!("'(' in {}", what)));
         }
 
-        let mut args: Vec<String> = Vec::new();
+        let mut params = Vec::new();
         loop {
             self.get_next_token();
 
             match self.cur_tok.take() {
-                Some(Token::Identifier(arg)) => args.push(arg),
-                Some(Token::Char(',')) => {}
+                Some(Token::Identifier(param)) => params.push(param),
+                Some(Token::Delim(',')) => {}
                 other => {
                     self.cur_tok = other;
                     break;
@@ -378,68 +1401,220 @@ where
             }
         }
 
-        if *self.cur_tok() != Token::Char(')') {
-            return Err("Expected ')' in prototype".into());
+        if *self.cur_tok() != Token::Delim(')') {
+            return Err(self.unexpected(&format!("identifier, ',' or ')' in {}", what)));
         }
 
         // Consume ')'.
         self.get_next_token();
 
-        Ok(PrototypeAST(id_name, args))
+        Ok(params)
     }
 
     /// definition ::= 'def' prototype expression
     ///
     /// Implement `std::unique_ptr<FunctionAST> ParseDefinition();` from the tutorial.
-    pub fn parse_definition(&mut self) -> ParseResult<FunctionAST> {
+    pub fn parse_definition(&mut self) -> ParseResult<Spanned<FunctionAST>> {
+        let start = self.cur_span.start;
         // Consume 'def' token.
-        assert_eq!(*self.cur_tok(), Token::Def);
+        if *self.cur_tok() != Token::Def {
+            return Err(self.expected(&["'def'"]));
+        }
         self.get_next_token();
 
         let proto = self.parse_prototype()?;
         let expr = self.parse_expression()?;
 
-        Ok(FunctionAST(proto, expr))
+        log_event!(debug, "parsed definition '{}'", proto.0);
+        Ok(self.spanned(start, FunctionAST(proto, expr)))
     }
 
     /// external ::= 'extern' prototype
     ///
     /// Implement `std::unique_ptr<PrototypeAST> ParseExtern();` from the tutorial.
-    pub fn parse_extern(&mut self) -> ParseResult<PrototypeAST> {
+    pub fn parse_extern(&mut self) -> ParseResult<Spanned<PrototypeAST>> {
+        let start = self.cur_span.start;
         // Consume 'extern' token.
-        assert_eq!(*self.cur_tok(), Token::Extern);
+        if *self.cur_tok() != Token::Extern {
+            return Err(self.expected(&["'extern'"]));
+        }
+        self.get_next_token();
+
+        let proto = self.parse_prototype()?;
+        log_event!(debug, "parsed extern '{}'", proto.0);
+        Ok(self.spanned(start, proto.node))
+    }
+
+    /// constant ::= 'const' identifier '=' expression
+    pub fn parse_const(&mut self) -> ParseResult<Spanned<ConstAST>> {
+        let start = self.cur_span.start;
+        // Consume 'const' token.
+        if *self.cur_tok() != Token::Const {
+            return Err(self.expected(&["'const'"]));
+        }
+        self.get_next_token();
+
+        let name = match self.cur_tok.take() {
+            Some(Token::Identifier(name)) => {
+                // Consume the identifier.
+                self.get_next_token();
+                name
+            }
+            other => {
+                // Plug back current token.
+                self.cur_tok = other;
+                return Err(self.unexpected("identifier after 'const'"));
+            }
+        };
+
+        // Consume the '=' token.
+        if !self.cur_tok().is_op("=") {
+            return Err(self.expected(&["'='"]));
+        }
         self.get_next_token();
 
-        self.parse_prototype()
+        let init = self.parse_expression()?;
+        log_event!(debug, "parsed constant '{}'", name);
+        Ok(self.spanned(start, ConstAST(name, init)))
     }
 
     /// toplevelexpr ::= expression
     ///
     /// Implement `std::unique_ptr<FunctionAST> ParseTopLevelExpr();` from the tutorial.
-    pub fn parse_top_level_expr(&mut self) -> ParseResult<FunctionAST> {
+    pub fn parse_top_level_expr(&mut self) -> ParseResult<Spanned<FunctionAST>> {
         let e = self.parse_expression()?;
-        let proto = PrototypeAST("__anon_expr".into(), Vec::new());
-        Ok(FunctionAST(proto, e))
+        // The anonymous prototype is not part of the source, it gets an empty span in front of
+        // the expression.
+        let span = e.span;
+        let proto = Spanned::new(
+            PrototypeAST(ANON_EXPR.into(), Vec::new(), 0),
+            Span::new(span.start, span.start),
+        );
+        log_event!(debug, "parsed top-level expression");
+        Ok(Spanned::new(FunctionAST(proto, e), span))
+    }
+
+    /// item
+    ///   ::= definition
+    ///   ::= external
+    ///   ::= constant
+    ///   ::= toplevelexpr
+    ///
+    /// Parse the next item like the driver loop `static void MainLoop();` from the tutorial,
+    /// skipping top-level semicolons in front of it. Return `None` at the end of the input.
+    ///
+    /// Reads the first token if [`get_next_token`](Parser::get_next_token) was not called yet.
+    pub fn parse_item(&mut self) -> ParseResult<Option<Item>> {
+        if self.cur_tok.is_none() {
+            self.get_next_token();
+        }
+
+        // Ignore top-level semicolons.
+        while *self.cur_tok() == Token::Delim(';') {
+            self.get_next_token();
+        }
+
+        match self.cur_tok() {
+            Token::Eof => Ok(None),
+            Token::Def => self.parse_definition().map(|func| Some(Item::Def(func))),
+            Token::Extern => self.parse_extern().map(|proto| Some(Item::Extern(proto))),
+            Token::Const => self.parse_const().map(|c| Some(Item::Const(c))),
+            _ => self
+                .parse_top_level_expr()
+                .map(|func| Some(Item::TopLevel(func))),
+        }
+    }
+
+    /// program ::= (item | ';')*
+    ///
+    /// Parse all the items up to the end of the input.
+    ///
+    /// The parser recovers from errors at the next item, see [`recover`](Parser::recover), on
+    /// failure all errors found by this call are returned.
+    pub fn parse_program(&mut self) -> Result<Vec<Item>, Vec<ParseError>> {
+        let errors = self.errors.len();
+
+        let items: Vec<_> = self.by_ref().filter_map(Result::ok).collect();
+
+        if self.errors.len() == errors {
+            Ok(items)
+        } else {
+            Err(self.errors[errors..].to_vec())
+        }
+    }
+}
+
+/// Iterate over the items of the input, see [`Parser::parse_item`].
+///
+/// After an error the parser recovers at the next item, see [`recover`](Parser::recover), such
+/// that the iteration continues with the following items.
+impl<I> Iterator for Parser<I>
+where
+    I: Iterator<Item = char>,
+{
+    type Item = Result<Item, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.parse_item() {
+            Ok(item) => item.map(Ok),
+            Err(err) => Some(Err(self.recover(err).clone())),
+        }
+    }
+}
+
+/// Parse the single expression `src`.
+///
+/// Return the error of the offending token if `src` is not a well-formed expression or contains
+/// more than one expression.
+pub fn parse_expr(src: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(Lexer::new(src.chars()));
+    parser.get_next_token();
+
+    let expr = parser.parse_expression()?;
+
+    match parser.cur_tok() {
+        Token::Eof => Ok(expr),
+        _ => {
+            let msg = format!(
+                "unexpected token '{}' after expression",
+                parser.cur_lexeme()
+            );
+            Err(parser.error(msg, &["end of input", BINOP]))
+        }
     }
 }
 
-/// Get the binary operator precedence.
+/// Parse all the items in `src`, see [`Parser::parse_program`].
 ///
-/// Implement `int GetTokPrecedence();` from the tutorial.
-fn get_tok_precedence(tok: &Token) -> isize {
-    match tok {
-        Token::Char('<') => 10,
-        Token::Char('+') => 20,
-        Token::Char('-') => 20,
-        Token::Char('*') => 40,
+/// The parser recovers from errors at the next item, on failure all errors in `src` are returned.
+pub fn parse_items(src: &str) -> Result<Vec<Item>, Vec<ParseError>> {
+    Parser::new(Lexer::new(src.chars())).parse_program()
+}
+
+/// Get the precedence of the binary operator `op`, `-1` if `op` is not a binary operator.
+///
+/// All binary operators are left associative, including `^`.
+fn binop_precedence(op: &str) -> isize {
+    match op {
+        "||" => 5,
+        "&&" => 6,
+        "<" | ">" | "<=" | ">=" | "==" | "!=" => 10,
+        "+" => 20,
+        "-" => 20,
+        "*" => 40,
+        "%" => 40,
+        "^" => 60,
         _ => -1,
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ExprAST, FunctionAST, Parser, PrototypeAST};
-    use crate::lexer::Lexer;
+    use super::{
+        parse_expr, parse_items, ConstAST, Expr, ExprAST, FunctionAST, Item, LogicalOp, ParseError,
+        ParseErrorKind, Parser, PrototypeAST, DEFAULT_BINOP_PRECEDENCE, MAX_DEPTH,
+    };
+    use crate::lexer::{Lexer, Span, Token};
 
     fn parser(input: &str) -> Parser<std::str::Chars> {
         let l = Lexer::new(input.chars());
@@ -451,11 +1626,17 @@ mod test {
         p
     }
 
+    fn boxed(expr: ExprAST) -> Box<Expr> {
+        Box::new(expr.into())
+    }
+
     #[test]
     fn parse_number() {
-        let mut p = parser("13.37");
+        let mut p = parser("13.37 42 '*'");
 
         assert_eq!(p.parse_num_expr(), Ok(ExprAST::Number(13.37f64)));
+        assert_eq!(p.parse_num_expr(), Ok(ExprAST::Number(42f64)));
+        assert_eq!(p.parse_num_expr(), Ok(ExprAST::Number(42f64)));
     }
 
     #[test]
@@ -472,17 +1653,23 @@ mod test {
     fn parse_if() {
         let mut p = parser("if 1 then 2 else 3");
 
-        let cond = Box::new(ExprAST::Number(1f64));
-        let then = Box::new(ExprAST::Number(2f64));
-        let else_ = Box::new(ExprAST::Number(3f64));
+        let cond = boxed(ExprAST::Number(1f64));
+        let then = boxed(ExprAST::Number(2f64));
+        let else_ = boxed(ExprAST::Number(3f64));
 
         assert_eq!(p.parse_if_expr(), Ok(ExprAST::If { cond, then, else_ }));
 
         let mut p = parser("if foo() then bar(2) else baz(3)");
 
-        let cond = Box::new(ExprAST::Call("foo".into(), vec![]));
-        let then = Box::new(ExprAST::Call("bar".into(), vec![ExprAST::Number(2f64)]));
-        let else_ = Box::new(ExprAST::Call("baz".into(), vec![ExprAST::Number(3f64)]));
+        let cond = boxed(ExprAST::Call("foo".into(), vec![]));
+        let then = boxed(ExprAST::Call(
+            "bar".into(),
+            vec![ExprAST::Number(2f64).into()],
+        ));
+        let else_ = boxed(ExprAST::Call(
+            "baz".into(),
+            vec![ExprAST::Number(3f64).into()],
+        ));
 
         assert_eq!(p.parse_if_expr(), Ok(ExprAST::If { cond, then, else_ }));
     }
@@ -492,10 +1679,10 @@ mod test {
         let mut p = parser("for i = 1, 2, 3 in 4");
 
         let var = String::from("i");
-        let start = Box::new(ExprAST::Number(1f64));
-        let end = Box::new(ExprAST::Number(2f64));
-        let step = Some(Box::new(ExprAST::Number(3f64)));
-        let body = Box::new(ExprAST::Number(4f64));
+        let start = boxed(ExprAST::Number(1f64));
+        let end = boxed(ExprAST::Number(2f64));
+        let step = Some(boxed(ExprAST::Number(3f64)));
+        let body = boxed(ExprAST::Number(4f64));
 
         assert_eq!(
             p.parse_for_expr(),
@@ -514,10 +1701,10 @@ mod test {
         let mut p = parser("for i = 1, 2 in 4");
 
         let var = String::from("i");
-        let start = Box::new(ExprAST::Number(1f64));
-        let end = Box::new(ExprAST::Number(2f64));
+        let start = boxed(ExprAST::Number(1f64));
+        let end = boxed(ExprAST::Number(2f64));
         let step = None;
-        let body = Box::new(ExprAST::Number(4f64));
+        let body = boxed(ExprAST::Number(4f64));
 
         assert_eq!(
             p.parse_for_expr(),
@@ -531,36 +1718,154 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_var() {
+        let mut p = parser("var a = 1, b in a + b");
+
+        let var = |name: &str| ExprAST::Variable(name.into());
+        assert_eq!(
+            p.parse_var_expr(),
+            Ok(ExprAST::VarIn {
+                vars: vec![
+                    ("a".into(), Some(ExprAST::Number(1f64).into())),
+                    ("b".into(), None)
+                ],
+                body: boxed(ExprAST::Binary(
+                    "+".into(),
+                    boxed(var("a")),
+                    boxed(var("b"))
+                )),
+            })
+        );
+
+        assert_eq!(
+            parser("var in 1").parse_var_expr().map_err(|err| err.msg),
+            Err("expected identifier after 'var', found 'in'".into())
+        );
+        assert_eq!(
+            parser("var x 1").parse_var_expr().map_err(|err| err.msg),
+            Err("expected '=', ',' or 'in', found '1'".into())
+        );
+    }
+
+    #[test]
+    fn parse_assign() {
+        let assign = |name: &str, value| ExprAST::Assign {
+            name: name.into(),
+            value: boxed(value),
+        };
+        let var = |name: &str| ExprAST::Variable(name.into());
+
+        assert_eq!(
+            parser("a = b = c + 1")
+                .parse_expression()
+                .map(|expr| expr.node),
+            Ok(assign(
+                "a",
+                assign(
+                    "b",
+                    ExprAST::Binary("+".into(), boxed(var("c")), boxed(ExprAST::Number(1f64)))
+                )
+            ))
+        );
+
+        let mut p = parser("a + b = 1");
+        assert_eq!(
+            p.parse_expression().map_err(|err| err.msg),
+            Err("destination of '=' must be a variable".into())
+        );
+        assert_eq!(p.cur_span(), Span::new(6, 7));
+    }
+
+    #[test]
+    fn parse_unary() {
+        let neg = |expr| ExprAST::Unary("-".into(), boxed(expr));
+        let var = |name: &str| ExprAST::Variable(name.into());
+
+        // Unary operators bind tighter than any binary operator.
+        assert_eq!(
+            parser("-a * - -b").parse_expression().map(|expr| expr.node),
+            Ok(ExprAST::Binary(
+                "*".into(),
+                boxed(neg(var("a"))),
+                boxed(neg(neg(var("b"))))
+            ))
+        );
+        assert_eq!(
+            parser("a - -1").parse_expression().map(|expr| expr.node),
+            Ok(ExprAST::Binary(
+                "-".into(),
+                boxed(var("a")),
+                boxed(neg(ExprAST::Number(1f64)))
+            ))
+        );
+        assert!(parser("- )").parse_expression().is_err());
+    }
+
+    #[test]
+    fn parse_logical() {
+        let var = |name: &str| boxed(ExprAST::Variable(name.into()));
+
+        // '&&' binds tighter than '||', both bind weaker than comparisons.
+        assert_eq!(
+            parser("a || b && c < 1")
+                .parse_expression()
+                .map(|expr| expr.node),
+            Ok(ExprAST::Logical(
+                LogicalOp::Or,
+                var("a"),
+                boxed(ExprAST::Logical(
+                    LogicalOp::And,
+                    var("b"),
+                    boxed(ExprAST::Binary(
+                        "<".into(),
+                        var("c"),
+                        boxed(ExprAST::Number(1f64))
+                    ))
+                ))
+            ))
+        );
+    }
+
     #[test]
     fn parse_primary() {
         let mut p = parser("1337 foop \n bla(123) \n if a then b else c \n for x=1,2 in 3");
 
-        assert_eq!(p.parse_primary(), Ok(ExprAST::Number(1337f64)));
+        assert_eq!(
+            p.parse_primary().map(|expr| expr.node),
+            Ok(ExprAST::Number(1337f64))
+        );
 
-        assert_eq!(p.parse_primary(), Ok(ExprAST::Variable("foop".into())));
+        assert_eq!(
+            p.parse_primary().map(|expr| expr.node),
+            Ok(ExprAST::Variable("foop".into()))
+        );
 
         assert_eq!(
-            p.parse_primary(),
-            Ok(ExprAST::Call("bla".into(), vec![ExprAST::Number(123f64)]))
+            p.parse_primary().map(|expr| expr.node),
+            Ok(ExprAST::Call(
+                "bla".into(),
+                vec![ExprAST::Number(123f64).into()]
+            ))
         );
 
         assert_eq!(
-            p.parse_primary(),
+            p.parse_primary().map(|expr| expr.node),
             Ok(ExprAST::If {
-                cond: Box::new(ExprAST::Variable("a".into())),
-                then: Box::new(ExprAST::Variable("b".into())),
-                else_: Box::new(ExprAST::Variable("c".into())),
+                cond: boxed(ExprAST::Variable("a".into())),
+                then: boxed(ExprAST::Variable("b".into())),
+                else_: boxed(ExprAST::Variable("c".into())),
             })
         );
 
         assert_eq!(
-            p.parse_primary(),
+            p.parse_primary().map(|expr| expr.node),
             Ok(ExprAST::For {
                 var: String::from("x"),
-                start: Box::new(ExprAST::Number(1f64)),
-                end: Box::new(ExprAST::Number(2f64)),
+                start: boxed(ExprAST::Number(1f64)),
+                end: boxed(ExprAST::Number(2f64)),
                 step: None,
-                body: Box::new(ExprAST::Number(3f64)),
+                body: boxed(ExprAST::Number(3f64)),
             })
         );
     }
@@ -577,18 +1882,18 @@ mod test {
         let mut p = parser("a + b - c");
 
         let binexpr_ab = ExprAST::Binary(
-            '+',
-            Box::new(ExprAST::Variable("a".into())),
-            Box::new(ExprAST::Variable("b".into())),
+            "+".into(),
+            boxed(ExprAST::Variable("a".into())),
+            boxed(ExprAST::Variable("b".into())),
         );
 
         let binexpr_abc = ExprAST::Binary(
-            '-',
-            Box::new(binexpr_ab),
-            Box::new(ExprAST::Variable("c".into())),
+            "-".into(),
+            boxed(binexpr_ab),
+            boxed(ExprAST::Variable("c".into())),
         );
 
-        assert_eq!(p.parse_expression(), Ok(binexpr_abc));
+        assert_eq!(p.parse_expression().map(|expr| expr.node), Ok(binexpr_abc));
     }
 
     #[test]
@@ -603,52 +1908,606 @@ mod test {
         let mut p = parser("a + b * c");
 
         let binexpr_bc = ExprAST::Binary(
-            '*',
-            Box::new(ExprAST::Variable("b".into())),
-            Box::new(ExprAST::Variable("c".into())),
+            "*".into(),
+            boxed(ExprAST::Variable("b".into())),
+            boxed(ExprAST::Variable("c".into())),
         );
 
         let binexpr_abc = ExprAST::Binary(
-            '+',
-            Box::new(ExprAST::Variable("a".into())),
-            Box::new(binexpr_bc),
+            "+".into(),
+            boxed(ExprAST::Variable("a".into())),
+            boxed(binexpr_bc),
         );
 
-        assert_eq!(p.parse_expression(), Ok(binexpr_abc));
+        assert_eq!(p.parse_expression().map(|expr| expr.node), Ok(binexpr_abc));
     }
 
     #[test]
     fn parse_prototype() {
         let mut p = parser("foo(a,b)");
 
-        let proto = PrototypeAST("foo".into(), vec!["a".into(), "b".into()]);
+        let proto = PrototypeAST("foo".into(), vec!["a".into(), "b".into()], 0);
 
-        assert_eq!(p.parse_prototype(), Ok(proto));
+        assert_eq!(p.parse_prototype().map(|proto| proto.node), Ok(proto));
     }
 
     #[test]
     fn parse_definition() {
         let mut p = parser("def bar( arg0 , arg1 ) arg0 + arg1");
 
-        let proto = PrototypeAST("bar".into(), vec!["arg0".into(), "arg1".into()]);
+        let proto = PrototypeAST("bar".into(), vec!["arg0".into(), "arg1".into()], 0);
 
         let body = ExprAST::Binary(
-            '+',
-            Box::new(ExprAST::Variable("arg0".into())),
-            Box::new(ExprAST::Variable("arg1".into())),
+            "+".into(),
+            boxed(ExprAST::Variable("arg0".into())),
+            boxed(ExprAST::Variable("arg1".into())),
         );
 
-        let func = FunctionAST(proto, body);
+        let func = FunctionAST(proto.into(), body.into());
 
-        assert_eq!(p.parse_definition(), Ok(func));
+        assert_eq!(p.parse_definition().map(|func| func.node), Ok(func));
+    }
+
+    #[test]
+    fn parse_spans() {
+        let expr = parse_expr("foo(1) + -(2 * x)").unwrap();
+        assert_eq!(expr.span, Span::new(0, 17));
+        match &expr.node {
+            ExprAST::Binary(_, lhs, rhs) => {
+                assert_eq!(lhs.span, Span::new(0, 6));
+                assert_eq!(rhs.span, Span::new(9, 17));
+                match &rhs.node {
+                    // Parentheses are part of the span of the parenthesized expression.
+                    ExprAST::Unary(_, operand) => assert_eq!(operand.span, Span::new(10, 17)),
+                    _ => panic!("expected unary expression"),
+                }
+            }
+            _ => panic!("expected binary expression"),
+        }
+
+        let mut p = parser("def f(x) a = x # comment\n; 1");
+        let func = p.parse_definition().unwrap();
+        assert_eq!(func.span, Span::new(0, 14));
+        assert_eq!(func.0.span, Span::new(4, 8));
+        assert_eq!(func.1.span, Span::new(9, 14));
+
+        p.get_next_token();
+        let func = p.parse_top_level_expr().unwrap();
+        assert_eq!(func.span, Span::new(27, 28));
+        assert_eq!(func.0.span, Span::new(27, 27));
+    }
+
+    #[test]
+    fn parse_skip_comments() {
+        let mut l = Lexer::new("extern # sin\n baz() # end".chars());
+        l.set_keep_comments(true);
+        let mut p = Parser::new(l);
+        p.get_next_token();
+
+        assert_eq!(
+            p.parse_extern().map(|proto| proto.node),
+            Ok(PrototypeAST("baz".into(), vec![], 0))
+        );
+        assert_eq!(*p.cur_tok(), Token::Eof);
     }
 
     #[test]
     fn parse_extern() {
         let mut p = parser("extern baz()");
 
-        let proto = PrototypeAST("baz".into(), vec![]);
+        let proto = PrototypeAST("baz".into(), vec![], 0);
+
+        assert_eq!(p.parse_extern().map(|proto| proto.node), Ok(proto));
+    }
+
+    #[test]
+    fn parse_const() {
+        let mut p = parser("const half = 0.5; const 2");
+
+        let c = p.parse_const().unwrap();
+        assert_eq!(c.node, ConstAST("half".into(), ExprAST::Number(0.5).into()));
+        assert_eq!(c.span, Span::new(0, 16));
+        assert_eq!(c.to_string(), "const half = 0.5");
+
+        p.get_next_token();
+        assert_eq!(
+            p.parse_const().unwrap_err().msg,
+            "expected identifier after 'const', found '2'"
+        );
+    }
+
+    #[test]
+    fn print_source() {
+        let print = |src: &str| {
+            let func = parser(src).parse_top_level_expr().unwrap();
+            let out = func.to_string();
+            // The rendered source must parse to the same AST.
+            assert_eq!(parser(&out).parse_top_level_expr(), Ok(func));
+            out
+        };
 
-        assert_eq!(p.parse_extern(), Ok(proto));
+        assert_eq!(print("(a + b) - c"), "a + b - c");
+        assert_eq!(print("a - (b - c)"), "a - (b - c)");
+        assert_eq!(print("(a + b) * c"), "(a + b) * c");
+        assert_eq!(print("a < (b * 2)"), "a < b * 2");
+        assert_eq!(print("(a % b) * c ^ (d ^ e)"), "a % b * c ^ (d ^ e)");
+        assert_eq!(print("(a * b) ^ 2"), "(a * b) ^ 2");
+        assert_eq!(
+            print("printd(\"a\\\"b\\\\c\\n\\x01\\x41\")"),
+            "printd(\"a\\\"b\\\\c\\n\\x01A\")"
+        );
+        assert_eq!(print("f((1), g(x+1))"), "f(1, g(x + 1))");
+        assert_eq!(
+            print("(if a then 1 else 2) + 1"),
+            "(if a then 1 else 2) + 1"
+        );
+        assert_eq!(
+            print("1.5 + for i=0,i<3 in i"),
+            "1.5 + (for i = 0, i < 3 in i)"
+        );
+        assert_eq!(print("1 + var a=1,b in a*b"), "1 + (var a = 1, b in a * b)");
+        assert_eq!(print("a = b = (c = 1) + 1"), "a = b = (c = 1) + 1");
+        assert_eq!(print("-(a + b) * - -c"), "-(a + b) * -(-c)");
+        assert_eq!(print("a - (-b)"), "a - -b");
+        assert_eq!(print("(a || b) && c < 1"), "(a || b) && c < 1");
+        assert_eq!(print("f(\\(a b) a * b, 1)"), "f(\\(a, b) a * b, 1)");
+        assert_eq!(print("(\\() 1) + 1"), "(\\() 1) + 1");
+        assert_eq!(
+            print("1 + match a with 1->x|'a' -> y | _ -> match b with _ -> z"),
+            "1 + (match a with 1 -> x | 97 -> y | _ -> match b with _ -> z)"
+        );
+        assert_eq!(
+            print("for i = 0, 1 in if i then break else continue"),
+            "for i = 0, 1 in if i then break else continue"
+        );
+
+        let mut p = parser("def foo(a, b) if a < b then a else b");
+        let func = p.parse_definition().unwrap();
+        assert_eq!(func.to_string(), "def foo(a, b) if a < b then a else b");
+    }
+
+    #[test]
+    fn parse_match() {
+        assert_eq!(
+            parse_expr("match x with -1 -> a | _ -> b").map(|expr| expr.node),
+            Ok(ExprAST::Match {
+                value: boxed(ExprAST::Variable("x".into())),
+                arms: vec![(-1f64, ExprAST::Variable("a".into()).into())],
+                default: boxed(ExprAST::Variable("b".into())),
+            })
+        );
+
+        let err = |src| parse_expr(src).unwrap_err().msg;
+        assert_eq!(
+            err("match x 1"),
+            "expected 'with' or binary operator, found '1'"
+        );
+        assert_eq!(
+            err("match x with y -> 1"),
+            "expected number or '_', found 'y'"
+        );
+        assert_eq!(
+            err("match x with 1 -> a _ -> b"),
+            "expected '|' or binary operator, found '_'"
+        );
+        assert_eq!(
+            err("match x with 1 -> a | - _ -> b"),
+            "expected number, found '_'"
+        );
+
+        // A user-defined '|' operator only continues an arm followed by another arm if it is
+        // parenthesized.
+        let items = parse_items(
+            "def binary| 5 (a b) a; match x with 1 -> a | 2 -> (b | c) | -3 -> f(d | e) | _ -> g | h",
+        )
+        .unwrap();
+        match &items[..] {
+            [Item::Def(_), Item::TopLevel(expr)] => assert_eq!(
+                expr.1.to_string(),
+                "match x with 1 -> a | 2 -> (b | c) | -3 -> f(d | e) | _ -> g | h"
+            ),
+            _ => panic!("expected a definition and an expression"),
+        }
+        assert_eq!(
+            parse_expr("match x with 1 -> if a then b else c | _ -> d")
+                .unwrap()
+                .to_string(),
+            "match x with 1 -> (if a then b else c) | _ -> d"
+        );
+    }
+
+    #[test]
+    fn parse_lambda() {
+        assert_eq!(
+            parse_expr("\\(x) x + 1").map(|expr| expr.node),
+            Ok(ExprAST::Lambda(
+                vec!["x".into()],
+                boxed(ExprAST::Binary(
+                    "+".into(),
+                    boxed(ExprAST::Variable("x".into())),
+                    boxed(ExprAST::Number(1f64))
+                ))
+            ))
+        );
+        assert_eq!(
+            parse_expr("\\x").unwrap_err().msg,
+            "expected '(' in lambda, found 'x'"
+        );
+    }
+
+    #[test]
+    fn parse_loop_jump() {
+        assert_eq!(
+            parse_expr("for i = 0, 1 in break").map(|expr| expr.node),
+            Ok(ExprAST::For {
+                var: "i".into(),
+                start: boxed(ExprAST::Number(0f64)),
+                end: boxed(ExprAST::Number(1f64)),
+                step: None,
+                body: boxed(ExprAST::Break),
+            })
+        );
+        assert!(parse_expr("for i = 0, 1 in for j = 0, 1 in f(continue)").is_ok());
+
+        // The loop must enclose the jump, lambdas start a new function body.
+        let err = |src| parse_expr(src).unwrap_err().msg;
+        assert_eq!(err("break"), "'break' outside of a loop body");
+        assert_eq!(
+            err("for i = 0, continue in 1"),
+            "'continue' outside of a loop body"
+        );
+        assert_eq!(
+            err("for i = 0, 1 in \\() break"),
+            "'break' outside of a loop body"
+        );
+        assert_eq!(
+            err("(for i = 0, 1 in 1) + break"),
+            "'break' outside of a loop body"
+        );
+    }
+
+    #[test]
+    fn parse_peek() {
+        let mut p = Parser::new({
+            let mut lex = Lexer::new("foo # c\n (x)".chars());
+            lex.set_keep_comments(true);
+            lex
+        });
+        p.get_next_token();
+        assert_eq!(p.peek_tok(), &Token::Delim('('));
+        assert_eq!(p.cur_tok(), &Token::Identifier("foo".into()));
+        assert_eq!(p.cur_lexeme(), "foo");
+        p.get_next_token();
+        assert_eq!(p.cur_tok(), &Token::Delim('('));
+    }
+
+    #[test]
+    fn parse_invalid_number() {
+        let mut p = parser("1 + 12.34.56");
+
+        assert_eq!(
+            p.parse_expression().map_err(|err| err.msg),
+            Err("invalid number '12.34.56'".into())
+        );
+        assert_eq!(p.cur_span(), Span::new(4, 12));
+
+        let mut p = parser("def f(x) x + 0x1p3; def \"g");
+        assert!(p.parse_definition().is_ok());
+        p.get_next_token();
+        assert_eq!(
+            p.parse_definition().map_err(|err| err.msg),
+            Err("unterminated string literal".into())
+        );
+    }
+
+    #[test]
+    fn parse_nesting_limit() {
+        let nested = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+
+        let src = nested(100);
+        let mut p = parser(&src);
+        assert_eq!(
+            p.parse_expression().map(|expr| expr.node),
+            Ok(ExprAST::Number(1f64))
+        );
+
+        let src = nested(100_000);
+        let mut p = parser(&src);
+        assert_eq!(
+            p.parse_expression().map_err(|err| err.msg),
+            Err("expression too deeply nested".into())
+        );
+        assert_eq!(p.cur_span(), Span::new(MAX_DEPTH, MAX_DEPTH + 1));
+
+        let mut p = parser("f(if a then b else (c))");
+        p.set_max_depth(3);
+        assert!(p.parse_expression().is_err());
+
+        // The depth is restored after errors.
+        let mut p = parser("(1 +) (1)");
+        p.set_max_depth(2);
+        assert!(p.parse_expression().is_err());
+        p.get_next_token();
+        assert_eq!(
+            p.parse_expression().map(|expr| expr.node),
+            Ok(ExprAST::Number(1f64))
+        );
+    }
+
+    #[test]
+    fn parse_expected_tokens() {
+        let err = |src: &str| parse_expr(src).unwrap_err().msg;
+
+        assert_eq!(err("(1 2)"), "expected ')' or binary operator, found '2'");
+        assert_eq!(
+            err("f(1; 2)"),
+            "expected ')', ',' or binary operator, found ';'"
+        );
+        assert_eq!(
+            err("if a else b"),
+            "expected 'then' or binary operator, found 'else'"
+        );
+        assert_eq!(
+            err("for i = 1, 2 if"),
+            "expected ',', 'in' or binary operator, found 'if'"
+        );
+        assert_eq!(
+            err("1 +"),
+            "unexpected end of input when expecting an expression, expected number, \
+             string, identifier, '(', 'if', 'for', 'var', 'match' or '\\'"
+        );
+    }
+
+    #[test]
+    fn parse_from_str() {
+        assert_eq!(
+            parse_expr(" foo ").map(|expr| expr.node),
+            Ok(ExprAST::Variable("foo".into()))
+        );
+        assert_eq!(
+            parse_expr("a b"),
+            Err(ParseError {
+                kind: ParseErrorKind::Syntax,
+                msg: "unexpected token 'b' after expression".into(),
+                span: Span::new(2, 3),
+                token: Token::Identifier("b".into()),
+                expected: vec!["end of input".into(), "binary operator".into()],
+            })
+        );
+        assert!(parse_expr("").is_err());
+
+        let func = |name: &str, expr: ExprAST| {
+            let proto = PrototypeAST(name.into(), vec![], 0);
+            FunctionAST(proto.into(), expr.into()).into()
+        };
+        let anon = |expr| func(super::ANON_EXPR, expr);
+        assert_eq!(
+            parse_items("extern sin(x); def one() 1; one()"),
+            Ok(vec![
+                Item::Extern(PrototypeAST("sin".into(), vec!["x".into()], 0).into()),
+                Item::Def(func("one", ExprAST::Number(1f64))),
+                Item::TopLevel(anon(ExprAST::Call("one".into(), vec![]))),
+            ])
+        );
+        assert_eq!(parse_items("def (x) x; 1 + ; 2").unwrap_err().len(), 2);
+
+        // Each malformed item is reported once, at its offending token.
+        let spans: Vec<_> = parse_items("extern f(1) def g(x) x def h(x) (x; 1 + ) extern k()")
+            .unwrap_err()
+            .into_iter()
+            .map(|err| err.span)
+            .collect();
+        assert_eq!(
+            spans,
+            vec![Span::new(9, 10), Span::new(34, 35), Span::new(40, 41)]
+        );
+    }
+
+    #[test]
+    fn parse_program() {
+        // The first token is read by the parser.
+        let mut p = Parser::new(Lexer::new(";; extern f(x); f(1);".chars()));
+        let items = p.parse_program().unwrap();
+        assert!(matches!(items[..], [Item::Extern(_), Item::TopLevel(_)]));
+        assert_eq!(p.parse_item(), Ok(None));
+
+        // Only errors of this call are returned, all are kept in the parser.
+        let mut p = parser("def f(; g(");
+        assert!(p.parse_item().is_err());
+        assert_eq!(p.parse_program().unwrap_err().len(), 1);
+        assert_eq!(p.errors().len(), 1);
+    }
+
+    #[test]
+    fn parse_operator_def() {
+        // The precedence of a user-defined operator applies to all following items.
+        let items = parse_items("def binary| 5 (a b) a; extern unary!(v); 1 | 2 * !3").unwrap();
+        match &items[..] {
+            [Item::Def(def), Item::Extern(ext), Item::TopLevel(expr)] => {
+                let or = PrototypeAST("binary|".into(), vec!["a".into(), "b".into()], 5);
+                assert_eq!(def.0.node, or);
+                assert_eq!(ext.node, PrototypeAST("unary!".into(), vec!["v".into()], 0));
+                assert_eq!(def.to_string(), "def binary| 5(a, b) a");
+                assert_eq!(expr.to_string(), "1 | (2 * !3)");
+            }
+            _ => panic!("expected a definition, an extern and an expression"),
+        }
+
+        // Operators defined by a previous input are declared up front.
+        let mut p = parser("1 |> 2 * 3 + ~~4");
+        p.declare_operator(&PrototypeAST("binary|>".into(), vec![], 50));
+        p.declare_operator(&PrototypeAST("unary~~".into(), vec![], 0));
+        let expr = p.parse_top_level_expr().unwrap();
+        assert_eq!(expr.1.to_string(), "(1 |> 2) * 3 + ~~4");
+
+        let def = |src| parser(src).parse_definition().map(|func| func.0 .2);
+        assert_eq!(def("def binary~(a b) a"), Ok(DEFAULT_BINOP_PRECEDENCE));
+
+        let err = |src| def(src).unwrap_err().msg;
+        assert_eq!(
+            err("def binary+ (a b) a"),
+            "can't redefine the built-in operator '+'"
+        );
+        assert_eq!(
+            err("def binary= (a b) a"),
+            "can't redefine the built-in operator '='"
+        );
+        assert_eq!(
+            err("def unary- (a) a"),
+            "can't redefine the built-in operator '-'"
+        );
+        assert_eq!(
+            err("def binary| 0 (a b) a"),
+            "Invalid precedence: must be 1..100"
+        );
+        assert_eq!(
+            err("def unary!(a b) a"),
+            "Invalid number of operands for operator '!', expected 1"
+        );
+        assert_eq!(
+            err("def binary x(a b) a"),
+            "expected operator after 'binary', found 'x'"
+        );
+    }
+
+    #[test]
+    fn parse_need_more_input() {
+        let kind = |src| parser(src).find_map(Result::err).map(|err| err.kind);
+        assert_eq!(kind("if x then 1"), Some(ParseErrorKind::NeedMoreInput));
+        assert_eq!(kind("def f(x"), Some(ParseErrorKind::NeedMoreInput));
+        assert_eq!(kind("\"abc"), Some(ParseErrorKind::NeedMoreInput));
+        assert_eq!(kind("if x then 1 )"), Some(ParseErrorKind::Syntax));
+        assert_eq!(kind("def f(x) x"), None);
+    }
+
+    #[test]
+    fn parse_iter() {
+        // Iteration continues with the next item after an error.
+        let items: Vec<_> = parser("def f(x x; extern g(); 1 +; g()").collect();
+        assert_eq!(items.len(), 4);
+        assert!(items[0].is_err());
+        assert!(matches!(items[1], Ok(Item::Extern(_))));
+        assert!(items[2].is_err());
+        assert_eq!(items[3].as_ref().map(Item::span), Ok(Span::new(28, 31)));
+
+        let mut p = parser("; ;");
+        assert_eq!(p.next(), None);
+        assert_eq!(p.next(), None);
+    }
+
+    #[test]
+    fn parse_operator_tokens() {
+        // Only operator tokens with a known precedence continue a binary expression, delimiters
+        // and unknown operators end it.
+        let unexpected = |src| parse_expr(src).unwrap_err().msg;
+        assert_eq!(unexpected("a , b"), "unexpected token ',' after expression");
+        assert_eq!(unexpected("a ~ b"), "unexpected token '~' after expression");
+        assert!(unexpected("a < )").starts_with("unknown token ')' when expecting"));
+        assert!(parse_expr("a < -b").is_ok());
+        assert!(parse_expr("a<b").is_ok());
+        assert!(parse_expr("a<=b").is_ok());
+
+        // Operator chars not forming a known operator are separate tokens.
+        assert_eq!(
+            parse_expr("x*-1+a<-b").unwrap().to_string(),
+            "x * -1 + a < -b"
+        );
+        assert_eq!(parse_expr("a==-1").unwrap().to_string(), "a == -1");
+    }
+
+    #[test]
+    fn parse_error_span() {
+        let mut p = parser("def foo(x) x + )");
+
+        assert!(p.parse_definition().is_err());
+        assert_eq!(p.cur_span(), Span::new(15, 16));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        // Parse all items like the driver, malformed input must never panic.
+        let parse_all = |src: &str| {
+            let mut p = parser(src);
+            loop {
+                let res = match p.cur_tok() {
+                    Token::Eof => break,
+                    Token::Delim(';') => {
+                        p.get_next_token();
+                        continue;
+                    }
+                    Token::Def => p.parse_definition().map(drop),
+                    Token::Extern => p.parse_extern().map(drop),
+                    _ => p.parse_top_level_expr().map(drop),
+                };
+                if let Err(err) = res {
+                    p.recover(err);
+                }
+            }
+            p.errors().len()
+        };
+
+        assert_eq!(parse_all("for f(x) = 1, 2 in x"), 1);
+        assert_eq!(parse_all("for 1 = 1, 2 in 3"), 1);
+        assert_eq!(parse_all("for x = 1 if 2 in 3"), 1);
+        assert_eq!(parse_all("1 then 2; a + else"), 2);
+        assert_eq!(parse_all("def extern 1 (; f(1 2"), 3);
+        assert_eq!(parse_all("def"), 1);
+        assert_eq!(parse_all("if a then"), 1);
+        assert_eq!(parse_all(")"), 1);
+
+        // Parse functions called on the wrong token.
+        let mut p = Parser::new(Lexer::new("foo".chars()));
+        assert_eq!(*p.cur_tok(), Token::Eof);
+        p.get_next_token();
+        assert!(p.parse_definition().is_err());
+        assert!(p.parse_extern().is_err());
+        assert!(p.parse_num_expr().is_err());
+        assert!(p.parse_paren_expr().is_err());
+        assert!(p.parse_if_expr().is_err());
+        assert!(p.parse_for_expr().is_err());
+        assert_eq!(
+            p.parse_identifier_expr(),
+            Ok(ExprAST::Variable("foo".into()))
+        );
+        assert!(p.parse_identifier_expr().is_err());
+    }
+
+    #[test]
+    fn parse_error_recovery() {
+        let mut p = parser("def foo(x) x + ) 1; def (x) x extern bar(x)");
+
+        let err = p.parse_definition().unwrap_err();
+        p.recover(err);
+        assert_eq!(*p.cur_tok(), Token::Delim(';'));
+        p.get_next_token();
+
+        let err = p.parse_definition().unwrap_err();
+        p.recover(err);
+        assert_eq!(
+            p.parse_extern().map(|proto| proto.node),
+            Ok(PrototypeAST("bar".into(), vec!["x".into()], 0))
+        );
+
+        assert_eq!(
+            p.errors(),
+            &[
+                ParseError {
+                    kind: ParseErrorKind::Syntax,
+                    msg: "unknown token ')' when expecting an expression, expected number, \
+                          string, identifier, '(', 'if', 'for', 'var', 'match' or '\\'"
+                        .into(),
+                    span: Span::new(15, 16),
+                    token: Token::Delim(')'),
+                    expected: super::EXPR_START.iter().map(|&tok| tok.into()).collect(),
+                },
+                ParseError {
+                    kind: ParseErrorKind::Syntax,
+                    msg: "expected function name in prototype, found '('".into(),
+                    span: Span::new(24, 25),
+                    token: Token::Delim('('),
+                    expected: vec!["function name in prototype".into()],
+                },
+            ]
+        );
     }
 }
@@ -0,0 +1,286 @@
+//! Embeddable kaleidoscope session.
+//!
+//! A [`Session`] compiles and evaluates kaleidoscope source with the LLVM JIT, which allows other
+//! Rust programs to embed the language without reimplementing the driver.
+//!
+//! ```no_run
+//! use llvm_kaleidoscope_rs::{llvm, session::Session};
+//!
+//! llvm::initialize_native_taget();
+//! let jit = llvm::LLJit::new();
+//!
+//! let mut session = Session::new(&jit);
+//! session.define("def sq(x) x * x").unwrap();
+//! assert_eq!(session.eval("sq(4)").unwrap(), Some(16.0));
+//! ```
+//!
+//! A [`CompiledProgram`] owns its JIT and hands out the defined functions as Rust closures.
+//!
+//! ```no_run
+//! use llvm_kaleidoscope_rs::session::CompiledProgram;
+//!
+//! let program = CompiledProgram::new("def fib(x) if x < 3 then 1 else fib(x-1) + fib(x-2)").unwrap();
+//! let fib = program.function::<(f64,)>("fib").unwrap();
+//! assert_eq!(fib((10.0,)), 55.0);
+//! ```
+
+use crate::backend::{compile_item, Backend, JitBackend};
+use crate::codegen::{CodegenError, CodegenOptions};
+use crate::lexer::{Lexer, Span};
+use crate::llvm::{self, JitFn, LLJit};
+use crate::parser::{Item, ParseError, Parser};
+
+/// Result type of [`Session`] operations with [`SessionError`] as Error type.
+pub type SessionResult<T> = Result<T, SessionError>;
+
+/// Error of a [`Session`] operation together with the location of the offending source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SessionError {
+    pub msg: String,
+    /// Span of the offending token, expression or item in the source passed to the session,
+    /// `None` if the error doesn't concern the source, eg for an unknown function looked up.
+    pub span: Option<Span>,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl From<ParseError> for SessionError {
+    fn from(err: ParseError) -> Self {
+        SessionError {
+            msg: err.msg,
+            span: Some(err.span),
+        }
+    }
+}
+
+impl From<CodegenError> for SessionError {
+    fn from(err: CodegenError) -> Self {
+        SessionError {
+            msg: err.msg,
+            span: err.span,
+        }
+    }
+}
+
+impl From<SessionError> for String {
+    fn from(err: SessionError) -> Self {
+        err.msg
+    }
+}
+
+/// Kaleidoscope session evaluating source code with the LLVM JIT.
+///
+/// Functions defined in a session persist across calls and can be redefined. The session borrows
+/// the `jit`, which must outlive it.
+pub struct Session<'jit> {
+    backend: JitBackend<'jit>,
+}
+
+impl<'jit> Session<'jit> {
+    /// Create a new session emitting code into `jit`.
+    ///
+    /// To call functions of the host process from kaleidoscope, eg with `extern sin(x)`, the
+    /// process symbols must be enabled on the `jit` with [`LLJit::enable_process_symbols`].
+    pub fn new(jit: &'jit LLJit) -> Self {
+        Self::with_options(jit, CodegenOptions::default())
+    }
+
+    /// Create a new session emitting code into `jit` using the codegen options `opts`.
+    pub fn with_options(jit: &'jit LLJit, opts: CodegenOptions) -> Self {
+        let mut backend = JitBackend::new(jit, opts);
+        backend.set_dump_ir(false);
+        Session { backend }
+    }
+
+    /// Compile and evaluate all items in `src` in order.
+    ///
+    /// Return the result of the last top-level expression, or `None` if `src` only contains
+    /// definitions and externs. Evaluation stops at the first error.
+    pub fn eval(&mut self, src: &str) -> SessionResult<Option<f64>> {
+        self.run(src, true)
+    }
+
+    /// Compile the function definitions and externs in `src`.
+    ///
+    /// Return an error if `src` contains a top-level expression, in which case the items before
+    /// the expression are already defined.
+    pub fn define(&mut self, src: &str) -> SessionResult<()> {
+        self.run(src, false).map(drop)
+    }
+
+    /// Register the Rust function `f` as native function `name`, which kaleidoscope code can call
+    /// without an `extern` declaration.
+    ///
+    /// The arity of the native function is given by the signature of `f`.
+    ///
+    /// ```no_run
+    /// # use llvm_kaleidoscope_rs::{llvm::LLJit, session::Session};
+    /// extern "C" fn plot(x: f64, y: f64) -> f64 {
+    ///     println!("({}, {})", x, y);
+    ///     0.0
+    /// }
+    ///
+    /// # let jit = LLJit::new();
+    /// # let mut session = Session::new(&jit);
+    /// session
+    ///     .register_native("plot", plot as extern "C" fn(f64, f64) -> f64)
+    ///     .unwrap();
+    /// session.eval("for x = 0, x < 10 in plot(x, x * x)").unwrap();
+    /// ```
+    pub fn register_native<F: JitFn>(&mut self, name: &str, f: F) -> SessionResult<()> {
+        // SAFETY: The signature of `f` matches the arity and function pointers are valid for the
+        // whole program.
+        unsafe { self.backend.register_native(name, F::ARITY, f.addr()) }
+            .map_err(SessionError::from)
+    }
+
+    /// Get the backend of the session.
+    pub fn backend(&self) -> &JitBackend<'jit> {
+        &self.backend
+    }
+
+    /// Get mutable access to the backend of the session, eg to inspect statistics.
+    pub fn backend_mut(&mut self) -> &mut JitBackend<'jit> {
+        &mut self.backend
+    }
+
+    fn run(&mut self, src: &str, allow_exprs: bool) -> SessionResult<Option<f64>> {
+        let mut parser = Parser::new(Lexer::new(src.chars()));
+        for proto in self.backend.operators() {
+            parser.declare_operator(&proto);
+        }
+
+        let mut res = None;
+        for item in parser {
+            let item = item?;
+            let span = item.span();
+            let expr = matches!(item, Item::TopLevel(_));
+            if expr && !allow_exprs {
+                return Err(SessionError {
+                    msg: "Expected definition or extern, found expression".into(),
+                    span: Some(span),
+                });
+            }
+
+            // Like the driver, locate errors without a more precise location at the whole item.
+            let item_res = compile_item(&mut self.backend, item).map_err(|err| SessionError {
+                msg: err.msg,
+                span: Some(err.span.unwrap_or(span)),
+            })?;
+            if expr {
+                res = item_res;
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Argument tuples of the functions looked up in a [`CompiledProgram`], from `()` up to four
+/// `f64` arguments.
+pub trait Args {
+    /// Number of arguments.
+    const ARITY: usize;
+
+    /// Signature of the functions taking these arguments.
+    type Fn: JitFn + Copy + 'static;
+
+    /// Call the function `f` with these arguments.
+    ///
+    /// # Safety
+    ///
+    /// `f` must point to a function in the JIT which is still alive.
+    unsafe fn call(self, f: Self::Fn) -> f64;
+}
+
+macro_rules! impl_args {
+    ($arity:expr; $($arg:ident: $ty:ty),*) => {
+        impl Args for ($($ty,)*) {
+            const ARITY: usize = $arity;
+            type Fn = unsafe extern "C" fn($($ty),*) -> f64;
+
+            unsafe fn call(self, f: Self::Fn) -> f64 {
+                let ($($arg,)*) = self;
+                f($($arg),*)
+            }
+        }
+    };
+}
+
+impl_args!(0;);
+impl_args!(1; a: f64);
+impl_args!(2; a: f64, b: f64);
+impl_args!(3; a: f64, b: f64, c: f64);
+impl_args!(4; a: f64, b: f64, c: f64, d: f64);
+
+/// Kaleidoscope program compiled with its own LLVM JIT, for embedding kaleidoscope functions into
+/// a Rust host.
+///
+/// Unlike a [`Session`], the program owns its JIT. The functions defined in the program are
+/// looked up as typed closures with [`CompiledProgram::function`], which keep the program
+/// borrowed, such that their code can't be removed while they are in use.
+pub struct CompiledProgram {
+    // The session borrows the JIT, hence it must be dropped before the JIT.
+    session: Session<'static>,
+    jit: Box<LLJit>,
+}
+
+impl CompiledProgram {
+    /// Compile the program `src`, running its top-level expressions.
+    ///
+    /// The functions of the host process can be called from the program, eg with
+    /// `extern sin(x)`.
+    pub fn new(src: &str) -> SessionResult<CompiledProgram> {
+        llvm::initialize_native_taget();
+
+        let jit = Box::new(LLJit::new());
+        jit.enable_process_symbols();
+
+        // SAFETY: The JIT lives on the heap, hence it doesn't move with the program, and it is
+        // dropped after the session borrowing it. The session is never handed out with the
+        // 'static lifetime.
+        let jit_ref = unsafe { &*(jit.as_ref() as *const LLJit) };
+        let session = Session::new(jit_ref);
+
+        let mut program = CompiledProgram { session, jit };
+        program.eval(src)?;
+        Ok(program)
+    }
+
+    /// Compile and evaluate all items in `src` in order, defining or redefining functions of the
+    /// program.
+    ///
+    /// Return the result of the last top-level expression, see [`Session::eval`].
+    pub fn eval(&mut self, src: &str) -> SessionResult<Option<f64>> {
+        self.session.eval(src)
+    }
+
+    /// Look up the function `name` as closure taking the arguments `A`, eg `(f64, f64)` for a
+    /// function with two parameters.
+    ///
+    /// Return an error if the function is not defined or takes a different number of arguments.
+    pub fn function<A: Args>(&self, name: &str) -> SessionResult<impl Fn(A) -> f64 + '_> {
+        let f = self.session.backend().lookup::<A::Fn>(name, A::ARITY)?;
+
+        // SAFETY: The signature matches the prototype of the function and the closure borrows the
+        // program, which keeps the code of the function in the JIT.
+        Ok(move |args: A| unsafe { args.call(f) })
+    }
+
+    /// Register the Rust function `f` as native function `name` of the program, see
+    /// [`Session::register_native`].
+    ///
+    /// Natives must be registered before the source using them is evaluated, hence the program
+    /// is usually created from empty source first.
+    pub fn register_native<F: JitFn>(&mut self, name: &str, f: F) -> SessionResult<()> {
+        self.session.register_native(name, f)
+    }
+
+    /// Get the JIT the program is compiled into.
+    pub fn jit(&self) -> &LLJit {
+        &self.jit
+    }
+}
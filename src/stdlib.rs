@@ -0,0 +1,68 @@
+//! Standard library of kaleidoscope, loaded into the sessions of the JIT. Output files of
+//! `--emit` and `--aot` don't include it.
+//!
+//! Compiling the library on every start delays the first prompt, hence the compiled library is
+//! cached as LLVM bitcode, see [`JitBackend::load_stdlib`](crate::backend::JitBackend::load_stdlib).
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use crate::codegen::OptLevel;
+
+/// Source of the standard library.
+pub const SOURCE: &str = include_str!("../ks/std.ks");
+
+/// Default directory of the compiled library cache, `$XDG_CACHE_HOME/kaleidoscope` or
+/// `$HOME/.cache/kaleidoscope`.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache.join("kaleidoscope"))
+}
+
+/// Name of the cache file of the library compiled at the optimization level `opt_level` for the
+/// target `triple`.
+///
+/// The name depends on the library source, the crate version, the target and the optimization
+/// level, such that a stale cache is not picked up after an update or by another configuration.
+/// A cache written with another LLVM version is rejected when it is loaded.
+pub fn cache_file_name(opt_level: OptLevel, triple: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    SOURCE.hash(&mut hasher);
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    triple.hash(&mut hasher);
+    format!("std-{:?}-{:016x}.bc", opt_level, hasher.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cache_file_name, SOURCE};
+    use crate::codegen::OptLevel;
+    use crate::parser::{parse_items, Item};
+
+    #[test]
+    fn parse_stdlib() {
+        let items = parse_items(SOURCE).unwrap();
+        assert!(items.iter().all(|item| matches!(item, Item::Def(_))));
+    }
+
+    #[test]
+    fn cache_file_per_configuration() {
+        let x86 = "x86_64-pc-linux-gnu";
+        assert_ne!(
+            cache_file_name(OptLevel::O0, x86),
+            cache_file_name(OptLevel::O2, x86)
+        );
+        assert_ne!(
+            cache_file_name(OptLevel::O2, x86),
+            cache_file_name(OptLevel::O2, "aarch64-unknown-linux-gnu")
+        );
+        assert_eq!(
+            cache_file_name(OptLevel::O2, x86),
+            cache_file_name(OptLevel::O2, x86)
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Type checking of the AST before codegen.
+//!
+//! Kaleidoscope only has the type `double` so far, hence checking an item boils down to resolving
+//! its names: each variable must be in scope, each callee must be known and must be passed as
+//! many arguments as it takes. Checking items before codegen reports these errors with the
+//! offending name instead of leaving a partially emitted function behind.
+//!
+//! Further types, eg integers or booleans, extend [`Type`] and the rules in [`check_expr`].
+
+use std::fmt;
+
+use crate::codegen::FnProtos;
+use crate::parser::{binary_fn_name, unary_fn_name, ExprAST, FunctionAST, PrototypeAST};
+
+/// Type of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    /// 64 bit floating point number.
+    Double,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Double => f.write_str("double"),
+        }
+    }
+}
+
+/// Result type of the type checker with String as Error type.
+pub type TypeResult<T> = Result<T, String>;
+
+/// Built-in binary operators, other operators are calls of user-defined operator functions.
+pub(crate) const BINOPS: &[&str] = &["+", "-", "*", "%", "^", "<", ">", "<=", ">=", "==", "!="];
+
+/// Built-in unary operators, other operators are calls of user-defined operator functions.
+pub(crate) const UNOPS: &[&str] = &["-"];
+
+/// Print-style builtins, which accept string literals as arguments in addition to numbers.
+pub const PRINT_BUILTINS: &[&str] = &["printd", "putchard"];
+
+/// Check the function definition `func` against the prototypes `fn_protos` of the functions
+/// defined so far and return the type of its body.
+///
+/// The function may call itself.
+pub fn check_function(func: &FunctionAST, fn_protos: &FnProtos) -> TypeResult<Type> {
+    let FunctionAST(proto, body) = func;
+    let lookup = |name: &str| {
+        if name == proto.0 {
+            Some(&proto.node)
+        } else {
+            fn_protos.get(name).map(|p| p.proto.as_ref())
+        }
+    };
+
+    let mut scope: Vec<&str> = proto.1.iter().map(String::as_str).collect();
+    check_expr(body, &mut scope, &lookup)
+}
+
+/// Check the expression `expr` with the variables in `scope`, looking up callees with `lookup`,
+/// and return its type.
+pub fn check_expr<'a, L>(
+    expr: &'a ExprAST,
+    scope: &mut Vec<&'a str>,
+    lookup: &L,
+) -> TypeResult<Type>
+where
+    L: Fn(&str) -> Option<&'a PrototypeAST>,
+{
+    match expr {
+        ExprAST::Number(_) | ExprAST::Break | ExprAST::Continue => Ok(Type::Double),
+        ExprAST::Variable(name) if scope.contains(&name.as_str()) => Ok(Type::Double),
+        ExprAST::Variable(name) if lookup(name).is_some() => Err(format!(
+            "function '{}' can't be used as a value, function pointers are not supported yet",
+            name
+        )),
+        ExprAST::Variable(name) => Err(format!("unknown variable '{}'", name)),
+        ExprAST::Str(_) => Err(format!(
+            "strings can only be passed to the builtins {}",
+            PRINT_BUILTINS.join(", ")
+        )),
+        ExprAST::Unary(op, operand) => {
+            if !UNOPS.contains(&op.as_str()) && lookup(&unary_fn_name(op)).is_none() {
+                return Err(format!("unknown unary operator '{}'", op));
+            }
+            expect(Type::Double, check_expr(operand, scope, lookup)?)?;
+            Ok(Type::Double)
+        }
+        ExprAST::Binary(op, lhs, rhs) => {
+            if !BINOPS.contains(&op.as_str()) && lookup(&binary_fn_name(op)).is_none() {
+                return Err(format!("unknown binary operator '{}'", op));
+            }
+            expect(Type::Double, check_expr(lhs, scope, lookup)?)?;
+            expect(Type::Double, check_expr(rhs, scope, lookup)?)?;
+            Ok(Type::Double)
+        }
+        ExprAST::Logical(_, lhs, rhs) => {
+            expect(Type::Double, check_expr(lhs, scope, lookup)?)?;
+            expect(Type::Double, check_expr(rhs, scope, lookup)?)?;
+            Ok(Type::Double)
+        }
+        ExprAST::Call(callee, args) => {
+            let proto = lookup(callee).ok_or_else(|| format!("unknown function '{}'", callee))?;
+            if proto.1.len() != args.len() {
+                return Err(format!(
+                    "type mismatch: function '{}' takes {} argument(s), found {}",
+                    callee,
+                    proto.1.len(),
+                    args.len()
+                ));
+            }
+            for arg in args {
+                if matches!(arg.node, ExprAST::Str(_)) && PRINT_BUILTINS.contains(&callee.as_str())
+                {
+                    continue;
+                }
+                expect(Type::Double, check_expr(arg, scope, lookup)?)?;
+            }
+            Ok(Type::Double)
+        }
+        ExprAST::If { cond, then, else_ } => {
+            expect(Type::Double, check_expr(cond, scope, lookup)?)?;
+            let then = check_expr(then, scope, lookup)?;
+            expect(then, check_expr(else_, scope, lookup)?)?;
+            Ok(then)
+        }
+        ExprAST::For {
+            var,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            expect(Type::Double, check_expr(start, scope, lookup)?)?;
+
+            // The loop variable is in scope for all but the start expression.
+            scope.push(var);
+            let res = (|| {
+                expect(Type::Double, check_expr(end, scope, lookup)?)?;
+                if let Some(step) = step {
+                    expect(Type::Double, check_expr(step, scope, lookup)?)?;
+                }
+                check_expr(body, scope, lookup)
+            })();
+            scope.pop();
+
+            // A for loop always evaluates to 0.0.
+            res.map(|_| Type::Double)
+        }
+        ExprAST::VarIn { vars, body } => {
+            // Each variable is in scope from the initializer of the next variable on.
+            let depth = scope.len();
+            let res = (|| {
+                for (var, init) in vars {
+                    if let Some(init) = init {
+                        expect(Type::Double, check_expr(init, scope, lookup)?)?;
+                    }
+                    scope.push(var);
+                }
+                check_expr(body, scope, lookup)
+            })();
+            scope.truncate(depth);
+            res
+        }
+        ExprAST::Assign { name, value } => {
+            if !scope.contains(&name.as_str()) {
+                return Err(format!("unknown variable '{}'", name));
+            }
+            expect(Type::Double, check_expr(value, scope, lookup)?)?;
+            Ok(Type::Double)
+        }
+        ExprAST::Match {
+            value,
+            arms,
+            default,
+        } => {
+            expect(Type::Double, check_expr(value, scope, lookup)?)?;
+            let ty = check_expr(default, scope, lookup)?;
+            for (_, arm) in arms {
+                expect(ty, check_expr(arm, scope, lookup)?)?;
+            }
+            Ok(ty)
+        }
+        ExprAST::Lambda(..) => {
+            Err("lambda must be lifted into a function before type checking".into())
+        }
+    }
+}
+
+/// Check that `found` is the `expected` type.
+fn expect(expected: Type, found: Type) -> TypeResult<()> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(format!(
+            "type mismatch: expected {}, found {}",
+            expected, found
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_function, Type};
+    use crate::codegen::{FnProto, FnProtos, ProtoOrigin};
+    use crate::parser::{parse_items, Item, PrototypeAST};
+    use std::rc::Rc;
+
+    fn check(src: &str) -> Result<Type, String> {
+        let mut fn_protos = FnProtos::new();
+        for name in ["sin", "printd"] {
+            let proto = PrototypeAST(name.into(), vec!["x".into()], 0);
+            let fn_proto = FnProto {
+                proto: Rc::new(proto),
+                origin: ProtoOrigin::Extern,
+            };
+            fn_protos.insert(name.into(), fn_proto);
+        }
+
+        match parse_items(src).unwrap().pop() {
+            Some(Item::Def(func)) | Some(Item::TopLevel(func)) => check_function(&func, &fn_protos),
+            _ => panic!("Expected a function in '{}'", src),
+        }
+    }
+
+    #[test]
+    fn check_names() {
+        assert_eq!(check("def f(x) sin(x) + f(x - 1)"), Ok(Type::Double));
+        assert_eq!(
+            check("def f(x) for i = 0, i < x in i * x"),
+            Ok(Type::Double)
+        );
+        assert_eq!(check("def f(x) y"), Err("unknown variable 'y'".into()));
+        assert_eq!(
+            check("def f(x) (for i = i, i < x in 0) + i"),
+            Err("unknown variable 'i'".into())
+        );
+        assert_eq!(check("var a = 1, b = a in a + b"), Ok(Type::Double));
+        assert_eq!(check("var a = a in a"), Err("unknown variable 'a'".into()));
+        assert_eq!(check("def f(x) x = x + 1"), Ok(Type::Double));
+        assert_eq!(check("def f(x) y = x"), Err("unknown variable 'y'".into()));
+        assert_eq!(check("g(1)"), Err("unknown function 'g'".into()));
+        assert_eq!(
+            check("sin + 1"),
+            Err(
+                "function 'sin' can't be used as a value, function pointers are not supported yet"
+                    .into()
+            )
+        );
+        assert_eq!(check("printd(\"x = \")"), Ok(Type::Double));
+        assert_eq!(
+            check("sin(\"x\")"),
+            Err("strings can only be passed to the builtins printd, putchard".into())
+        );
+        assert_eq!(check("!1"), Err("unknown unary operator '!'".into()));
+        assert_eq!(check("def unary!(x) !(x - 1)"), Ok(Type::Double));
+        assert_eq!(
+            check("sin(1, 2)"),
+            Err("type mismatch: function 'sin' takes 1 argument(s), found 2".into())
+        );
+    }
+}
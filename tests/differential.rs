@@ -0,0 +1,82 @@
+//! Differential test running random programs through the interpreter and the LLVM JIT.
+//!
+//! The number of programs defaults to 200 and can be changed with `KALEIDOSCOPE_DIFF_PROGRAMS`.
+//! A failing program is printed together with its seed.
+
+mod support;
+
+use llvm_kaleidoscope_rs::backend::{Backend, BackendResult, InterpBackend, JitBackend};
+use llvm_kaleidoscope_rs::codegen::{CodegenOptions, OptLevel};
+use llvm_kaleidoscope_rs::llvm::{self, LLJit};
+
+use support::gen::{self, Config, Program, Rng};
+
+/// Run `program` with `backend` and return the results of the top-level expressions.
+fn run(backend: &mut dyn Backend, program: &Program) -> BackendResult<Vec<f64>> {
+    for func in &program.defs {
+        backend.compile_function(func)?;
+    }
+    program
+        .exprs
+        .iter()
+        .map(|func| {
+            backend
+                .call_top_level(func)
+                .map(|res| res.unwrap_or(f64::NAN))
+        })
+        .collect()
+}
+
+/// Results match if they are bitwise equal or both NaN.
+fn same(a: &[f64], b: &[f64]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()))
+}
+
+#[test]
+fn interpreter_matches_jit() {
+    llvm::initialize_native_taget();
+
+    let programs = std::env::var("KALEIDOSCOPE_DIFF_PROGRAMS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(200);
+    let config = Config::default();
+
+    for seed in 0..programs {
+        let program = gen::program(&mut Rng::new(seed), &config);
+        let expected = run(&mut InterpBackend::new(), &program).unwrap_or_else(|err| {
+            panic!("seed {}: interpreter failed: {}\n{}", seed, err, program)
+        });
+
+        for (opt_level, fold) in [
+            (OptLevel::O0, false),
+            (OptLevel::O2, false),
+            (OptLevel::O0, true),
+        ] {
+            let jit = LLJit::new();
+            let opts = CodegenOptions {
+                opt_level,
+                fold,
+                ..Default::default()
+            };
+            let mut backend = JitBackend::new(&jit, opts);
+            backend.set_dump_ir(false);
+
+            let actual = run(&mut backend, &program)
+                .unwrap_or_else(|err| panic!("seed {}: JIT failed: {}\n{}", seed, err, program));
+            assert!(
+                same(&expected, &actual),
+                "seed {}: interpreter returned {:?}, JIT at {:?} (fold: {}) returned {:?}\n{}",
+                seed,
+                expected,
+                opt_level,
+                fold,
+                actual,
+                program
+            );
+        }
+    }
+}
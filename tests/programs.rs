@@ -0,0 +1,86 @@
+//! Golden tests running every `tests/programs/*.ks` through the driver.
+//!
+//! For each program `name.ks` the printed results are compared against `name.out`. If
+//! `name.ll` exists, the LLVM IR emitted at `-O0` is compared against it as well.
+//!
+//! Run with `UPDATE_EXPECT=1 cargo test --test programs` to update the expectations.
+
+mod support;
+
+use std::path::Path;
+
+#[test]
+fn golden_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let programs = support::programs(&dir);
+    assert!(!programs.is_empty(), "No programs in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for program in &programs {
+        let file = program.to_string_lossy();
+
+        let run = support::run_driver(&[&file]);
+        if !run.success {
+            failures.push(format!("{}: failed\n{}", file, run.stderr));
+            continue;
+        }
+        failures.extend(support::check_expect(
+            &program.with_extension("out"),
+            &run.stdout,
+        ));
+
+        let ll = program.with_extension("ll");
+        if ll.exists() {
+            let tmp = std::env::temp_dir().join(format!(
+                "kaleidoscope-golden-{}-{}.ll",
+                std::process::id(),
+                program.file_stem().unwrap().to_string_lossy()
+            ));
+            let tmp_file = tmp.to_string_lossy();
+
+            let run = support::run_driver(&["-O0", "--emit=ir", "-o", &tmp_file, &file]);
+            let ir = std::fs::read_to_string(&tmp).unwrap_or_default();
+            let _ = std::fs::remove_file(&tmp);
+
+            if !run.success {
+                failures.push(format!("{}: --emit=ir failed\n{}", file, run.stderr));
+                continue;
+            }
+            failures.extend(support::check_expect(&ll, &support::normalize_ir(&ir)));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn codegen_errors_point_at_expression() {
+    // Emitting IR skips the type checker of the JIT, such that codegen reports the error.
+    let tmp = std::env::temp_dir().join(format!("kaleidoscope-error-{}.ll", std::process::id()));
+    let tmp_file = tmp.to_string_lossy();
+
+    let run = support::run_driver(&["--emit=ir", "-o", &tmp_file, "-e", "def f(x) x + g(y)"]);
+    let _ = std::fs::remove_file(&tmp);
+
+    // The caret underlines the call instead of the whole function.
+    let expected = "<cmdline>:1:14: error: Unknown function referenced\n\
+                    def f(x) x + g(y)\n\
+                    \x20            ^~~~";
+    assert!(!run.success);
+    assert!(run.stderr.contains(expected), "{}", run.stderr);
+}
+
+#[test]
+fn emit_many_top_level_expressions() {
+    let tmp = std::env::temp_dir().join(format!("kaleidoscope-many-{}.ll", std::process::id()));
+    let tmp_file = tmp.to_string_lossy();
+
+    // From the 1000th expression on the names of the anonymous functions exceed a small C string.
+    let program = (0..1100).map(|i| format!("{};", i)).collect::<String>();
+    let run = support::run_driver(&["--emit=ir", "-o", &tmp_file, "-e", &program]);
+    let ir = std::fs::read_to_string(&tmp).unwrap_or_default();
+    let _ = std::fs::remove_file(&tmp);
+
+    assert!(run.success, "{}", run.stderr);
+    assert!(ir.contains("@__anon_expr.1099()"), "{}", ir);
+}
@@ -0,0 +1,12 @@
+define double @sq(double %x) {
+entry:
+  %multmp = fmul double %x, %x
+  ret double %multmp
+}
+
+define double @lt(double %a, double %b) {
+entry:
+  %cmptmp = fcmp ult double %a, %b
+  %booltmp = uitofp i1 %cmptmp to double
+  ret double %booltmp
+}
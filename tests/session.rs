@@ -0,0 +1,153 @@
+//! Tests of the embeddable [`Session`], which keeps its state across calls.
+
+use llvm_kaleidoscope_rs::lexer::Span;
+use llvm_kaleidoscope_rs::llvm::{self, Context, LLJit, Module};
+use llvm_kaleidoscope_rs::session::Session;
+use llvm_kaleidoscope_rs::stdlib;
+
+#[test]
+fn operator_defined_in_previous_call() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+
+    session
+        .define("def binary| 5 (a b) if a then 1 else if b then 1 else 0")
+        .unwrap();
+    // `|` binds weaker than `<`, which has precedence 10.
+    assert_eq!(session.eval("0 | 2 < 1").unwrap(), Some(0.0));
+    assert_eq!(session.eval("1 | 0").unwrap(), Some(1.0));
+}
+
+#[test]
+fn undefined_symbol_is_an_error() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+
+    assert!(session.eval("extern nope(); nope()").is_err());
+    // The session is still usable after the failed lookup.
+    assert_eq!(session.eval("1 + 2").unwrap(), Some(3.0));
+}
+
+#[test]
+fn errors_are_located() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+
+    // Parse errors point at the offending token.
+    let err = session.eval("1; def f(x) x + )").unwrap_err();
+    assert_eq!(err.span, Some(Span::new(16, 17)), "{}", err);
+
+    // Errors without a more precise location point at the whole item.
+    let err = session.eval("1; nope(1)").unwrap_err();
+    assert_eq!(err.msg, "unknown function 'nope'");
+    assert_eq!(err.span, Some(Span::new(3, 10)));
+
+    let err = session.define("def f(x) x; f(1)").unwrap_err();
+    assert_eq!(err.span, Some(Span::new(12, 16)), "{}", err);
+    assert_eq!(session.eval("f(2)").unwrap(), Some(2.0));
+}
+
+#[test]
+fn many_top_level_expressions() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+
+    // From the 1000th expression on the names of the anonymous functions exceed a small C string.
+    for i in 0..1100 {
+        assert_eq!(
+            session.eval(&format!("{} + 1", i)).unwrap(),
+            Some(i as f64 + 1.0)
+        );
+    }
+}
+
+#[test]
+fn lambdas_are_lifted() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+
+    session
+        .define("def twice(x) var f = \\(y) y * 2 in f(f(x))")
+        .unwrap();
+    assert_eq!(session.eval("twice(3)").unwrap(), Some(12.0));
+    assert_eq!(
+        session.eval("var g = \\(a b) a - b in g(5, 2)").unwrap(),
+        Some(3.0)
+    );
+
+    // Lambdas can't be used as values yet, nothing is compiled for them.
+    let err = session.define("def h() \\(x) x").unwrap_err();
+    assert!(
+        err.msg.contains("function pointers are not supported yet"),
+        "{}",
+        err
+    );
+    assert!(!session.backend().session_ir().contains("__lambda.2"));
+}
+
+#[test]
+fn deferred_definitions_are_checked() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+    session.backend_mut().set_lazy_definitions(true);
+
+    let err = session.define("extern f(a); def f(a b) a").unwrap_err();
+    assert!(
+        err.msg.starts_with("Conflicting declaration of 'f'"),
+        "{}",
+        err
+    );
+    let err = session.define("def g(a) f(a, a)").unwrap_err();
+    assert!(err.msg.starts_with("type mismatch"), "{}", err);
+
+    session.define("def h(a) f(a) + 1").unwrap();
+}
+
+#[test]
+fn invalid_stdlib_cache_is_replaced() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let dir = std::env::temp_dir().join(format!("kaleidoscope-cache-{}", std::process::id()));
+    let cache = dir.join(stdlib::cache_file_name(Default::default(), &jit.triple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let invalid = b"BC\xc0\xdenot bitcode";
+    std::fs::write(&cache, invalid).unwrap();
+
+    let mut session = Session::new(&jit);
+    session.backend_mut().load_stdlib(Some(&dir)).unwrap();
+    assert_eq!(session.eval("max(1, abs(0 - 3))").unwrap(), Some(3.0));
+
+    // The library is compiled again and the cache overwritten.
+    let written = std::fs::read(&cache).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_ne!(written, invalid);
+}
+
+#[test]
+fn session_ir_parses_back() {
+    llvm::initialize_native_taget();
+    let jit = LLJit::new();
+    let mut session = Session::new(&jit);
+
+    session
+        .define("def binary| 5 (a b) a; def unary!(x) 0 - x; extern sin(x)")
+        .unwrap();
+    session.define("def f(x) !x | x ^ 2 + sin(x)").unwrap();
+
+    let ir = session.backend().session_ir();
+    assert!(ir.contains("@\"binary|\""), "{}", ir);
+    assert!(
+        ir.contains("declare double @llvm.pow.f64(double, double)"),
+        "{}",
+        ir
+    );
+
+    let module = Module::parse_ir(&Context::new(), &ir).unwrap();
+    module.verify().unwrap();
+}
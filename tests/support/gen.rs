@@ -0,0 +1,191 @@
+//! Generator of random well-formed kaleidoscope programs for property style tests.
+//!
+//! Generated programs always terminate: functions only call functions defined before them and
+//! loops count up to a small constant bound.
+
+use llvm_kaleidoscope_rs::parser::{
+    Expr, ExprAST, FunctionAST, LogicalOp, PrototypeAST, ANON_EXPR,
+};
+
+/// Deterministic pseudo random number generator (xorshift64*), such that a failing program can
+/// be reproduced from its seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The state must not be zero.
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Random number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Limits of the generated programs.
+pub struct Config {
+    /// Maximum number of function definitions.
+    pub max_fns: usize,
+    /// Maximum number of parameters of a function.
+    pub max_params: usize,
+    /// Maximum nesting depth of expressions.
+    pub max_depth: usize,
+    /// Number of top-level expressions.
+    pub exprs: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_fns: 5,
+            max_params: 3,
+            max_depth: 4,
+            exprs: 3,
+        }
+    }
+}
+
+/// Generated program, the definitions are followed by the top-level expressions.
+pub struct Program {
+    pub defs: Vec<FunctionAST>,
+    pub exprs: Vec<FunctionAST>,
+}
+
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for func in self.defs.iter().chain(&self.exprs) {
+            writeln!(f, "{};", func)?;
+        }
+        Ok(())
+    }
+}
+
+struct Gen<'a> {
+    rng: &'a mut Rng,
+    config: &'a Config,
+    /// Prototypes of the functions defined so far.
+    protos: Vec<PrototypeAST>,
+    /// Variables in scope.
+    scope: Vec<String>,
+}
+
+impl Gen<'_> {
+    fn number(&mut self) -> Expr {
+        // Small multiples of 0.5, exactly representable and prone to hit comparison edge cases.
+        ExprAST::Number(self.rng.below(21) as f64 * 0.5 - 5.0).into()
+    }
+
+    fn leaf(&mut self) -> Expr {
+        if self.scope.is_empty() || self.rng.below(3) == 0 {
+            self.number()
+        } else {
+            let var = self.scope[self.rng.below(self.scope.len())].clone();
+            ExprAST::Variable(var).into()
+        }
+    }
+
+    fn expr(&mut self, depth: usize) -> Expr {
+        if depth == 0 {
+            return self.leaf();
+        }
+
+        let boxed = |g: &mut Self| Box::new(g.expr(depth - 1));
+        let expr = match self.rng.below(8) {
+            0 | 1 => return self.leaf(),
+            2 | 3 => {
+                const OPS: &[&str] = &["+", "-", "*", "<", ">", "<=", ">=", "==", "!="];
+                let op = OPS[self.rng.below(OPS.len())];
+                ExprAST::Binary(op.into(), boxed(self), boxed(self))
+            }
+            4 if !self.protos.is_empty() => {
+                let proto = self.protos[self.rng.below(self.protos.len())].clone();
+                let args = proto.1.iter().map(|_| self.expr(depth - 1)).collect();
+                ExprAST::Call(proto.0, args)
+            }
+            5 => ExprAST::If {
+                cond: boxed(self),
+                then: boxed(self),
+                else_: boxed(self),
+            },
+            6 => {
+                // Count up to a small bound, the loop variable may shadow another variable.
+                let var = format!("i{}", self.rng.below(2));
+                let start = Box::new(self.number());
+                let bound = Box::new(ExprAST::Number(self.rng.below(5) as f64).into());
+                let end = Box::new(
+                    ExprAST::Binary(
+                        "<".into(),
+                        Box::new(ExprAST::Variable(var.clone()).into()),
+                        bound,
+                    )
+                    .into(),
+                );
+                let step = match self.rng.below(2) {
+                    0 => None,
+                    _ => Some(Box::new(
+                        ExprAST::Number(self.rng.below(3) as f64 * 0.5 + 0.5).into(),
+                    )),
+                };
+
+                self.scope.push(var.clone());
+                let body = boxed(self);
+                self.scope.pop();
+
+                ExprAST::For {
+                    var,
+                    start,
+                    end,
+                    step,
+                    body,
+                }
+            }
+            7 => {
+                let op = [LogicalOp::And, LogicalOp::Or][self.rng.below(2)];
+                ExprAST::Logical(op, boxed(self), boxed(self))
+            }
+            _ => ExprAST::Binary("+".into(), boxed(self), boxed(self)),
+        };
+        expr.into()
+    }
+}
+
+/// Generate a random program within the limits of `config`.
+pub fn program(rng: &mut Rng, config: &Config) -> Program {
+    let mut gen = Gen {
+        rng,
+        config,
+        protos: Vec::new(),
+        scope: Vec::new(),
+    };
+
+    let mut defs = Vec::new();
+    for n in 0..gen.rng.below(gen.config.max_fns + 1) {
+        let params: Vec<String> = (0..gen.rng.below(gen.config.max_params + 1))
+            .map(|p| format!("p{}", p))
+            .collect();
+        let proto = PrototypeAST(format!("f{}", n), params.clone(), 0);
+
+        gen.scope = params;
+        let body = gen.expr(gen.config.max_depth);
+        gen.protos.push(proto.clone());
+        defs.push(FunctionAST(proto.into(), body));
+    }
+
+    gen.scope.clear();
+    let exprs = (0..gen.config.exprs)
+        .map(|_| {
+            let proto = PrototypeAST(ANON_EXPR.into(), Vec::new(), 0);
+            FunctionAST(proto.into(), gen.expr(gen.config.max_depth))
+        })
+        .collect();
+
+    Program { defs, exprs }
+}
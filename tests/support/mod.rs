@@ -0,0 +1,87 @@
+//! Support for the end-to-end tests of kaleidoscope programs.
+//!
+//! Expectations are checked-in files next to the programs. Running the tests with
+//! `UPDATE_EXPECT=1` writes the actual output to the expectation files instead of comparing.
+
+// Each test binary only uses parts of the support module.
+#![allow(dead_code)]
+
+pub mod gen;
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Output of a run of the driver.
+pub struct Run {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Run the driver binary with the arguments `args`, without the standard library such that the
+/// results don't depend on it or its cache.
+pub fn run_driver(args: &[&str]) -> Run {
+    let out = Command::new(env!("CARGO_BIN_EXE_llvm-kaleidoscope-rs"))
+        .arg("--no-std")
+        .args(args)
+        .env_remove("KALEIDOSCOPE_OPT_LEVEL")
+        .env_remove("KALEIDOSCOPE_DUMP_IR")
+        .env_remove("KALEIDOSCOPE_TARGET")
+        .output()
+        .expect("Failed to run the driver binary");
+
+    Run {
+        success: out.status.success(),
+        stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+    }
+}
+
+/// All kaleidoscope programs in `dir`, sorted by name.
+pub fn programs(dir: &Path) -> Vec<PathBuf> {
+    let mut programs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", dir.display(), err))
+        .map(|entry| entry.expect("Failed to read directory entry").path())
+        .filter(|path| path.extension() == Some(OsStr::new("ks")))
+        .collect();
+    programs.sort();
+    programs
+}
+
+/// Strip the parts of textual LLVM IR which depend on the host or the file name, ie comments,
+/// the source file name and the target description, as well as trailing blank lines.
+pub fn normalize_ir(ir: &str) -> String {
+    let lines: Vec<&str> = ir
+        .lines()
+        .filter(|l| {
+            !(l.starts_with(';') || l.starts_with("source_filename") || l.starts_with("target "))
+        })
+        .collect();
+    format!("{}\n", lines.join("\n").trim())
+}
+
+/// Compare `actual` with the expectation file `expect`, or update the file if the environment
+/// variable `UPDATE_EXPECT` is set.
+///
+/// Return a description of the mismatch, if any.
+pub fn check_expect(expect: &Path, actual: &str) -> Option<String> {
+    if std::env::var_os("UPDATE_EXPECT").is_some() {
+        std::fs::write(expect, actual)
+            .unwrap_or_else(|err| panic!("Failed to write {}: {}", expect.display(), err));
+        return None;
+    }
+
+    let expected = std::fs::read_to_string(expect)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", expect.display(), err));
+    if expected == actual {
+        None
+    } else {
+        Some(format!(
+            "{}: mismatch\n--- expected\n{}--- actual\n{}",
+            expect.display(),
+            expected,
+            actual
+        ))
+    }
+}